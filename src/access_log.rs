@@ -0,0 +1,68 @@
+use crate::{request_id, telemetry};
+use std::{future::Future, net::SocketAddr, time::Duration};
+use tracing::{field, Instrument, Span};
+use warp::http::{Method, StatusCode};
+
+tokio::task_local! {
+    static REQUEST_SPAN: Span;
+}
+
+/// Records the authenticated caller on the current request's span.
+pub fn set_uid(uid: &str) {
+    REQUEST_SPAN
+        .try_with(|span| {
+            span.record("uid", uid);
+        })
+        .ok();
+}
+
+/// Records the error code the current request was answered with.
+pub fn set_error(code: &'static str) {
+    REQUEST_SPAN
+        .try_with(|span| {
+            span.record("error", code);
+        })
+        .ok();
+}
+
+/// Runs `future`, the handling of one request, inside a root span carrying
+/// the request ID, which every event and handler span for the request
+/// nests under. When traces are exported it continues `parent`, the
+/// caller's trace, if any. The span is returned alongside the output for
+/// [`log`].
+///
+/// `path` should not include the query string, which may carry one-time
+/// tokens. Request bodies, and so passwords, are never recorded.
+pub async fn scope<F: Future>(
+    remote: Option<SocketAddr>,
+    method: &Method,
+    path: &str,
+    parent: opentelemetry::Context,
+    future: F,
+) -> (Span, F::Output) {
+    let span = tracing::info_span!(
+        "request",
+        request_id = request_id::current().as_deref().unwrap_or_default(),
+        remote_addr = remote.map(field::display),
+        method = %method,
+        path,
+        uid = field::Empty,
+        error = field::Empty,
+    );
+    telemetry::set_parent(&span, parent);
+    let output = REQUEST_SPAN
+        .scope(span.clone(), future.instrument(span.clone()))
+        .await;
+    (span, output)
+}
+
+/// Writes the access log event for a finished request.
+pub fn log(span: &Span, status: StatusCode, latency: Duration) {
+    span.in_scope(|| {
+        tracing::info!(
+            status = status.as_u16(),
+            latency_ms = latency.as_secs_f64() * 1000.0,
+            "request finished"
+        )
+    });
+}
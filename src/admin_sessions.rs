@@ -0,0 +1,297 @@
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{AuthContext, Claims},
+    error::Error,
+    pagination::{self, Order, PageRequest, SortKey},
+    repository::timed,
+    sessions::{ClientInfo, Session},
+    User, WebResult,
+};
+use mongodb::{
+    bson::{doc, spec::ElementType, DateTime, Document},
+    options::FindOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use utoipa::{IntoParams, ToSchema};
+use warp::{reject, reply, Reply};
+
+/// Most uids one `DELETE /admin/sessions` may name.
+pub const MAX_FILTER_UIDS: usize = 1000;
+/// Most recently used first, `_id` breaking ties.
+const SESSION_ORDER: Order = &[
+    SortKey {
+        field: "last_used",
+        descending: true,
+        kind: ElementType::DateTime,
+    },
+    SortKey {
+        field: "_id",
+        descending: true,
+        kind: ElementType::ObjectId,
+    },
+];
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AdminSessionQuery {
+    /// Only this account's sessions.
+    pub uid: Option<String>,
+    /// Only sessions last used from this IP address.
+    pub ip: Option<String>,
+    /// Only sessions used at or after this RFC 3339 time.
+    pub since: Option<String>,
+    /// The `next_cursor` of the previous page; the first page without it.
+    pub cursor: Option<String>,
+    /// Deprecated: a page number starting at 1, instead of `cursor`.
+    pub page: Option<u64>,
+    /// Sessions per page, at most 200 (default 50).
+    pub limit: Option<u64>,
+}
+
+/// An active session as `GET /admin/sessions` shows it.
+#[derive(Serialize, ToSchema)]
+pub struct AdminSessionResponse {
+    pub id: String,
+    pub uid: String,
+    /// The account's email, unless the account is gone.
+    pub email: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: Option<String>,
+    pub last_used: Option<String>,
+    pub remember_me: bool,
+}
+
+/// Which sessions `DELETE /admin/sessions` ends. Every field given must
+/// match, and at least one is needed.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct SessionFilter {
+    /// Sessions of these accounts, at most 1000.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uids: Option<Vec<String>>,
+    /// Sessions last used from this IP address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    /// Sessions started before this RFC 3339 time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub older_than: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RevokedSessions {
+    /// How many sessions were ended.
+    pub revoked: usize,
+}
+
+/// Unused, unexpired refresh tokens: one per live session.
+fn active() -> Document {
+    doc! {"used": false, "expires_at": {"$gt": DateTime::now()}}
+}
+
+fn list_filter(query: &AdminSessionQuery) -> Result<Document, Error> {
+    let mut filter = active();
+    if let Some(uid) = &query.uid {
+        filter.insert("uid", uid);
+    }
+    if let Some(ip) = &query.ip {
+        filter.insert("ip", ip);
+    }
+    if let Some(since) = &query.since {
+        filter.insert("last_used", doc! {"$gte": audit::parse_time(since)?});
+    }
+    Ok(filter)
+}
+
+fn revoke_filter(filter: &SessionFilter) -> Result<Document, Error> {
+    if filter.uids.is_none() && filter.ip.is_none() && filter.older_than.is_none() {
+        return Err(Error::InvalidSessionFilterError);
+    }
+    let mut document = active();
+    if let Some(uids) = &filter.uids {
+        if uids.len() > MAX_FILTER_UIDS {
+            return Err(Error::InvalidSessionFilterError);
+        }
+        document.insert("uid", doc! {"$in": uids});
+    }
+    if let Some(ip) = &filter.ip {
+        document.insert("ip", ip);
+    }
+    if let Some(older_than) = &filter.older_than {
+        document.insert("created_at", doc! {"$lt": audit::parse_time(older_than)?});
+    }
+    Ok(document)
+}
+
+/// The emails of `uids`, read in one query.
+async fn emails(
+    users_collection: &Collection<User>,
+    uids: Vec<&str>,
+) -> Result<HashMap<String, String>, Error> {
+    let options = FindOptions::builder()
+        .projection(doc! {"uid": 1, "email": 1})
+        .build();
+    let mut cursor = timed(
+        users_collection
+            .clone_with_type::<Document>()
+            .find(doc! {"uid": {"$in": uids}}, options),
+    )
+    .await?;
+    let mut emails = HashMap::new();
+    while timed(cursor.advance()).await? {
+        let user = cursor.deserialize_current()?;
+        if let (Ok(uid), Ok(email)) = (user.get_str("uid"), user.get_str("email")) {
+            emails.insert(uid.to_owned(), email.to_owned());
+        }
+    }
+    Ok(emails)
+}
+
+/// Active sessions of every account, most recently used first.
+#[utoipa::path(
+    get,
+    path = "/admin/sessions",
+    tag = "admin",
+    params(AdminSessionQuery),
+    responses(
+        (status = 200, description = "One page of active sessions", body = AdminSessionPage),
+        (status = 400, description = "Unparseable `since`, `page` or `limit` out of range, or an invalid `cursor`",
+            body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn list_all_sessions_handler(
+    _claims: Claims,
+    sessions_collection: Collection<Session>,
+    users_collection: Collection<User>,
+    query: AdminSessionQuery,
+) -> WebResult<impl Reply> {
+    let request = PageRequest::new(
+        SESSION_ORDER,
+        query.cursor.as_deref(),
+        query.page,
+        query.limit,
+    )
+    .map_err(reject::custom)?;
+    let filter = list_filter(&query).map_err(reject::custom)?;
+    let page = pagination::find(&sessions_collection, filter, SESSION_ORDER, &request, None)
+        .await
+        .map_err(reject::custom)?;
+
+    let uids: HashSet<&str> = page.items.iter().map(|s| s.uid.as_str()).collect();
+    let emails = emails(&users_collection, uids.into_iter().collect())
+        .await
+        .map_err(reject::custom)?;
+    let page = page.map(|session| AdminSessionResponse {
+        email: emails.get(&session.uid).cloned(),
+        id: session.family_id,
+        uid: session.uid,
+        ip: session.ip,
+        user_agent: session.user_agent,
+        created_at: session.created_at.try_to_rfc3339_string().ok(),
+        last_used: session.last_used.try_to_rfc3339_string().ok(),
+        remember_me: session.remember_me,
+    });
+    Ok(pagination::reply(&page, &request))
+}
+
+/// Ends every active session matching the filter at once: their refresh
+/// tokens stop working and the access tokens issued for them within the
+/// last token lifetime are revoked. Each call goes to the audit log as
+/// `sessions_revoked`, with the filter and the number of sessions ended,
+/// even when nothing matched.
+#[utoipa::path(
+    delete,
+    path = "/admin/sessions",
+    tag = "admin",
+    request_body = SessionFilter,
+    responses(
+        (status = 200, description = "How many sessions were ended", body = RevokedSessions),
+        (status = 400, description = "An empty filter, more than 1000 uids or an unparseable `older_than`",
+            body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn revoke_sessions_handler(
+    claims: Claims,
+    context: AuthContext,
+    sessions_collection: Collection<Session>,
+    client: ClientInfo,
+    filter: SessionFilter,
+) -> WebResult<impl Reply> {
+    let matching = revoke_filter(&filter).map_err(reject::custom)?;
+    let options = FindOptions::builder()
+        .projection(doc! {"family_id": 1})
+        .build();
+    let mut cursor = timed(
+        sessions_collection
+            .clone_with_type::<Document>()
+            .find(matching, options),
+    )
+    .await
+    .map_err(reject::custom)?;
+    let mut families = Vec::new();
+    while timed(cursor.advance()).await.map_err(reject::custom)? {
+        let session = cursor
+            .deserialize_current()
+            .map_err(|e| reject::custom(Error::from(e)))?;
+        if let Ok(family_id) = session.get_str("family_id") {
+            families.push(family_id.to_owned());
+        }
+    }
+
+    if !families.is_empty() {
+        // Earlier links of a family may have issued access tokens that are
+        // still accepted, so those are revoked along with the current one.
+        let issued_after = DateTime::from_millis(
+            DateTime::now().timestamp_millis() - context.access_token_lifetime().as_millis() as i64,
+        );
+        let options = FindOptions::builder()
+            .projection(doc! {"access_jti": 1})
+            .build();
+        let mut cursor = timed(sessions_collection.clone_with_type::<Document>().find(
+            doc! {
+                "family_id": {"$in": &families},
+                "access_jti": {"$type": "string"},
+                "last_used": {"$gt": issued_after},
+            },
+            options,
+        ))
+        .await
+        .map_err(reject::custom)?;
+        let mut jtis = Vec::new();
+        while timed(cursor.advance()).await.map_err(reject::custom)? {
+            let session = cursor
+                .deserialize_current()
+                .map_err(|e| reject::custom(Error::from(e)))?;
+            if let Ok(jti) = session.get_str("access_jti") {
+                jtis.push(jti.to_owned());
+            }
+        }
+
+        timed(sessions_collection.delete_many(doc! {"family_id": {"$in": &families}}, None))
+            .await
+            .map_err(reject::custom)?;
+        context.revoke_jtis(&jtis).await.map_err(reject::custom)?;
+    }
+
+    let described = serde_json::to_string(&filter).unwrap_or_default();
+    let mut event = AuditEvent::new(AuditAction::SessionsRevoked, &client)
+        .actor(&claims.sub)
+        .detail(&format!(
+            "{} sessions, filter {}",
+            families.len(),
+            described
+        ));
+    if let Some([uid]) = filter.uids.as_deref() {
+        event = event.target(uid);
+    }
+    audit::record(event);
+
+    Ok(reply::json(&RevokedSessions {
+        revoked: families.len(),
+    }))
+}
@@ -0,0 +1,216 @@
+use crate::{
+    access_log,
+    auth::{constant_time_eq, hash_token, random_token, AuthContext, Claims, Role},
+    error::Error,
+    repository::timed,
+    scopes, Result, WebResult,
+};
+use mongodb::{
+    bson::{doc, uuid, DateTime},
+    options::IndexOptions,
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::ToSchema;
+use warp::{http::StatusCode, reject, reply, Filter, Rejection, Reply};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+const PREFIX_LENGTH: usize = 8;
+const SECRET_LENGTH: usize = 40;
+
+/// A machine credential. Only the SHA-256 of the full key is stored; the
+/// plaintext prefix exists so lookups don't need to scan every hash.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub prefix: String,
+    pub key_hash: String,
+    pub uid: String,
+    pub role: String,
+    pub created_at: DateTime,
+    pub expires_at: Option<DateTime>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateApiKeyRequest {
+    pub uid: Option<String>,
+    pub role: String,
+    pub expires_in_days: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CreateApiKeyResponse {
+    pub id: String,
+    pub key: String,
+    pub uid: String,
+    pub role: String,
+    pub expires_at: Option<String>,
+}
+
+pub async fn create_indexes(collection: &Collection<ApiKey>) -> mongodb::error::Result<()> {
+    let prefix_index = IndexModel::builder()
+        .keys(doc! {"prefix": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    let id_index = IndexModel::builder()
+        .keys(doc! {"id": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    collection
+        .create_indexes(vec![prefix_index, id_index], None)
+        .await?;
+    Ok(())
+}
+
+/// Authenticates a request by its `X-Api-Key` header, producing the same
+/// `Claims` as `with_auth` so the two can be combined with `or`.
+pub fn with_api_key(
+    role: Role,
+    context: AuthContext,
+    api_keys: Collection<ApiKey>,
+) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(API_KEY_HEADER).and_then(move |key: Option<String>| {
+        let role = role.clone();
+        let context = context.clone();
+        let api_keys = api_keys.clone();
+        async move {
+            let key = key.ok_or_else(|| reject::custom(Error::NoAuthHeaderError))?;
+            let claims = authenticate_api_key(&context, &api_keys, &key)
+                .await
+                .map_err(reject::custom)?;
+            access_log::set_uid(&claims.sub);
+            if !claims.role.has_permission(&role) {
+                return Err(reject::custom(Error::NoPermissionError));
+            }
+            Ok::<_, Rejection>(claims)
+        }
+    })
+}
+
+async fn authenticate_api_key(
+    context: &AuthContext,
+    api_keys: &Collection<ApiKey>,
+    key: &str,
+) -> Result<Claims> {
+    let (prefix, _) = key.split_once('.').ok_or(Error::InvalidApiKeyError)?;
+    let stored = timed(api_keys.find_one(doc! {"prefix": prefix}, None))
+        .await?
+        .ok_or(Error::InvalidApiKeyError)?;
+
+    if !constant_time_eq(hash_token(key).as_bytes(), stored.key_hash.as_bytes()) {
+        return Err(Error::InvalidApiKeyError);
+    }
+    if let Some(expires_at) = stored.expires_at {
+        if expires_at < DateTime::now() {
+            return Err(Error::InvalidApiKeyError);
+        }
+    }
+
+    // Keys die with their owner's account being deleted or deactivated.
+    if context.token_version(&stored.uid).await?.is_none() {
+        return Err(Error::InvalidApiKeyError);
+    }
+
+    let role = context.roles().resolve(&stored.uid, &stored.role)?;
+    let scopes = scopes::for_role(&role, context.roles());
+    let now = DateTime::now().timestamp_millis() / 1000;
+    Ok(Claims {
+        sub: stored.uid,
+        role,
+        exp: stored
+            .expires_at
+            .map(|e| e.timestamp_millis() / 1000)
+            .unwrap_or(i64::MAX) as usize,
+        iat: now as usize,
+        jti: stored.id,
+        ver: 0,
+        iss: None,
+        aud: None,
+        impersonator: None,
+        scopes,
+        purpose: None,
+        source: None,
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/apikeys",
+    tag = "api keys",
+    request_body = CreateApiKeyRequest,
+    responses(
+        (status = 201, description = "The key, shown only this once", body = CreateApiKeyResponse),
+        (status = 400, description = "Unknown role", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn create_api_key_handler(
+    claims: Claims,
+    context: AuthContext,
+    api_keys: Collection<ApiKey>,
+    body: CreateApiKeyRequest,
+) -> WebResult<impl Reply> {
+    let role = Role::from_str(&body.role).map_err(reject::custom)?;
+    if !context.roles().is_assignable(&role) {
+        return Err(reject::custom(Error::InvalidRoleError));
+    }
+
+    let prefix = random_token(PREFIX_LENGTH);
+    let key = format!("{}.{}", prefix, random_token(SECRET_LENGTH));
+    let expires_at = body.expires_in_days.map(|days| {
+        DateTime::now().saturating_add_duration(Duration::from_secs(days * 24 * 60 * 60))
+    });
+
+    let api_key = ApiKey {
+        id: uuid::Uuid::new().to_string(),
+        prefix,
+        key_hash: hash_token(&key),
+        uid: body.uid.unwrap_or(claims.sub),
+        role: role.to_string(),
+        created_at: DateTime::now(),
+        expires_at,
+    };
+    timed(api_keys.insert_one(&api_key, None))
+        .await
+        .map_err(reject::custom)?;
+
+    Ok(reply::with_status(
+        reply::json(&CreateApiKeyResponse {
+            id: api_key.id,
+            key,
+            uid: api_key.uid,
+            role: api_key.role,
+            expires_at: expires_at.and_then(|e| e.try_to_rfc3339_string().ok()),
+        }),
+        StatusCode::CREATED,
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/apikeys/{id}",
+    tag = "api keys",
+    params(("id" = String, Path, description = "API key id")),
+    responses(
+        (status = 204, description = "Key revoked"),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such key", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn delete_api_key_handler(
+    id: String,
+    _claims: Claims,
+    api_keys: Collection<ApiKey>,
+) -> WebResult<impl Reply> {
+    let result = timed(api_keys.delete_one(doc! {"id": &id}, None))
+        .await
+        .map_err(reject::custom)?;
+    if result.deleted_count == 0 {
+        return Err(reject::custom(Error::ApiKeyNotFoundError));
+    }
+
+    Ok(reply::with_status(reply(), StatusCode::NO_CONTENT))
+}
@@ -0,0 +1,324 @@
+use crate::{
+    auth::Claims,
+    error::Error,
+    events::{self, AdminEventKind},
+    pagination::{self, Order, PageRequest, SortKey},
+    request_id,
+    sessions::ClientInfo,
+    WebResult,
+};
+use mongodb::{
+    bson::{doc, spec::ElementType, DateTime, Document},
+    ClientSession, Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use utoipa::{IntoParams, ToSchema};
+use warp::{reject, Reply};
+
+/// The actor of events without an authenticated caller.
+pub const ANONYMOUS: &str = "anonymous";
+/// Longest string kept in any field, so a hostile user agent can't bloat
+/// the log.
+const MAX_FIELD_LENGTH: usize = 256;
+/// Newest first, `_id` breaking ties between events of the same
+/// millisecond.
+const AUDIT_ORDER: Order = &[
+    SortKey {
+        field: "at",
+        descending: true,
+        kind: ElementType::DateTime,
+    },
+    SortKey {
+        field: "_id",
+        descending: true,
+        kind: ElementType::ObjectId,
+    },
+];
+
+static AUDIT_LOG: OnceLock<Collection<AuditEvent>> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    LoginSucceeded,
+    LoginFailed,
+    Signup,
+    PasswordChanged,
+    RoleChanged,
+    AccountDeleted,
+    ImpersonationStarted,
+    ImpersonatedRequest,
+    UserBanned,
+    UserUnbanned,
+    PasswordChangeRequired,
+    DataExported,
+    MaintenanceChanged,
+    TokenExchanged,
+    SessionsRevoked,
+}
+
+/// One entry in the `audit_log` collection. The server only ever inserts
+/// entries; nothing updates, expires or purges them.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub action: AuditAction,
+    pub at: DateTime,
+    /// uid of whoever caused the event, or [`ANONYMOUS`].
+    pub actor_uid: String,
+    /// The account the event is about, if it is known.
+    pub target_uid: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+    /// Context such as why a login failed. Never a password or token.
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    /// An event from `client` by an anonymous actor; the other builder
+    /// methods fill in the rest.
+    pub fn new(action: AuditAction, client: &ClientInfo) -> Self {
+        AuditEvent {
+            action,
+            at: DateTime::now(),
+            actor_uid: ANONYMOUS.to_string(),
+            target_uid: None,
+            ip: client.ip.as_deref().map(capped),
+            user_agent: client.user_agent.as_deref().map(capped),
+            request_id: request_id::current(),
+            detail: None,
+        }
+    }
+
+    pub fn actor(mut self, uid: &str) -> Self {
+        self.actor_uid = capped(uid);
+        self
+    }
+
+    pub fn target(mut self, uid: &str) -> Self {
+        self.target_uid = Some(capped(uid));
+        self
+    }
+
+    pub fn detail(mut self, detail: &str) -> Self {
+        self.detail = Some(capped(detail));
+        self
+    }
+}
+
+fn capped(value: &str) -> String {
+    let mut end = value.len().min(MAX_FIELD_LENGTH);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value[..end].to_string()
+}
+
+/// One index per filter combination `GET /audit` offers, each ending in
+/// [`AUDIT_ORDER`] so the newest-first sort, its cursors and the time range
+/// come from the index, and one on `actor_uid` for personal data exports.
+pub async fn create_indexes(collection: &Collection<AuditEvent>) -> mongodb::error::Result<()> {
+    let indexes = [
+        doc! {"at": -1, "_id": -1},
+        doc! {"target_uid": 1, "at": -1, "_id": -1},
+        doc! {"action": 1, "at": -1, "_id": -1},
+        doc! {"target_uid": 1, "action": 1, "at": -1, "_id": -1},
+        doc! {"actor_uid": 1, "at": -1},
+    ]
+    .into_iter()
+    .map(|keys| IndexModel::builder().keys(keys).build());
+    collection.create_indexes(indexes, None).await?;
+    Ok(())
+}
+
+/// Makes [`record`] write to `collection`. `main` calls this at startup;
+/// without it events are dropped, as in setups without MongoDB.
+pub fn init(collection: Collection<AuditEvent>) {
+    AUDIT_LOG.set(collection).ok();
+}
+
+/// Adds `event` to the audit log. The write runs in the background like
+/// `users::record_login`, so a slow or failing insert never holds up or
+/// fails the request; failures are logged.
+pub fn record(event: AuditEvent) {
+    let Some(collection) = AUDIT_LOG.get().cloned() else {
+        return;
+    };
+    request_id::spawn(async move {
+        if let Err(e) = collection.insert_one(&event, None).await {
+            tracing::error!("writing the {:?} audit event failed: {}", event.action, e);
+        }
+    });
+}
+
+/// Writes `event` as part of `session`'s transaction, for changes whose
+/// audit entry must not go missing or outlive them.
+pub async fn record_with_session(
+    event: &AuditEvent,
+    session: &mut ClientSession,
+) -> mongodb::error::Result<()> {
+    let Some(collection) = AUDIT_LOG.get() else {
+        return Ok(());
+    };
+    collection
+        .insert_one_with_session(event, None, session)
+        .await?;
+    Ok(())
+}
+
+/// Announces `login`, a [`AuditAction::LoginSucceeded`] event, as
+/// `new_device` on `GET /events` and in the log when its account has signed
+/// in before but never from this address and user agent; the hook for
+/// "new sign-in" emails. An account's first sign-in is not announced. Runs
+/// in the background like [`record`], and only looks at events older than
+/// `login`, so it does not matter which of the two writes first.
+pub fn flag_new_device(login: &AuditEvent) {
+    let (Some(collection), Some(uid)) = (AUDIT_LOG.get().cloned(), login.target_uid.clone()) else {
+        return;
+    };
+    let earlier = doc! {
+        "action": "login_succeeded",
+        "target_uid": &uid,
+        "at": {"$lt": login.at},
+    };
+    let mut same_device = earlier.clone();
+    same_device.insert("ip", login.ip.clone());
+    same_device.insert("user_agent", login.user_agent.clone());
+    let (ip, user_agent) = (login.ip.clone(), login.user_agent.clone());
+    request_id::spawn(async move {
+        let seen = async {
+            if collection.find_one(earlier, None).await?.is_none() {
+                return Ok(true);
+            }
+            Ok::<_, mongodb::error::Error>(collection.find_one(same_device, None).await?.is_some())
+        };
+        match seen.await {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::info!(%uid, ?ip, ?user_agent, "sign-in from a new device");
+                events::publish(AdminEventKind::NewDevice, Some(&uid));
+            }
+            Err(e) => tracing::error!("checking for a sign-in from a new device failed: {}", e),
+        }
+    });
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    /// Only events about this account.
+    pub uid: Option<String>,
+    /// Only events of this kind, such as `login_failed`.
+    pub event: Option<AuditAction>,
+    /// Only events at or after this RFC 3339 time.
+    pub from: Option<String>,
+    /// Only events before this RFC 3339 time.
+    pub to: Option<String>,
+    /// The `next_cursor` of the previous page; the first page without it.
+    pub cursor: Option<String>,
+    /// Deprecated: a page number starting at 1, instead of `cursor`.
+    pub page: Option<u64>,
+    /// Events per page, at most 200 (default 50).
+    pub limit: Option<u64>,
+}
+
+/// An `AuditEvent` as `GET /audit` shows it.
+#[derive(Serialize, ToSchema)]
+pub struct AuditEntry {
+    pub action: AuditAction,
+    pub at: Option<String>,
+    pub actor_uid: String,
+    pub target_uid: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+    pub detail: Option<String>,
+}
+
+impl From<AuditEvent> for AuditEntry {
+    fn from(event: AuditEvent) -> Self {
+        AuditEntry {
+            action: event.action,
+            at: event.at.try_to_rfc3339_string().ok(),
+            actor_uid: event.actor_uid,
+            target_uid: event.target_uid,
+            ip: event.ip,
+            user_agent: event.user_agent,
+            request_id: event.request_id,
+            detail: event.detail,
+        }
+    }
+}
+
+pub fn parse_time(value: &str) -> Result<DateTime, Error> {
+    DateTime::parse_rfc3339_str(value).map_err(|_| Error::InvalidTimestampError)
+}
+
+fn query_filter(query: &AuditQuery) -> Result<Document, Error> {
+    let mut filter = Document::new();
+    if let Some(uid) = &query.uid {
+        filter.insert("target_uid", uid);
+    }
+    if let Some(event) = query.event {
+        filter.insert(
+            "action",
+            mongodb::bson::to_bson(&event).map_err(Error::from)?,
+        );
+    }
+    let mut at = Document::new();
+    if let Some(from) = &query.from {
+        at.insert("$gte", parse_time(from)?);
+    }
+    if let Some(to) = &query.to {
+        at.insert("$lt", parse_time(to)?);
+    }
+    if !at.is_empty() {
+        filter.insert("at", at);
+    }
+    Ok(filter)
+}
+
+/// Newest events first. Only the fields of `AuditEntry` are read from the
+/// database, whatever else a document may hold.
+#[utoipa::path(
+    get,
+    path = "/audit",
+    tag = "users",
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "One page of audit events", body = AuditPage),
+        (status = 400, description = "Unknown `event`, unparseable time, `page` or `limit` out of range, or an invalid `cursor`",
+            body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn list_audit_handler(
+    _claims: Claims,
+    audit_log: Collection<AuditEvent>,
+    query: AuditQuery,
+) -> WebResult<impl Reply> {
+    let request = PageRequest::new(
+        AUDIT_ORDER,
+        query.cursor.as_deref(),
+        query.page,
+        query.limit,
+    )
+    .map_err(reject::custom)?;
+    let filter = query_filter(&query).map_err(reject::custom)?;
+    let projection = doc! {
+        "action": 1,
+        "at": 1,
+        "actor_uid": 1,
+        "target_uid": 1,
+        "ip": 1,
+        "user_agent": 1,
+        "request_id": 1,
+        "detail": 1,
+    };
+    let page = pagination::find(&audit_log, filter, AUDIT_ORDER, &request, Some(projection))
+        .await
+        .map_err(reject::custom)?;
+    Ok(pagination::reply(&page.map(AuditEntry::from), &request))
+}
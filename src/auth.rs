@@ -1,34 +1,335 @@
-use crate::{error::Error, Result, WebResult};
+use crate::{
+    access_log,
+    audit::{self, AuditAction, AuditEvent},
+    config,
+    error::Error,
+    ip_allowlist, metrics,
+    repository::timed,
+    roles::RoleRegistry,
+    scopes,
+    sessions::{with_client_info, ClientInfo},
+    sockets::SocketRegistry,
+    two_factor::TotpCipher,
+    Result, User, WebResult,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use chrono::prelude::*;
-use dotenv::dotenv;
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{
+    decode, decode_header, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header,
+    Validation,
+};
+use mongodb::{
+    bson::{doc, uuid, DateTime},
+    options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument},
+    Collection, IndexModel,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use rsa::{
+    pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey, traits::PublicKeyParts, RsaPublicKey,
+};
 use serde::{Deserialize, Serialize};
-use std::{env, fmt};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use utoipa::ToSchema;
 use warp::{
-    filters::header::headers_cloned,
-    http::header::{HeaderMap, HeaderValue, AUTHORIZATION},
+    filters::{header::headers_cloned, BoxedFilter},
+    http::{
+        header::{HeaderMap, HeaderValue, AUTHORIZATION, COOKIE},
+        Method,
+    },
+    path::FullPath,
     reject, Filter, Rejection,
 };
 
 const BEARER: &str = "Bearer ";
+pub const SESSION_COOKIE: &str = "auth_token";
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+const DEFAULT_JWT_EXPIRY_SECONDS: u32 = 3600;
+const DEFAULT_JWT_LEEWAY_SECONDS: u64 = 30;
+const IMPERSONATION_EXPIRY_SECONDS: i64 = 15 * 60;
+const PASSWORD_CHANGE_EXPIRY_SECONDS: i64 = 5 * 60;
+/// The `purpose` of the token from a login that must change its password
+/// first.
+pub const PASSWORD_CHANGE_PURPOSE: &str = "password_change";
+const REFRESH_TOKEN_LENGTH: usize = 64;
+/// Most accounts whose `token_version` is cached at once, so tokens of
+/// ever more accounts cannot grow the cache without bound.
+const MAX_CACHED_TOKEN_VERSIONS: usize = 100_000;
+const CSRF_TOKEN_LENGTH: usize = 32;
+pub const REFRESH_TOKEN_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Clone)]
+struct VerificationKey {
+    kid: String,
+    key: DecodingKey<'static>,
+}
+
+#[derive(Clone)]
+pub struct JwtConfig {
+    algorithm: Algorithm,
+    kid: String,
+    encoding_key: EncodingKey,
+    verification_keys: Vec<VerificationKey>,
+    public_jwk: Option<Jwk>,
+    expiry_seconds: i64,
+    /// Clock skew tolerated on `exp` and `iat`.
+    leeway_seconds: u64,
+    issuer: Option<String>,
+    audience: Option<String>,
+    cookie_auth: bool,
+}
+
+/// Public half of an RS256 signing key in JSON Web Key format.
+#[derive(Clone, Serialize, ToSchema)]
+pub struct Jwk {
+    #[schema(value_type = String)]
+    kty: &'static str,
+    #[schema(value_type = String)]
+    alg: &'static str,
+    kid: String,
+    #[serde(rename = "use")]
+    #[schema(value_type = String)]
+    key_use: &'static str,
+    n: String,
+    e: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+impl JwtConfig {
+    /// Reads the signing configuration from the environment, recording
+    /// missing or unreadable key material in `problems` so a misconfigured
+    /// deployment fails at startup rather than on the first login. `None`
+    /// when anything was recorded.
+    ///
+    /// `JWT_ALGORITHM` selects `HS512` (the default), `HS256` or `RS256`. The
+    /// HMAC algorithms sign with the first of `secrets` (from
+    /// `Config::jwt_secrets`) and still accept the others for verification;
+    /// RS256 loads PEM keys from `JWT_PRIVATE_KEY_PATH` and
+    /// `JWT_PUBLIC_KEY_PATH`. When `JWT_ISSUER` or `JWT_AUDIENCE` are set they
+    /// are stamped into new tokens and required on incoming ones.
+    /// `JWT_LEEWAY_SECONDS` (default 30) is how far past `exp`, or before
+    /// `iat`, a token is still accepted, for clocks that drift apart.
+    /// `AUTH_COOKIE=true` additionally delivers and accepts the access token in
+    /// an `HttpOnly` session cookie for browser clients.
+    pub(crate) fn from_env(secrets: &[String], problems: &mut Vec<String>) -> Option<JwtConfig> {
+        let algorithm = match env::var("JWT_ALGORITHM").as_deref() {
+            Err(_) | Ok("HS512") => Some(Algorithm::HS512),
+            Ok("HS256") => Some(Algorithm::HS256),
+            Ok("RS256") => Some(Algorithm::RS256),
+            Ok(other) => {
+                problems.push(format!(
+                    "JWT_ALGORITHM must be one of HS256, HS512 or RS256, got {:?}",
+                    other
+                ));
+                None
+            }
+        };
+        let expiry_seconds = config::parse_var(
+            "JWT_EXPIRY_SECONDS",
+            NonZeroU32::new(DEFAULT_JWT_EXPIRY_SECONDS).expect("nonzero default"),
+            "a positive number of seconds",
+            problems,
+        );
+        let leeway_seconds = config::parse_var(
+            "JWT_LEEWAY_SECONDS",
+            DEFAULT_JWT_LEEWAY_SECONDS,
+            "a number of seconds",
+            problems,
+        );
+
+        let algorithm = algorithm?;
+        let (kid, encoding_key, verification_keys, public_jwk) = match algorithm {
+            Algorithm::RS256 => load_rsa_keys().map_err(|e| problems.push(e)).ok()?,
+            // `Config::from_env` has already reported missing secrets.
+            _ if secrets.is_empty() => return None,
+            _ => load_hmac_keys(secrets),
+        };
+
+        Some(JwtConfig {
+            algorithm,
+            kid,
+            encoding_key,
+            verification_keys,
+            public_jwk,
+            expiry_seconds: i64::from(expiry_seconds.get()),
+            leeway_seconds,
+            issuer: non_empty_var("JWT_ISSUER"),
+            audience: non_empty_var("JWT_AUDIENCE"),
+            cookie_auth: matches!(env::var("AUTH_COOKIE").as_deref(), Ok("true") | Ok("1")),
+        })
+    }
+
+    /// Verifies `token` against the key named by its `kid` header. Tokens
+    /// issued before key ids were introduced carry no `kid` and are tried
+    /// against every configured key.
+    fn decode(&self, token: &str) -> Result<Claims> {
+        let mut validation = Validation::new(self.algorithm);
+        validation.leeway = self.leeway_seconds;
+        validation.iss = self.issuer.clone();
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
+        let header = decode_header(token).map_err(|_| Error::JWTTokenError)?;
+
+        let candidates: Vec<&VerificationKey> = match header.kid {
+            Some(kid) => self
+                .verification_keys
+                .iter()
+                .filter(|k| k.kid == kid)
+                .collect(),
+            None => self.verification_keys.iter().collect(),
+        };
+
+        // The signature is checked before the expiry, so an expired token
+        // was genuinely issued by us and a refresh will fix it.
+        let mut result = Err(Error::JWTTokenError);
+        for key in candidates {
+            match decode::<Claims>(token, &key.key, &validation) {
+                Ok(decoded) => return self.check_issued_at(decoded.claims),
+                Err(e) if matches!(e.kind(), ErrorKind::ExpiredSignature) => {
+                    result = Err(Error::JWTTokenExpiredError)
+                }
+                Err(_) => {}
+            }
+        }
+        result
+    }
+
+    /// Refuses tokens issued further in the future than the leeway allows.
+    /// Ours carry no `nbf`, so `iat` stands in for it.
+    fn check_issued_at(&self, claims: Claims) -> Result<Claims> {
+        let latest = Utc::now().timestamp() as u64 + self.leeway_seconds;
+        if claims.iat as u64 > latest {
+            return Err(Error::JWTTokenError);
+        }
+        Ok(claims)
+    }
+}
+
+fn non_empty_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+/// Derives a stable, non-reversible key id from key material so the same
+/// key keeps its id as it moves down the rotation list.
+fn key_id(material: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(&Sha256::digest(material)[..8])
+}
+
+fn load_hmac_keys(secrets: &[String]) -> SigningKeys {
+    let current = secrets.first().expect("Config requires a JWT secret");
+
+    let verification_keys = secrets
+        .iter()
+        .map(|secret| VerificationKey {
+            kid: key_id(secret.as_bytes()),
+            key: DecodingKey::from_secret(secret.as_bytes()).into_static(),
+        })
+        .collect();
+
+    (
+        key_id(current.as_bytes()),
+        EncodingKey::from_secret(current.as_bytes()),
+        verification_keys,
+        None,
+    )
+}
 
-pub fn get_jwt_secret() -> Vec<u8> {
-    dotenv().ok();
-    let secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set in the environment");
-    secret.into_bytes()
+fn read_key_file(var: &str) -> std::result::Result<Vec<u8>, String> {
+    let path = env::var(var)
+        .ok()
+        .filter(|v| !v.is_empty())
+        .ok_or_else(|| format!("{} must be set when JWT_ALGORITHM=RS256", var))?;
+    fs::read(&path).map_err(|e| format!("could not read {} ({}): {}", var, path, e))
 }
 
-#[derive(Clone, PartialEq)]
+type SigningKeys = (String, EncodingKey, Vec<VerificationKey>, Option<Jwk>);
+
+fn load_rsa_keys() -> std::result::Result<SigningKeys, String> {
+    let private_pem = read_key_file("JWT_PRIVATE_KEY_PATH")?;
+    let public_pem = read_key_file("JWT_PUBLIC_KEY_PATH")?;
+
+    let encoding_key = EncodingKey::from_rsa_pem(&private_pem)
+        .map_err(|e| format!("JWT_PRIVATE_KEY_PATH is not a valid RSA PEM key: {}", e))?;
+    let decoding_key = DecodingKey::from_rsa_pem(&public_pem)
+        .map_err(|e| format!("JWT_PUBLIC_KEY_PATH is not a valid RSA PEM key: {}", e))?
+        .into_static();
+
+    let public_pem = String::from_utf8_lossy(&public_pem);
+    let public_key = RsaPublicKey::from_public_key_pem(&public_pem)
+        .or_else(|_| RsaPublicKey::from_pkcs1_pem(&public_pem))
+        .map_err(|e| format!("JWT_PUBLIC_KEY_PATH is not a valid RSA public key: {}", e))?;
+    let modulus = public_key.n().to_bytes_be();
+    let kid = key_id(&modulus);
+    let jwk = Jwk {
+        kty: "RSA",
+        alg: "RS256",
+        kid: kid.clone(),
+        key_use: "sig",
+        n: URL_SAFE_NO_PAD.encode(&modulus),
+        e: URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be()),
+    };
+
+    Ok((
+        kid.clone(),
+        encoding_key,
+        vec![VerificationKey {
+            kid,
+            key: decoding_key,
+        }],
+        Some(jwk),
+    ))
+}
+
+/// `User`, `Admin` and `Guest` are built in; any other name refers to a
+/// role defined in the `roles` collection. Roles serialize as their plain
+/// name.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum Role {
     User,
     Admin,
+    /// An anonymous account from `POST /guest`, until it is upgraded.
+    Guest,
+    Custom(String),
 }
 
 impl Role {
-    pub fn from_str(role: &str) -> Role {
-        match role {
-            "Admin" => Role::Admin,
-            _ => Role::User,
+    /// The built-in roles in any case, otherwise a custom role's name.
+    /// Empty names, names with surrounding whitespace and names with
+    /// control characters are an `InvalidRoleError`. Whether a custom role
+    /// is defined is up to the `RoleRegistry`.
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(role: &str) -> Result<Role> {
+        for builtin in [Role::Admin, Role::User, Role::Guest] {
+            if role.eq_ignore_ascii_case(&builtin.to_string()) {
+                return Ok(builtin);
+            }
+        }
+        if role.is_empty() || role != role.trim() || role.chars().any(char::is_control) {
+            return Err(Error::InvalidRoleError);
+        }
+        Ok(Role::Custom(role.to_owned()))
+    }
+
+    /// Roles form a hierarchy in which `Admin` can do anything, and every
+    /// role but `Guest` can do what `User` can. Guests only get into routes
+    /// that name their role.
+    pub fn has_permission(&self, required: &Role) -> bool {
+        match required {
+            Role::User => *self != Role::Guest,
+            _ => self == required || *self == Role::Admin,
         }
     }
 }
@@ -38,63 +339,876 @@ impl fmt::Display for Role {
         match self {
             Role::User => write!(f, "User"),
             Role::Admin => write!(f, "Admin"),
+            Role::Guest => write!(f, "Guest"),
+            Role::Custom(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl TryFrom<String> for Role {
+    type Error = Error;
+
+    fn try_from(role: String) -> Result<Self> {
+        Role::from_str(&role)
+    }
+}
+
+/// Logs a role read from the database for `uid` that doesn't parse or
+/// isn't defined, which means the data is corrupted, and gives the error
+/// to answer with. Such accounts are never given a role in its place.
+pub fn invalid_role_data(uid: &str, role: &str) -> Error {
+    tracing::error!(uid, role, "stored role is invalid");
+    Error::InvalidRoleDataError
+}
+
+impl From<Role> for String {
+    fn from(role: Role) -> Self {
+        role.to_string()
+    }
+}
+
+/// Claims carried by every access token. A token whose role is no longer
+/// defined in the role registry is rejected as invalid.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Claims {
+    pub sub: String,
+    pub role: Role,
+    pub exp: usize,
+    pub iat: usize,
+    pub jti: String,
+    /// The user's `token_version` when the token was issued; tokens from
+    /// before the claim existed decode as version 0.
+    #[serde(default)]
+    pub ver: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    /// uid of the admin who had this token issued to act as `sub`; see
+    /// [`create_impersonation_jwt`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<String>,
+    /// What the token may be used for, from [`scopes::for_role`] when it
+    /// was issued.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+    /// Set on tokens that are good for one thing only, such as
+    /// [`PASSWORD_CHANGE_PURPOSE`]. Only routes taking that purpose accept
+    /// them; see [`with_password_change_claims`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purpose: Option<String>,
+    /// Issuer of the peer token this one was exchanged for at
+    /// `POST /token/exchange`; see [`create_exchanged_jwt`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+impl Claims {
+    /// Tokens issued before scopes existed carry none; they get the scopes
+    /// of their role as it is defined now.
+    pub fn has_scope(&self, scope: &str, roles: &RoleRegistry) -> bool {
+        if self.scopes.is_empty() {
+            return scopes::for_role(&self.role, roles)
+                .iter()
+                .any(|s| s == scope);
+        }
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RevokedToken {
+    pub jti: String,
+    pub expires_at: DateTime,
+}
+
+#[derive(Clone)]
+pub struct AuthContext {
+    jwt: JwtConfig,
+    revoked_tokens: Collection<RevokedToken>,
+    roles: RoleRegistry,
+    totp_cipher: Option<TotpCipher>,
+    users: Collection<User>,
+    token_versions: Arc<Mutex<TokenVersions>>,
+    /// The open `/ws` sockets, closed along with the tokens they were
+    /// opened with.
+    sockets: SocketRegistry,
+    /// `Config::trust_proxy`, for finding the client address.
+    trust_proxy: bool,
+}
+
+/// The cache behind [`AuthContext::token_version`].
+#[derive(Default)]
+struct TokenVersions {
+    entries: HashMap<String, (u32, Instant)>,
+    /// Counts [`AuthContext::forget_user`] calls, so a lookup that was
+    /// already reading from MongoDB when one happened does not put back
+    /// what it read.
+    forgotten: u64,
+}
+
+impl TokenVersions {
+    /// Caches `version` for `uid`. A full cache first drops the entries
+    /// older than `USER_CACHE_TTL_SECS`, which would not be served anyway,
+    /// and if it is still full leaves `uid` uncached.
+    fn insert(&mut self, uid: &str, version: u32) {
+        if self.entries.len() >= MAX_CACHED_TOKEN_VERSIONS && !self.entries.contains_key(uid) {
+            let ttl = config::user_cache_ttl();
+            self.entries
+                .retain(|_, (_, fetched)| fetched.elapsed() < ttl);
+            if self.entries.len() >= MAX_CACHED_TOKEN_VERSIONS {
+                return;
+            }
         }
+        self.entries
+            .insert(uid.to_owned(), (version, Instant::now()));
+    }
+}
+
+impl AuthContext {
+    pub fn new(
+        jwt: JwtConfig,
+        revoked_tokens: Collection<RevokedToken>,
+        roles: RoleRegistry,
+        totp_cipher: Option<TotpCipher>,
+        users: Collection<User>,
+        trust_proxy: bool,
+    ) -> Self {
+        AuthContext {
+            jwt,
+            revoked_tokens,
+            roles,
+            totp_cipher,
+            users,
+            token_versions: Arc::default(),
+            sockets: SocketRegistry::default(),
+            trust_proxy,
+        }
+    }
+
+    pub fn sockets(&self) -> &SocketRegistry {
+        &self.sockets
+    }
+
+    pub fn roles(&self) -> &RoleRegistry {
+        &self.roles
+    }
+
+    pub fn totp_cipher(&self) -> Result<&TotpCipher> {
+        self.totp_cipher
+            .as_ref()
+            .ok_or(Error::TwoFactorUnavailableError)
+    }
+
+    pub fn cookie_auth(&self) -> bool {
+        self.jwt.cookie_auth
+    }
+
+    pub fn session_cookie(&self, token: &str) -> HeaderValue {
+        HeaderValue::from_str(&format!(
+            "{}={}; HttpOnly; Secure; SameSite=Strict; Path=/; Max-Age={}",
+            SESSION_COOKIE, token, self.jwt.expiry_seconds
+        ))
+        .expect("jwt is a valid header value")
+    }
+
+    pub fn clear_session_cookie(&self) -> HeaderValue {
+        HeaderValue::from_str(&format!(
+            "{}=; HttpOnly; Secure; SameSite=Strict; Path=/; Max-Age=0",
+            SESSION_COOKIE
+        ))
+        .expect("cookie is a valid header value")
+    }
+
+    /// The CSRF cookie is deliberately readable by scripts so the frontend can
+    /// echo it back in the `X-CSRF-Token` header (double-submit pattern). It
+    /// lasts as long as the session's refresh tokens, `lifetime`.
+    pub fn csrf_cookie(&self, csrf_token: &str, lifetime: Duration) -> HeaderValue {
+        HeaderValue::from_str(&format!(
+            "{}={}; Secure; SameSite=Strict; Path=/; Max-Age={}",
+            CSRF_COOKIE,
+            csrf_token,
+            lifetime.as_secs()
+        ))
+        .expect("csrf token is a valid header value")
+    }
+
+    pub fn clear_csrf_cookie(&self) -> HeaderValue {
+        HeaderValue::from_str(&format!(
+            "{}=; Secure; SameSite=Strict; Path=/; Max-Age=0",
+            CSRF_COOKIE
+        ))
+        .expect("cookie is a valid header value")
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self.jwt.public_jwk.iter().cloned().collect(),
+        }
+    }
+
+    /// Revoked entries are only needed until the token would have expired
+    /// anyway, leeway included, so a TTL index lets MongoDB drop them after
+    /// that point.
+    pub async fn create_indexes(&self) -> mongodb::error::Result<()> {
+        let index = IndexModel::builder()
+            .keys(doc! {"expires_at": 1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(0))
+                    .build(),
+            )
+            .build();
+        self.revoked_tokens.create_index(index, None).await?;
+        Ok(())
+    }
+
+    pub async fn revoke(&self, claims: &Claims) -> Result<()> {
+        let revoked = RevokedToken {
+            jti: claims.jti.clone(),
+            expires_at: DateTime::from_millis(claims.exp as i64 * 1000)
+                .saturating_add_duration(Duration::from_secs(self.jwt.leeway_seconds)),
+        };
+        timed(self.revoked_tokens.insert_one(revoked, None)).await?;
+        Ok(())
+    }
+
+    /// Revokes an access token known only by its id, such as the one tied
+    /// to a deleted session. It is kept on the list for a full token lifetime.
+    #[tracing::instrument(skip(self))]
+    pub async fn revoke_jti(&self, jti: &str) -> Result<()> {
+        let revoked = RevokedToken {
+            jti: jti.to_owned(),
+            expires_at: DateTime::now().saturating_add_duration(self.access_token_lifetime()),
+        };
+        timed(self.revoked_tokens.insert_one(revoked, None)).await?;
+        Ok(())
+    }
+
+    /// [`AuthContext::revoke_jti`] for many tokens in one insert.
+    #[tracing::instrument(skip_all, fields(count = jtis.len()))]
+    pub async fn revoke_jtis(&self, jtis: &[String]) -> Result<()> {
+        if jtis.is_empty() {
+            return Ok(());
+        }
+        let expires_at = DateTime::now().saturating_add_duration(self.access_token_lifetime());
+        let revoked = jtis.iter().map(|jti| RevokedToken {
+            jti: jti.clone(),
+            expires_at,
+        });
+        timed(self.revoked_tokens.insert_many(revoked, None)).await?;
+        Ok(())
+    }
+
+    /// The uid of the signed, unexpired token a request with `headers`
+    /// carries, if any. Only the signature and expiry are checked: nothing
+    /// is looked up, audited or refused, so rate limiters can key on it
+    /// before the route's own auth filter does the full checks.
+    pub fn token_subject(&self, headers: &HeaderMap<HeaderValue>) -> Option<String> {
+        let (jwt, _) = jwt_from_request(self, headers).ok()?;
+        self.jwt.decode(&jwt).ok().map(|claims| claims.sub)
+    }
+
+    /// How long an access token is accepted after it is issued, leeway
+    /// included.
+    pub fn access_token_lifetime(&self) -> Duration {
+        Duration::from_secs(self.jwt.expiry_seconds as u64 + self.jwt.leeway_seconds)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn is_revoked(&self, jti: &str) -> Result<bool> {
+        let revoked = timed(self.revoked_tokens.find_one(doc! {"jti": jti}, None)).await?;
+        Ok(revoked.is_some())
+    }
+
+    /// Current `token_version` for `uid`, or `None` if the user no longer
+    /// exists, is deactivated or is soft-deleted. Served from a cache for up
+    /// to `USER_CACHE_TTL_SECS`; deleting, deactivating and `/logout-all`
+    /// update the local cache immediately, so the TTL only bounds how stale
+    /// other server instances can be, and changes made to MongoDB directly.
+    /// A hit costs a mutex and a hash lookup, well under a microsecond; a
+    /// miss is one indexed `find_one` by uid. Both are counted in the
+    /// `user_cache_lookups_total` metric.
+    #[tracing::instrument(skip(self))]
+    pub async fn token_version(&self, uid: &str) -> Result<Option<u32>> {
+        let forgotten = {
+            let versions = self
+                .token_versions
+                .lock()
+                .expect("token version lock poisoned");
+            if let Some((version, fetched)) = versions.entries.get(uid) {
+                if fetched.elapsed() < config::user_cache_ttl() {
+                    metrics::record_user_cache(true);
+                    return Ok(Some(*version));
+                }
+            }
+            versions.forgotten
+        };
+        metrics::record_user_cache(false);
+
+        let user = timed(self.users.find_one(
+            crate::users::not_banned(crate::users::active(
+                doc! {"uid": uid, "active": {"$ne": false}},
+            )),
+            None,
+        ))
+        .await?;
+        let mut versions = self
+            .token_versions
+            .lock()
+            .expect("token version lock poisoned");
+        match user {
+            Some(user) => {
+                if versions.forgotten == forgotten {
+                    versions.insert(uid, user.token_version);
+                }
+                Ok(Some(user.token_version))
+            }
+            None => {
+                versions.entries.remove(uid);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Drops any cached `token_version` for `uid`, so the next request with
+    /// one of its tokens sees the account as stored. Handlers call this
+    /// after deleting, deactivating, banning or otherwise changing an
+    /// account in a way tokens depend on, so those take effect immediately
+    /// instead of after the cache TTL. The account's open sockets are
+    /// closed too, since they were opened with such a token.
+    pub fn forget_user(&self, uid: &str) {
+        let mut versions = self
+            .token_versions
+            .lock()
+            .expect("token version lock poisoned");
+        versions.entries.remove(uid);
+        versions.forgotten += 1;
+        drop(versions);
+        self.sockets.disconnect(uid);
+    }
+
+    /// Increments the user's `token_version`, invalidating every access
+    /// token issued before the call and closing the sockets opened with
+    /// them, and returns the updated user.
+    #[tracing::instrument(skip(self))]
+    pub async fn bump_token_version(&self, uid: &str) -> Result<User> {
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let user = timed(self.users.find_one_and_update(
+            doc! {"uid": uid},
+            doc! {"$inc": {"token_version": 1}},
+            options,
+        ))
+        .await?
+        .ok_or(Error::UserNotFoundError)?;
+
+        self.token_versions
+            .lock()
+            .expect("token version lock poisoned")
+            .insert(uid, user.token_version);
+        self.sockets.disconnect(uid);
+        Ok(user)
+    }
+}
+
+/// Requires a token for `role`. Admin routes are also held to
+/// `ADMIN_IP_ALLOWLIST`, checked before the token is looked at.
+pub fn with_auth(role: Role, context: AuthContext) -> BoxedFilter<(Claims,)> {
+    // Boxed: it is on most routes, and inlined its future makes theirs
+    // big enough to overflow a worker thread's stack in debug builds.
+    ip_allowlist::check(role == Role::Admin, context.trust_proxy)
+        .and(with_claims(context))
+        .and_then(move |claims: Claims| authorize(role.clone(), claims))
+        .boxed()
+}
+
+/// Like `with_auth`, but accepts a token whose role is any of `roles`.
+/// Routes open to `Admin`, or to a role that currently has privileged
+/// scopes, are held to `ADMIN_IP_ALLOWLIST` like admin routes.
+pub fn with_any_role(
+    roles: &[Role],
+    context: AuthContext,
+) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+    let registry = context.roles().clone();
+    let gated = roles.to_vec();
+    let privileged = move || {
+        gated
+            .iter()
+            .any(|role| scopes::is_privileged_role(role, &registry))
+    };
+    let roles = roles.to_vec();
+    ip_allowlist::check_if(privileged, context.trust_proxy)
+        .and(with_claims(context))
+        .and_then(move |claims: Claims| authorize_any(roles.clone(), claims))
+}
+
+/// Authorizes against a permission granted to the caller's role in the
+/// role registry rather than against a role name. Permissions grant more
+/// than the `User` role has, so these routes are held to
+/// `ADMIN_IP_ALLOWLIST` too.
+pub fn with_permission(
+    permission: &'static str,
+    context: AuthContext,
+) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+    let registry = context.roles().clone();
+    ip_allowlist::check(true, context.trust_proxy)
+        .and(with_claims(context))
+        .and_then(move |claims: Claims| {
+            let registry = registry.clone();
+            async move {
+                if !registry.has_permission(&claims.role, permission) {
+                    return Err(reject::custom(Error::NoPermissionError));
+                }
+                Ok(claims)
+            }
+        })
+}
+
+/// Authorizes against a scope carried by the token, such as
+/// `users:write`. Unlike [`with_permission`] this checks what the token was
+/// issued with, so it also narrows API keys and impersonation tokens. A
+/// missing scope is rejected with 403 naming it. Scopes beyond the
+/// `User` ones are also held to `ADMIN_IP_ALLOWLIST`, like admin routes.
+pub fn with_scope(
+    scope: &'static str,
+    context: AuthContext,
+) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+    let registry = context.roles().clone();
+    ip_allowlist::check(scopes::is_privileged(scope), context.trust_proxy)
+        .and(with_claims(context))
+        .and_then(move |claims: Claims| {
+            let registry = registry.clone();
+            async move {
+                if !claims.has_scope(scope, &registry) {
+                    return Err(reject::custom(Error::InsufficientScopeError(
+                        scope.to_string(),
+                    )));
+                }
+                Ok(claims)
+            }
+        })
+}
+
+pub fn with_claims(
+    context: AuthContext,
+) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+    let trust_proxy = context.trust_proxy;
+    warp::method()
+        .and(headers_cloned())
+        .map(move |method: Method, headers: HeaderMap<HeaderValue>| {
+            (context.clone(), method, headers)
+        })
+        .and_then(authenticate)
+        .and(with_request_info(trust_proxy))
+        .map(|claims: Claims, request: RequestInfo| {
+            audit_impersonation(&claims, &request);
+            claims
+        })
+}
+
+/// Like `with_claims`, but also accepts the token a login hands out when
+/// the account must change its password; see
+/// [`create_password_change_jwt`].
+pub fn with_password_change_claims(
+    context: AuthContext,
+) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+    warp::method()
+        .and(headers_cloned())
+        .map(move |method: Method, headers: HeaderMap<HeaderValue>| {
+            (context.clone(), method, headers)
+        })
+        .and_then(|request| authenticate_for(request, Some(PASSWORD_CHANGE_PURPOSE)))
+}
+
+/// Extracts `Some(claims)` for a valid token and `None` when no token was
+/// sent at all. A token that is present but invalid is still rejected.
+pub fn with_auth_optional(
+    context: AuthContext,
+) -> impl Filter<Extract = (Option<Claims>,), Error = Rejection> + Clone {
+    let trust_proxy = context.trust_proxy;
+    warp::method()
+        .and(headers_cloned())
+        .map(move |method: Method, headers: HeaderMap<HeaderValue>| {
+            (context.clone(), method, headers)
+        })
+        .and_then(authenticate_optional)
+        .and(with_request_info(trust_proxy))
+        .map(|claims: Option<Claims>, request: RequestInfo| {
+            if let Some(claims) = &claims {
+                audit_impersonation(claims, &request);
+            }
+            claims
+        })
+}
+
+/// What the audit log needs to know about a request made while
+/// impersonating someone.
+type RequestInfo = (Method, FullPath, ClientInfo);
+
+fn with_request_info(
+    trust_proxy: bool,
+) -> impl Filter<Extract = (RequestInfo,), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(with_client_info(trust_proxy))
+        .map(|method, path, client| (method, path, client))
+}
+
+/// Every request made with an impersonation token is audited, with the
+/// admin as the actor and the impersonated user as the target.
+fn audit_impersonation(claims: &Claims, (method, path, client): &RequestInfo) {
+    if let Some(impersonator) = &claims.impersonator {
+        audit::record(
+            AuditEvent::new(AuditAction::ImpersonatedRequest, client)
+                .actor(impersonator)
+                .target(&claims.sub)
+                .detail(&format!("{} {}", method, path.as_str())),
+        );
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Claims {
-    sub: String,
-    role: String,
-    exp: usize,
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
 }
 
-pub fn with_auth(role: Role) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
-    headers_cloned()
-        .map(move |headers: HeaderMap<HeaderValue>| (role.clone(), headers))
-        .and_then(authorize)
+/// Like `with_claims`, for WebSocket upgrades: browsers cannot set headers
+/// on those, so the token may also come as a `?token=` query parameter.
+/// The session cookie is not accepted, because a cross-site page could
+/// open a socket with it and no CSRF header can guard the upgrade.
+pub fn with_socket_claims(
+    context: AuthContext,
+) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+    let trust_proxy = context.trust_proxy;
+    warp::query::<TokenQuery>()
+        .and(headers_cloned())
+        .and_then(move |query: TokenQuery, headers: HeaderMap<HeaderValue>| {
+            let context = context.clone();
+            async move {
+                let jwt = match (jwt_from_header(&headers), query.token) {
+                    (Err(Error::NoAuthHeaderError), Some(token)) => token,
+                    (result, _) => result.map_err(reject::custom)?,
+                };
+                validate_jwt(&context, &jwt, None).await
+            }
+        })
+        .and(with_request_info(trust_proxy))
+        .map(|claims: Claims, request: RequestInfo| {
+            audit_impersonation(&claims, &request);
+            claims
+        })
+}
+
+/// A signed access token, with its `jti` and how many seconds it is valid
+/// for, taken from its own claims. `expires_in` leaves out the leeway, so
+/// clients that refresh on it do so before the server would refuse the
+/// token even with their clocks somewhat off.
+pub struct AccessToken {
+    pub token: String,
+    pub jti: String,
+    pub expires_in: u64,
+}
+
+/// Signs an access token.
+pub fn create_jwt(
+    context: &AuthContext,
+    uid: &str,
+    role: &Role,
+    token_version: u32,
+) -> Result<AccessToken> {
+    sign_access_token(
+        context,
+        uid,
+        role,
+        token_version,
+        context.jwt.expiry_seconds,
+        None,
+        None,
+        None,
+    )
 }
 
-pub fn create_jwt(uid: &str, role: &Role) -> Result<String> {
-    let expiration = Utc::now()
-        .checked_add_signed(chrono::Duration::seconds(60))
+/// Signs a 15 minute access token for `uid` that records `impersonator`
+/// as the admin acting as them. There is no refresh token to go with it.
+pub fn create_impersonation_jwt(
+    context: &AuthContext,
+    uid: &str,
+    role: &Role,
+    token_version: u32,
+    impersonator: &str,
+) -> Result<AccessToken> {
+    sign_access_token(
+        context,
+        uid,
+        role,
+        token_version,
+        IMPERSONATION_EXPIRY_SECONDS,
+        Some(impersonator.to_owned()),
+        None,
+        None,
+    )
+}
+
+/// Signs a 5 minute token that only `PUT /me/password` accepts, for a login
+/// whose account must change its password before it gets real tokens.
+pub fn create_password_change_jwt(
+    context: &AuthContext,
+    uid: &str,
+    role: &Role,
+    token_version: u32,
+) -> Result<AccessToken> {
+    sign_access_token(
+        context,
+        uid,
+        role,
+        token_version,
+        PASSWORD_CHANGE_EXPIRY_SECONDS,
+        None,
+        Some(PASSWORD_CHANGE_PURPOSE),
+        None,
+    )
+}
+
+/// Signs an access token for `uid` on behalf of the trusted peer `source`,
+/// expiring at least a second before the peer's token, at `peer_exp`, and
+/// no later than our own tokens would. There is no refresh token to go
+/// with it.
+pub fn create_exchanged_jwt(
+    context: &AuthContext,
+    uid: &str,
+    role: &Role,
+    token_version: u32,
+    peer_exp: i64,
+    source: &str,
+) -> Result<AccessToken> {
+    let remaining = peer_exp - Utc::now().timestamp() - 1;
+    if remaining <= 0 {
+        return Err(Error::JWTTokenExpiredError);
+    }
+    sign_access_token(
+        context,
+        uid,
+        role,
+        token_version,
+        context.jwt.expiry_seconds.min(remaining),
+        None,
+        None,
+        Some(source.to_owned()),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn sign_access_token(
+    context: &AuthContext,
+    uid: &str,
+    role: &Role,
+    token_version: u32,
+    expiry_seconds: i64,
+    impersonator: Option<String>,
+    purpose: Option<&str>,
+    source: Option<String>,
+) -> Result<AccessToken> {
+    let now = Utc::now();
+    let expiration = now
+        .checked_add_signed(chrono::Duration::seconds(expiry_seconds))
         .expect("valid timestamp")
         .timestamp();
 
     let claims = Claims {
         sub: uid.to_owned(),
-        role: role.to_string(),
+        role: role.clone(),
         exp: expiration as usize,
+        iat: now.timestamp() as usize,
+        jti: uuid::Uuid::new().to_string(),
+        ver: token_version,
+        iss: context.jwt.issuer.clone(),
+        aud: context.jwt.audience.clone(),
+        impersonator,
+        scopes: scopes::for_role(role, context.roles()),
+        purpose: purpose.map(str::to_owned),
+        source,
     };
-    let header = Header::new(Algorithm::HS512);
-    encode(
-        &header,
-        &claims,
-        &EncodingKey::from_secret(&get_jwt_secret()),
-    )
-    .map_err(|_| Error::JWTTokenCreationError)
+    let mut header = Header::new(context.jwt.algorithm);
+    header.kid = Some(context.jwt.kid.clone());
+    let token = encode(&header, &claims, &context.jwt.encoding_key)
+        .map_err(Error::JWTTokenCreationError)?;
+    Ok(AccessToken {
+        token,
+        expires_in: (claims.exp - claims.iat) as u64,
+        jti: claims.jti,
+    })
 }
 
-async fn authorize((role, headers): (Role, HeaderMap<HeaderValue>)) -> WebResult<String> {
-    match jwt_from_header(&headers) {
-        Ok(jwt) => {
-            let decoded = decode::<Claims>(
-                &jwt,
-                &DecodingKey::from_secret(&get_jwt_secret()),
-                &Validation::new(Algorithm::HS512),
-            )
-            .map_err(|_| reject::custom(Error::JWTTokenError))?;
+pub(crate) fn random_token(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
 
-            if role == Role::Admin && Role::from_str(&decoded.claims.role) != Role::Admin {
-                return Err(reject::custom(Error::NoPermissionError));
-            }
+pub fn create_refresh_token() -> String {
+    random_token(REFRESH_TOKEN_LENGTH)
+}
+
+pub fn create_csrf_token() -> String {
+    random_token(CSRF_TOKEN_LENGTH)
+}
+
+async fn authenticate(request: (AuthContext, Method, HeaderMap<HeaderValue>)) -> WebResult<Claims> {
+    authenticate_for(request, None).await
+}
+
+async fn authenticate_for(
+    (context, method, headers): (AuthContext, Method, HeaderMap<HeaderValue>),
+    purpose: Option<&str>,
+) -> WebResult<Claims> {
+    let (jwt, source) = jwt_from_request(&context, &headers).map_err(reject::custom)?;
+
+    // Browsers attach cookies to cross-site requests automatically, so
+    // cookie-authenticated writes must prove they can read the CSRF cookie.
+    if source == TokenSource::Cookie && !is_safe_method(&method) {
+        verify_csrf(&headers).map_err(reject::custom)?;
+    }
+
+    validate_jwt(&context, &jwt, purpose).await
+}
+
+/// The checks every authenticated request goes through once its token has
+/// been found: signature and expiry, a known role, not revoked, and issued
+/// since the account's last logout everywhere. Single-purpose tokens are
+/// only accepted where `purpose` is theirs.
+async fn validate_jwt(
+    context: &AuthContext,
+    jwt: &str,
+    purpose: Option<&str>,
+) -> WebResult<Claims> {
+    let claims = context.jwt.decode(jwt).map_err(reject::custom)?;
+
+    if claims.purpose.is_some() && claims.purpose.as_deref() != purpose {
+        return Err(reject::custom(Error::JWTTokenError));
+    }
+
+    if !context.roles.is_known(&claims.role) {
+        return Err(reject::custom(Error::JWTTokenError));
+    }
+
+    if context
+        .is_revoked(&claims.jti)
+        .await
+        .map_err(reject::custom)?
+    {
+        return Err(reject::custom(Error::TokenRevokedError));
+    }
+
+    // With `AUTH_VERIFY_USER=false` a token outlives its account, and
+    // `/logout-all` and password changes no longer end existing tokens.
+    if config::verify_user() {
+        match context
+            .token_version(&claims.sub)
+            .await
+            .map_err(reject::custom)?
+        {
+            Some(version) if version == claims.ver => {}
+            Some(_) => return Err(reject::custom(Error::TokenRevokedError)),
+            None => return Err(reject::custom(Error::JWTTokenError)),
+        }
+    }
+    access_log::set_uid(&claims.sub);
+    Ok(claims)
+}
+
+async fn authenticate_optional(
+    request: (AuthContext, Method, HeaderMap<HeaderValue>),
+) -> WebResult<Option<Claims>> {
+    match authenticate(request).await {
+        Ok(claims) => Ok(Some(claims)),
+        Err(rejection) if matches!(rejection.find::<Error>(), Some(Error::NoAuthHeaderError)) => {
+            Ok(None)
+        }
+        Err(rejection) => Err(rejection),
+    }
+}
+
+fn is_safe_method(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+fn verify_csrf(headers: &HeaderMap<HeaderValue>) -> Result<()> {
+    let cookie = cookie_value(headers, CSRF_COOKIE).ok_or(Error::CsrfError)?;
+    let header = headers
+        .get(CSRF_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .ok_or(Error::CsrfError)?;
+
+    if !constant_time_eq(cookie.as_bytes(), header.as_bytes()) {
+        return Err(Error::CsrfError);
+    }
+    Ok(())
+}
+
+/// Hex SHA-256 used to store one-time tokens without keeping the plaintext.
+pub(crate) fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn authorize(role: Role, claims: Claims) -> WebResult<Claims> {
+    if !claims.role.has_permission(&role) {
+        return Err(reject::custom(Error::NoPermissionError));
+    }
 
-            Ok(decoded.claims.sub)
+    Ok(claims)
+}
+
+/// Like `authorize`, so a role passes a `with_any_role` gate exactly when
+/// it would pass `with_auth` for one of its roles.
+async fn authorize_any(roles: Vec<Role>, claims: Claims) -> WebResult<Claims> {
+    if !roles.iter().any(|role| claims.role.has_permission(role)) {
+        return Err(reject::custom(Error::NoPermissionError));
+    }
+
+    Ok(claims)
+}
+
+#[derive(PartialEq)]
+enum TokenSource {
+    Header,
+    Cookie,
+}
+
+/// The `Authorization` header takes precedence; the session cookie is only
+/// consulted when cookie auth is enabled and no header was sent.
+fn jwt_from_request(
+    context: &AuthContext,
+    headers: &HeaderMap<HeaderValue>,
+) -> Result<(String, TokenSource)> {
+    match jwt_from_header(headers) {
+        Err(Error::NoAuthHeaderError) if context.jwt.cookie_auth => {
+            cookie_value(headers, SESSION_COOKIE)
+                .map(|jwt| (jwt, TokenSource::Cookie))
+                .ok_or(Error::NoAuthHeaderError)
         }
-        Err(e) => return Err(reject::custom(e)),
+        result => result.map(|jwt| (jwt, TokenSource::Header)),
     }
 }
 
+pub fn cookie_value(headers: &HeaderMap<HeaderValue>, name: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, value)| *key == name && !value.is_empty())
+        .map(|(_, value)| value.to_owned())
+}
+
 fn jwt_from_header(headers: &HeaderMap<HeaderValue>) -> Result<String> {
     let header = match headers.get(AUTHORIZATION) {
         Some(v) => v,
@@ -109,3 +1223,434 @@ fn jwt_from_header(headers: &HeaderMap<HeaderValue>) -> Result<String> {
     }
     Ok(auth_header.trim_start_matches(BEARER).to_owned())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{routes::AppState, test_support};
+    use serde_json::Value;
+    use warp::http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        StatusCode,
+    };
+
+    #[test]
+    fn a_full_token_version_cache_drops_stale_entries() {
+        let stale = Instant::now() - config::user_cache_ttl() - Duration::from_secs(1);
+        let mut versions = TokenVersions {
+            entries: (0..MAX_CACHED_TOKEN_VERSIONS)
+                .map(|i| (i.to_string(), (0, stale)))
+                .collect(),
+            forgotten: 0,
+        };
+        versions.insert("fresh", 1);
+        assert_eq!(versions.entries.len(), 1);
+        assert_eq!(versions.entries["fresh"].0, 1);
+    }
+
+    #[test]
+    fn a_token_version_cache_of_fresh_entries_stops_growing() {
+        let mut versions = TokenVersions {
+            entries: (0..MAX_CACHED_TOKEN_VERSIONS)
+                .map(|i| (i.to_string(), (0, Instant::now())))
+                .collect(),
+            forgotten: 0,
+        };
+        versions.insert("new", 1);
+        assert_eq!(versions.entries.len(), MAX_CACHED_TOKEN_VERSIONS);
+        assert!(!versions.entries.contains_key("new"));
+        versions.insert("0", 2);
+        assert_eq!(versions.entries["0"].0, 2);
+    }
+
+    fn claims(role: &str) -> Claims {
+        serde_json::from_value(serde_json::json!({
+            "sub": "someone",
+            "role": role,
+            "exp": usize::MAX,
+            "iat": 0,
+            "jti": "jti",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn admins_have_every_permission_users_have() {
+        assert!(Role::Admin.has_permission(&Role::User));
+        assert!(Role::Admin.has_permission(&Role::Admin));
+        assert!(Role::User.has_permission(&Role::User));
+        assert!(Role::Custom("support".to_string()).has_permission(&Role::User));
+    }
+
+    #[test]
+    fn admins_and_roles_granted_privileged_scopes_are_privileged() {
+        let registry = RoleRegistry::default();
+        let support = Role::Custom("support".to_string());
+        registry.insert(crate::roles::RoleDefinition {
+            name: "support".to_string(),
+            permissions: vec![scopes::PROFILE_READ.to_string()],
+        });
+        assert!(scopes::is_privileged_role(&Role::Admin, &registry));
+        assert!(!scopes::is_privileged_role(&Role::User, &registry));
+        assert!(!scopes::is_privileged_role(&Role::Guest, &registry));
+        assert!(!scopes::is_privileged_role(&support, &registry));
+
+        registry.insert(crate::roles::RoleDefinition {
+            name: "support".to_string(),
+            permissions: vec![scopes::USERS_WRITE.to_string()],
+        });
+        assert!(scopes::is_privileged_role(&support, &registry));
+    }
+
+    #[test]
+    fn users_do_not_have_admin_permissions() {
+        assert!(!Role::User.has_permission(&Role::Admin));
+        assert!(!Role::Custom("support".to_string()).has_permission(&Role::Admin));
+        assert!(!Role::Guest.has_permission(&Role::User));
+        assert!(!Role::Guest.has_permission(&Role::Admin));
+    }
+
+    #[test]
+    fn built_in_roles_parse_in_any_case() {
+        for (name, role) in [
+            ("Admin", Role::Admin),
+            ("User", Role::User),
+            ("Guest", Role::Guest),
+        ] {
+            for spelling in [name.to_string(), name.to_lowercase(), name.to_uppercase()] {
+                assert_eq!(Role::from_str(&spelling).unwrap(), role, "{}", spelling);
+            }
+            assert_eq!(role.to_string(), name);
+        }
+        assert_eq!(
+            Role::from_str("support").unwrap(),
+            Role::Custom("support".to_string())
+        );
+    }
+
+    #[test]
+    fn malformed_role_names_are_refused() {
+        for name in ["", " ", " Admin", "Admin ", "ad\nmin"] {
+            assert!(
+                matches!(Role::from_str(name), Err(Error::InvalidRoleError)),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn a_stored_role_nobody_defined_is_corrupt_data() {
+        let roles = RoleRegistry::default();
+        assert_eq!(roles.resolve("uid", "admin").unwrap(), Role::Admin);
+        for name in ["Superuser", "", "Admin "] {
+            assert!(
+                matches!(roles.resolve("uid", name), Err(Error::InvalidRoleDataError)),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_routes_refuse_users() {
+        let rejection = authorize(Role::Admin, claims("User")).await.unwrap_err();
+        assert!(matches!(
+            rejection.find::<Error>(),
+            Some(Error::NoPermissionError)
+        ));
+        assert!(authorize(Role::User, claims("Admin")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn any_role_gates_follow_the_role_hierarchy() {
+        for role in ["Admin", "User", "support"] {
+            assert!(
+                authorize_any(vec![Role::User], claims(role)).await.is_ok(),
+                "{}",
+                role
+            );
+        }
+        let rejection = authorize_any(vec![Role::User, Role::Admin], claims("Guest"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            rejection.find::<Error>(),
+            Some(Error::NoPermissionError)
+        ));
+        assert!(authorize_any(vec![Role::Admin], claims("User"))
+            .await
+            .is_err());
+    }
+
+    /// `GET /admin` through the whole filter tree, with `token` if any.
+    async fn get_admin(app: &AppState, token: Option<&str>) -> (StatusCode, Option<String>, Value) {
+        let mut request = warp::test::request().path("/api/v1/admin");
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let response = request.reply(&crate::routes(app.clone())).await;
+        let challenge = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .map(|v| v.to_str().unwrap().to_owned());
+        let body = serde_json::from_slice(response.body()).unwrap();
+        (response.status(), challenge, body)
+    }
+
+    #[tokio::test]
+    async fn no_token_asks_for_one() {
+        let app = test_support::offline_app().await;
+        let (status, challenge, body) = get_admin(&app, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(challenge.as_deref(), Some("Bearer"));
+        assert_eq!(body["code"], "NO_AUTH_HEADER");
+    }
+
+    #[tokio::test]
+    async fn a_garbage_token_is_invalid() {
+        let app = test_support::offline_app().await;
+        let (status, challenge, body) = get_admin(&app, Some("garbage")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(challenge.as_deref(), Some("Bearer error=\"invalid_token\""));
+        assert_eq!(body["code"], "INVALID_TOKEN");
+    }
+
+    #[tokio::test]
+    async fn an_expired_token_says_so() {
+        let app = test_support::offline_app().await;
+        let mut claims = claims("Admin");
+        claims.exp = (Utc::now().timestamp() - 3600) as usize;
+        let mut header = Header::new(app.auth_context.jwt.algorithm);
+        header.kid = Some(app.auth_context.jwt.kid.clone());
+        let expired = encode(&header, &claims, &app.auth_context.jwt.encoding_key).unwrap();
+        let (status, challenge, body) = get_admin(&app, Some(&expired)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(challenge.as_deref(), Some("Bearer error=\"invalid_token\""));
+        assert_eq!(body["code"], "TOKEN_EXPIRED");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn a_user_token_is_forbidden_from_admin_routes() {
+        let app = test_support::app().await;
+        let user = User::new("a@example.com".to_string(), String::new(), &Role::User);
+        app.users.insert_one(&user, None).await.unwrap();
+        let token = create_jwt(
+            &app.auth_context,
+            &user.uid,
+            &Role::User,
+            user.token_version,
+        )
+        .unwrap();
+        let (status, challenge, body) = get_admin(&app, Some(&token.token)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(challenge, None);
+        assert_eq!(body["code"], "NO_PERMISSION");
+    }
+
+    /// `with_auth_optional` on a `GET` with `token`, if any.
+    async fn optional_claims(app: &AppState, token: Option<&str>) -> WebResult<Option<Claims>> {
+        let mut request = warp::test::request();
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        request
+            .filter(&with_auth_optional(app.auth_context.clone()))
+            .await
+    }
+
+    #[tokio::test]
+    async fn optional_auth_without_a_token_is_anonymous() {
+        let app = test_support::offline_app().await;
+        assert!(optional_claims(&app, None).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn optional_auth_still_refuses_an_invalid_token() {
+        let app = test_support::offline_app().await;
+        let rejection = optional_claims(&app, Some("garbage")).await.unwrap_err();
+        assert!(matches!(
+            rejection.find::<Error>(),
+            Some(Error::JWTTokenError)
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn optional_auth_with_a_valid_token_has_its_claims() {
+        let app = test_support::app().await;
+        let user = User::new("a@example.com".to_string(), String::new(), &Role::User);
+        app.users.insert_one(&user, None).await.unwrap();
+        let token = create_jwt(&app.auth_context, &user.uid, &Role::User, 0).unwrap();
+        let claims = optional_claims(&app, Some(&token.token)).await.unwrap();
+        assert_eq!(claims.map(|c| c.sub), Some(user.uid));
+    }
+
+    /// `POST /logout`, a write, through the whole filter tree with `headers`.
+    async fn post_logout(app: &AppState, headers: &[(&str, String)]) -> (StatusCode, Value) {
+        let mut request = warp::test::request().method("POST").path("/api/v1/logout");
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        let response = request.reply(&crate::routes(app.clone())).await;
+        let body = serde_json::from_slice(response.body()).unwrap_or(Value::Null);
+        (response.status(), body)
+    }
+
+    #[tokio::test]
+    async fn a_cookie_write_without_the_csrf_header_is_refused() {
+        let app = test_support::offline_app_with(&[("AUTH_COOKIE", "true")]).await;
+        let token = create_jwt(&app.auth_context, "uid", &Role::User, 0).unwrap();
+        let cookies = format!("{}={}; {}=csrf", SESSION_COOKIE, token.token, CSRF_COOKIE);
+
+        for headers in [
+            vec![("cookie", cookies.clone())],
+            vec![
+                ("cookie", cookies.clone()),
+                (CSRF_HEADER, "other".to_string()),
+            ],
+        ] {
+            let (status, body) = post_logout(&app, &headers).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+            assert_eq!(body["code"], "CSRF_FAILED");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn header_writes_and_cookie_writes_with_the_csrf_header_pass() {
+        let app = test_support::app().await;
+        let mut context = app.auth_context.clone();
+        context.jwt.cookie_auth = true;
+        let app = AppState {
+            auth_context: context,
+            ..app
+        };
+        let user = User::new("a@example.com".to_string(), String::new(), &Role::User);
+        app.users.insert_one(&user, None).await.unwrap();
+        let token = || {
+            create_jwt(&app.auth_context, &user.uid, &Role::User, 0)
+                .unwrap()
+                .token
+        };
+
+        let (status, _) =
+            post_logout(&app, &[("authorization", format!("Bearer {}", token()))]).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let cookies = format!("{}={}; {}=csrf", SESSION_COOKIE, token(), CSRF_COOKIE);
+        let headers = [("cookie", cookies), (CSRF_HEADER, "csrf".to_string())];
+        let (status, _) = post_logout(&app, &headers).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    /// The signing settings with `JWT_LEEWAY_SECONDS` set to `leeway`.
+    fn jwt_with_leeway(leeway: &str) -> JwtConfig {
+        test_support::test_config(&[("JWT_LEEWAY_SECONDS", leeway)]).jwt
+    }
+
+    /// A token signed by `jwt`, issued and expiring the given number of
+    /// seconds from now.
+    fn token(jwt: &JwtConfig, issued_in: i64, expires_in: i64) -> String {
+        let now = Utc::now().timestamp();
+        let mut claims = claims("User");
+        claims.iat = (now + issued_in) as usize;
+        claims.exp = (now + expires_in) as usize;
+        let mut header = Header::new(jwt.algorithm);
+        header.kid = Some(jwt.kid.clone());
+        encode(&header, &claims, &jwt.encoding_key).unwrap()
+    }
+
+    #[test]
+    fn a_token_that_just_expired_is_within_the_leeway() {
+        let jwt = jwt_with_leeway("30");
+        assert!(jwt.decode(&token(&jwt, -3600, -10)).is_ok());
+        assert!(matches!(
+            jwt.decode(&token(&jwt, -3600, -40)),
+            Err(Error::JWTTokenExpiredError)
+        ));
+
+        let jwt = jwt_with_leeway("0");
+        assert!(matches!(
+            jwt.decode(&token(&jwt, -3600, -10)),
+            Err(Error::JWTTokenExpiredError)
+        ));
+    }
+
+    #[test]
+    fn a_token_from_a_fast_clock_is_within_the_leeway() {
+        let jwt = jwt_with_leeway("30");
+        assert!(jwt.decode(&token(&jwt, 10, 3600)).is_ok());
+        assert!(matches!(
+            jwt.decode(&token(&jwt, 40, 3600)),
+            Err(Error::JWTTokenError)
+        ));
+
+        let jwt = jwt_with_leeway("0");
+        assert!(matches!(
+            jwt.decode(&token(&jwt, 10, 3600)),
+            Err(Error::JWTTokenError)
+        ));
+    }
+
+    /// The signing settings with `JWT_SECRETS` set to `secrets`.
+    fn jwt_with_secrets(secrets: &str) -> JwtConfig {
+        test_support::test_config(&[("JWT_SECRETS", secrets)]).jwt
+    }
+
+    const KEY_A: &str = "the key tokens were signed with before the rotation";
+    const KEY_B: &str = "the key tokens are signed with after the rotation";
+
+    #[test]
+    fn tokens_of_both_keys_verify_while_the_old_one_is_listed() {
+        let before = jwt_with_secrets(KEY_A);
+        let old = token(&before, 0, 3600);
+
+        let during = jwt_with_secrets(&format!("{},{}", KEY_B, KEY_A));
+        assert_ne!(during.kid, before.kid);
+        let new = token(&during, 0, 3600);
+        assert!(during.decode(&old).is_ok());
+        assert!(during.decode(&new).is_ok());
+
+        let after = jwt_with_secrets(KEY_B);
+        assert_eq!(after.kid, during.kid);
+        assert!(after.decode(&new).is_ok());
+        assert!(matches!(after.decode(&old), Err(Error::JWTTokenError)));
+    }
+
+    #[test]
+    fn a_token_without_a_kid_is_tried_against_every_key() {
+        let before = jwt_with_secrets(KEY_A);
+        let mut claims = claims("User");
+        claims.iat = Utc::now().timestamp() as usize;
+        let legacy = encode(
+            &Header::new(before.algorithm),
+            &claims,
+            &before.encoding_key,
+        )
+        .unwrap();
+
+        let during = jwt_with_secrets(&format!("{},{}", KEY_B, KEY_A));
+        assert!(during.decode(&legacy).is_ok());
+        assert!(matches!(
+            jwt_with_secrets(KEY_B).decode(&legacy),
+            Err(Error::JWTTokenError)
+        ));
+    }
+
+    #[tokio::test]
+    async fn expires_in_leaves_out_the_leeway() {
+        let app = test_support::offline_app_with(&[
+            ("JWT_EXPIRY_SECONDS", "600"),
+            ("JWT_LEEWAY_SECONDS", "30"),
+        ])
+        .await;
+        let token = create_jwt(&app.auth_context, "uid", &Role::User, 0).unwrap();
+        assert_eq!(token.expires_in, 600);
+        assert_eq!(
+            app.auth_context.access_token_lifetime(),
+            Duration::from_secs(630)
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use crate::{
+    auth::{random_token, Claims},
+    config,
+    error::Error,
+    repository::timed,
+    users, Result, User, WebResult,
+};
+use bytes::{Buf, BufMut};
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, DateTime},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+    Collection,
+};
+use std::{convert::Infallible, env, io::ErrorKind, path::PathBuf, sync::Arc};
+use utoipa::ToSchema;
+use warp::{
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE, ETAG},
+        HeaderValue, StatusCode,
+    },
+    multipart::{FormData, Part},
+    reject, reply, Filter, Reply,
+};
+
+pub const MAX_AVATAR_BYTES: usize = 2 * 1024 * 1024;
+/// Room for the multipart boundaries and part headers around the image.
+pub const MAX_FORM_BYTES: u64 = MAX_AVATAR_BYTES as u64 + 64 * 1024;
+const AVATAR_FIELD: &str = "avatar";
+const FILE_ID_LENGTH: usize = 16;
+const CACHE_MAX_AGE_SECONDS: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Copy)]
+enum ImageType {
+    Jpeg,
+    Png,
+}
+
+impl ImageType {
+    /// Identifies an image by its leading bytes, ignoring what the client
+    /// claims it uploaded.
+    fn sniff(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(ImageType::Jpeg)
+        } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageType::Png)
+        } else {
+            None
+        }
+    }
+
+    fn from_extension(file: &str) -> Option<Self> {
+        match file.rsplit_once('.')?.1 {
+            "jpg" => Some(ImageType::Jpeg),
+            "png" => Some(ImageType::Png),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ImageType::Jpeg => "jpg",
+            ImageType::Png => "png",
+        }
+    }
+
+    fn mime(self) -> &'static str {
+        match self {
+            ImageType::Jpeg => "image/jpeg",
+            ImageType::Png => "image/png",
+        }
+    }
+}
+
+/// Avatar images on local disk, one file per upload. The user document
+/// records the current file name, so a replaced file can be removed.
+#[derive(Clone)]
+pub struct AvatarStore {
+    dir: Arc<PathBuf>,
+}
+
+impl AvatarStore {
+    /// Reads `AVATAR_DIR` (default `avatars`) and creates the directory.
+    pub async fn from_env() -> std::io::Result<Self> {
+        let dir = PathBuf::from(env::var("AVATAR_DIR").unwrap_or_else(|_| "avatars".to_string()));
+        tokio::fs::create_dir_all(&dir).await?;
+        Ok(AvatarStore { dir: Arc::new(dir) })
+    }
+
+    async fn write(&self, file: &str, bytes: &[u8]) -> Result<()> {
+        tokio::fs::write(self.dir.join(file), bytes)
+            .await
+            .map_err(|_| Error::AvatarStorageError)
+    }
+
+    async fn read(&self, file: &str) -> Result<Vec<u8>> {
+        tokio::fs::read(self.dir.join(file))
+            .await
+            .map_err(|e| match e.kind() {
+                ErrorKind::NotFound => Error::AvatarNotFoundError,
+                _ => Error::AvatarStorageError,
+            })
+    }
+
+    async fn remove(&self, file: &str) {
+        if let Err(e) = tokio::fs::remove_file(self.dir.join(file)).await {
+            if e.kind() != ErrorKind::NotFound {
+                tracing::error!("removing avatar {} failed: {}", file, e);
+            }
+        }
+    }
+}
+
+pub fn with_avatar_store(
+    store: AvatarStore,
+) -> impl Filter<Extract = (AvatarStore,), Error = Infallible> + Clone {
+    warp::any().map(move || store.clone())
+}
+
+/// Reads a part into memory, giving up as soon as it exceeds
+/// `MAX_AVATAR_BYTES` instead of buffering the whole upload.
+async fn read_part(part: Part) -> Result<Vec<u8>> {
+    part.stream()
+        .map_err(|_| Error::InvalidAvatarUploadError)
+        .try_fold(Vec::new(), |mut bytes, chunk| async move {
+            if bytes.len() + chunk.remaining() > MAX_AVATAR_BYTES {
+                return Err(Error::AvatarTooLargeError);
+            }
+            bytes.put(chunk);
+            Ok(bytes)
+        })
+        .await
+}
+
+async fn read_avatar(mut form: FormData) -> Result<(ImageType, Vec<u8>)> {
+    while let Some(part) = form
+        .try_next()
+        .await
+        .map_err(|_| Error::InvalidAvatarUploadError)?
+    {
+        if part.name() != AVATAR_FIELD {
+            continue;
+        }
+        if !matches!(part.content_type(), Some("image/jpeg" | "image/png")) {
+            return Err(Error::UnsupportedAvatarTypeError);
+        }
+        let bytes = read_part(part).await?;
+        let image_type = ImageType::sniff(&bytes).ok_or(Error::UnsupportedAvatarTypeError)?;
+        return Ok((image_type, bytes));
+    }
+    Err(Error::InvalidAvatarUploadError)
+}
+
+/// The form `upload_avatar_handler` reads, described for the API docs.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct AvatarUpload {
+    /// A JPEG or PNG image.
+    #[schema(value_type = String, format = Binary)]
+    avatar: Vec<u8>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/me/avatar",
+    tag = "profile",
+    request_body(content = AvatarUpload, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "The new avatar URL", body = Object,
+            example = json!({"avatar_url": "/api/v1/avatars/0b6c.../?v=0b6c...-Xk2p.png"})),
+        (status = 400, description = "No avatar field", body = ErrorResponse),
+        (status = 413, description = "Image over 2 MB", body = ErrorResponse),
+        (status = 415, description = "Not a JPEG or PNG", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn upload_avatar_handler(
+    claims: Claims,
+    store: AvatarStore,
+    users_collection: Collection<User>,
+    form: FormData,
+) -> WebResult<impl Reply> {
+    let (image_type, bytes) = read_avatar(form).await.map_err(reject::custom)?;
+
+    let file = format!(
+        "{}-{}.{}",
+        claims.sub,
+        random_token(FILE_ID_LENGTH),
+        image_type.extension()
+    );
+    store.write(&file, &bytes).await.map_err(reject::custom)?;
+
+    // The file name in the query string changes with every upload, so
+    // clients may cache the image for as long as they like.
+    let avatar_url = format!("{}/avatars/{}?v={}", config::api_prefix(), claims.sub, file);
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::Before)
+        .build();
+    let previous = timed(users_collection.find_one_and_update(
+        doc! {"uid": &claims.sub},
+        doc! {
+            "$set": {
+                "avatar_file": &file,
+                "avatar_url": &avatar_url,
+                "updated_at": DateTime::now(),
+            },
+            "$inc": {"version": 1},
+        },
+        options,
+    ))
+    .await;
+    let previous = match previous {
+        Ok(Some(previous)) => previous,
+        Ok(None) => {
+            store.remove(&file).await;
+            return Err(reject::custom(Error::UserNotFoundError));
+        }
+        Err(e) => {
+            store.remove(&file).await;
+            return Err(reject::custom(e));
+        }
+    };
+    if let Some(old_file) = previous.avatar_file {
+        store.remove(&old_file).await;
+    }
+
+    Ok(reply::with_status(
+        reply::json(&serde_json::json!({ "avatar_url": avatar_url })),
+        StatusCode::CREATED,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/avatars/{uid}",
+    tag = "profile",
+    params(("uid" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The image", content_type = "image/png"),
+        (status = 304, description = "The `If-None-Match` ETag is still current"),
+        (status = 404, description = "No avatar", body = ErrorResponse),
+    )
+)]
+pub async fn get_avatar_handler(
+    uid: String,
+    if_none_match: Option<String>,
+    store: AvatarStore,
+    users_collection: Collection<User>,
+) -> WebResult<reply::Response> {
+    let file = timed(users_collection.find_one(users::active(doc! {"uid": &uid}), None))
+        .await
+        .map_err(reject::custom)?
+        .and_then(|user| user.avatar_file)
+        .ok_or_else(|| reject::custom(Error::AvatarNotFoundError))?;
+    let image_type = ImageType::from_extension(&file)
+        .ok_or_else(|| reject::custom(Error::AvatarNotFoundError))?;
+
+    let etag = format!("\"{}\"", file);
+    let mut response = if if_none_match.as_deref() == Some(etag.as_str()) {
+        reply::with_status(reply(), StatusCode::NOT_MODIFIED).into_response()
+    } else {
+        let bytes = store.read(&file).await.map_err(reject::custom)?;
+        let mut response = reply::Response::new(bytes.into());
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(image_type.mime()));
+        response
+    };
+
+    let headers = response.headers_mut();
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        headers.insert(ETAG, etag);
+    }
+    if let Ok(cache_control) =
+        HeaderValue::from_str(&format!("public, max-age={}", CACHE_MAX_AGE_SECONDS))
+    {
+        headers.insert(CACHE_CONTROL, cache_control);
+    }
+    Ok(response)
+}
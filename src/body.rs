@@ -0,0 +1,134 @@
+use crate::{config, error::Error};
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use warp::{http::header::CONTENT_TYPE, reject, Filter, Rejection};
+
+const JSON: &str = "application/json";
+const FORM: &str = "application/x-www-form-urlencoded";
+
+/// Like `warp::body::json`, but refuses bodies over `MAX_BODY_BYTES` (see
+/// [`config::max_body_bytes`]) with a `PayloadTooLargeError` instead of
+/// buffering whatever the client sends.
+pub fn json<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+{
+    json_bytes().and_then(|body: Bytes| async move { parse(&body).map_err(reject::custom) })
+}
+
+/// The body of a JSON request, unparsed, for filters that need the exact
+/// bytes as well as the value. See [`parse`].
+pub fn json_bytes() -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    typed_body(&[JSON], JSON, config::max_body_bytes()).map(|_, body: Bytes| body)
+}
+
+/// Like [`json`], but for bulk requests, up to `MAX_UPLOAD_BYTES` (see
+/// [`config::max_upload_bytes`]) rather than `MAX_BODY_BYTES`.
+pub fn bulk_json<T: DeserializeOwned + Send>(
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    typed_body(&[JSON], JSON, config::max_upload_bytes())
+        .and_then(|_, body: Bytes| async move { parse(&body).map_err(reject::custom) })
+}
+
+/// Like [`json`], but also takes `application/x-www-form-urlencoded`
+/// bodies, for clients that post credentials the way HTML forms and
+/// OAuth tooling do. Anything else is refused with 415.
+pub fn json_or_form<T: DeserializeOwned + Send>(
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    typed_body(
+        &[JSON, FORM],
+        "application/json or application/x-www-form-urlencoded",
+        config::max_body_bytes(),
+    )
+    .and_then(|mime: Option<&'static str>, body: Bytes| async move {
+        if mime == Some(FORM) {
+            serde_urlencoded::from_bytes(&body)
+                .map_err(|e| reject::custom(Error::InvalidBodyError(e.to_string())))
+        } else {
+            parse(&body).map_err(reject::custom)
+        }
+    })
+}
+
+/// The body, of at most `limit` bytes, with which of `accepted` its
+/// `Content-Type` is. Any other type is refused with an
+/// `UnsupportedMediaTypeError` naming `accepted_names`, and so is a body
+/// sent without one rather than guessed at; only an empty body may leave
+/// it out, for the parser to report as missing.
+fn typed_body(
+    accepted: &'static [&'static str],
+    accepted_names: &'static str,
+    limit: u64,
+) -> impl Filter<Extract = (Option<&'static str>, Bytes), Error = Rejection> + Clone {
+    warp::header::optional::<String>(CONTENT_TYPE.as_str())
+        .and_then(move |content_type: Option<String>| async move {
+            match content_type {
+                None => Ok(None),
+                Some(value) => accepted
+                    .iter()
+                    .find(|mime| is_mime(&value, mime))
+                    .map(|mime| Some(*mime))
+                    .ok_or_else(|| {
+                        reject::custom(Error::UnsupportedMediaTypeError(accepted_names))
+                    }),
+            }
+        })
+        .and(bytes(limit))
+        .and_then(move |mime: Option<&'static str>, body: Bytes| async move {
+            if mime.is_none() && !body.is_empty() {
+                return Err(reject::custom(Error::UnsupportedMediaTypeError(
+                    accepted_names,
+                )));
+            }
+            Ok((mime, body))
+        })
+        .untuple_one()
+}
+
+/// Parses a body taken with [`json_bytes`] the way [`json`] does.
+pub fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, Error> {
+    serde_json::from_slice(body).map_err(|e| Error::InvalidBodyError(e.to_string()))
+}
+
+/// The raw body, of at most `limit` bytes. A `Content-Length` over the
+/// limit is refused before anything is read; bodies sent without one
+/// (chunked) are read until they pass the limit, rather than refused
+/// outright like `warp::body::content_length_limit` does.
+pub fn bytes(limit: u64) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and(warp::body::stream())
+        .and_then(move |length: Option<u64>, body| async move {
+            if length.is_some_and(|length| length > limit) {
+                return Err(reject::custom(Error::PayloadTooLargeError));
+            }
+            read_limited(body, limit).await.map_err(reject::custom)
+        })
+}
+
+async fn read_limited<S, B>(body: S, limit: u64) -> Result<Bytes, Error>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    futures_util::pin_mut!(body);
+    let mut buffer = BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.map_err(|e| Error::InvalidBodyError(e.to_string()))?;
+        if (buffer.len() + chunk.remaining()) as u64 > limit {
+            return Err(Error::PayloadTooLargeError);
+        }
+        while chunk.has_remaining() {
+            let part = chunk.chunk();
+            let len = part.len();
+            buffer.extend_from_slice(part);
+            chunk.advance(len);
+        }
+    }
+    Ok(buffer.freeze())
+}
+
+fn is_mime(content_type: &str, expected: &str) -> bool {
+    content_type
+        .split(';')
+        .next()
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case(expected))
+}
@@ -0,0 +1,284 @@
+use crate::{config, error::Error, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::{convert::Infallible, env, sync::Arc, time::Duration};
+use warp::Filter;
+
+const TURNSTILE_VERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+const RECAPTCHA_VERIFY_URL: &str = "https://www.google.com/recaptcha/api/siteverify";
+/// How long signup waits for the provider before treating it as down.
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(3);
+const DEFAULT_MIN_SCORE: f64 = 0.5;
+
+/// Checks the CAPTCHA token a client solved before signing up.
+#[async_trait]
+pub trait CaptchaVerifier: Send + Sync {
+    /// `Ok` if `token` proves a human, `CaptchaFailedError` if it is
+    /// missing or doesn't.
+    async fn verify(&self, token: Option<&str>, remote_ip: Option<&str>) -> Result<()>;
+}
+
+pub type Captcha = Arc<dyn CaptchaVerifier>;
+
+/// Builds the verifier `CAPTCHA_PROVIDER` names, `turnstile` or
+/// `recaptcha`, with its `CAPTCHA_SECRET`. When unset every signup passes.
+/// Invalid settings are recorded in `problems`, for `Config::from_env`.
+pub(crate) fn from_env(problems: &mut Vec<String>) -> Captcha {
+    let provider = match env::var("CAPTCHA_PROVIDER").as_deref() {
+        Ok("") | Err(_) => return Arc::new(NoCaptcha),
+        Ok("turnstile") => Provider::Turnstile,
+        Ok("recaptcha") => {
+            let min_score = config::parse_var(
+                "CAPTCHA_MIN_SCORE",
+                DEFAULT_MIN_SCORE,
+                "a number between 0 and 1",
+                problems,
+            );
+            if !(0.0..=1.0).contains(&min_score) {
+                problems.push(format!(
+                    "CAPTCHA_MIN_SCORE must be a number between 0 and 1, got {}",
+                    min_score
+                ));
+            }
+            Provider::Recaptcha { min_score }
+        }
+        Ok(other) => {
+            problems.push(format!(
+                "CAPTCHA_PROVIDER must be turnstile or recaptcha, got {:?}",
+                other
+            ));
+            return Arc::new(NoCaptcha);
+        }
+    };
+    let fail_open = match env::var("CAPTCHA_FAIL_OPEN").as_deref() {
+        Ok("true") | Ok("1") => true,
+        Ok("false") | Ok("0") | Ok("") | Err(_) => false,
+        Ok(other) => {
+            problems.push(format!(
+                "CAPTCHA_FAIL_OPEN must be true or false, got {:?}",
+                other
+            ));
+            false
+        }
+    };
+    let secret = env::var("CAPTCHA_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+        .unwrap_or_else(|| {
+            problems.push("CAPTCHA_SECRET must be set when CAPTCHA_PROVIDER is set".to_string());
+            String::new()
+        });
+    Arc::new(SiteVerifier::new(provider, secret, fail_open))
+}
+
+pub fn with_captcha(
+    captcha: Captcha,
+) -> impl Filter<Extract = (Captcha,), Error = Infallible> + Clone {
+    warp::any().map(move || captcha.clone())
+}
+
+/// Lets everything through, for when no provider is configured.
+pub struct NoCaptcha;
+
+#[async_trait]
+impl CaptchaVerifier for NoCaptcha {
+    async fn verify(&self, _token: Option<&str>, _remote_ip: Option<&str>) -> Result<()> {
+        Ok(())
+    }
+}
+
+enum Provider {
+    Turnstile,
+    /// reCAPTCHA v3, whose tokens carry a score from 0 (a bot) to 1.
+    Recaptcha {
+        min_score: f64,
+    },
+}
+
+impl Provider {
+    fn is_human(&self, verdict: &SiteVerifyResponse) -> bool {
+        verdict.success
+            && match self {
+                Provider::Turnstile => true,
+                Provider::Recaptcha { min_score } => {
+                    verdict.score.is_some_and(|score| score >= *min_score)
+                }
+            }
+    }
+}
+
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+    /// Only from reCAPTCHA v3.
+    score: Option<f64>,
+}
+
+/// Posts tokens to the provider's `siteverify` endpoint. When the provider
+/// can't be reached in time, or answers with anything but a verdict,
+/// signups go through with `fail_open` and get `CaptchaUnavailableError`
+/// without.
+pub struct SiteVerifier {
+    provider: Provider,
+    secret: String,
+    fail_open: bool,
+    http: reqwest::Client,
+}
+
+impl SiteVerifier {
+    fn new(provider: Provider, secret: String, fail_open: bool) -> Self {
+        SiteVerifier {
+            provider,
+            secret,
+            fail_open,
+            http: reqwest::Client::builder()
+                .timeout(VERIFY_TIMEOUT)
+                .build()
+                .expect("building the CAPTCHA HTTP client failed"),
+        }
+    }
+
+    fn url(&self) -> &'static str {
+        match self.provider {
+            Provider::Turnstile => TURNSTILE_VERIFY_URL,
+            Provider::Recaptcha { .. } => RECAPTCHA_VERIFY_URL,
+        }
+    }
+
+    async fn site_verify(
+        &self,
+        token: &str,
+        remote_ip: Option<&str>,
+    ) -> reqwest::Result<SiteVerifyResponse> {
+        let mut form = vec![("secret", self.secret.as_str()), ("response", token)];
+        if let Some(ip) = remote_ip {
+            form.push(("remoteip", ip));
+        }
+        self.http
+            .post(self.url())
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+}
+
+#[async_trait]
+impl CaptchaVerifier for SiteVerifier {
+    async fn verify(&self, token: Option<&str>, remote_ip: Option<&str>) -> Result<()> {
+        let token = token
+            .filter(|token| !token.is_empty())
+            .ok_or(Error::CaptchaFailedError)?;
+        let verdict = match self.site_verify(token, remote_ip).await {
+            Ok(verdict) => verdict,
+            Err(e) if self.fail_open => {
+                tracing::warn!(
+                    "CAPTCHA provider unavailable, letting the signup through: {}",
+                    e
+                );
+                return Ok(());
+            }
+            Err(e) => {
+                tracing::error!("CAPTCHA provider unavailable: {}", e);
+                return Err(Error::CaptchaUnavailableError);
+            }
+        };
+        if self.provider.is_human(&verdict) {
+            Ok(())
+        } else {
+            Err(Error::CaptchaFailedError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::Role,
+        repository::{InMemoryUserRepository, UserRepository},
+        routes::AppState,
+        test_support, User,
+    };
+    use serde_json::{json, Value};
+    use warp::http::StatusCode;
+
+    /// Takes `human` as the only proof of a human.
+    struct StubCaptcha;
+
+    #[async_trait]
+    impl CaptchaVerifier for StubCaptcha {
+        async fn verify(&self, token: Option<&str>, _remote_ip: Option<&str>) -> Result<()> {
+            match token {
+                Some("human") => Ok(()),
+                _ => Err(Error::CaptchaFailedError),
+            }
+        }
+    }
+
+    /// A signup for an email that is taken, so one that gets past the
+    /// CAPTCHA is refused as a duplicate without needing a database.
+    async fn signup(captcha_token: Option<&str>) -> (StatusCode, Value) {
+        let repo = InMemoryUserRepository::new();
+        let taken = User::new("a@example.com".to_string(), String::new(), &Role::User);
+        repo.insert(&taken).await.unwrap();
+        let app = AppState {
+            captcha: Arc::new(StubCaptcha),
+            user_repo: Arc::new(repo),
+            ..test_support::offline_app().await
+        };
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/v1/signup")
+            .json(&json!({
+                "email": "a@example.com",
+                "pw": "a long password",
+                "captcha_token": captcha_token,
+            }))
+            .reply(&crate::routes(app))
+            .await;
+        let body = serde_json::from_slice(response.body()).unwrap();
+        (response.status(), body)
+    }
+
+    #[tokio::test]
+    async fn a_signup_the_verifier_accepts_goes_on() {
+        let (status, body) = signup(Some("human")).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "USER_ALREADY_EXISTS");
+    }
+
+    #[tokio::test]
+    async fn a_signup_the_verifier_rejects_is_refused() {
+        for token in [Some("bot"), None] {
+            let (status, body) = signup(token).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+            assert_eq!(body["code"], "CAPTCHA_FAILED");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_missing_token_is_refused_without_asking_the_provider() {
+        let verifier = SiteVerifier::new(Provider::Turnstile, "secret".to_string(), true);
+        for token in [None, Some("")] {
+            assert!(matches!(
+                verifier.verify(token, None).await,
+                Err(Error::CaptchaFailedError)
+            ));
+        }
+    }
+
+    #[test]
+    fn recaptcha_verdicts_below_the_minimum_score_are_bots() {
+        let recaptcha = Provider::Recaptcha { min_score: 0.5 };
+        let verdict = |success, score| SiteVerifyResponse { success, score };
+        assert!(recaptcha.is_human(&verdict(true, Some(0.5))));
+        assert!(!recaptcha.is_human(&verdict(true, Some(0.4))));
+        assert!(!recaptcha.is_human(&verdict(true, None)));
+        assert!(!recaptcha.is_human(&verdict(false, Some(0.9))));
+        assert!(Provider::Turnstile.is_human(&verdict(true, None)));
+        assert!(!Provider::Turnstile.is_human(&verdict(false, None)));
+    }
+}
@@ -0,0 +1,258 @@
+use crate::{config, metrics};
+use std::{
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_FAILURES: u64 = 5;
+pub const DEFAULT_WINDOW_SECS: u64 = 30;
+pub const DEFAULT_COOL_DOWN_SECS: u64 = 10;
+
+/// When the breaker in front of MongoDB opens, and for how long.
+#[derive(Clone, Copy)]
+pub struct BreakerSettings {
+    /// Failed operations in a row that open the breaker, when they all
+    /// fall within `window` of the first.
+    pub failures: u64,
+    pub window: Duration,
+    /// How long the breaker stays open before letting a probe through.
+    pub cool_down: Duration,
+}
+
+impl Default for BreakerSettings {
+    fn default() -> Self {
+        BreakerSettings {
+            failures: DEFAULT_FAILURES,
+            window: Duration::from_secs(DEFAULT_WINDOW_SECS),
+            cool_down: Duration::from_secs(DEFAULT_COOL_DOWN_SECS),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Closed {
+        failures: u64,
+        since: Option<Instant>,
+    },
+    Open {
+        until: Instant,
+    },
+    /// One operation has been let through to see whether MongoDB is back.
+    /// If it hasn't reported back within the cool-down, as when its request
+    /// was dropped, the next one is let through instead.
+    HalfOpen {
+        probe_sent: Instant,
+    },
+}
+
+const CLOSED: State = State::Closed {
+    failures: 0,
+    since: None,
+};
+
+/// Fails MongoDB operations fast while the database looks down, instead
+/// of letting every request wait out a server selection timeout. Only
+/// failures that mean the database can't be reached count; a rejected
+/// command is an answer, and counts as success.
+pub struct CircuitBreaker {
+    settings: BreakerSettings,
+    state: Mutex<State>,
+}
+
+static BREAKER: LazyLock<CircuitBreaker> =
+    LazyLock::new(|| CircuitBreaker::new(config::db_breaker()));
+
+/// The breaker in front of every operation made through
+/// `repository::timed`.
+pub fn global() -> &'static CircuitBreaker {
+    &BREAKER
+}
+
+impl CircuitBreaker {
+    pub fn new(settings: BreakerSettings) -> Self {
+        CircuitBreaker {
+            settings,
+            state: Mutex::new(CLOSED),
+        }
+    }
+
+    /// Whether an operation may go to the database now. Once the cool-down
+    /// has passed, the first caller is let through as the probe.
+    pub fn allow(&self) -> bool {
+        let now = Instant::now();
+        let mut state = self.lock();
+        if self.refuses(*state, now) {
+            return false;
+        }
+        if !matches!(*state, State::Closed { .. }) {
+            self.transition(&mut state, State::HalfOpen { probe_sent: now });
+        }
+        true
+    }
+
+    fn refuses(&self, state: State, now: Instant) -> bool {
+        match state {
+            State::Closed { .. } => false,
+            State::Open { until } => now < until,
+            State::HalfOpen { probe_sent } => now < probe_sent + self.settings.cool_down,
+        }
+    }
+
+    /// Records how an operation that [`allow`](Self::allow) let through
+    /// went: whether the database could be reached.
+    pub fn record(&self, reachable: bool) {
+        let now = Instant::now();
+        let mut state = self.lock();
+        let next = match (*state, reachable) {
+            (_, true) => CLOSED,
+            (State::Closed { failures, since }, false) => {
+                let since = since.filter(|since| now < *since + self.settings.window);
+                let failures = if since.is_some() { failures + 1 } else { 1 };
+                if failures >= self.settings.failures {
+                    State::Open {
+                        until: now + self.settings.cool_down,
+                    }
+                } else {
+                    State::Closed {
+                        failures,
+                        since: since.or(Some(now)),
+                    }
+                }
+            }
+            (State::HalfOpen { .. }, false) => State::Open {
+                until: now + self.settings.cool_down,
+            },
+            // A late failure from before the breaker opened.
+            (open @ State::Open { .. }, false) => open,
+        };
+        self.transition(&mut state, next);
+    }
+
+    /// Whether operations are being refused right now. Once the cool-down
+    /// is over this is false again even before a probe has gone out, so
+    /// that traffic comes back to send one.
+    pub fn is_open(&self) -> bool {
+        self.refuses(*self.lock(), Instant::now())
+    }
+
+    fn transition(&self, state: &mut State, next: State) {
+        match (*state, next) {
+            (State::Closed { .. }, State::Open { .. }) => {
+                tracing::error!(
+                    cool_down_secs = self.settings.cool_down.as_secs(),
+                    "MongoDB circuit breaker opened, failing database operations fast"
+                );
+            }
+            (State::HalfOpen { .. }, State::Open { .. }) => {
+                tracing::warn!("MongoDB circuit breaker probe failed, staying open");
+            }
+            (State::Open { .. }, State::HalfOpen { .. }) => {
+                tracing::info!("MongoDB circuit breaker half-open, sending a probe");
+            }
+            (State::HalfOpen { .. } | State::Open { .. }, State::Closed { .. }) => {
+                tracing::info!("MongoDB circuit breaker closed, database reachable again");
+            }
+            _ => {}
+        }
+        *state = next;
+        metrics::set_circuit_state(match next {
+            State::Closed { .. } => 0,
+            State::HalfOpen { .. } => 1,
+            State::Open { .. } => 2,
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("circuit breaker lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    fn breaker(failures: u64, window_ms: u64, cool_down_ms: u64) -> CircuitBreaker {
+        CircuitBreaker::new(BreakerSettings {
+            failures,
+            window: Duration::from_millis(window_ms),
+            cool_down: Duration::from_millis(cool_down_ms),
+        })
+    }
+
+    fn fail(breaker: &CircuitBreaker, times: u64) {
+        for _ in 0..times {
+            assert!(breaker.allow());
+            breaker.record(false);
+        }
+    }
+
+    #[test]
+    fn opens_after_enough_failures_in_a_row() {
+        let breaker = breaker(3, 60_000, 60_000);
+        fail(&breaker, 2);
+        assert!(!breaker.is_open());
+        fail(&breaker, 1);
+        assert!(breaker.is_open());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn a_success_starts_the_count_over() {
+        let breaker = breaker(3, 60_000, 60_000);
+        fail(&breaker, 2);
+        breaker.record(true);
+        fail(&breaker, 2);
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn failures_spread_beyond_the_window_do_not_open_it() {
+        let breaker = breaker(2, 20, 60_000);
+        fail(&breaker, 1);
+        sleep(Duration::from_millis(40));
+        fail(&breaker, 1);
+        assert!(!breaker.is_open());
+        fail(&breaker, 1);
+        assert!(breaker.is_open());
+    }
+
+    #[test]
+    fn after_the_cool_down_one_probe_decides() {
+        let breaker = breaker(1, 60_000, 20);
+        fail(&breaker, 1);
+        assert!(!breaker.allow());
+        sleep(Duration::from_millis(40));
+        assert!(!breaker.is_open());
+
+        assert!(breaker.allow());
+        // Only the probe, until it reports back.
+        assert!(!breaker.allow());
+        breaker.record(true);
+        assert!(breaker.allow());
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn a_failed_probe_opens_it_again() {
+        let breaker = breaker(1, 60_000, 20);
+        fail(&breaker, 1);
+        sleep(Duration::from_millis(40));
+        assert!(breaker.allow());
+        breaker.record(false);
+        assert!(breaker.is_open());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn a_lost_probe_is_replaced_after_the_cool_down() {
+        let breaker = breaker(1, 60_000, 20);
+        fail(&breaker, 1);
+        sleep(Duration::from_millis(40));
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        sleep(Duration::from_millis(40));
+        assert!(breaker.allow());
+    }
+}
@@ -0,0 +1,223 @@
+use async_compression::{
+    tokio::bufread::{BrotliEncoder, GzipEncoder},
+    Level,
+};
+use futures_util::TryStreamExt;
+use std::io;
+use tokio_util::io::{ReaderStream, StreamReader};
+use warp::{
+    http::{
+        header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY},
+        HeaderMap, HeaderValue, Method, StatusCode,
+    },
+    hyper::{body::HttpBody, Body},
+    reply::Response,
+};
+
+/// Below this, the encoding overhead outweighs the savings.
+const MIN_COMPRESS_BYTES: u64 = 1024;
+/// Brotli's default quality (11) is meant for static assets and far too
+/// slow per request.
+const BROTLI_QUALITY: i32 = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+}
+
+/// The encoding to use for a request's `Accept-Encoding`, preferring
+/// brotli when the client weights it at least as high as gzip.
+pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let mut gzip = 0.0;
+    let mut brotli = 0.0;
+    for value in headers.get_all("accept-encoding") {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for entry in value.split(',') {
+            let mut parts = entry.split(';');
+            let coding = parts.next().unwrap_or_default().trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+                .unwrap_or(0.0);
+            match coding.to_ascii_lowercase().as_str() {
+                "gzip" | "x-gzip" => gzip = quality,
+                "br" => brotli = quality,
+                "*" => {
+                    gzip = f32::max(gzip, quality);
+                    brotli = f32::max(brotli, quality);
+                }
+                _ => {}
+            }
+        }
+    }
+    if brotli > 0.0 && brotli >= gzip {
+        Some(Encoding::Brotli)
+    } else if gzip > 0.0 {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// Compresses `response` with `encoding` if it is worth it: a body of a
+/// text-like content type that is not known to be tiny and is not already
+/// encoded. Streamed bodies are compressed as they stream.
+pub fn compress(method: &Method, response: Response, encoding: Encoding) -> Response {
+    let headers = response.headers();
+    let small = response
+        .body()
+        .size_hint()
+        .exact()
+        .is_some_and(|length| length < MIN_COMPRESS_BYTES);
+    let compressible = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(is_compressible);
+    if *method == Method::HEAD
+        || matches!(
+            response.status(),
+            StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+        )
+        || headers.contains_key(CONTENT_ENCODING)
+        || small
+        || !compressible
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = TryStreamExt::map_err(body, io::Error::other);
+    let reader = StreamReader::new(body);
+    let body = match encoding {
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader))),
+        Encoding::Brotli => Body::wrap_stream(ReaderStream::new(BrotliEncoder::with_quality(
+            reader,
+            Level::Precise(BROTLI_QUALITY),
+        ))),
+    };
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    Response::from_parts(parts, body)
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    // Event streams must reach the client event by event, which an
+    // encoder buffering toward a full block would hold up.
+    (mime.starts_with("text/") && mime != "text/event-stream")
+        || mime == "application/json"
+        || mime == "application/x-ndjson"
+        || mime == "application/javascript"
+        || mime == "application/xml"
+        || mime == "image/svg+xml"
+        || mime.ends_with("+json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{create_jwt, Role},
+        server::ListenAddr,
+        test_support, User,
+    };
+    use async_compression::tokio::bufread::GzipDecoder;
+    use serde_json::Value;
+    use tokio::io::AsyncReadExt;
+
+    async fn gunzip(bytes: &[u8]) -> Vec<u8> {
+        let mut plain = Vec::new();
+        GzipDecoder::new(bytes)
+            .read_to_end(&mut plain)
+            .await
+            .unwrap();
+        plain
+    }
+
+    #[tokio::test]
+    async fn a_large_json_body_is_gzipped_to_the_same_bytes() {
+        let json = serde_json::to_vec(&vec!["someone@example.com"; 200]).unwrap();
+        let mut response = Response::new(Body::from(json.clone()));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let response = compress(&Method::GET, response, Encoding::Gzip);
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[VARY], "accept-encoding");
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert!(body.len() < json.len());
+        assert_eq!(gunzip(&body).await, json);
+    }
+
+    #[test]
+    fn no_accept_encoding_means_no_encoding() {
+        assert!(negotiate(&HeaderMap::new()).is_none());
+        let mut headers = HeaderMap::new();
+        headers.insert("accept-encoding", HeaderValue::from_static("identity"));
+        assert!(negotiate(&headers).is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn the_user_list_is_gzipped_only_when_asked() {
+        let app = test_support::app_with(&[("PORT", "0")]).await;
+        let admin = User::new("admin@example.com".to_string(), String::new(), &Role::Admin);
+        app.users.insert_one(&admin, None).await.unwrap();
+        let users = (0..50)
+            .map(|i| User::new(format!("user{}@example.com", i), String::new(), &Role::User));
+        app.users.insert_many(users, None).await.unwrap();
+        let token = create_jwt(&app.auth_context, &admin.uid, &Role::Admin, 0)
+            .unwrap()
+            .token;
+        let (ListenAddr::Tcp(addr), server) =
+            crate::start_server(app, std::future::pending()).unwrap()
+        else {
+            panic!("expected a TCP address");
+        };
+        tokio::spawn(server);
+
+        let url = format!("http://{}/api/v1/users?limit=100", addr);
+        let http = reqwest::Client::new();
+        let plain = http.get(&url).bearer_auth(&token).send().await.unwrap();
+        assert_eq!(plain.status(), 200);
+        assert!(plain.headers().get(CONTENT_ENCODING).is_none());
+        let plain: Value = serde_json::from_slice(&plain.bytes().await.unwrap()).unwrap();
+
+        let gzipped = http
+            .get(&url)
+            .bearer_auth(&token)
+            .header("accept-encoding", "gzip")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(gzipped.status(), 200);
+        assert_eq!(gzipped.headers()[CONTENT_ENCODING], "gzip");
+        let body = gunzip(&gzipped.bytes().await.unwrap()).await;
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), plain);
+    }
+}
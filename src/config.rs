@@ -0,0 +1,1321 @@
+use crate::{
+    apikeys::API_KEY_HEADER,
+    auth::{JwtConfig, CSRF_HEADER},
+    captcha::{self, Captcha},
+    circuit_breaker::{self, BreakerSettings},
+    features::Features,
+    frontend,
+    health::DegradedPolicy,
+    idempotency,
+    ip_allowlist::IpRange,
+    lockout::{self, FailurePolicy},
+    mailer::SmtpSender,
+    maintenance::MaintenanceMode,
+    oauth::OAuthProviders,
+    ratelimit, request_id,
+    security_headers::{self, SecurityHeaders},
+    server::{self, ListenAddr},
+    token_exchange::TrustedPeers,
+    two_factor::TotpCipher,
+    webhooks::WebhookSettings,
+};
+use argon2::Params;
+use dotenv::dotenv;
+use rustls::ServerConfig;
+use std::{
+    env, fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::{NonZeroU32, NonZeroU64},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use warp::http::{header, uri::Authority, HeaderValue, Method, StatusCode};
+
+const DEFAULT_PORT: u16 = 8000;
+const DEFAULT_MONGO_HOST: &str = "localhost:27017";
+const DEFAULT_MONGO_DB_NAME: &str = "my_app";
+const DEFAULT_USERS_COLLECTION: &str = "users";
+const DEFAULT_MONGO_CONNECT_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_DB_OP_TIMEOUT_MS: u64 = 3000;
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_SHUTDOWN_DRAIN_SECS: u64 = 20;
+const DEFAULT_SWEEP_INTERVAL_SECS: u64 = 60 * 60;
+const DEFAULT_GUEST_MAX_AGE_DAYS: u64 = 30;
+const DEFAULT_HEALTH_DEGRADED_LATENCY_MS: u64 = 500;
+const DEFAULT_CORS_MAX_AGE_SECS: u64 = 600;
+const DEFAULT_UNIX_SOCKET_MODE: u32 = 0o660;
+const DEFAULT_USER_CACHE_TTL_SECS: u64 = 30;
+const DEFAULT_REMEMBER_ME_TTL_DAYS: u64 = 30;
+const DEFAULT_API_PREFIX: &str = "/api/v1";
+const DEFAULT_MAX_BODY_BYTES: u64 = 16 * 1024;
+/// Generous for a 10k record user import, while still bounding what gets
+/// buffered.
+const DEFAULT_MAX_UPLOAD_BYTES: u64 = 16 * 1024 * 1024;
+const DEFAULT_PASSWORD_HISTORY: usize = 5;
+/// Every remembered password costs a full hash verification per change.
+const MAX_PASSWORD_HISTORY: usize = 24;
+/// OWASP's recommended Argon2id settings: 19 MiB, 2 passes, 1 lane.
+const DEFAULT_ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+const DEFAULT_ARGON2_ITERATIONS: u32 = 2;
+const DEFAULT_ARGON2_PARALLELISM: u32 = 1;
+const DEFAULT_MIN_PASSWORD_LENGTH: usize = 8;
+const DEFAULT_RATE_LIMIT_REQUESTS: u64 = 30;
+const DEFAULT_RATE_LIMIT_WINDOW_SECS: u64 = 60;
+const DEFAULT_USER_RETENTION_DAYS: u64 = 30;
+
+static ARGON2_PARAMS: OnceLock<Params> = OnceLock::new();
+static PASSWORD_PEPPER: OnceLock<Option<String>> = OnceLock::new();
+static MAX_BODY_BYTES: OnceLock<u64> = OnceLock::new();
+static MAX_UPLOAD_BYTES: OnceLock<u64> = OnceLock::new();
+static PASSWORD_HISTORY: OnceLock<usize> = OnceLock::new();
+static COMPRESSION: OnceLock<bool> = OnceLock::new();
+static SERVER_TIMING: OnceLock<bool> = OnceLock::new();
+static API_PREFIX: OnceLock<String> = OnceLock::new();
+static SIGNUP_LOGIN: OnceLock<bool> = OnceLock::new();
+static REQUIRE_INVITE: OnceLock<bool> = OnceLock::new();
+static VERIFY_USER: OnceLock<bool> = OnceLock::new();
+static USER_CACHE_TTL: OnceLock<Duration> = OnceLock::new();
+static DB_OP_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+static REQUEST_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+static DB_BREAKER: OnceLock<BreakerSettings> = OnceLock::new();
+static SHOW_BAN_REASON: OnceLock<bool> = OnceLock::new();
+static REMEMBER_ME_TTL: OnceLock<Duration> = OnceLock::new();
+static REQUIRE_FRESH_LOGIN: OnceLock<bool> = OnceLock::new();
+static REQUIRE_IF_MATCH: OnceLock<bool> = OnceLock::new();
+static ADMIN_IP_ALLOWLIST: OnceLock<Vec<IpRange>> = OnceLock::new();
+static MIN_PASSWORD_LENGTH: OnceLock<usize> = OnceLock::new();
+
+/// Server settings read once at startup. Feature-specific settings (SMTP,
+/// OAuth providers, ...) are parsed by their own modules, but from
+/// [`Config::from_env`], so their problems are reported with the rest.
+pub struct Config {
+    pub bind_addr: IpAddr,
+    pub port: u16,
+    /// Serve HTTPS on `port` when set.
+    pub tls: Option<Arc<ServerConfig>>,
+    /// Plain HTTP port that redirects to HTTPS, only with `tls`.
+    pub http_redirect_port: Option<u16>,
+    /// Serve `/metrics` on this port instead of `port`, e.g. to keep it off
+    /// the public interface.
+    pub metrics_port: Option<u16>,
+    /// Serve the API on this unix socket instead of `bind_addr` and
+    /// `port`, for a reverse proxy on the same host.
+    pub unix_socket: Option<PathBuf>,
+    /// Permissions given to `unix_socket`.
+    pub unix_socket_mode: u32,
+    /// Connection string, used as is; see [`Config::from_env`].
+    pub mongo_uri: String,
+    pub mongo_db_name: String,
+    /// How long startup keeps retrying an unreachable MongoDB.
+    pub mongo_connect_timeout: Duration,
+    /// How long a request waits on one MongoDB operation, and on finding
+    /// a server and connecting to it.
+    pub db_op_timeout: Duration,
+    /// How long a request may take to get its response, unless its route
+    /// sets its own budget.
+    pub request_timeout: Duration,
+    /// When MongoDB operations start failing fast; see `circuit_breaker`.
+    pub db_breaker: BreakerSettings,
+    pub users_collection: String,
+    /// Path the API is mounted under, such as `/api/v1`, without a trailing
+    /// slash.
+    pub api_prefix: String,
+    /// Also serve the API at the unprefixed paths it had before versioning.
+    pub legacy_routes: bool,
+    /// Route groups that are switched on.
+    pub features: Features,
+    /// Sign new accounts in from `/signup`, before their email is verified.
+    pub signup_login: bool,
+    /// Only let `/signup` create accounts with a valid invite code.
+    pub require_invite: bool,
+    /// Check on every authenticated request that the token's account still
+    /// exists, is active and has the token's `token_version`.
+    pub verify_user: bool,
+    /// How long such a check is answered from memory.
+    pub user_cache_ttl: Duration,
+    /// Tell banned users why when they try to sign in.
+    pub show_ban_reason: bool,
+    /// How long the refresh tokens of a login with `remember_me` last.
+    pub remember_me_ttl: Duration,
+    /// Refuse password and role changes made from a `remember_me` session,
+    /// asking for a login without it first.
+    pub require_fresh_login: bool,
+    /// Refuse user updates without `If-Match` rather than let the last
+    /// write win.
+    pub require_if_match: bool,
+    /// Sources admin routes answer; any when empty.
+    pub admin_ip_allowlist: Vec<IpRange>,
+    /// Argon2id memory (KiB), iterations and parallelism for new password
+    /// hashes.
+    pub argon2_params: Params,
+    /// Secret mixed into every password before it is hashed; see
+    /// `password::hash`.
+    pub password_pepper: Option<String>,
+    /// Largest JSON request body accepted.
+    pub max_body_bytes: u64,
+    /// Largest body for upload endpoints such as the user import.
+    pub max_upload_bytes: u64,
+    /// How many of a user's latest passwords, the current one included, a
+    /// new one must differ from; 0 turns the check off.
+    pub password_history: usize,
+    /// Gzip or brotli encode responses for clients that accept it.
+    pub compression: bool,
+    /// Send `Server-Timing` with every response. It tells clients how long
+    /// the database took, so it is off unless asked for.
+    pub server_timing: bool,
+    /// Frontend build served alongside the API.
+    pub static_dir: Option<PathBuf>,
+    /// Hardening headers added to every response.
+    pub security_headers: SecurityHeaders,
+    /// How long in-flight requests may run after a shutdown signal.
+    pub shutdown_drain: Duration,
+    /// How often expired tokens and other stale documents are deleted.
+    pub sweep_interval: Duration,
+    /// How old a guest account gets before it is purged, unless upgraded.
+    pub guest_max_age: Duration,
+    /// When and how `/health` reports MongoDB as slow.
+    pub health_degraded: DegradedPolicy,
+    /// Whether the server starts in maintenance mode.
+    pub maintenance_mode: MaintenanceMode,
+    /// Services whose tokens `POST /token/exchange` accepts.
+    pub token_exchange_peers: TrustedPeers,
+    /// HMAC signing secrets, current first. Empty when `JWT_ALGORITHM=RS256`.
+    pub jwt_secrets: Vec<String>,
+    /// How access tokens are signed and checked; see [`JwtConfig::from_env`].
+    pub jwt: JwtConfig,
+    /// Take the client address from `X-Forwarded-For`. Only for deployments
+    /// behind a reverse proxy that sets it.
+    pub trust_proxy: bool,
+    /// Consecutive failed logins before an account is locked out or slowed
+    /// down.
+    pub login_max_failures: u32,
+    pub login_failure_policy: FailurePolicy,
+    /// Requests per client and window on signup and other limited routes.
+    pub rate_limit_requests: u64,
+    pub rate_limit_window: Duration,
+    /// How long a deleted account can still be restored before it is purged.
+    pub user_retention: Duration,
+    /// Shortest password accepted.
+    pub min_password_length: usize,
+    /// Encrypts TOTP secrets; 2FA is unavailable without it.
+    pub totp_cipher: Option<TotpCipher>,
+    /// The SMTP relay; emails are only logged without it.
+    pub smtp: Option<SmtpSender>,
+    /// Verifies signup CAPTCHAs; everyone passes when none is configured.
+    pub captcha: Captcha,
+    /// Where events are delivered; nowhere when `None`.
+    pub webhooks: Option<WebhookSettings>,
+    pub oauth_providers: OAuthProviders,
+    /// Cross-origin callers allowed by the CORS layer; no layer when `None`.
+    pub cors_origins: Option<CorsOrigins>,
+    /// How long browsers may cache a preflight response.
+    pub cors_max_age: Duration,
+    /// Let cross-origin requests carry cookies, for `AUTH_COOKIE`.
+    pub cors_allow_credentials: bool,
+    pub log_format: LogFormat,
+}
+
+pub enum CorsOrigins {
+    Any,
+    List(Vec<String>),
+}
+
+/// How log lines are written: `text` for people, `json` for log
+/// collectors.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<LogFormat, ()> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Every problem found in the environment, so a deployment can be fixed in
+/// one go instead of one restart per variable.
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for problem in &self.0 {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads `BIND_ADDR` (default `0.0.0.0`), `PORT` (default 8000),
+    /// `TLS_CERT_PATH` and `TLS_KEY_PATH` (both or neither),
+    /// `HTTP_REDIRECT_PORT`, `METRICS_PORT`, `LISTEN_UNIX_SOCKET`,
+    /// `LISTEN_UNIX_SOCKET_MODE` (octal, default `660`),
+    /// `MONGO_URI` or, failing that, `MONGO_INITDB_ROOT_USERNAME`,
+    /// `MONGO_INITDB_ROOT_PASSWORD` and `MONGO_HOST` (default
+    /// `localhost:27017`), `MONGO_DB_NAME` (default `my_app`),
+    /// `MONGO_CONNECT_TIMEOUT_SECS` (default 60), `DB_OP_TIMEOUT_MS`
+    /// (default 3000), `REQUEST_TIMEOUT_MS` (default 10000),
+    /// `DB_BREAKER_FAILURES` (default 5), `DB_BREAKER_WINDOW_SECS` (default
+    /// 30), `DB_BREAKER_COOL_DOWN_SECS` (default 10),
+    /// `USERS_COLLECTION` (default `users`), `API_PREFIX` (default
+    /// `/api/v1`), `LEGACY_ROUTES` (default `false`), `ENABLE_SIGNUP`,
+    /// `ENABLE_PASSWORD_LOGIN`, `ENABLE_MAGIC_LINK` and `ENABLE_ADMIN_API`
+    /// (all default `true`),
+    /// `SIGNUP_LOGIN` (default `false`), `REQUIRE_INVITE` (default
+    /// `false`), `AUTH_VERIFY_USER` (default `true`),
+    /// `USER_CACHE_TTL_SECS` (default 30), `SHOW_BAN_REASON` (default
+    /// `false`), `REMEMBER_ME_TTL_DAYS` (default 30),
+    /// `REQUIRE_FRESH_LOGIN` (default `false`), `REQUIRE_IF_MATCH` (default `true`), `ADMIN_IP_ALLOWLIST`,
+    /// `ARGON2_MEMORY_KIB` (default 19456), `ARGON2_ITERATIONS` (default 2),
+    /// `ARGON2_PARALLELISM` (default 1), `PASSWORD_PEPPER`,
+    /// `MAX_BODY_BYTES` (default 16 KiB), `MAX_UPLOAD_BYTES` (default
+    /// 16 MiB), `PASSWORD_HISTORY` (default 5, at most 24),
+    /// `COMPRESSION` (default `true`),
+    /// `SERVER_TIMING` (default `false`), `STATIC_DIR`,
+    /// `FORCE_HSTS` (default `false`), `STRICT_TRANSPORT_SECURITY`,
+    /// `X_FRAME_OPTIONS`, `REFERRER_POLICY`, `CONTENT_SECURITY_POLICY`,
+    /// `SHUTDOWN_DRAIN_SECS` (default 20), `SWEEP_INTERVAL_SECS` (default
+    /// 3600), `GUEST_MAX_AGE_DAYS` (default 30),
+    /// `HEALTH_DEGRADED_LATENCY_MS` (default 500), `HEALTH_DEGRADED_STATUS`
+    /// (200 or 503, default 200), `MAINTENANCE_MODE` (`off`, `on` or
+    /// `writes_only`, default `off`), `TOKEN_EXCHANGE_PEERS`, the JWT
+    /// secrets (`JWT_SECRETS` or `JWT_SECRET`, unless
+    /// `JWT_ALGORITHM=RS256`) and the rest of the signing settings (see
+    /// [`JwtConfig::from_env`]), `TRUST_PROXY` (default `false`),
+    /// `LOGIN_MAX_FAILURES` (default 5), `LOGIN_FAILURE_POLICY` (`lockout`
+    /// or `delay`, default `lockout`), `RATE_LIMIT_REQUESTS` (default 30),
+    /// `RATE_LIMIT_WINDOW_SECONDS` (default 60), `USER_RETENTION_DAYS`
+    /// (default 30), `PASSWORD_MIN_LENGTH` (default 8),
+    /// `TOTP_ENCRYPTION_KEY`, the SMTP, CAPTCHA, webhook and OAuth provider
+    /// settings, `CORS_ALLOWED_ORIGINS`
+    /// `CORS_MAX_AGE_SECS` (default 600) and `LOG_FORMAT` (`text` or
+    /// `json`, default `text`). Also makes the Argon2 parameters, pepper,
+    /// body limits, password history, compression and `Server-Timing` settings, API prefix, signup, user check,
+    /// database and request timeouts, circuit breaker, ban, `If-Match`, admin allowlist and
+    /// password length settings available to [`argon2_params`],
+    /// [`password_pepper`], [`max_body_bytes`], [`max_upload_bytes`],
+    /// [`password_history`],
+    /// [`compression`], [`server_timing`], [`api_prefix`], [`signup_login`],
+    /// [`require_invite`], [`verify_user`], [`user_cache_ttl`],
+    /// [`db_op_timeout`], [`request_timeout`], [`db_breaker`], [`show_ban_reason`],
+    /// [`remember_me_ttl`], [`require_fresh_login`], [`require_if_match`],
+    /// [`admin_ip_allowlist`] and [`min_password_length`].
+    pub fn from_env() -> Result<Config, ConfigError> {
+        dotenv().ok();
+        let mut problems = Vec::new();
+
+        let bind_addr = parse_var(
+            "BIND_ADDR",
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            "an IP address",
+            &mut problems,
+        );
+        let port = parse_var("PORT", DEFAULT_PORT, "a port number", &mut problems);
+        let tls_paths = (
+            env::var("TLS_CERT_PATH").ok().filter(|v| !v.is_empty()),
+            env::var("TLS_KEY_PATH").ok().filter(|v| !v.is_empty()),
+        );
+        let tls_requested = tls_paths != (None, None);
+        let tls = match tls_paths {
+            (Some(cert), Some(key)) => server::load_tls(Path::new(&cert), Path::new(&key))
+                .map(Arc::new)
+                .map_err(|e| problems.push(e))
+                .ok(),
+            (None, None) => None,
+            _ => {
+                problems.push("TLS_CERT_PATH and TLS_KEY_PATH must be set together".to_string());
+                None
+            }
+        };
+        let http_redirect_port =
+            parse_optional_var::<u16>("HTTP_REDIRECT_PORT", "a port number", &mut problems);
+        if http_redirect_port.is_some() && !tls_requested {
+            problems.push("HTTP_REDIRECT_PORT requires TLS_CERT_PATH and TLS_KEY_PATH".to_string());
+        }
+        if http_redirect_port == Some(port) {
+            problems.push("HTTP_REDIRECT_PORT must differ from PORT".to_string());
+        }
+        let metrics_port =
+            parse_optional_var::<u16>("METRICS_PORT", "a port number", &mut problems);
+        if metrics_port.is_some()
+            && (metrics_port == Some(port) || metrics_port == http_redirect_port)
+        {
+            problems.push("METRICS_PORT must differ from PORT and HTTP_REDIRECT_PORT".to_string());
+        }
+        let unix_socket = env::var("LISTEN_UNIX_SOCKET")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from);
+        let unix_socket_mode = parse_unix_socket_mode(&mut problems);
+        if unix_socket.is_some() {
+            if cfg!(not(unix)) {
+                problems.push("LISTEN_UNIX_SOCKET is only supported on unix".to_string());
+            }
+            // The proxy in front terminates TLS, and there is no port to
+            // redirect from.
+            if tls_requested || http_redirect_port.is_some() {
+                problems.push(
+                    "LISTEN_UNIX_SOCKET cannot be combined with TLS_CERT_PATH, TLS_KEY_PATH or HTTP_REDIRECT_PORT"
+                        .to_string(),
+                );
+            }
+        }
+        // A full URI covers Atlas (`mongodb+srv://`), replica sets and TLS
+        // options; the separate variables only describe a single host.
+        let mongo_uri = match env::var("MONGO_URI") {
+            Ok(uri) if !uri.is_empty() => uri,
+            _ => {
+                let username = required_var("MONGO_INITDB_ROOT_USERNAME", &mut problems);
+                let password = required_var("MONGO_INITDB_ROOT_PASSWORD", &mut problems);
+                format!(
+                    "mongodb://{}:{}@{}",
+                    percent_encode(&username),
+                    percent_encode(&password),
+                    string_var("MONGO_HOST", DEFAULT_MONGO_HOST)
+                )
+            }
+        };
+        let mongo_db_name = string_var("MONGO_DB_NAME", DEFAULT_MONGO_DB_NAME);
+        let mongo_connect_timeout = Duration::from_secs(parse_var(
+            "MONGO_CONNECT_TIMEOUT_SECS",
+            DEFAULT_MONGO_CONNECT_TIMEOUT_SECS,
+            "a number of seconds",
+            &mut problems,
+        ));
+        let users_collection = string_var("USERS_COLLECTION", DEFAULT_USERS_COLLECTION);
+        let api_prefix = parse_api_prefix(&mut problems);
+        let legacy_routes = parse_var("LEGACY_ROUTES", false, "true or false", &mut problems);
+        let features = features_vars(&mut problems);
+        let signup_login = parse_var("SIGNUP_LOGIN", false, "true or false", &mut problems);
+        let require_invite = parse_var("REQUIRE_INVITE", false, "true or false", &mut problems);
+        let verify_user = parse_var("AUTH_VERIFY_USER", true, "true or false", &mut problems);
+        let user_cache_ttl = Duration::from_secs(parse_var(
+            "USER_CACHE_TTL_SECS",
+            DEFAULT_USER_CACHE_TTL_SECS,
+            "a number of seconds",
+            &mut problems,
+        ));
+        let db_op_timeout = Duration::from_millis(
+            parse_var(
+                "DB_OP_TIMEOUT_MS",
+                NonZeroU64::new(DEFAULT_DB_OP_TIMEOUT_MS).expect("nonzero default"),
+                "a positive number of milliseconds",
+                &mut problems,
+            )
+            .get(),
+        );
+        let request_timeout = Duration::from_millis(
+            parse_var(
+                "REQUEST_TIMEOUT_MS",
+                NonZeroU64::new(DEFAULT_REQUEST_TIMEOUT_MS).expect("nonzero default"),
+                "a positive number of milliseconds",
+                &mut problems,
+            )
+            .get(),
+        );
+        let db_breaker = db_breaker_vars(&mut problems);
+        let show_ban_reason = parse_var("SHOW_BAN_REASON", false, "true or false", &mut problems);
+        let remember_me_ttl = Duration::from_secs(
+            parse_var(
+                "REMEMBER_ME_TTL_DAYS",
+                NonZeroU64::new(DEFAULT_REMEMBER_ME_TTL_DAYS).expect("nonzero default"),
+                "a positive number of days",
+                &mut problems,
+            )
+            .get()
+                * 24
+                * 60
+                * 60,
+        );
+        let require_fresh_login =
+            parse_var("REQUIRE_FRESH_LOGIN", false, "true or false", &mut problems);
+        let require_if_match = parse_var("REQUIRE_IF_MATCH", true, "true or false", &mut problems);
+        let admin_ip_allowlist = admin_ip_allowlist_var(&mut problems);
+
+        let argon2_params = parse_argon2_params(&mut problems);
+        let password_pepper = env::var("PASSWORD_PEPPER")
+            .ok()
+            .filter(|pepper| !pepper.is_empty());
+
+        let max_body_bytes = parse_var(
+            "MAX_BODY_BYTES",
+            DEFAULT_MAX_BODY_BYTES,
+            "a number of bytes",
+            &mut problems,
+        );
+        let max_upload_bytes = parse_var(
+            "MAX_UPLOAD_BYTES",
+            DEFAULT_MAX_UPLOAD_BYTES,
+            "a number of bytes",
+            &mut problems,
+        );
+        let password_history = parse_var(
+            "PASSWORD_HISTORY",
+            DEFAULT_PASSWORD_HISTORY,
+            "a number of passwords",
+            &mut problems,
+        );
+        if password_history > MAX_PASSWORD_HISTORY {
+            problems.push(format!(
+                "PASSWORD_HISTORY must be at most {}, got {}",
+                MAX_PASSWORD_HISTORY, password_history
+            ));
+        }
+
+        let compression = parse_var("COMPRESSION", true, "true or false", &mut problems);
+        let server_timing = parse_var("SERVER_TIMING", false, "true or false", &mut problems);
+        let static_dir = env::var("STATIC_DIR")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .and_then(|dir| frontend::check_dir(&dir).map_err(|e| problems.push(e)).ok());
+
+        // Behind a proxy that terminates TLS the server can't tell, hence
+        // `FORCE_HSTS`.
+        let force_hsts = parse_var("FORCE_HSTS", false, "true or false", &mut problems);
+        let hsts = header_var(
+            "STRICT_TRANSPORT_SECURITY",
+            security_headers::DEFAULT_HSTS,
+            &mut problems,
+        )
+        .filter(|_| tls_requested || force_hsts);
+        let security_headers = SecurityHeaders {
+            hsts,
+            frame_options: header_var(
+                "X_FRAME_OPTIONS",
+                security_headers::DEFAULT_FRAME_OPTIONS,
+                &mut problems,
+            ),
+            referrer_policy: header_var(
+                "REFERRER_POLICY",
+                security_headers::DEFAULT_REFERRER_POLICY,
+                &mut problems,
+            ),
+            content_security_policy: header_var(
+                "CONTENT_SECURITY_POLICY",
+                &security_headers::default_content_security_policy(),
+                &mut problems,
+            ),
+        };
+
+        let shutdown_drain = Duration::from_secs(parse_var(
+            "SHUTDOWN_DRAIN_SECS",
+            DEFAULT_SHUTDOWN_DRAIN_SECS,
+            "a number of seconds",
+            &mut problems,
+        ));
+        let sweep_interval = Duration::from_secs(
+            parse_var(
+                "SWEEP_INTERVAL_SECS",
+                NonZeroU64::new(DEFAULT_SWEEP_INTERVAL_SECS).expect("nonzero default"),
+                "a positive number of seconds",
+                &mut problems,
+            )
+            .get(),
+        );
+        let guest_max_age = Duration::from_secs(
+            parse_var(
+                "GUEST_MAX_AGE_DAYS",
+                NonZeroU64::new(DEFAULT_GUEST_MAX_AGE_DAYS).expect("nonzero default"),
+                "a positive number of days",
+                &mut problems,
+            )
+            .get()
+                * 24
+                * 60
+                * 60,
+        );
+        let health_degraded_status = parse_var(
+            "HEALTH_DEGRADED_STATUS",
+            StatusCode::OK.as_u16(),
+            "200 or 503",
+            &mut problems,
+        );
+        let health_degraded = DegradedPolicy {
+            threshold: Duration::from_millis(parse_var(
+                "HEALTH_DEGRADED_LATENCY_MS",
+                DEFAULT_HEALTH_DEGRADED_LATENCY_MS,
+                "a number of milliseconds",
+                &mut problems,
+            )),
+            status: if health_degraded_status == StatusCode::SERVICE_UNAVAILABLE.as_u16() {
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                if health_degraded_status != StatusCode::OK.as_u16() {
+                    problems.push(format!(
+                        "HEALTH_DEGRADED_STATUS must be 200 or 503, got {}",
+                        health_degraded_status
+                    ));
+                }
+                StatusCode::OK
+            },
+        };
+
+        let maintenance_mode = parse_var(
+            "MAINTENANCE_MODE",
+            MaintenanceMode::default(),
+            "off, on or writes_only",
+            &mut problems,
+        );
+
+        let token_exchange_peers = match env::var("TOKEN_EXCHANGE_PEERS") {
+            Ok(path) if !path.is_empty() => {
+                TrustedPeers::load(Path::new(&path)).unwrap_or_else(|e| {
+                    problems.push(e);
+                    TrustedPeers::default()
+                })
+            }
+            _ => TrustedPeers::default(),
+        };
+
+        let jwt_secrets = if env::var("JWT_ALGORITHM").as_deref() == Ok("RS256") {
+            Vec::new()
+        } else {
+            let secrets = jwt_secrets();
+            if secrets.is_empty() {
+                problems.push("JWT_SECRET (or JWT_SECRETS) must be set".to_string());
+            }
+            secrets
+        };
+        let jwt = JwtConfig::from_env(&jwt_secrets, &mut problems);
+
+        // Only behind a reverse proxy that sets `X-Forwarded-For`.
+        let trust_proxy = matches!(env::var("TRUST_PROXY").as_deref(), Ok("true") | Ok("1"));
+        let login_max_failures = parse_var(
+            "LOGIN_MAX_FAILURES",
+            NonZeroU32::new(lockout::DEFAULT_MAX_FAILURES).expect("nonzero default"),
+            "a positive integer",
+            &mut problems,
+        )
+        .get();
+        let login_failure_policy = parse_var(
+            "LOGIN_FAILURE_POLICY",
+            FailurePolicy::default(),
+            "lockout or delay",
+            &mut problems,
+        );
+        let rate_limit_requests = parse_var(
+            "RATE_LIMIT_REQUESTS",
+            DEFAULT_RATE_LIMIT_REQUESTS,
+            "a positive integer",
+            &mut problems,
+        );
+        let rate_limit_window = Duration::from_secs(parse_var(
+            "RATE_LIMIT_WINDOW_SECONDS",
+            DEFAULT_RATE_LIMIT_WINDOW_SECS,
+            "a number of seconds",
+            &mut problems,
+        ));
+        let user_retention = Duration::from_secs(
+            parse_var(
+                "USER_RETENTION_DAYS",
+                DEFAULT_USER_RETENTION_DAYS,
+                "a number of days",
+                &mut problems,
+            ) * 24
+                * 60
+                * 60,
+        );
+        let min_password_length = parse_var(
+            "PASSWORD_MIN_LENGTH",
+            DEFAULT_MIN_PASSWORD_LENGTH,
+            "a positive integer",
+            &mut problems,
+        );
+        let totp_cipher = TotpCipher::from_env(&mut problems);
+        let smtp = SmtpSender::from_env(&mut problems);
+        let captcha = captcha::from_env(&mut problems);
+        let webhooks = WebhookSettings::from_env(&mut problems);
+        let oauth_providers = OAuthProviders::from_env(&mut problems);
+
+        let cors_allow_credentials =
+            matches!(env::var("AUTH_COOKIE").as_deref(), Ok("true") | Ok("1"));
+        let cors_origins = cors_origins(&mut problems);
+        if cors_allow_credentials && matches!(cors_origins, Some(CorsOrigins::Any)) {
+            // Browsers refuse credentialed responses to a wildcard origin.
+            problems.push(
+                "CORS_ALLOWED_ORIGINS cannot be * with AUTH_COOKIE enabled; list the origins"
+                    .to_string(),
+            );
+        }
+        let cors_max_age = Duration::from_secs(parse_var(
+            "CORS_MAX_AGE_SECS",
+            DEFAULT_CORS_MAX_AGE_SECS,
+            "a number of seconds",
+            &mut problems,
+        ));
+
+        let log_format = parse_var(
+            "LOG_FORMAT",
+            LogFormat::default(),
+            "text or json",
+            &mut problems,
+        );
+
+        let Some(jwt) = jwt.filter(|_| problems.is_empty()) else {
+            return Err(ConfigError(problems));
+        };
+        ARGON2_PARAMS.get_or_init(|| argon2_params.clone());
+        PASSWORD_PEPPER.get_or_init(|| password_pepper.clone());
+        MAX_BODY_BYTES.get_or_init(|| max_body_bytes);
+        MAX_UPLOAD_BYTES.get_or_init(|| max_upload_bytes);
+        PASSWORD_HISTORY.get_or_init(|| password_history);
+        COMPRESSION.get_or_init(|| compression);
+        SERVER_TIMING.get_or_init(|| server_timing);
+        API_PREFIX.get_or_init(|| api_prefix.clone());
+        SIGNUP_LOGIN.get_or_init(|| signup_login);
+        REQUIRE_INVITE.get_or_init(|| require_invite);
+        VERIFY_USER.get_or_init(|| verify_user);
+        USER_CACHE_TTL.get_or_init(|| user_cache_ttl);
+        DB_OP_TIMEOUT.get_or_init(|| db_op_timeout);
+        REQUEST_TIMEOUT.get_or_init(|| request_timeout);
+        DB_BREAKER.get_or_init(|| db_breaker);
+        SHOW_BAN_REASON.get_or_init(|| show_ban_reason);
+        REMEMBER_ME_TTL.get_or_init(|| remember_me_ttl);
+        REQUIRE_FRESH_LOGIN.get_or_init(|| require_fresh_login);
+        REQUIRE_IF_MATCH.get_or_init(|| require_if_match);
+        ADMIN_IP_ALLOWLIST.get_or_init(|| admin_ip_allowlist.clone());
+        MIN_PASSWORD_LENGTH.get_or_init(|| min_password_length);
+        Ok(Config {
+            bind_addr,
+            port,
+            tls,
+            http_redirect_port,
+            metrics_port,
+            unix_socket,
+            unix_socket_mode,
+            mongo_uri,
+            mongo_db_name,
+            mongo_connect_timeout,
+            db_op_timeout,
+            request_timeout,
+            db_breaker,
+            users_collection,
+            api_prefix,
+            legacy_routes,
+            features,
+            signup_login,
+            require_invite,
+            verify_user,
+            user_cache_ttl,
+            show_ban_reason,
+            remember_me_ttl,
+            require_fresh_login,
+            require_if_match,
+            admin_ip_allowlist,
+            argon2_params,
+            password_pepper,
+            max_body_bytes,
+            max_upload_bytes,
+            password_history,
+            compression,
+            server_timing,
+            static_dir,
+            security_headers,
+            shutdown_drain,
+            sweep_interval,
+            guest_max_age,
+            health_degraded,
+            maintenance_mode,
+            token_exchange_peers,
+            jwt_secrets,
+            jwt,
+            trust_proxy,
+            login_max_failures,
+            login_failure_policy,
+            rate_limit_requests,
+            rate_limit_window,
+            user_retention,
+            min_password_length,
+            totp_cipher,
+            smtp,
+            captcha,
+            webhooks,
+            oauth_providers,
+            cors_origins,
+            cors_max_age,
+            cors_allow_credentials,
+            log_format,
+        })
+    }
+
+    /// The CORS layer for `CORS_ALLOWED_ORIGINS`, if any. It answers
+    /// preflights itself, before authentication runs.
+    pub fn cors(&self) -> Option<warp::cors::Builder> {
+        let cors = warp::cors()
+            .allow_methods([
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ])
+            .allow_headers([
+                header::AUTHORIZATION.as_str(),
+                header::CONTENT_TYPE.as_str(),
+                header::IF_NONE_MATCH.as_str(),
+                header::IF_MATCH.as_str(),
+                CSRF_HEADER,
+                API_KEY_HEADER,
+                idempotency::IDEMPOTENCY_KEY_HEADER,
+            ])
+            .expose_headers([
+                header::ETAG.as_str(),
+                header::RETRY_AFTER.as_str(),
+                header::WWW_AUTHENTICATE.as_str(),
+                request_id::REQUEST_ID_HEADER,
+                ratelimit::LIMIT_HEADER,
+                ratelimit::REMAINING_HEADER,
+                ratelimit::RESET_HEADER,
+                idempotency::REPLAYED_HEADER,
+            ])
+            .max_age(self.cors_max_age)
+            .allow_credentials(self.cors_allow_credentials);
+        match self.cors_origins.as_ref()? {
+            CorsOrigins::Any => Some(cors.allow_any_origin()),
+            CorsOrigins::List(origins) => {
+                Some(cors.allow_origins(origins.iter().map(String::as_str)))
+            }
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_addr, self.port)
+    }
+
+    /// Where the API listens: `unix_socket` when set, otherwise
+    /// [`socket_addr`](Self::socket_addr).
+    pub fn listen_addr(&self) -> ListenAddr {
+        match &self.unix_socket {
+            Some(path) => ListenAddr::Unix(path.clone()),
+            None => ListenAddr::Tcp(self.socket_addr()),
+        }
+    }
+}
+
+/// The configured Argon2id parameters for new password hashes, or the
+/// defaults before the configuration has been loaded.
+pub fn argon2_params() -> Params {
+    ARGON2_PARAMS
+        .get()
+        .cloned()
+        .unwrap_or_else(default_argon2_params)
+}
+
+/// The configured password pepper, if any; none before the configuration
+/// has been loaded.
+pub fn password_pepper() -> Option<&'static str> {
+    PASSWORD_PEPPER.get().and_then(Option::as_deref)
+}
+
+fn default_argon2_params() -> Params {
+    Params::new(
+        DEFAULT_ARGON2_MEMORY_KIB,
+        DEFAULT_ARGON2_ITERATIONS,
+        DEFAULT_ARGON2_PARALLELISM,
+        None,
+    )
+    .expect("the default Argon2 parameters are valid")
+}
+
+/// The configured JSON body limit, or the default before the
+/// configuration has been loaded.
+pub fn max_body_bytes() -> u64 {
+    MAX_BODY_BYTES
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_MAX_BODY_BYTES)
+}
+
+/// The configured upload body limit, or the default before the
+/// configuration has been loaded.
+pub fn max_upload_bytes() -> u64 {
+    MAX_UPLOAD_BYTES
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_MAX_UPLOAD_BYTES)
+}
+
+/// How many recent passwords a new one is checked against, or the default
+/// before the configuration has been loaded.
+pub fn password_history() -> usize {
+    PASSWORD_HISTORY
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_PASSWORD_HISTORY)
+}
+
+/// Whether responses may be compressed; off before the configuration has
+/// been loaded.
+pub fn compression() -> bool {
+    COMPRESSION.get().copied().unwrap_or(false)
+}
+
+/// Whether responses carry `Server-Timing`; off before the configuration
+/// has been loaded.
+pub fn server_timing() -> bool {
+    SERVER_TIMING.get().copied().unwrap_or(false)
+}
+
+/// Whether `/signup` signs the new account in; off before the
+/// configuration has been loaded.
+pub fn signup_login() -> bool {
+    SIGNUP_LOGIN.get().copied().unwrap_or(false)
+}
+
+/// Whether `/signup` requires an invite code; off before the configuration
+/// has been loaded.
+pub fn require_invite() -> bool {
+    REQUIRE_INVITE.get().copied().unwrap_or(false)
+}
+
+/// Whether authentication looks up the token's account; on before the
+/// configuration has been loaded.
+pub fn verify_user() -> bool {
+    VERIFY_USER.get().copied().unwrap_or(true)
+}
+
+/// How long an account lookup made by authentication is cached, or the
+/// default before the configuration has been loaded.
+pub fn user_cache_ttl() -> Duration {
+    USER_CACHE_TTL
+        .get()
+        .copied()
+        .unwrap_or(Duration::from_secs(DEFAULT_USER_CACHE_TTL_SECS))
+}
+
+/// How long a request waits on one MongoDB operation, or the default before
+/// the configuration has been loaded.
+pub fn db_op_timeout() -> Duration {
+    DB_OP_TIMEOUT
+        .get()
+        .copied()
+        .unwrap_or(Duration::from_millis(DEFAULT_DB_OP_TIMEOUT_MS))
+}
+
+/// How long a request may take to get its response unless its route says
+/// otherwise, or the default before the configuration has been loaded.
+pub fn request_timeout() -> Duration {
+    REQUEST_TIMEOUT
+        .get()
+        .copied()
+        .unwrap_or(Duration::from_millis(DEFAULT_REQUEST_TIMEOUT_MS))
+}
+
+/// When the MongoDB circuit breaker opens, or the defaults before the
+/// configuration has been loaded.
+pub fn db_breaker() -> BreakerSettings {
+    DB_BREAKER.get().copied().unwrap_or_default()
+}
+
+/// Whether a banned user is told the reason; off before the configuration
+/// has been loaded.
+pub fn show_ban_reason() -> bool {
+    SHOW_BAN_REASON.get().copied().unwrap_or(false)
+}
+
+/// How long a `remember_me` session's refresh tokens last, or the default
+/// before the configuration has been loaded.
+pub fn remember_me_ttl() -> Duration {
+    REMEMBER_ME_TTL
+        .get()
+        .copied()
+        .unwrap_or(Duration::from_secs(
+            DEFAULT_REMEMBER_ME_TTL_DAYS * 24 * 60 * 60,
+        ))
+}
+
+/// Whether sensitive changes need a session without `remember_me`; off
+/// before the configuration has been loaded.
+pub fn require_fresh_login() -> bool {
+    REQUIRE_FRESH_LOGIN.get().copied().unwrap_or(false)
+}
+
+/// Whether user updates must send `If-Match`; on before the configuration
+/// has been loaded.
+pub fn require_if_match() -> bool {
+    REQUIRE_IF_MATCH.get().copied().unwrap_or(true)
+}
+
+/// The sources admin routes accept requests from, or none (so any source)
+/// before the configuration has been loaded.
+pub fn admin_ip_allowlist() -> &'static [IpRange] {
+    ADMIN_IP_ALLOWLIST.get().map_or(&[], Vec::as_slice)
+}
+
+/// The shortest password accepted, or the default before the
+/// configuration has been loaded.
+pub fn min_password_length() -> usize {
+    MIN_PASSWORD_LENGTH
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_MIN_PASSWORD_LENGTH)
+}
+
+/// The configured API prefix, for links to API routes, or the default
+/// before the configuration has been loaded.
+pub fn api_prefix() -> &'static str {
+    API_PREFIX.get().map_or(DEFAULT_API_PREFIX, String::as_str)
+}
+
+fn string_var(name: &str, default: &str) -> String {
+    env::var(name)
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| default.to_string())
+}
+
+pub(crate) fn required_var(name: &str, problems: &mut Vec<String>) -> String {
+    match env::var(name) {
+        Ok(v) if !v.is_empty() => v,
+        _ => {
+            problems.push(format!("{} must be set", name));
+            String::new()
+        }
+    }
+}
+
+/// A header value from `name`, `default` when it is unset, or none when
+/// it is set but empty, which turns the header off.
+fn header_var(name: &str, default: &str, problems: &mut Vec<String>) -> Option<HeaderValue> {
+    let value = match env::var(name) {
+        Ok(value) if value.trim().is_empty() => return None,
+        Ok(value) => value,
+        Err(_) => default.to_string(),
+    };
+    HeaderValue::from_str(value.trim())
+        .map_err(|_| problems.push(format!("{} is not a valid header value", name)))
+        .ok()
+}
+
+/// Parses `name` if it is set, recording a problem (and returning the
+/// default so the remaining variables still get checked) if it is invalid.
+pub(crate) fn parse_var<T: FromStr>(
+    name: &str,
+    default: T,
+    expected: &str,
+    problems: &mut Vec<String>,
+) -> T {
+    parse_optional_var(name, expected, problems).unwrap_or(default)
+}
+
+fn parse_optional_var<T: FromStr>(
+    name: &str,
+    expected: &str,
+    problems: &mut Vec<String>,
+) -> Option<T> {
+    let value = env::var(name).ok()?;
+    let parsed = value.trim().parse().ok();
+    if parsed.is_none() {
+        problems.push(format!("{} must be {}, got {:?}", name, expected, value));
+    }
+    parsed
+}
+
+/// Encodes everything but RFC 3986 unreserved characters, so credentials
+/// containing `@`, `:` or `/` survive being embedded in a URI.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn parse_unix_socket_mode(problems: &mut Vec<String>) -> u32 {
+    let Ok(value) = env::var("LISTEN_UNIX_SOCKET_MODE") else {
+        return DEFAULT_UNIX_SOCKET_MODE;
+    };
+    match u32::from_str_radix(value.trim(), 8) {
+        Ok(mode) if mode <= 0o777 => mode,
+        _ => {
+            problems.push(format!(
+                "LISTEN_UNIX_SOCKET_MODE must be octal permissions such as 660, got {:?}",
+                value
+            ));
+            DEFAULT_UNIX_SOCKET_MODE
+        }
+    }
+}
+
+fn parse_argon2_params(problems: &mut Vec<String>) -> Params {
+    let memory_kib = parse_var(
+        "ARGON2_MEMORY_KIB",
+        DEFAULT_ARGON2_MEMORY_KIB,
+        "a number of KiB",
+        problems,
+    );
+    let iterations = parse_var(
+        "ARGON2_ITERATIONS",
+        DEFAULT_ARGON2_ITERATIONS,
+        "a positive integer",
+        problems,
+    );
+    let parallelism = parse_var(
+        "ARGON2_PARALLELISM",
+        DEFAULT_ARGON2_PARALLELISM,
+        "a positive integer",
+        problems,
+    );
+    Params::new(memory_kib, iterations, parallelism, None).unwrap_or_else(|e| {
+        problems.push(format!(
+            "ARGON2_MEMORY_KIB, ARGON2_ITERATIONS and ARGON2_PARALLELISM are not usable together: {}",
+            e
+        ));
+        default_argon2_params()
+    })
+}
+
+fn db_breaker_vars(problems: &mut Vec<String>) -> BreakerSettings {
+    let mut positive = |name: &str, default: u64, what: &str| {
+        parse_var(
+            name,
+            NonZeroU64::new(default).expect("nonzero default"),
+            what,
+            problems,
+        )
+        .get()
+    };
+    BreakerSettings {
+        failures: positive(
+            "DB_BREAKER_FAILURES",
+            circuit_breaker::DEFAULT_FAILURES,
+            "a positive integer",
+        ),
+        window: Duration::from_secs(positive(
+            "DB_BREAKER_WINDOW_SECS",
+            circuit_breaker::DEFAULT_WINDOW_SECS,
+            "a positive number of seconds",
+        )),
+        cool_down: Duration::from_secs(positive(
+            "DB_BREAKER_COOL_DOWN_SECS",
+            circuit_breaker::DEFAULT_COOL_DOWN_SECS,
+            "a positive number of seconds",
+        )),
+    }
+}
+
+fn features_vars(problems: &mut Vec<String>) -> Features {
+    let mut flag = |name: &str| parse_var(name, true, "true or false", problems);
+    Features {
+        signup: flag("ENABLE_SIGNUP"),
+        password_login: flag("ENABLE_PASSWORD_LOGIN"),
+        magic_link: flag("ENABLE_MAGIC_LINK"),
+        admin_api: flag("ENABLE_ADMIN_API"),
+    }
+}
+
+/// `API_PREFIX`: one or more path segments such as `/api/v1`. A trailing
+/// slash is dropped.
+fn parse_api_prefix(problems: &mut Vec<String>) -> String {
+    let value = string_var("API_PREFIX", DEFAULT_API_PREFIX);
+    let prefix = value.trim_end_matches('/');
+    let valid = prefix.starts_with('/')
+        && prefix[1..].split('/').all(|segment| {
+            !segment.is_empty()
+                && segment != "."
+                && segment != ".."
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '~'))
+        });
+    if !valid {
+        problems.push(format!(
+            "API_PREFIX must be a path such as /api/v1, got {:?}",
+            value
+        ));
+    }
+    prefix.to_string()
+}
+
+/// `CORS_ALLOWED_ORIGINS`: `*`, or comma-separated origins such as
+/// `https://app.example.com` (scheme, host and optional port, no path).
+fn cors_origins(problems: &mut Vec<String>) -> Option<CorsOrigins> {
+    let value = env::var("CORS_ALLOWED_ORIGINS").ok()?;
+    let origins: Vec<String> = value
+        .split(',')
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty())
+        .collect();
+    if origins.is_empty() {
+        return None;
+    }
+    if origins.iter().any(|origin| origin == "*") {
+        if origins.len() > 1 {
+            problems.push("CORS_ALLOWED_ORIGINS cannot mix * with other origins".to_string());
+        }
+        return Some(CorsOrigins::Any);
+    }
+    for origin in &origins {
+        let valid = match origin.split_once("://") {
+            Some((scheme, host)) => {
+                matches!(scheme, "http" | "https") && host.parse::<Authority>().is_ok()
+            }
+            None => false,
+        };
+        if !valid {
+            problems.push(format!(
+                "CORS_ALLOWED_ORIGINS entries must look like https://host[:port], got {:?}",
+                origin
+            ));
+        }
+    }
+    Some(CorsOrigins::List(origins))
+}
+
+/// `ADMIN_IP_ALLOWLIST`: comma-separated CIDR ranges such as
+/// `10.8.0.0/16, fd00::/8`, or bare addresses.
+fn admin_ip_allowlist_var(problems: &mut Vec<String>) -> Vec<IpRange> {
+    let Ok(value) = env::var("ADMIN_IP_ALLOWLIST") else {
+        return Vec::new();
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .filter_map(|range| match range.parse() {
+            Ok(range) => Some(range),
+            Err(()) => {
+                problems.push(format!(
+                    "ADMIN_IP_ALLOWLIST entries must be CIDR ranges such as 10.0.0.0/8, got {:?}",
+                    range
+                ));
+                None
+            }
+        })
+        .collect()
+}
+
+/// `JWT_SECRETS` (comma-separated, current key first) or a single
+/// `JWT_SECRET`, with blank entries dropped.
+fn jwt_secrets() -> Vec<String> {
+    match env::var("JWT_SECRETS") {
+        Ok(v) => v
+            .split(',')
+            .map(|s| s.trim().to_owned())
+            .filter(|s| !s.is_empty())
+            .collect(),
+        Err(_) => env::var("JWT_SECRET")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .into_iter()
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, TEST_JWT_SECRET};
+
+    const MONGO_URI: &str = "mongodb://localhost:27017";
+
+    #[test]
+    fn unset_variables_fall_back_to_the_defaults() {
+        let config =
+            test_support::config(&[("JWT_SECRET", TEST_JWT_SECRET), ("MONGO_URI", MONGO_URI)])
+                .unwrap();
+        assert_eq!(config.socket_addr(), "0.0.0.0:8000".parse().unwrap());
+        assert_eq!(config.mongo_db_name, "my_app");
+        assert_eq!(config.users_collection, "users");
+        assert_eq!(config.api_prefix, "/api/v1");
+        assert_eq!(config.db_op_timeout, Duration::from_millis(3000));
+        assert!(config.verify_user);
+        assert!(!config.trust_proxy);
+    }
+
+    #[test]
+    fn set_variables_are_parsed() {
+        let config = test_support::config(&[
+            ("JWT_SECRET", TEST_JWT_SECRET),
+            ("MONGO_URI", MONGO_URI),
+            ("BIND_ADDR", "127.0.0.1"),
+            ("PORT", " 9001 "),
+            ("MONGO_DB_NAME", "accounts"),
+            ("USERS_COLLECTION", "people"),
+            ("MONGO_CONNECT_TIMEOUT_SECS", "5"),
+        ])
+        .unwrap();
+        assert_eq!(config.socket_addr(), "127.0.0.1:9001".parse().unwrap());
+        assert_eq!(config.mongo_db_name, "accounts");
+        assert_eq!(config.users_collection, "people");
+        assert_eq!(config.mongo_connect_timeout, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn the_mongo_uri_is_built_from_its_parts() {
+        let config = test_support::config(&[
+            ("JWT_SECRET", TEST_JWT_SECRET),
+            ("MONGO_INITDB_ROOT_USERNAME", "admin@example"),
+            ("MONGO_INITDB_ROOT_PASSWORD", "p:ss/word"),
+            ("MONGO_HOST", "db:27017"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.mongo_uri,
+            "mongodb://admin%40example:p%3Ass%2Fword@db:27017"
+        );
+    }
+
+    #[test]
+    fn unusable_argon2_parameters_are_refused_at_startup() {
+        for (name, value) in [
+            ("ARGON2_ITERATIONS", "0"),
+            ("ARGON2_PARALLELISM", "0"),
+            // Less than the 8 KiB per lane Argon2 needs.
+            ("ARGON2_MEMORY_KIB", "4"),
+            ("ARGON2_MEMORY_KIB", "lots"),
+        ] {
+            let result = test_support::config(&[
+                ("JWT_SECRET", TEST_JWT_SECRET),
+                ("MONGO_URI", MONGO_URI),
+                (name, value),
+            ]);
+            let Err(ConfigError(problems)) = result else {
+                panic!("{}={} was accepted", name, value);
+            };
+            assert!(
+                problems.iter().any(|problem| problem.contains(name)),
+                "{:?}",
+                problems
+            );
+        }
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let Err(ConfigError(problems)) =
+            test_support::config(&[("PORT", "eighty"), ("BIND_ADDR", "localhost")])
+        else {
+            panic!("the configuration was accepted");
+        };
+        assert_eq!(
+            problems,
+            [
+                "BIND_ADDR must be an IP address, got \"localhost\"",
+                "PORT must be a port number, got \"eighty\"",
+                "MONGO_INITDB_ROOT_USERNAME must be set",
+                "MONGO_INITDB_ROOT_PASSWORD must be set",
+                "JWT_SECRET (or JWT_SECRETS) must be set",
+            ]
+        );
+    }
+}
@@ -0,0 +1,234 @@
+use crate::{
+    auth::{hash_token, random_token, Claims},
+    config,
+    error::Error,
+    mailer::Mailer,
+    password,
+    repository::timed,
+    users::normalize_email,
+    validation::Validator,
+    Result, User, WebResult,
+};
+use mongodb::{
+    bson::{doc, DateTime},
+    Collection,
+};
+use serde::Deserialize;
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+use warp::{http::StatusCode, reject, reply, Reply};
+
+const EMAIL_CHANGE_TOKEN_LENGTH: usize = 48;
+const EMAIL_CHANGE_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Deserialize, ToSchema)]
+pub struct EmailChangeRequest {
+    /// The address to move to once it is confirmed.
+    pub email: String,
+    /// The caller's current password.
+    pub pw: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EmailChangeConfirmQuery {
+    /// The token mailed to the new address.
+    pub token: String,
+}
+
+/// Starts moving the caller to a new email address: the address is kept as
+/// `pending_email` and a confirmation link is mailed to it, and the old
+/// address is told about the change. Until the link is followed the account
+/// keeps its old address, which is still the one to sign in with. A new
+/// request replaces the pending one.
+#[utoipa::path(
+    post,
+    path = "/me/email-change",
+    tag = "profile",
+    request_body = EmailChangeRequest,
+    responses(
+        (status = 202, description = "A confirmation link was sent to the new address", body = String),
+        (status = 403, description = "Wrong password", body = ErrorResponse),
+        (status = 409, description = "Email already in use", body = ErrorResponse),
+        (status = 422, description = "Invalid email", body = ErrorResponse),
+        (status = 503, description = "The confirmation email could not be sent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn request_email_change_handler(
+    claims: Claims,
+    mailer: Mailer,
+    users_collection: Collection<User>,
+    body: EmailChangeRequest,
+) -> WebResult<impl Reply> {
+    let user = timed(users_collection.find_one(doc! {"uid": &claims.sub}, None))
+        .await
+        .map_err(reject::custom)?
+        .ok_or_else(|| reject::custom(Error::UserNotFoundError))?;
+    let is_password_correct = password::verify(&body.pw, &user.pw).map_err(reject::custom)?;
+    if !is_password_correct {
+        return Err(reject::custom(Error::WrongCredentialsError));
+    }
+
+    let email = normalize_email(&body.email);
+    let mut validator = Validator::new();
+    validator.email("email", &email);
+    validator.finish().map_err(reject::custom)?;
+    if email_taken(&users_collection, &user.uid, &email)
+        .await
+        .map_err(reject::custom)?
+    {
+        return Err(reject::custom(Error::EmailAlreadyInUseError));
+    }
+
+    let token = random_token(EMAIL_CHANGE_TOKEN_LENGTH);
+    timed(users_collection.update_one(
+        doc! {"uid": &user.uid},
+        doc! {"$set": {
+            "pending_email": &email,
+            "email_change_token_hash": hash_token(&token),
+            "email_change_expires_at":
+                DateTime::now().saturating_add_duration(EMAIL_CHANGE_EXPIRY),
+        }},
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?;
+
+    let confirmation = format!(
+        "Confirm your new email address by visiting {}/email-change/confirm?token={}\n\
+         The link expires in 24 hours.",
+        config::api_prefix(),
+        token
+    );
+    mailer
+        .send(&email, "Confirm your new email address", &confirmation)
+        .await
+        .map_err(reject::custom)?;
+    // Only logged: the change itself can still go ahead.
+    let notice = format!(
+        "Someone asked to change the email address of your account to {}. \
+         Nothing changes unless the new address is confirmed. If this wasn't you, \
+         change your password.",
+        email
+    );
+    if mailer
+        .send(&user.email, "Your email address is being changed", &notice)
+        .await
+        .is_err()
+    {
+        tracing::error!("email change notice for {} was not sent", user.uid);
+    }
+
+    Ok(reply::with_status(
+        "A confirmation link has been sent to the new address",
+        StatusCode::ACCEPTED,
+    ))
+}
+
+/// Completes a change started with `POST /me/email-change`. The pending
+/// address becomes the account's email in one update that also consumes
+/// the token, so a link only works once; if someone else has taken the
+/// address since, the change is refused with 409 and the old address stays.
+#[utoipa::path(
+    get,
+    path = "/email-change/confirm",
+    tag = "profile",
+    params(EmailChangeConfirmQuery),
+    responses(
+        (status = 200, description = "Email changed", body = String),
+        (status = 400, description = "Unknown, used or expired token", body = ErrorResponse),
+        (status = 409, description = "The new address was taken in the meantime", body = ErrorResponse),
+    )
+)]
+pub async fn confirm_email_change_handler(
+    query: EmailChangeConfirmQuery,
+    users_collection: Collection<User>,
+) -> WebResult<impl Reply> {
+    let token_hash = hash_token(&query.token);
+    let pending = doc! {
+        "email_change_token_hash": &token_hash,
+        "email_change_expires_at": {"$gt": DateTime::now()},
+    };
+    let user = timed(users_collection.find_one(pending.clone(), None))
+        .await
+        .map_err(reject::custom)?
+        .ok_or_else(|| reject::custom(Error::InvalidEmailChangeTokenError))?;
+    let Some(email) = user.pending_email else {
+        return Err(reject::custom(Error::InvalidEmailChangeTokenError));
+    };
+    if email_taken(&users_collection, &user.uid, &email)
+        .await
+        .map_err(reject::custom)?
+    {
+        return Err(reject::custom(Error::EmailAlreadyInUseError));
+    }
+
+    // The unique index on `email_lower` catches a signup that claims the
+    // address between the check above and this update.
+    let mut filter = pending;
+    filter.insert("pending_email", &email);
+    let result = timed(users_collection.update_one(
+        filter,
+        doc! {
+            "$set": {
+                "email": &email,
+                "email_lower": &email,
+                "email_verified": true,
+                "updated_at": DateTime::now(),
+            },
+            "$unset": {
+                "pending_email": "",
+                "email_change_token_hash": "",
+                "email_change_expires_at": "",
+            },
+            "$inc": {"version": 1},
+        },
+        None,
+    ))
+    .await
+    .map_err(|e| match e {
+        Error::DuplicateKeyError => reject::custom(Error::EmailAlreadyInUseError),
+        other => reject::custom(other),
+    })?;
+    if result.matched_count == 0 {
+        return Err(reject::custom(Error::InvalidEmailChangeTokenError));
+    }
+
+    Ok(reply::with_status(
+        "Email changed successfully",
+        StatusCode::OK,
+    ))
+}
+
+async fn email_taken(users_collection: &Collection<User>, uid: &str, email: &str) -> Result<bool> {
+    let taken = timed(
+        users_collection.count_documents(doc! {"email_lower": email, "uid": {"$ne": uid}}, None),
+    )
+    .await?;
+    Ok(taken > 0)
+}
+
+/// Drops email changes whose confirmation link has expired. Run by the
+/// purge task.
+#[tracing::instrument(skip_all)]
+pub async fn purge_expired(users_collection: &Collection<User>) -> Result<()> {
+    let result = users_collection
+        .update_many(
+            doc! {"email_change_expires_at": {"$lt": DateTime::now()}},
+            doc! {"$unset": {
+                "pending_email": "",
+                "email_change_token_hash": "",
+                "email_change_expires_at": "",
+            }},
+            None,
+        )
+        .await?;
+    if result.modified_count > 0 {
+        tracing::info!(
+            purged = result.modified_count,
+            "purged expired email changes"
+        );
+    }
+    Ok(())
+}
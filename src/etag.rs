@@ -0,0 +1,139 @@
+use crate::{config, error::Error};
+use mongodb::bson::{doc, Bson, Document};
+use warp::{
+    http::{
+        header::{ETAG, IF_MATCH},
+        HeaderValue,
+    },
+    reject,
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+/// The `ETag` of a user at `version`, such as `"3"`.
+pub fn for_version(version: i64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// `reply` with the `ETag` of a user at `version`, for clients to send
+/// back as `If-Match`.
+pub fn with_etag(reply: impl Reply, version: i64) -> Response {
+    let mut response = reply.into_response();
+    if let Ok(etag) = HeaderValue::from_str(&for_version(version)) {
+        response.headers_mut().insert(ETAG, etag);
+    }
+    response
+}
+
+/// The versions an update is allowed to apply to, from its `If-Match`.
+pub enum IfMatch {
+    /// `*`, or no `If-Match` while `REQUIRE_IF_MATCH` is off.
+    Any,
+    /// Strong tags only; weak ones never match, so an `If-Match` holding
+    /// nothing else fails every update with 412.
+    Versions(Vec<i64>),
+}
+
+/// Takes `If-Match` on a user update. Without one the request is refused
+/// with 428, unless `REQUIRE_IF_MATCH=false` lets it overwrite whatever is
+/// stored.
+pub fn if_match() -> impl Filter<Extract = (IfMatch,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(IF_MATCH.as_str()).and_then(
+        |value: Option<String>| async move {
+            match value {
+                None if config::require_if_match() => {
+                    Err(reject::custom(Error::PreconditionRequiredError))
+                }
+                None => Ok(IfMatch::Any),
+                Some(value) => Ok(IfMatch::parse(&value)),
+            }
+        },
+    )
+}
+
+impl IfMatch {
+    fn parse(value: &str) -> Self {
+        if value.trim() == "*" {
+            return IfMatch::Any;
+        }
+        IfMatch::Versions(
+            value
+                .split(',')
+                .filter_map(|tag| {
+                    tag.trim()
+                        .strip_prefix('"')?
+                        .strip_suffix('"')?
+                        .parse()
+                        .ok()
+                })
+                .collect(),
+        )
+    }
+
+    pub fn matches(&self, version: i64) -> bool {
+        match self {
+            IfMatch::Any => true,
+            IfMatch::Versions(versions) => versions.contains(&version),
+        }
+    }
+
+    /// `filter` narrowed to the allowed versions, for a `find_one_and_update`
+    /// that should miss when the user has changed since it was read.
+    pub fn apply(&self, mut filter: Document) -> Document {
+        if let IfMatch::Versions(versions) = self {
+            let mut allowed: Vec<Bson> = versions.iter().map(|&version| version.into()).collect();
+            // Accounts from before `version` existed have no field yet.
+            if versions.contains(&0) {
+                allowed.push(Bson::Null);
+            }
+            filter.insert("version", doc! {"$in": allowed});
+        }
+        filter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::Role,
+        error::handle_rejection,
+        repository::{InMemoryUserRepository, UserRepository},
+        User,
+    };
+
+    #[tokio::test]
+    async fn the_second_of_two_writes_from_one_read_is_refused() {
+        let repo = InMemoryUserRepository::new();
+        let user = User::new("a@example.com".to_string(), String::new(), &Role::User);
+        repo.insert(&user).await.unwrap();
+
+        // Two clients read the same version and both send its ETag back.
+        let read = repo.find_by_uid(&user.uid).await.unwrap().unwrap();
+        let if_match = IfMatch::parse(&for_version(read.version));
+        let first = User {
+            display_name: Some("First".to_string()),
+            ..read.clone()
+        };
+        let second = User {
+            display_name: Some("Second".to_string()),
+            ..read
+        };
+
+        let written = repo.update_if_match(&first, &if_match).await.unwrap();
+        assert_eq!(written.version, 1);
+        let Err(error) = repo.update_if_match(&second, &if_match).await else {
+            panic!("the write from the stale read went through");
+        };
+        assert!(matches!(error, Error::PreconditionFailedError));
+        let response = handle_rejection(reject::custom(error))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), 412);
+
+        let stored = repo.find_by_uid(&user.uid).await.unwrap().unwrap();
+        assert_eq!(stored.display_name.as_deref(), Some("First"));
+        assert_eq!(stored.version, 1);
+    }
+}
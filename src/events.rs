@@ -0,0 +1,181 @@
+use crate::{auth::Claims, WebResult};
+use futures_util::{stream, Stream, StreamExt};
+use mongodb::bson::DateTime;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+use tokio::sync::broadcast::{self, error::RecvError, Receiver};
+use warp::{sse, Reply};
+
+/// Events a lagging subscriber may fall behind by before it skips ahead.
+const CHANNEL_CAPACITY: usize = 256;
+/// Recent events kept for clients reconnecting with `Last-Event-ID`.
+const BACKLOG_LENGTH: usize = 100;
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+static BUS: OnceLock<EventBus> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug)]
+pub enum AdminEventKind {
+    Signup,
+    Login,
+    LockedOut,
+    /// A sign-in from an address and user agent its account never used
+    /// before.
+    NewDevice,
+}
+
+impl AdminEventKind {
+    fn name(self) -> &'static str {
+        match self {
+            AdminEventKind::Signup => "signup",
+            AdminEventKind::Login => "login",
+            AdminEventKind::LockedOut => "locked_out",
+            AdminEventKind::NewDevice => "new_device",
+        }
+    }
+}
+
+/// One event of `GET /events`. `kind` and `id` go into the SSE `event`
+/// and `id` fields; the rest is the JSON `data`.
+#[derive(Clone, Serialize)]
+pub struct AdminEvent {
+    #[serde(skip)]
+    pub id: u64,
+    #[serde(skip)]
+    pub kind: AdminEventKind,
+    /// The account concerned, when the event is about a known one.
+    pub uid: Option<String>,
+    pub at: Option<String>,
+}
+
+struct EventBus {
+    sender: broadcast::Sender<AdminEvent>,
+    /// The latest events with the id the next one gets. Publishing holds
+    /// this lock while it sends, so a subscriber taking it sees every event
+    /// either in the backlog or on its receiver, never both or neither.
+    backlog: Mutex<(VecDeque<AdminEvent>, u64)>,
+}
+
+fn bus() -> &'static EventBus {
+    BUS.get_or_init(|| EventBus {
+        sender: broadcast::channel(CHANNEL_CAPACITY).0,
+        backlog: Mutex::new((VecDeque::with_capacity(BACKLOG_LENGTH), 1)),
+    })
+}
+
+/// Sends an event to every `GET /events` stream. Cheap and non-blocking;
+/// with nobody listening it only lands in the backlog.
+pub fn publish(kind: AdminEventKind, uid: Option<&str>) {
+    let bus = bus();
+    let mut backlog = bus.backlog.lock().expect("event backlog lock poisoned");
+    let (events, next_id) = &mut *backlog;
+    let event = AdminEvent {
+        id: *next_id,
+        kind,
+        uid: uid.map(str::to_string),
+        at: DateTime::now().try_to_rfc3339_string().ok(),
+    };
+    *next_id += 1;
+    if events.len() == BACKLOG_LENGTH {
+        events.pop_front();
+    }
+    events.push_back(event.clone());
+    // No receivers is not an error here.
+    bus.sender.send(event).ok();
+}
+
+/// The backlog after `last_event_id`, then live events. The receiver is
+/// owned by the stream, so it is dropped with it when the client goes
+/// away.
+fn subscribe(last_event_id: Option<u64>) -> impl Stream<Item = AdminEvent> {
+    let bus = bus();
+    let (replay, receiver) = {
+        let backlog = bus.backlog.lock().expect("event backlog lock poisoned");
+        let replay: Vec<AdminEvent> = match last_event_id {
+            Some(last) => backlog.0.iter().filter(|e| e.id > last).cloned().collect(),
+            None => Vec::new(),
+        };
+        (replay, bus.sender.subscribe())
+    };
+    stream::iter(replay).chain(stream::unfold(receiver, live_event))
+}
+
+async fn live_event(
+    mut receiver: Receiver<AdminEvent>,
+) -> Option<(AdminEvent, Receiver<AdminEvent>)> {
+    loop {
+        match receiver.recv().await {
+            Ok(event) => return Some((event, receiver)),
+            // A consumer that fell behind misses the overwritten events
+            // rather than stalling everyone else.
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::warn!("event stream fell behind, skipped {} events", skipped);
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+fn to_sse(event: AdminEvent) -> Result<sse::Event, Infallible> {
+    Ok(sse::Event::default()
+        .id(event.id.to_string())
+        .event(event.kind.name())
+        .json_data(&event)
+        .expect("admin events serialize"))
+}
+
+/// A `text/event-stream` of signups, logins and lockouts as they happen,
+/// with a keep-alive comment every 15 seconds. A reconnecting client that
+/// sends `Last-Event-ID` first gets the events it missed, as far as the
+/// last 100 go back.
+#[utoipa::path(
+    get,
+    path = "/events",
+    tag = "users",
+    params(("Last-Event-ID" = Option<u64>, Header, description = "The `id` of the last event received")),
+    responses(
+        (status = 200, description = "`signup`, `login`, `new_device` and `locked_out` events, each with `uid` and `at` as JSON data",
+            content_type = "text/event-stream", body = String),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn events_handler(_claims: Claims, last_event_id: Option<u64>) -> WebResult<impl Reply> {
+    let events = subscribe(last_event_id).map(to_sse);
+    Ok(sse::reply(
+        sse::keep_alive()
+            .interval(KEEP_ALIVE_INTERVAL)
+            .stream(events),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use warp::hyper::body::HttpBody;
+
+    #[tokio::test]
+    async fn a_consumer_going_away_drops_its_subscription() {
+        let claims = serde_json::from_value(
+            json!({"sub": "admin", "role": "Admin", "exp": 0, "iat": 0, "jti": ""}),
+        )
+        .unwrap();
+        let response = events_handler(claims, None).await.unwrap().into_response();
+        assert_eq!(bus().sender.receiver_count(), 1);
+
+        let mut body = response.into_body();
+        publish(AdminEventKind::Signup, Some("uid"));
+        let chunk = body.data().await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&chunk).contains("event:"));
+
+        // The client disconnecting mid-stream drops the body.
+        drop(body);
+        assert_eq!(bus().sender.receiver_count(), 0);
+    }
+}
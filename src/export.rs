@@ -0,0 +1,385 @@
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::Claims,
+    error::Error,
+    repository::timed,
+    sessions::ClientInfo,
+    users::{validate_uid, UserData},
+    User, WebResult,
+};
+use futures_util::{stream, stream::BoxStream, StreamExt};
+use mongodb::{
+    bson::{doc, Bson, DateTime, Document},
+    options::{FindOneOptions, FindOptions},
+    Collection,
+};
+use serde::Deserialize;
+use std::time::Duration;
+use utoipa::IntoParams;
+use warp::{
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderValue,
+    },
+    hyper::Body,
+    reject, reply,
+};
+
+const CSV_HEADER: &str = "uid,email,role,created_at,last_login_at\r\n";
+/// How often a user may export their own data, since an export reads their
+/// whole audit history.
+pub const PERSONAL_EXPORT_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// The least time an export gets to start its response, since a personal
+/// export is gathered whole first and the CSV's sorted query can be slow
+/// to return its first batch.
+pub const EXPORT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The exported columns. Reading into this instead of `User`, with a
+/// matching projection, keeps password hashes from ever leaving MongoDB.
+#[derive(Deserialize)]
+struct ExportRow {
+    uid: String,
+    email: String,
+    role: String,
+    #[serde(default)]
+    created_at: Option<DateTime>,
+    #[serde(default)]
+    last_login_at: Option<DateTime>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportUsersQuery {
+    /// Only export users with this role.
+    pub role: Option<String>,
+}
+
+/// Quotes a field when it contains a delimiter, quote or line break, as
+/// RFC 4180 requires. A field a spreadsheet would take for a formula, such
+/// as an email chosen as `=HYPERLINK(...)`, gets a leading `'` so the admin
+/// opening the export sees it as text.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn csv_timestamp(value: Option<DateTime>) -> String {
+    value
+        .and_then(|t| t.try_to_rfc3339_string().ok())
+        .unwrap_or_default()
+}
+
+fn csv_row(user: ExportRow) -> String {
+    format!(
+        "{},{},{},{},{}\r\n",
+        csv_field(&user.uid),
+        csv_field(&user.email),
+        csv_field(&user.role),
+        csv_timestamp(user.created_at),
+        csv_timestamp(user.last_login_at),
+    )
+}
+
+/// Streams every user as CSV straight from the cursor, so memory use does
+/// not grow with the collection. Password hashes are never included.
+#[utoipa::path(
+    get,
+    path = "/users/export",
+    tag = "users",
+    params(ExportUsersQuery),
+    responses(
+        (status = 200, description = "`users.csv`", content_type = "text/csv", body = String),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn export_users_handler(
+    _claims: Claims,
+    users_collection: Collection<User>,
+    query: ExportUsersQuery,
+) -> WebResult<reply::Response> {
+    let mut filter = Document::new();
+    if let Some(role) = query.role {
+        filter.insert("role", role);
+    }
+    let options = FindOptions::builder()
+        .sort(doc! {"_id": 1})
+        .projection(doc! {
+            "uid": 1,
+            "email": 1,
+            "role": 1,
+            "created_at": 1,
+            "last_login_at": 1,
+        })
+        .build();
+    let cursor = timed(
+        users_collection
+            .clone_with_type::<ExportRow>()
+            .find(filter, options),
+    )
+    .await
+    .map_err(reject::custom)?;
+
+    let rows = cursor.map(|user| user.map(csv_row));
+    let body = stream::once(async { Ok(CSV_HEADER.to_string()) }).chain(rows);
+
+    let mut response = reply::Response::new(Body::wrap_stream(body));
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/csv; charset=utf-8"),
+    );
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_static("attachment; filename=\"users.csv\""),
+    );
+    Ok(response)
+}
+
+/// One array of a personal data export: the `name` field, filled from the
+/// documents of `collection` matching `filter`.
+struct Section {
+    name: &'static str,
+    collection: Collection<Document>,
+    filter: Document,
+    /// Fields left out, such as token hashes.
+    omit: &'static [&'static str],
+}
+
+/// `,"name":[...]`, written document by document as the cursor yields them.
+fn section_json(section: Section) -> BoxStream<'static, mongodb::error::Result<String>> {
+    let Section {
+        name,
+        collection,
+        filter,
+        omit,
+    } = section;
+    let open = async move {
+        let options = FindOptions::builder()
+            .sort(doc! {"_id": 1})
+            .projection(projection(omit))
+            .build();
+        collection.find(filter, options).await
+    };
+    let items = stream::once(open).flat_map(|cursor| match cursor {
+        Ok(cursor) => cursor
+            .enumerate()
+            .map(|(i, document)| {
+                let separator = if i == 0 { "" } else { "," };
+                document.map(|document| format!("{}{}", separator, to_json(document)))
+            })
+            .boxed(),
+        Err(e) => stream::once(async { Err(e) }).boxed(),
+    });
+    stream::once(async move { Ok(format!(",\"{}\":[", name)) })
+        .chain(items)
+        .chain(stream::once(async { Ok("]".to_string()) }))
+        .boxed()
+}
+
+fn projection(omit: &[&str]) -> Document {
+    let mut projection = doc! {"_id": 0};
+    for field in omit {
+        projection.insert(*field, 0);
+    }
+    projection
+}
+
+/// Relaxed extended JSON, so dates read as `{"$date": "2024-01-01T..."}`.
+fn to_json(document: Document) -> String {
+    Bson::Document(document).into_relaxed_extjson().to_string()
+}
+
+/// Everything held about `uid` as one JSON document: the account minus
+/// its password hash and other secrets, its sessions, API keys, linked
+/// accounts and organization memberships, and the audit log entries it
+/// caused or is the subject of. Only the account is read up front; the
+/// rest is streamed from cursors, so a long audit history is never held
+/// in memory.
+async fn personal_export(
+    uid: &str,
+    users_collection: Collection<User>,
+    user_data: UserData,
+    audit_log: Collection<AuditEvent>,
+) -> WebResult<reply::Response> {
+    let options = FindOneOptions::builder()
+        .projection(projection(&[
+            "pw",
+            "pw_history",
+            "totp_secret",
+            "verification_token_hash",
+            "email_change_token_hash",
+        ]))
+        .build();
+    let user = timed(
+        users_collection
+            .clone_with_type::<Document>()
+            .find_one(doc! {"uid": uid}, options),
+    )
+    .await
+    .map_err(reject::custom)?
+    .ok_or_else(|| reject::custom(Error::UserNotFoundError))?;
+
+    let sections = [
+        Section {
+            name: "sessions",
+            collection: user_data.sessions.clone_with_type(),
+            filter: doc! {"uid": uid},
+            omit: &["token_hash", "access_jti"],
+        },
+        Section {
+            name: "api_keys",
+            collection: user_data.api_keys.clone_with_type(),
+            filter: doc! {"uid": uid},
+            omit: &["key_hash"],
+        },
+        Section {
+            name: "linked_accounts",
+            collection: user_data.federated_identities.clone_with_type(),
+            filter: doc! {"uid": uid},
+            omit: &[],
+        },
+        Section {
+            name: "memberships",
+            collection: user_data.memberships.clone_with_type(),
+            filter: doc! {"uid": uid},
+            omit: &[],
+        },
+        Section {
+            name: "audit_log",
+            collection: audit_log.clone_with_type(),
+            filter: doc! {"$or": [{"actor_uid": uid}, {"target_uid": uid}]},
+            omit: &[],
+        },
+    ];
+    let exported_at = DateTime::now().try_to_rfc3339_string().unwrap_or_default();
+    let head = format!(
+        "{{\"exported_at\":\"{}\",\"user\":{}",
+        exported_at,
+        to_json(user)
+    );
+    let body = stream::once(async { Ok(head) })
+        .chain(stream::iter(sections).flat_map(section_json))
+        .chain(stream::once(async { Ok("}".to_string()) }));
+
+    let mut response = reply::Response::new(Body::wrap_stream(body));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    let disposition = format!("attachment; filename=\"export-{}.json\"", uid);
+    if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+        headers.insert(CONTENT_DISPOSITION, disposition);
+    }
+    Ok(response)
+}
+
+/// A copy of everything held about the caller, for data access requests.
+/// Limited to one export an hour per user.
+#[utoipa::path(
+    get,
+    path = "/me/export",
+    tag = "profile",
+    responses(
+        (status = 200, description = "`export-{uid}.json`", content_type = "application/json", body = Object),
+        (status = 401, description = "Missing or invalid token", body = ErrorResponse),
+        (status = 429, description = "Already exported within the last hour", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn export_my_data_handler(
+    claims: Claims,
+    users_collection: Collection<User>,
+    user_data: UserData,
+    audit_log: Collection<AuditEvent>,
+    client: ClientInfo,
+) -> WebResult<reply::Response> {
+    let response = personal_export(&claims.sub, users_collection, user_data, audit_log).await?;
+    audit::record(
+        AuditEvent::new(AuditAction::DataExported, &client)
+            .actor(&claims.sub)
+            .target(&claims.sub),
+    );
+    Ok(response)
+}
+
+/// The same export as `GET /me/export` for any account, deleted ones
+/// included, for admins answering a request on a user's behalf.
+#[utoipa::path(
+    get,
+    path = "/users/{uid}/export",
+    tag = "users",
+    params(("uid" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "`export-{uid}.json`", content_type = "application/json", body = Object),
+        (status = 400, description = "The uid is not a UUID", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such user", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn export_user_data_handler(
+    uid: String,
+    claims: Claims,
+    users_collection: Collection<User>,
+    user_data: UserData,
+    audit_log: Collection<AuditEvent>,
+    client: ClientInfo,
+) -> WebResult<reply::Response> {
+    validate_uid(&uid).map_err(reject::custom)?;
+    let response = personal_export(&uid, users_collection, user_data, audit_log).await?;
+    audit::record(
+        AuditEvent::new(AuditAction::DataExported, &client)
+            .actor(&claims.sub)
+            .target(&uid),
+    );
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::Role, test_support};
+    use serde_json::json;
+    use warp::hyper::body::HttpBody;
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn a_large_export_streams_every_row_in_order() {
+        let app = test_support::app().await;
+        let users: Vec<User> = (0..5000)
+            .map(|i| User::new(format!("user{}@example.com", i), String::new(), &Role::User))
+            .collect();
+        app.users.insert_many(&users, None).await.unwrap();
+        let claims = serde_json::from_value(
+            json!({"sub": "admin", "role": "Admin", "exp": 0, "iat": 0, "jti": ""}),
+        )
+        .unwrap();
+
+        let response =
+            export_users_handler(claims, app.users.clone(), ExportUsersQuery { role: None })
+                .await
+                .unwrap();
+        let mut body = response.into_body();
+        let (mut csv, mut chunks) = (String::new(), 0);
+        while let Some(chunk) = body.data().await {
+            csv.push_str(std::str::from_utf8(&chunk.unwrap()).unwrap());
+            chunks += 1;
+        }
+        assert!(chunks > 1, "the export came in one piece");
+
+        let lines: Vec<&str> = csv.trim_end().split("\r\n").collect();
+        assert_eq!(lines[0], CSV_HEADER.trim_end());
+        assert_eq!(lines.len(), users.len() + 1);
+        let row = |user: &User| format!("{},{},", user.uid, user.email);
+        assert!(lines[1].starts_with(&row(&users[0])), "{}", lines[1]);
+        let last = lines[users.len()];
+        assert!(last.starts_with(&row(users.last().unwrap())), "{}", last);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{auth::Claims, error::Error, WebResult};
+use serde::Serialize;
+use utoipa::ToSchema;
+use warp::{filters::BoxedFilter, reject, reply, Filter, Reply};
+
+/// Route groups a deployment can switch off, each with an `ENABLE_*`
+/// variable. A disabled group's routes stay mounted and answer 403
+/// `FEATURE_DISABLED`, so clients can tell a switched-off feature from a
+/// mistyped path.
+#[derive(Clone, Copy, Serialize, ToSchema)]
+pub struct Features {
+    /// `/signup`, `/guest` and `/guest/upgrade`.
+    pub signup: bool,
+    /// `/login` with a password, and the password reset routes.
+    pub password_login: bool,
+    /// `/login/magic` and its confirmation link.
+    pub magic_link: bool,
+    /// Every route that needs the `Admin` role or a `users:*` scope,
+    /// except `/admin/features` itself.
+    pub admin_api: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            signup: true,
+            password_login: true,
+            magic_link: true,
+            admin_api: true,
+        }
+    }
+}
+
+/// Lets requests through when `enabled`, and rejects them with
+/// `FeatureDisabledError` otherwise. Goes after a route's path and method,
+/// so that requests for other routes are unaffected.
+pub fn enabled(enabled: bool) -> BoxedFilter<()> {
+    if enabled {
+        return warp::any().boxed();
+    }
+    warp::any()
+        .and_then(|| async { Err::<(), _>(reject::custom(Error::FeatureDisabledError)) })
+        .untuple_one()
+        .boxed()
+}
+
+/// Which features this instance serves, as configured at startup. They
+/// can't be changed at runtime.
+#[utoipa::path(
+    get,
+    path = "/admin/features",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The enabled features", body = Features),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn features_handler(_claims: Claims, features: Features) -> WebResult<impl Reply> {
+    Ok(reply::json(&features))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        auth::{create_jwt, Role},
+        routes::AppState,
+        test_support, User,
+    };
+    use serde_json::{json, Value};
+
+    const FLAGS: [&str; 4] = [
+        "ENABLE_SIGNUP",
+        "ENABLE_PASSWORD_LOGIN",
+        "ENABLE_MAGIC_LINK",
+        "ENABLE_ADMIN_API",
+    ];
+
+    /// The error code `method path` with `body` gets, if any.
+    async fn code(app: &AppState, method: &str, path: &str, body: &str) -> Option<String> {
+        let response = warp::test::request()
+            .method(method)
+            .path(&format!("/api/v1{}", path))
+            .header("content-type", "application/json")
+            .body(body)
+            .reply(&crate::routes(app.clone()))
+            .await;
+        let body: Value = serde_json::from_slice(response.body()).unwrap_or(Value::Null);
+        body["code"].as_str().map(str::to_owned)
+    }
+
+    /// Whether the route group behind each of `FLAGS` answers
+    /// `FEATURE_DISABLED`.
+    async fn disabled_groups(app: &AppState) -> [bool; 4] {
+        let probes = [
+            ("POST", "/signup", r#"{"email": "nope", "pw": ""}"#),
+            (
+                "POST",
+                "/login",
+                r#"{"identifier": "a@example.com", "pw": ""}"#,
+            ),
+            ("POST", "/login/magic", "{"),
+            ("GET", "/admin", ""),
+        ];
+        let mut disabled = [false; 4];
+        for (group, (method, path, body)) in probes.into_iter().enumerate() {
+            disabled[group] =
+                code(app, method, path, body).await.as_deref() == Some("FEATURE_DISABLED");
+        }
+        disabled
+    }
+
+    #[tokio::test]
+    async fn each_flag_switches_off_its_own_routes_only() {
+        let all_on: Vec<(&str, &str)> = FLAGS.iter().map(|flag| (*flag, "true")).collect();
+        let app = test_support::offline_app_with(&all_on).await;
+        assert_eq!(disabled_groups(&app).await, [false; 4]);
+
+        let all_off: Vec<(&str, &str)> = FLAGS.iter().map(|flag| (*flag, "false")).collect();
+        let app = test_support::offline_app_with(&all_off).await;
+        assert_eq!(disabled_groups(&app).await, [true; 4]);
+
+        for (off, flag) in FLAGS.iter().enumerate() {
+            let app = test_support::offline_app_with(&[(flag, "false")]).await;
+            let mut expected = [false; 4];
+            expected[off] = true;
+            assert_eq!(disabled_groups(&app).await, expected, "{}", flag);
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_features_stays_up_without_the_admin_api() {
+        let app = test_support::offline_app_with(&[("ENABLE_ADMIN_API", "false")]).await;
+        assert_eq!(
+            code(&app, "GET", "/admin/features", "").await.as_deref(),
+            Some("NO_AUTH_HEADER")
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn admin_features_shows_the_admin_api_switched_off() {
+        let app = test_support::app_with(&[("ENABLE_ADMIN_API", "false")]).await;
+        let admin = User::new("admin@example.com".to_string(), String::new(), &Role::Admin);
+        app.users.insert_one(&admin, None).await.unwrap();
+        let token = create_jwt(&app.auth_context, &admin.uid, &Role::Admin, 0).unwrap();
+
+        let response = warp::test::request()
+            .path("/api/v1/admin/features")
+            .header("authorization", format!("Bearer {}", token.token))
+            .reply(&crate::routes(app.clone()))
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "signup": true,
+                "password_login": true,
+                "magic_link": true,
+                "admin_api": false,
+            })
+        );
+    }
+}
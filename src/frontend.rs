@@ -0,0 +1,163 @@
+use crate::error;
+use std::{
+    convert::Infallible,
+    path::{Path, PathBuf},
+};
+use warp::{
+    filters::{fs::File, path::FullPath, BoxedFilter},
+    http::{
+        header::{ACCEPT, CACHE_CONTROL},
+        HeaderValue,
+    },
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+const INDEX_FILE: &str = "index.html";
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const NO_CACHE: &str = "no-cache";
+
+/// Serves `dir` behind `api`, which keeps precedence: files are only
+/// looked up when no API route matched, and `index.html` is served for
+/// other GET requests from browsers so client-side routing works. Unknown
+/// paths below `api_prefix` are not client-side routes, and API errors
+/// other than not found are answered as usual.
+///
+/// `warp::fs::dir` refuses `..` segments, so requests cannot escape `dir`.
+pub fn with_frontend(
+    api: BoxedFilter<(Response,)>,
+    dir: &Path,
+    api_prefix: &str,
+) -> BoxedFilter<(Response,)> {
+    let index = dir.join(INDEX_FILE);
+    let files = warp::fs::dir(dir.to_path_buf()).map(cached);
+    let api_prefix = format!("{}/", api_prefix);
+    let fallback = warp::get()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>(ACCEPT.as_str()))
+        .and_then(move |path: FullPath, accept: Option<String>| {
+            let in_api = format!("{}/", path.as_str()).starts_with(&api_prefix);
+            async move {
+                if !in_api && accept.is_some_and(|accept| accept.contains("text/html")) {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one()
+        .and(warp::fs::file(index))
+        .map(cached);
+    api.or(files)
+        .unify()
+        .recover(answer_unless_not_found)
+        .unify()
+        .or(fallback)
+        .unify()
+        .boxed()
+}
+
+async fn answer_unless_not_found(err: Rejection) -> Result<Response, Rejection> {
+    if err.is_not_found() {
+        return Err(err);
+    }
+    let reply = error::handle_rejection(err)
+        .await
+        .unwrap_or_else(|never: Infallible| match never {});
+    Ok(reply.into_response())
+}
+
+/// Content-hashed build output (`app.3f9a2c1b.js`, `index-BdK2x9qA.css`)
+/// never changes under the same name; everything else, `index.html` in
+/// particular, must be revalidated so a deploy is picked up.
+fn cached(file: File) -> Response {
+    let policy = if is_hashed(file.path()) {
+        IMMUTABLE
+    } else {
+        NO_CACHE
+    };
+    let mut response = file.into_response();
+    response
+        .headers_mut()
+        .insert(CACHE_CONTROL, HeaderValue::from_static(policy));
+    response
+}
+
+fn is_hashed(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    name != INDEX_FILE
+        && name.split(['.', '-', '_']).any(|part| {
+            part.len() >= 8
+                && part.chars().all(|c| c.is_ascii_alphanumeric())
+                && part.chars().any(|c| c.is_ascii_digit())
+        })
+}
+
+/// `STATIC_DIR`, checked to be a directory with an `index.html`.
+pub fn check_dir(dir: &str) -> Result<PathBuf, String> {
+    let dir = PathBuf::from(dir);
+    if !dir.join(INDEX_FILE).is_file() {
+        return Err(format!(
+            "STATIC_DIR ({}) must be a directory containing {}",
+            dir.display(),
+            INDEX_FILE
+        ));
+    }
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "[package]\nname = \"secret\"\n";
+
+    /// A `public` directory to serve, next to a `Cargo.toml` a traversal
+    /// would reach.
+    fn site() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("frontend-{}", rand::random::<u64>()));
+        let public = root.join("public");
+        std::fs::create_dir_all(&public).unwrap();
+        std::fs::write(public.join(INDEX_FILE), "<html></html>").unwrap();
+        std::fs::write(root.join("Cargo.toml"), SECRET).unwrap();
+        public
+    }
+
+    fn app(dir: &Path) -> BoxedFilter<(Response,)> {
+        let api = warp::path!("api" / "ping")
+            .map(|| "pong".into_response())
+            .boxed();
+        with_frontend(api, dir, "/api")
+    }
+
+    #[tokio::test]
+    async fn serves_files_from_the_directory() {
+        let dir = site();
+        let res = warp::test::request()
+            .path("/index.html")
+            .reply(&app(&dir))
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "<html></html>");
+    }
+
+    #[tokio::test]
+    async fn parent_segments_do_not_leave_the_directory() {
+        let dir = site();
+        for path in ["/../Cargo.toml", "/%2e%2e/Cargo.toml", "/%2E%2E/Cargo.toml"] {
+            let res = warp::test::request().path(path).reply(&app(&dir)).await;
+            assert_eq!(res.status(), 404, "{}", path);
+            assert!(!String::from_utf8_lossy(res.body()).contains("secret"));
+
+            // Browsers get the app's index instead, never the file.
+            let res = warp::test::request()
+                .path(path)
+                .header("accept", "text/html")
+                .reply(&app(&dir))
+                .await;
+            assert_eq!(res.body(), "<html></html>", "{}", path);
+        }
+    }
+}
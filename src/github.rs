@@ -0,0 +1,129 @@
+use crate::{
+    config,
+    error::Error,
+    oauth::{ExternalIdentity, OAuthProvider},
+    Result,
+};
+use async_trait::async_trait;
+use serde::Deserialize;
+use url::Url;
+
+const AUTHORIZE_URL: &str = "https://github.com/login/oauth/authorize";
+const TOKEN_URL: &str = "https://github.com/login/oauth/access_token";
+const USER_URL: &str = "https://api.github.com/user";
+const EMAILS_URL: &str = "https://api.github.com/user/emails";
+/// GitHub's API rejects requests without a `User-Agent`.
+const USER_AGENT: &str = "rust-warp-jwt";
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GitHubUser {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct GitHubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+/// GitHub OAuth app login. The identity is the numeric account id plus the
+/// account's primary verified email.
+pub struct GitHubProvider {
+    client_id: String,
+    client_secret: String,
+    redirect_uri: String,
+    http: reqwest::Client,
+}
+
+impl GitHubProvider {
+    /// Reads `GITHUB_CLIENT_ID`, `GITHUB_CLIENT_SECRET` and
+    /// `GITHUB_REDIRECT_URI`, all of which are required; `None`, with the
+    /// missing ones recorded in `problems`, otherwise.
+    pub(crate) fn from_env(problems: &mut Vec<String>) -> Option<Self> {
+        let client_id = config::required_var("GITHUB_CLIENT_ID", problems);
+        let client_secret = config::required_var("GITHUB_CLIENT_SECRET", problems);
+        let redirect_uri = config::required_var("GITHUB_REDIRECT_URI", problems);
+        if client_id.is_empty() || client_secret.is_empty() || redirect_uri.is_empty() {
+            return None;
+        }
+        Some(GitHubProvider {
+            client_id,
+            client_secret,
+            redirect_uri,
+            http: reqwest::Client::new(),
+        })
+    }
+
+    async fn get<T: for<'de> Deserialize<'de>>(&self, url: &str, token: &str) -> Result<T> {
+        self.http
+            .get(url)
+            .bearer_auth(token)
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|_| Error::OAuthProviderError)?
+            .json()
+            .await
+            .map_err(|_| Error::OAuthProviderError)
+    }
+}
+
+#[async_trait]
+impl OAuthProvider for GitHubProvider {
+    fn authorize_url(&self, state: &str) -> String {
+        Url::parse_with_params(
+            AUTHORIZE_URL,
+            &[
+                ("client_id", self.client_id.as_str()),
+                ("redirect_uri", self.redirect_uri.as_str()),
+                ("scope", "read:user user:email"),
+                ("state", state),
+            ],
+        )
+        .expect("authorize url is valid")
+        .to_string()
+    }
+
+    async fn exchange_code(&self, code: &str) -> Result<String> {
+        // GitHub reports a bad code with a 200 response and no access_token.
+        let response: TokenResponse = self
+            .http
+            .post(TOKEN_URL)
+            .header(reqwest::header::ACCEPT, "application/json")
+            .form(&[
+                ("code", code),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+                ("redirect_uri", &self.redirect_uri),
+            ])
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|_| Error::OAuthProviderError)?
+            .json()
+            .await
+            .map_err(|_| Error::OAuthProviderError)?;
+        response.access_token.ok_or(Error::OAuthCallbackError)
+    }
+
+    async fn fetch_identity(&self, token: &str) -> Result<ExternalIdentity> {
+        let user: GitHubUser = self.get(USER_URL, token).await?;
+        let emails: Vec<GitHubEmail> = self.get(EMAILS_URL, token).await?;
+        let email = emails
+            .into_iter()
+            .find(|e| e.primary && e.verified)
+            .ok_or(Error::OAuthEmailUnverifiedError)?;
+        Ok(ExternalIdentity {
+            external_id: user.id.to_string(),
+            email: email.email,
+        })
+    }
+}
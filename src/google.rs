@@ -0,0 +1,173 @@
+use crate::{
+    config,
+    error::Error,
+    oauth::{ExternalIdentity, OAuthProvider},
+    Result,
+};
+use async_trait::async_trait;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use url::Url;
+
+const AUTHORIZE_URL: &str = "https://accounts.google.com/o/oauth2/v2/auth";
+const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const JWKS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+const ISSUERS: [&str; 2] = ["https://accounts.google.com", "accounts.google.com"];
+const JWKS_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Deserialize)]
+struct GoogleJwk {
+    kid: String,
+    n: String,
+    e: String,
+}
+
+#[derive(Deserialize)]
+struct GoogleJwks {
+    keys: Vec<GoogleJwk>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    email: String,
+    #[serde(default)]
+    email_verified: bool,
+}
+
+type KeyCache = Arc<RwLock<Option<(Instant, Vec<GoogleJwk>)>>>;
+
+/// Google sign-in via OpenID Connect. The identity comes from the ID token,
+/// which is verified against Google's published signing keys.
+pub struct GoogleProvider {
+    client_id: String,
+    client_secret: String,
+    redirect_uri: String,
+    http: reqwest::Client,
+    jwks: KeyCache,
+}
+
+impl GoogleProvider {
+    /// Reads `GOOGLE_CLIENT_ID`, `GOOGLE_CLIENT_SECRET` and
+    /// `GOOGLE_REDIRECT_URI`, all of which are required; `None`, with the
+    /// missing ones recorded in `problems`, otherwise.
+    pub(crate) fn from_env(problems: &mut Vec<String>) -> Option<Self> {
+        let client_id = config::required_var("GOOGLE_CLIENT_ID", problems);
+        let client_secret = config::required_var("GOOGLE_CLIENT_SECRET", problems);
+        let redirect_uri = config::required_var("GOOGLE_REDIRECT_URI", problems);
+        if client_id.is_empty() || client_secret.is_empty() || redirect_uri.is_empty() {
+            return None;
+        }
+        Some(GoogleProvider {
+            client_id,
+            client_secret,
+            redirect_uri,
+            http: reqwest::Client::new(),
+            jwks: Arc::default(),
+        })
+    }
+
+    /// Looks up a signing key by id, refetching the key set when it is stale
+    /// or doesn't contain the key (Google rotates keys regularly).
+    async fn signing_key(&self, kid: &str) -> Result<GoogleJwk> {
+        {
+            let cache = self.jwks.read().expect("jwks cache lock poisoned");
+            if let Some((fetched, keys)) = cache.as_ref() {
+                if fetched.elapsed() < JWKS_CACHE_TTL {
+                    if let Some(key) = keys.iter().find(|k| k.kid == kid) {
+                        return Ok(key.clone());
+                    }
+                }
+            }
+        }
+
+        let jwks: GoogleJwks = self
+            .http
+            .get(JWKS_URL)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|_| Error::OAuthProviderError)?
+            .json()
+            .await
+            .map_err(|_| Error::OAuthProviderError)?;
+        let key = jwks.keys.iter().find(|k| k.kid == kid).cloned();
+        *self.jwks.write().expect("jwks cache lock poisoned") = Some((Instant::now(), jwks.keys));
+        key.ok_or(Error::InvalidIdTokenError)
+    }
+}
+
+#[async_trait]
+impl OAuthProvider for GoogleProvider {
+    fn authorize_url(&self, state: &str) -> String {
+        Url::parse_with_params(
+            AUTHORIZE_URL,
+            &[
+                ("client_id", self.client_id.as_str()),
+                ("redirect_uri", self.redirect_uri.as_str()),
+                ("response_type", "code"),
+                ("scope", "openid email"),
+                ("state", state),
+            ],
+        )
+        .expect("authorize url is valid")
+        .to_string()
+    }
+
+    async fn exchange_code(&self, code: &str) -> Result<String> {
+        let response: TokenResponse = self
+            .http
+            .post(TOKEN_URL)
+            .form(&[
+                ("code", code),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+                ("redirect_uri", &self.redirect_uri),
+                ("grant_type", "authorization_code"),
+            ])
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|_| Error::OAuthProviderError)?
+            .json()
+            .await
+            .map_err(|_| Error::OAuthProviderError)?;
+        Ok(response.id_token)
+    }
+
+    async fn fetch_identity(&self, id_token: &str) -> Result<ExternalIdentity> {
+        let kid = decode_header(id_token)
+            .ok()
+            .and_then(|header| header.kid)
+            .ok_or(Error::InvalidIdTokenError)?;
+        let key = self.signing_key(&kid).await?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[&self.client_id]);
+        let claims = decode::<IdTokenClaims>(
+            id_token,
+            &DecodingKey::from_rsa_components(&key.n, &key.e),
+            &validation,
+        )
+        .map_err(|_| Error::InvalidIdTokenError)?
+        .claims;
+
+        if !ISSUERS.contains(&claims.iss.as_str()) || !claims.email_verified {
+            return Err(Error::InvalidIdTokenError);
+        }
+        Ok(ExternalIdentity {
+            external_id: claims.sub,
+            email: claims.email,
+        })
+    }
+}
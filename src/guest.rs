@@ -0,0 +1,211 @@
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{AuthContext, Claims, Role},
+    config,
+    error::Error,
+    events::{self, AdminEventKind},
+    mailer::Mailer,
+    metrics, password,
+    repository::{timed, UserRepo},
+    sessions::{ClientInfo, Session},
+    set_session_cookies, start_session,
+    users::{self, normalize_email},
+    validation::{Validate, Validator},
+    verification,
+    webhooks::{self, WebhookEvent},
+    SignupResponse, User, UserResponse, WebResult,
+};
+use mongodb::{
+    bson::{doc, DateTime},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+    Collection,
+};
+use serde::Deserialize;
+use utoipa::ToSchema;
+use warp::{http::StatusCode, reject, reply, reply::Response, Reply};
+
+/// The email, password and optional username that turn a guest into a
+/// normal account.
+#[derive(Deserialize, ToSchema)]
+pub struct GuestUpgradeRequest {
+    #[serde(deserialize_with = "crate::users::normalized_email")]
+    pub email: String,
+    pub pw: String,
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+impl Validate for GuestUpgradeRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.email("email", &self.email);
+        validator.password("pw", &self.pw);
+        if let Some(username) = &self.username {
+            validator.username("username", username);
+        }
+    }
+}
+
+/// Creates an anonymous `Guest` account and signs it in, so the app can be
+/// tried before signing up. Guests only get into routes that allow their
+/// role, can't sign in again once their session ends, and are purged after
+/// `GUEST_MAX_AGE_DAYS` unless upgraded with `POST /guest/upgrade`.
+#[utoipa::path(
+    post,
+    path = "/guest",
+    tag = "account",
+    responses(
+        (status = 201, description = "Guest created and signed in", body = SignupResponse),
+        (status = 403, description = "Signups need an invite", body = ErrorResponse),
+        (status = 429, description = "Rate limited", body = ErrorResponse),
+    )
+)]
+pub async fn create_guest_handler(
+    users_collection: Collection<User>,
+    context: AuthContext,
+    sessions_collection: Collection<Session>,
+    client: ClientInfo,
+) -> WebResult<Response> {
+    // A guest can upgrade without an invite, so it would be a way around
+    // the requirement.
+    if config::require_invite() {
+        return Err(reject::custom(Error::InviteRequiredError));
+    }
+
+    let guest = User::guest();
+    timed(users::documents(&users_collection).insert_one(guest.document(), None))
+        .await
+        .map_err(reject::custom)?;
+    let session = start_session(&context, &sessions_collection, &guest, &client, false).await?;
+    let token = session.token.clone();
+    let response = reply::json(&SignupResponse {
+        user: UserResponse::from(guest),
+        session: Some(session),
+    });
+    let mut response = reply::with_status(response, StatusCode::CREATED).into_response();
+    set_session_cookies(&context, &mut response, &token, false);
+    Ok(response)
+}
+
+/// Turns the calling guest into a normal `User` account in place, keeping
+/// its uid and so everything stored under it. Like `/signup`, a
+/// verification email is sent and the email must be verified before
+/// password logins work. The response carries tokens with the new role;
+/// the guest's own still only get into guest routes until they expire,
+/// and its refresh tokens now refresh into the account.
+#[utoipa::path(
+    post,
+    path = "/guest/upgrade",
+    tag = "account",
+    request_body = GuestUpgradeRequest,
+    responses(
+        (status = 200, description = "The upgraded account, signed in", body = SignupResponse),
+        (status = 403, description = "Not a guest", body = ErrorResponse),
+        (status = 404, description = "Already upgraded or purged", body = ErrorResponse),
+        (status = 409, description = "Email or username already registered", body = ErrorResponse),
+        (status = 422, description = "Invalid email, password or username", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn upgrade_guest_handler(
+    claims: Claims,
+    mailer: Mailer,
+    users: UserRepo,
+    users_collection: Collection<User>,
+    context: AuthContext,
+    sessions_collection: Collection<Session>,
+    client: ClientInfo,
+    body: GuestUpgradeRequest,
+) -> WebResult<Response> {
+    if users.find_by_email(&body.email).await?.is_some() {
+        return Err(reject::custom(Error::UserAlreadyExistsError));
+    }
+    if let Some(username) = &body.username {
+        if users.find_by_username(username).await?.is_some() {
+            return Err(reject::custom(Error::UsernameTakenError));
+        }
+    }
+
+    let pw = password::hash(&body.pw).map_err(reject::custom)?;
+    let (verification_token, verification_token_hash) = verification::create_verification_token();
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    // Only while it is still a guest, so two upgrades can't both win. The
+    // unique indexes settle a race with a signup for the same address.
+    let upgraded = timed(users_collection.find_one_and_update(
+        doc! {"uid": &claims.sub, "role": Role::Guest.to_string()},
+        doc! {
+            "$set": {
+                "email": &body.email,
+                "email_lower": normalize_email(&body.email),
+                "username": &body.username,
+                "username_lower": body.username.as_deref().map(str::to_lowercase),
+                "pw": &pw,
+                "pw_history": [&pw],
+                "role": Role::User.to_string(),
+                "email_verified": false,
+                "verification_token_hash": verification_token_hash,
+                "updated_at": DateTime::now(),
+            },
+            "$inc": {"version": 1},
+        },
+        options,
+    ))
+    .await
+    .map_err(|e| {
+        reject::custom(match e {
+            Error::DuplicateKeyError => Error::UserAlreadyExistsError,
+            other => other,
+        })
+    })?;
+    let Some(user) = upgraded else {
+        return Err(reject::custom(Error::UserNotFoundError));
+    };
+    context.forget_user(&user.uid);
+
+    // Without the email the account could never be verified, so make it a
+    // guest again and let the client retry.
+    if verification::send_verification_email(mailer.as_ref(), &user.email, &verification_token)
+        .await
+        .is_err()
+    {
+        timed(users_collection.update_one(
+            doc! {"uid": &user.uid},
+            doc! {
+                "$set": {"role": Role::Guest.to_string(), "email_verified": true},
+                "$unset": {
+                    "email": "",
+                    "email_lower": "",
+                    "username": "",
+                    "username_lower": "",
+                    "pw": "",
+                    "verification_token_hash": "",
+                },
+            },
+            None,
+        ))
+        .await
+        .map_err(reject::custom)?;
+        return Err(reject::custom(Error::EmailDeliveryError));
+    }
+
+    metrics::record_signup();
+    audit::record(
+        AuditEvent::new(AuditAction::Signup, &client)
+            .actor(&user.uid)
+            .target(&user.uid)
+            .detail("upgraded from guest"),
+    );
+    events::publish(AdminEventKind::Signup, Some(&user.uid));
+    webhooks::dispatch(WebhookEvent::UserSignedUp, &user, None);
+    let session = start_session(&context, &sessions_collection, &user, &client, false).await?;
+    let token = session.token.clone();
+    let mut response = reply::json(&SignupResponse {
+        user: UserResponse::from(user),
+        session: Some(session),
+    })
+    .into_response();
+    set_session_cookies(&context, &mut response, &token, false);
+    Ok(response)
+}
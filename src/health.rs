@@ -0,0 +1,395 @@
+use crate::{circuit_breaker, maintenance::Maintenance};
+use mongodb::{
+    bson::doc,
+    event::cmap::{
+        CmapEventHandler, ConnectionCheckedInEvent, ConnectionCheckedOutEvent,
+        ConnectionClosedEvent, ConnectionCreatedEvent,
+    },
+    options::ClientOptions,
+    Client, Database,
+};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+use utoipa::ToSchema;
+use warp::{http::StatusCode, reply, Reply};
+
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+const MONGO_RETRY_INITIAL_DELAY: Duration = Duration::from_millis(500);
+const MONGO_RETRY_MAX_DELAY: Duration = Duration::from_secs(8);
+const MONGO_STARTUP_PING_TIMEOUT: Duration = Duration::from_secs(5);
+const READINESS_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Pings judged together for the degraded state, about half a minute's
+/// worth of readiness checks.
+const LATENCY_WINDOW: usize = 6;
+
+static OPEN_CONNECTIONS: AtomicU32 = AtomicU32::new(0);
+static CONNECTIONS_IN_USE: AtomicU32 = AtomicU32::new(0);
+
+/// Whether this instance should receive traffic, kept up to date by
+/// `spawn_readiness_check` so probes never wait on the database.
+#[derive(Clone, Default)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, ready: bool) {
+        self.0.store(ready, Ordering::Relaxed);
+    }
+}
+
+/// Round trips of the most recent successful pings, from both the
+/// readiness check and `/health`.
+#[derive(Clone, Default)]
+pub struct PingLatencies(Arc<Mutex<VecDeque<Duration>>>);
+
+impl PingLatencies {
+    fn record(&self, latency: Duration) {
+        let mut latencies = self.0.lock().expect("ping latency lock poisoned");
+        if latencies.len() == LATENCY_WINDOW {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency);
+    }
+
+    /// The median of the window, so a single slow ping does not flip the
+    /// status back and forth.
+    fn median(&self) -> Option<Duration> {
+        let mut latencies: Vec<Duration> = self
+            .0
+            .lock()
+            .expect("ping latency lock poisoned")
+            .iter()
+            .copied()
+            .collect();
+        latencies.sort();
+        latencies.get(latencies.len() / 2).copied()
+    }
+}
+
+/// When `/health` calls MongoDB slow: once the median recent ping takes
+/// longer than `threshold`, it reports `degraded` with `status`.
+#[derive(Clone, Copy)]
+pub struct DegradedPolicy {
+    pub threshold: Duration,
+    pub status: StatusCode,
+}
+
+/// Keeps the connection counts `/health` reports. The driver has no way to
+/// ask a pool how big it is, so `connect_to_mongo` installs this to count
+/// its events instead.
+pub struct PoolEvents;
+
+impl CmapEventHandler for PoolEvents {
+    fn handle_connection_created_event(&self, _event: ConnectionCreatedEvent) {
+        OPEN_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn handle_connection_closed_event(&self, _event: ConnectionClosedEvent) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn handle_connection_checked_out_event(&self, _event: ConnectionCheckedOutEvent) {
+        CONNECTIONS_IN_USE.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn handle_connection_checked_in_event(&self, _event: ConnectionCheckedInEvent) {
+        CONNECTIONS_IN_USE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Connections to MongoDB across every server's pool.
+#[derive(Serialize, ToSchema)]
+pub struct PoolStats {
+    open: u32,
+    in_use: u32,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct HealthResponse {
+    /// `ok`, `degraded` or `unavailable`.
+    #[schema(value_type = String)]
+    status: &'static str,
+    /// `up` or `down`.
+    #[schema(value_type = String)]
+    mongo: &'static str,
+    /// Round trip of this request's ping.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mongo_latency_ms: Option<u64>,
+    mongo_pool: PoolStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[schema(value_type = String)]
+    version: &'static str,
+    uptime_seconds: u64,
+}
+
+/// Pings MongoDB and reports 200 when it answers within `PING_TIMEOUT`,
+/// 503 with the reason otherwise. While recent pings have been slower than
+/// `HEALTH_DEGRADED_LATENCY_MS` it reports `degraded`, with 200 or, if so
+/// configured, 503. Meant for load balancer probes, so it needs no
+/// authentication and is not rate limited.
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "probes",
+    responses(
+        (status = 200, description = "MongoDB answered, perhaps slowly", body = HealthResponse),
+        (status = 503, description = "MongoDB did not answer, or is slow with `HEALTH_DEGRADED_STATUS=503`",
+            body = HealthResponse),
+    )
+)]
+pub async fn health_handler(
+    db: Database,
+    started: Instant,
+    latencies: PingLatencies,
+    degraded: DegradedPolicy,
+) -> Result<impl Reply, Infallible> {
+    let result = ping(&db, &latencies).await;
+    let slow = latencies
+        .median()
+        .is_some_and(|median| median > degraded.threshold);
+    let (status, body_status) = match &result {
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+        Ok(_) if slow => (degraded.status, "degraded"),
+        Ok(_) => (StatusCode::OK, "ok"),
+    };
+    let body = HealthResponse {
+        status: body_status,
+        mongo: if result.is_ok() { "up" } else { "down" },
+        mongo_latency_ms: result
+            .as_ref()
+            .ok()
+            .map(|latency| latency.as_millis() as u64),
+        mongo_pool: PoolStats {
+            open: OPEN_CONNECTIONS.load(Ordering::Relaxed),
+            in_use: CONNECTIONS_IN_USE.load(Ordering::Relaxed),
+        },
+        reason: result.err(),
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_seconds: started.elapsed().as_secs(),
+    };
+    Ok(reply::with_status(reply::json(&body), status))
+}
+
+/// Always 200: answering at all shows the process and its event loop are
+/// alive.
+#[utoipa::path(
+    get,
+    path = "/livez",
+    tag = "probes",
+    responses((status = 200, description = "Alive", body = Object, example = json!({"status": "ok"})))
+)]
+pub async fn livez_handler() -> Result<impl Reply, Infallible> {
+    Ok(reply::json(&serde_json::json!({"status": "ok"})))
+}
+
+/// 200 while `readiness` is set, the MongoDB circuit breaker is closed and
+/// maintenance mode is off, 503 otherwise, so an orchestrator stops routing
+/// traffic here during a database outage, maintenance or a shutdown drain
+/// without restarting the process.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "probes",
+    responses(
+        (status = 200, description = "Ready for traffic", body = Object, example = json!({"status": "ok"})),
+        (status = 503, description = "Database unreachable, in maintenance or shutting down", body = Object,
+            example = json!({"status": "unavailable"})),
+    )
+)]
+pub async fn readyz_handler(
+    readiness: Readiness,
+    maintenance: Maintenance,
+) -> Result<impl Reply, Infallible> {
+    let ready =
+        readiness.is_ready() && !circuit_breaker::global().is_open() && !maintenance.is_enabled();
+    let (status, body) = if ready {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+    Ok(reply::with_status(
+        reply::json(&serde_json::json!({ "status": body })),
+        status,
+    ))
+}
+
+/// Pings MongoDB every `READINESS_CHECK_INTERVAL`, updating `readiness`
+/// and `latencies` and logging when the database goes away or comes back.
+pub fn spawn_readiness_check(
+    db: Database,
+    readiness: Readiness,
+    latencies: PingLatencies,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(READINESS_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let result = ping(&db, &latencies).await;
+            match (&result, readiness.is_ready()) {
+                (Err(e), true) => tracing::warn!("MongoDB unreachable, not ready: {}", e),
+                (Ok(_), false) => tracing::info!("MongoDB reachable again, ready"),
+                _ => {}
+            }
+            readiness.set(result.is_ok());
+        }
+    })
+}
+
+/// The round trip of a successful ping, which is also added to
+/// `latencies`.
+async fn ping(db: &Database, latencies: &PingLatencies) -> Result<Duration, String> {
+    let sent = Instant::now();
+    match tokio::time::timeout(PING_TIMEOUT, db.run_command(doc! {"ping": 1}, None)).await {
+        Ok(Ok(_)) => {
+            let latency = sent.elapsed();
+            latencies.record(latency);
+            Ok(latency)
+        }
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("ping timed out after {}s", PING_TIMEOUT.as_secs())),
+    }
+}
+
+/// A client for `uri` that gives up finding a server after `op_timeout`,
+/// and connecting to one after as long, unless the URI sets
+/// `serverSelectionTimeoutMS` or `connectTimeoutMS` itself. The driver's
+/// defaults of 30 and 10 seconds would leave operations that are not
+/// wrapped in [`timed`](crate::repository::timed) waiting that long on a
+/// database that is down.
+#[tracing::instrument(skip_all)]
+pub async fn connect_to_mongo(uri: &str, op_timeout: Duration) -> mongodb::error::Result<Client> {
+    let mut options = ClientOptions::parse(uri).await?;
+    options.app_name = Some("MyApp".to_string());
+    options.cmap_event_handler = Some(Arc::new(PoolEvents));
+    options.server_selection_timeout.get_or_insert(op_timeout);
+    options.connect_timeout.get_or_insert(op_timeout);
+    Client::with_options(options)
+}
+
+/// Pings MongoDB until it answers, backing off exponentially between
+/// attempts, so the server can start before the database does (as it often
+/// will under docker-compose). Gives up once `timeout` has passed.
+#[tracing::instrument(skip(client))]
+pub async fn wait_for_mongo(
+    client: &Client,
+    db_name: &str,
+    timeout: Duration,
+) -> Result<(), String> {
+    let deadline = Instant::now() + timeout;
+    let db = client.database(db_name);
+    let mut delay = MONGO_RETRY_INITIAL_DELAY;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let ping = db.run_command(doc! {"ping": 1}, None);
+        let error =
+            match tokio::time::timeout(remaining.min(MONGO_STARTUP_PING_TIMEOUT), ping).await {
+                Ok(Ok(_)) => return Ok(()),
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => "ping timed out".to_string(),
+            };
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(format!(
+                "MongoDB was not reachable within {}s ({} attempts), last error: {}",
+                timeout.as_secs(),
+                attempt,
+                error
+            ));
+        }
+        let wait = delay.min(remaining);
+        tracing::warn!(
+            "MongoDB not reachable (attempt {}): {}; retrying in {}ms",
+            attempt,
+            error,
+            wait.as_millis()
+        );
+        tokio::time::sleep(wait).await;
+        delay = (delay * 2).min(MONGO_RETRY_MAX_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config, error, repository::timed};
+    use mongodb::bson::Document;
+
+    #[tokio::test]
+    async fn wait_for_mongo_gives_up_at_the_deadline() {
+        // Nothing listens on port 1, so every ping fails fast.
+        let mut options = ClientOptions::parse("mongodb://127.0.0.1:1/?directConnection=true")
+            .await
+            .unwrap();
+        options.server_selection_timeout = Some(Duration::from_millis(100));
+        let client = Client::with_options(options).unwrap();
+
+        let started = Instant::now();
+        let error = wait_for_mongo(&client, "test", Duration::from_millis(700))
+            .await
+            .unwrap_err();
+
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert!(
+            error.starts_with("MongoDB was not reachable within 0s"),
+            "{}",
+            error
+        );
+        assert!(!error.contains("(1 attempts)"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_database_answers_503_within_the_timeout() {
+        // Nothing answers at 10.255.255.1; packets to it are dropped or
+        // unroutable, like those to a database host that went away.
+        let client = connect_to_mongo(
+            "mongodb://10.255.255.1:27017/?directConnection=true",
+            Duration::from_millis(200),
+        )
+        .await
+        .unwrap();
+        let users = client.database("test").collection::<Document>("users");
+
+        let started = Instant::now();
+        let error = timed(users.find_one(doc! {}, None)).await.unwrap_err();
+
+        assert!(started.elapsed() < config::db_op_timeout());
+        let response = error::handle_rejection(warp::reject::custom(error))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn a_reachable_database_is_healthy() {
+        let app = crate::test_support::app().await;
+        let response = warp::test::request()
+            .path("/health")
+            .reply(&crate::routes(app))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["mongo"], "up");
+        assert!(body["mongo_latency_ms"].is_u64());
+        assert!(body.get("reason").is_none());
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    }
+}
@@ -0,0 +1,466 @@
+use std::future::Future;
+use warp::http::{header::ACCEPT_LANGUAGE, HeaderMap};
+
+/// A language error messages are available in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Language {
+    #[default]
+    English,
+    German,
+    French,
+}
+
+impl Language {
+    /// The tag sent in `Content-Language`.
+    pub fn tag(self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::German => "de",
+            Language::French => "fr",
+        }
+    }
+
+    /// Matches on the primary subtag only, so `de-CH` gets German.
+    fn from_tag(tag: &str) -> Option<Language> {
+        let primary = tag.split('-').next().unwrap_or_default();
+        match primary.to_ascii_lowercase().as_str() {
+            "en" | "*" => Some(Language::English),
+            "de" => Some(Language::German),
+            "fr" => Some(Language::French),
+            _ => None,
+        }
+    }
+}
+
+tokio::task_local! {
+    static LANGUAGE: Language;
+}
+
+/// The language a request's `Accept-Language` prefers most among those
+/// there are messages in, English when it names none of them. Of equally
+/// weighted languages the first listed wins.
+pub fn negotiate(headers: &HeaderMap) -> Language {
+    let mut best = (Language::English, 0.0);
+    for value in headers.get_all(ACCEPT_LANGUAGE) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for entry in value.split(',') {
+            let mut parts = entry.split(';');
+            let tag = parts.next().unwrap_or_default().trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+                .unwrap_or(0.0);
+            if let Some(language) = Language::from_tag(tag) {
+                if quality > best.1 {
+                    best = (language, quality);
+                }
+            }
+        }
+    }
+    best.0
+}
+
+/// Runs `future`, the handling of one request, with `language` as the
+/// one its error messages are given in.
+pub async fn scope<F: Future>(language: Language, future: F) -> F::Output {
+    LANGUAGE.scope(language, future).await
+}
+
+/// The current request's language; English outside a request.
+pub fn current() -> Language {
+    LANGUAGE.try_with(|language| *language).unwrap_or_default()
+}
+
+/// The message for the error `code` in `language`, or `None` when it has
+/// none there and the English message should be kept. `{}` in a message
+/// stands for `detail`, such as the seconds to wait on `TOO_MANY_REQUESTS`;
+/// a message that needs one is only used when it is given.
+pub fn message(language: Language, code: &str, detail: Option<&str>) -> Option<String> {
+    let template = match language {
+        Language::English => return None,
+        Language::German => german(code)?,
+        Language::French => french(code)?,
+    };
+    match (template.contains("{}"), detail) {
+        (false, _) => Some(template.to_owned()),
+        (true, Some(detail)) => Some(template.replace("{}", detail)),
+        (true, None) => None,
+    }
+}
+
+/// Messages that quote the request, such as `INVALID_BODY`'s parser
+/// output, have no translation. Every 500 is sent as `INTERNAL_ERROR`'s.
+fn german(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "WRONG_CREDENTIALS" => "Falsche Anmeldedaten",
+        "INVALID_TOKEN" => "Das Token ist ungültig",
+        "TOKEN_EXPIRED" => "Das Token ist abgelaufen",
+        "INVALID_REFRESH_TOKEN" => "Das Refresh-Token ist ungültig",
+        "SESSION_NOT_FOUND" => "Sitzung nicht gefunden",
+        "REFRESH_TOKEN_REUSE" => {
+            "Ein Refresh-Token wurde erneut verwendet, alle zugehörigen Sitzungen wurden beendet; bitte erneut anmelden"
+        }
+        "INVALID_RESET_TOKEN" => {
+            "Der Link zum Zurücksetzen des Passworts ist ungültig, abgelaufen oder wurde bereits verwendet"
+        }
+        "EMAIL_NOT_VERIFIED" => {
+            "Die E-Mail-Adresse wurde noch nicht bestätigt, bitte den Bestätigungslink im Posteingang öffnen"
+        }
+        "INVALID_MAGIC_LINK" => {
+            "Der Anmeldelink ist ungültig, abgelaufen oder wurde bereits verwendet"
+        }
+        "INVALID_VERIFICATION_TOKEN" => {
+            "Der Bestätigungslink ist ungültig oder wurde bereits verwendet"
+        }
+        "INVALID_EMAIL_CHANGE_TOKEN" => {
+            "Der Link zur Änderung der E-Mail-Adresse ist ungültig, abgelaufen oder wurde bereits verwendet"
+        }
+        "EMAIL_DELIVERY_FAILED" => {
+            "Die E-Mail konnte nicht gesendet werden, bitte später erneut versuchen"
+        }
+        "ACCOUNT_DISABLED" => "Dieses Konto wurde deaktiviert",
+        "ACCOUNT_BANNED" => "Dieses Konto ist gesperrt",
+        "PASSWORD_CHANGE_REQUIRED" => "Das Passwort muss vor der Anmeldung geändert werden",
+        "CANNOT_BAN_SELF" => "Administratoren können ihr eigenes Konto nicht sperren",
+        "INVALID_BAN_REQUEST" => "expires_in_days muss zwischen 1 und 3650 liegen",
+        "CANNOT_DEACTIVATE_SELF" => {
+            "Administratoren können ihr eigenes Konto nicht deaktivieren"
+        }
+        "ACCOUNT_LOCKED" => {
+            "Zu viele fehlgeschlagene Anmeldeversuche, bitte später erneut versuchen"
+        }
+        "TOO_MANY_REQUESTS" => "Zu viele Anfragen, bitte in {} Sekunden erneut versuchen",
+        "RATE_LIMIT_EXCEEDED" => {
+            "Anfragelimit überschritten, bitte in {} Sekunden erneut versuchen"
+        }
+        "OAUTH_CALLBACK_INVALID" => {
+            "Dem OAuth-Callback fehlt der Code oder der State stimmt nicht überein"
+        }
+        "OAUTH_PROVIDER_FAILED" => "Die Anfrage an den Identitätsanbieter ist fehlgeschlagen",
+        "INVALID_ID_TOKEN" => "Das Identitätstoken ist ungültig",
+        "OAUTH_EMAIL_UNVERIFIED" => {
+            "Das Konto beim Identitätsanbieter hat keine bestätigte E-Mail-Adresse"
+        }
+        "IDENTITY_ALREADY_LINKED" => {
+            "Dieses externe Konto ist bereits mit einem anderen Benutzer verknüpft"
+        }
+        "INVALID_TOTP_CODE" => "Der Bestätigungscode ist ungültig",
+        "INVALID_PENDING_TOKEN" => {
+            "Die Zwei-Faktor-Anmeldung ist ungültig oder abgelaufen, bitte erneut anmelden"
+        }
+        "TWO_FACTOR_NOT_ENROLLED" => {
+            "Die Zwei-Faktor-Authentifizierung ist für dieses Konto nicht eingerichtet"
+        }
+        "TWO_FACTOR_ALREADY_ENABLED" => "Die Zwei-Faktor-Authentifizierung ist bereits aktiv",
+        "TWO_FACTOR_UNAVAILABLE" => {
+            "Die Zwei-Faktor-Authentifizierung ist auf diesem Server nicht verfügbar"
+        }
+        "TOKEN_REVOKED" => "Das Token wurde widerrufen",
+        "NO_AUTH_HEADER" => "Der Authorization-Header fehlt",
+        "INVALID_AUTH_HEADER" => "Der Authorization-Header ist ungültig",
+        "NO_PERMISSION" => "Keine Berechtigung",
+        "CSRF_FAILED" => "Das CSRF-Token fehlt oder stimmt nicht überein",
+        "INVALID_API_KEY" => "Der API-Schlüssel ist ungültig",
+        "API_KEY_NOT_FOUND" => "API-Schlüssel nicht gefunden",
+        "DUPLICATE_KEY" => "Die Ressource existiert bereits",
+        "DATABASE_TIMEOUT" => {
+            "Die Datenbank hat nicht rechtzeitig geantwortet, bitte später erneut versuchen"
+        }
+        "DATABASE_UNAVAILABLE" => {
+            "Die Datenbank ist nicht erreichbar, bitte später erneut versuchen"
+        }
+        "MAINTENANCE" => "Der Server wird gerade gewartet, bitte später erneut versuchen",
+        "REQUEST_TIMEOUT" => "Die Anfrage hat zu lange gedauert, bitte später erneut versuchen",
+        "USER_ALREADY_EXISTS" => "Der Benutzer existiert bereits",
+        "USER_NOT_FOUND" => "Benutzer nicht gefunden",
+        "PRECONDITION_FAILED" => {
+            "Der Benutzer wurde seit dem Abruf geändert; bitte erneut abrufen"
+        }
+        "PRECONDITION_REQUIRED" => "If-Match mit dem ETag des Benutzers ist erforderlich",
+        "EMAIL_ALREADY_IN_USE" => "Die E-Mail-Adresse wird bereits verwendet",
+        "USERNAME_TAKEN" => "Der Benutzername ist bereits vergeben",
+        "INVALID_USER_ID" => "Die Benutzer-ID muss eine UUID sein",
+        "INVALID_ROLE" => "Ungültige Rolle",
+        "ROLE_ALREADY_EXISTS" => "Die Rolle existiert bereits",
+        "ROLE_IN_USE" => "Die Rolle ist noch Konten oder API-Schlüsseln zugewiesen",
+        "ROLE_NOT_FOUND" => "Rolle nicht gefunden",
+        "VALIDATION_FAILED" => "Die Anfrage ist ungültig",
+        "PAYLOAD_TOO_LARGE" => "Der Anfragetext ist zu groß",
+        "UNSUPPORTED_MEDIA_TYPE" => "Der Anfragetext muss {} sein",
+        "LENGTH_REQUIRED" => "Der Content-Length-Header ist erforderlich",
+        "INVALID_HEADER" => "Ungültige Anfrage-Header",
+        "INVALID_QUERY" => "Ungültige Abfrageparameter",
+        "CORS_FORBIDDEN" => "Origin, Methode oder Header sind laut CORS-Richtlinie nicht erlaubt",
+        "INVALID_IDEMPOTENCY_KEY" => {
+            "Idempotency-Key muss aus 1 bis 255 druckbaren ASCII-Zeichen bestehen"
+        }
+        "IDEMPOTENCY_KEY_REUSED" => {
+            "Idempotency-Key wurde bereits für eine andere Anfrage verwendet"
+        }
+        "PASSWORD_RECENTLY_USED" => {
+            "Das neue Passwort muss sich von Ihren letzten Passwörtern unterscheiden"
+        }
+        "IDEMPOTENCY_KEY_IN_USE" => {
+            "Eine Anfrage mit diesem Idempotency-Key wird noch bearbeitet"
+        }
+        "IDEMPOTENCY_KEY_NOT_ALLOWED" => {
+            "Idempotency-Key wird bei Anfragen, die anmelden, nicht angenommen"
+        }
+        "INVALID_AVATAR_UPLOAD" => "Erwartet wird ein Multipart-Formular mit einem Feld avatar",
+        "AVATAR_TOO_LARGE" => "Das Profilbild darf höchstens 2 MB groß sein",
+        "UNSUPPORTED_AVATAR_TYPE" => "Das Profilbild muss ein JPEG- oder PNG-Bild sein",
+        "AVATAR_NOT_FOUND" => "Profilbild nicht gefunden",
+        "INVALID_IMPORT" => {
+            "Der Import muss ein JSON-Array oder zeilenweise getrenntes JSON sein"
+        }
+        "IMPORT_TOO_LARGE" => "Ein Import darf höchstens 10000 Datensätze enthalten",
+        "ROLE_BATCH_TOO_LARGE" => "Ein Stapel darf höchstens 1000 Rollenänderungen enthalten",
+        "INVALID_SEARCH_QUERY" => "Die Suchanfrage muss mindestens 2 Zeichen lang sein",
+        "INVALID_PAGINATION" => {
+            "page muss mindestens 1 sein, limit zwischen 1 und 200 liegen, und page und cursor dürfen nicht beide angegeben werden"
+        }
+        "INVALID_CURSOR" => "Ungültiger Seiten-Cursor",
+        "INVALID_TIMESTAMP" => "from, to, since und older_than müssen RFC-3339-Zeitstempel sein",
+        "INVALID_SESSION_FILTER" => {
+            "Mindestens eines von uids, ip und older_than ist nötig, und höchstens 1000 uids"
+        }
+        "TOO_MANY_SOCKETS" => "Zu viele offene Verbindungen für dieses Konto",
+        "CANNOT_IMPERSONATE_ADMIN" => "Administratoren und Rollen mit privilegierten Berechtigungen können nicht imitiert werden",
+        "IMPERSONATION_FORBIDDEN" => {
+            "Nicht erlaubt, während ein anderer Benutzer imitiert wird"
+        }
+        "FRESH_LOGIN_REQUIRED" => {
+            "Melden Sie sich dafür erneut ohne „Angemeldet bleiben“ an"
+        }
+        "TOKEN_EXCHANGE_REFUSED" => "Dieser Token-Austausch ist nicht erlaubt",
+        "INVITE_REQUIRED" => "Für die Registrierung ist ein Einladungscode erforderlich",
+        "INVALID_INVITE" => "Der Einladungscode ist ungültig",
+        "INVITE_EXPIRED" => "Der Einladungscode ist abgelaufen",
+        "INVITE_EXHAUSTED" => "Der Einladungscode wurde bereits aufgebraucht",
+        "INVALID_INVITE_REQUEST" => {
+            "max_uses muss zwischen 1 und 10000 und expires_in_days zwischen 1 und 365 liegen"
+        }
+        "CAPTCHA_FAILED" => "Die CAPTCHA-Prüfung ist fehlgeschlagen",
+        "CAPTCHA_UNAVAILABLE" => {
+            "Das CAPTCHA konnte nicht geprüft werden, bitte später erneut versuchen"
+        }
+        "INSUFFICIENT_SCOPE" => "Dem Token fehlt der Scope `{}`",
+        "ORG_NOT_FOUND" => "Organisation nicht gefunden",
+        "ALREADY_MEMBER" => "Der Benutzer ist bereits Mitglied dieser Organisation",
+        "LAST_ADMIN" => "Der letzte verbleibende Administrator kann nicht entfernt werden",
+        "CANNOT_DELETE_SELF" => "Administratoren können ihr eigenes Konto nicht löschen",
+        "IP_NOT_ALLOWED" => "Admin-Routen sind von dieser Adresse aus nicht erreichbar",
+        "FEATURE_DISABLED" => "Diese Funktion ist auf diesem Server deaktiviert",
+        "NOT_FOUND" => "Nicht gefunden",
+        "METHOD_NOT_ALLOWED" => "Methode nicht erlaubt",
+        "INTERNAL_ERROR" => "Interner Serverfehler",
+        _ => return None,
+    })
+}
+
+fn french(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "WRONG_CREDENTIALS" => "Identifiants incorrects",
+        "INVALID_TOKEN" => "Le jeton n'est pas valide",
+        "TOKEN_EXPIRED" => "Le jeton a expiré",
+        "INVALID_REFRESH_TOKEN" => "Le jeton de rafraîchissement n'est pas valide",
+        "SESSION_NOT_FOUND" => "Session introuvable",
+        "REFRESH_TOKEN_REUSE" => {
+            "Réutilisation d'un jeton de rafraîchissement détectée, toutes les sessions liées ont été révoquées ; reconnectez-vous"
+        }
+        "INVALID_RESET_TOKEN" => {
+            "Le lien de réinitialisation du mot de passe est invalide, expiré ou déjà utilisé"
+        }
+        "EMAIL_NOT_VERIFIED" => {
+            "L'adresse e-mail n'a pas été vérifiée, consultez votre boîte de réception pour le lien de vérification"
+        }
+        "INVALID_MAGIC_LINK" => "Le lien de connexion est invalide, expiré ou déjà utilisé",
+        "INVALID_VERIFICATION_TOKEN" => "Le lien de vérification est invalide ou déjà utilisé",
+        "INVALID_EMAIL_CHANGE_TOKEN" => {
+            "Le lien de changement d'adresse e-mail est invalide, expiré ou déjà utilisé"
+        }
+        "EMAIL_DELIVERY_FAILED" => "L'e-mail n'a pas pu être envoyé, veuillez réessayer plus tard",
+        "ACCOUNT_DISABLED" => "Ce compte a été désactivé",
+        "ACCOUNT_BANNED" => "Ce compte est banni",
+        "PASSWORD_CHANGE_REQUIRED" => "Le mot de passe doit être changé avant de se connecter",
+        "CANNOT_BAN_SELF" => "Les administrateurs ne peuvent pas bannir leur propre compte",
+        "INVALID_BAN_REQUEST" => "expires_in_days doit être compris entre 1 et 3650",
+        "CANNOT_DEACTIVATE_SELF" => {
+            "Les administrateurs ne peuvent pas désactiver leur propre compte"
+        }
+        "ACCOUNT_LOCKED" => {
+            "Trop de tentatives de connexion échouées, veuillez réessayer plus tard"
+        }
+        "TOO_MANY_REQUESTS" => "Trop de requêtes, veuillez réessayer dans {} secondes",
+        "RATE_LIMIT_EXCEEDED" => "Limite de requêtes dépassée, veuillez réessayer dans {} secondes",
+        "OAUTH_CALLBACK_INVALID" => {
+            "Le code manque au retour OAuth ou le paramètre state ne correspond pas"
+        }
+        "OAUTH_PROVIDER_FAILED" => "La requête au fournisseur d'identité a échoué",
+        "INVALID_ID_TOKEN" => "Le jeton d'identité n'est pas valide",
+        "OAUTH_EMAIL_UNVERIFIED" => {
+            "Le compte du fournisseur d'identité n'a pas d'adresse e-mail vérifiée"
+        }
+        "IDENTITY_ALREADY_LINKED" => "Ce compte externe est déjà lié à un autre utilisateur",
+        "INVALID_TOTP_CODE" => "Le code de vérification n'est pas valide",
+        "INVALID_PENDING_TOKEN" => {
+            "La connexion à deux facteurs est invalide ou a expiré, reconnectez-vous"
+        }
+        "TWO_FACTOR_NOT_ENROLLED" => {
+            "L'authentification à deux facteurs n'est pas configurée pour ce compte"
+        }
+        "TWO_FACTOR_ALREADY_ENABLED" => "L'authentification à deux facteurs est déjà activée",
+        "TWO_FACTOR_UNAVAILABLE" => {
+            "L'authentification à deux facteurs n'est pas disponible sur ce serveur"
+        }
+        "TOKEN_REVOKED" => "Le jeton a été révoqué",
+        "NO_AUTH_HEADER" => "L'en-tête Authorization est absent",
+        "INVALID_AUTH_HEADER" => "L'en-tête Authorization n'est pas valide",
+        "NO_PERMISSION" => "Permission refusée",
+        "CSRF_FAILED" => "Le jeton CSRF est absent ou ne correspond pas",
+        "INVALID_API_KEY" => "La clé d'API n'est pas valide",
+        "API_KEY_NOT_FOUND" => "Clé d'API introuvable",
+        "DUPLICATE_KEY" => "La ressource existe déjà",
+        "DATABASE_TIMEOUT" => {
+            "La base de données n'a pas répondu à temps, veuillez réessayer plus tard"
+        }
+        "DATABASE_UNAVAILABLE" => {
+            "La base de données est indisponible, veuillez réessayer plus tard"
+        }
+        "MAINTENANCE" => "Le serveur est en maintenance, veuillez réessayer plus tard",
+        "REQUEST_TIMEOUT" => "La requête a pris trop de temps, veuillez réessayer plus tard",
+        "USER_ALREADY_EXISTS" => "L'utilisateur existe déjà",
+        "USER_NOT_FOUND" => "Utilisateur introuvable",
+        "PRECONDITION_FAILED" => {
+            "L'utilisateur a été modifié depuis sa lecture ; récupérez-le à nouveau"
+        }
+        "PRECONDITION_REQUIRED" => "If-Match avec l'ETag de l'utilisateur est requis",
+        "EMAIL_ALREADY_IN_USE" => "L'adresse e-mail est déjà utilisée",
+        "USERNAME_TAKEN" => "Le nom d'utilisateur est déjà pris",
+        "INVALID_USER_ID" => "L'identifiant utilisateur doit être un UUID",
+        "INVALID_ROLE" => "Rôle invalide",
+        "ROLE_ALREADY_EXISTS" => "Le rôle existe déjà",
+        "ROLE_IN_USE" => "Le rôle est encore attribué à des comptes ou des clés d'API",
+        "ROLE_NOT_FOUND" => "Rôle introuvable",
+        "VALIDATION_FAILED" => "La requête n'est pas valide",
+        "PAYLOAD_TOO_LARGE" => "Le corps de la requête est trop volumineux",
+        "UNSUPPORTED_MEDIA_TYPE" => "Le corps de la requête doit être {}",
+        "LENGTH_REQUIRED" => "L'en-tête Content-Length est requis",
+        "INVALID_HEADER" => "En-têtes de requête invalides",
+        "INVALID_QUERY" => "Paramètres de requête invalides",
+        "CORS_FORBIDDEN" => {
+            "Origine, méthode ou en-tête non autorisé par la politique CORS"
+        }
+        "INVALID_IDEMPOTENCY_KEY" => {
+            "Idempotency-Key doit comporter de 1 à 255 caractères ASCII imprimables"
+        }
+        "IDEMPOTENCY_KEY_REUSED" => {
+            "Idempotency-Key a déjà été utilisée pour une autre requête"
+        }
+        "PASSWORD_RECENTLY_USED" => {
+            "Le nouveau mot de passe doit être différent de vos mots de passe récents"
+        }
+        "IDEMPOTENCY_KEY_IN_USE" => {
+            "Une requête avec cette Idempotency-Key est encore en cours"
+        }
+        "IDEMPOTENCY_KEY_NOT_ALLOWED" => {
+            "Idempotency-Key n'est pas acceptée sur les requêtes qui ouvrent une session"
+        }
+        "INVALID_AVATAR_UPLOAD" => {
+            "Un formulaire multipart avec un champ avatar est attendu"
+        }
+        "AVATAR_TOO_LARGE" => "L'avatar ne doit pas dépasser 2 Mo",
+        "UNSUPPORTED_AVATAR_TYPE" => "L'avatar doit être une image JPEG ou PNG",
+        "AVATAR_NOT_FOUND" => "Avatar introuvable",
+        "INVALID_IMPORT" => {
+            "L'import doit être un tableau JSON ou du JSON délimité par des sauts de ligne"
+        }
+        "IMPORT_TOO_LARGE" => "Un import peut contenir au plus 10000 enregistrements",
+        "ROLE_BATCH_TOO_LARGE" => "Un lot peut contenir au plus 1000 changements de rôle",
+        "INVALID_SEARCH_QUERY" => "La recherche doit comporter au moins 2 caractères",
+        "INVALID_PAGINATION" => {
+            "page doit valoir au moins 1, limit être compris entre 1 et 200, et page et cursor ne peuvent pas être donnés ensemble"
+        }
+        "INVALID_CURSOR" => "Curseur de pagination invalide",
+        "INVALID_TIMESTAMP" => {
+            "from, to, since et older_than doivent être des horodatages RFC 3339"
+        }
+        "INVALID_SESSION_FILTER" => {
+            "Donnez au moins un de uids, ip et older_than, et au plus 1000 uids"
+        }
+        "TOO_MANY_SOCKETS" => "Trop de connexions ouvertes pour ce compte",
+        "CANNOT_IMPERSONATE_ADMIN" => "Les administrateurs et les rôles aux droits privilégiés ne peuvent pas être usurpés",
+        "IMPERSONATION_FORBIDDEN" => {
+            "Action interdite pendant l'usurpation d'un utilisateur"
+        }
+        "FRESH_LOGIN_REQUIRED" => {
+            "Reconnectez-vous sans « Rester connecté » pour effectuer cette action"
+        }
+        "TOKEN_EXCHANGE_REFUSED" => "Cet échange de jeton n'est pas autorisé",
+        "INVITE_REQUIRED" => "Un code d'invitation est requis pour s'inscrire",
+        "INVALID_INVITE" => "Le code d'invitation n'est pas valide",
+        "INVITE_EXPIRED" => "Le code d'invitation a expiré",
+        "INVITE_EXHAUSTED" => "Le code d'invitation a déjà été entièrement utilisé",
+        "INVALID_INVITE_REQUEST" => {
+            "max_uses doit être compris entre 1 et 10000 et expires_in_days entre 1 et 365"
+        }
+        "CAPTCHA_FAILED" => "La vérification CAPTCHA a échoué",
+        "CAPTCHA_UNAVAILABLE" => {
+            "Le CAPTCHA n'a pas pu être vérifié, veuillez réessayer plus tard"
+        }
+        "INSUFFICIENT_SCOPE" => "Il manque au jeton la portée `{}`",
+        "ORG_NOT_FOUND" => "Organisation introuvable",
+        "ALREADY_MEMBER" => "L'utilisateur est déjà membre de cette organisation",
+        "LAST_ADMIN" => "Impossible de retirer le dernier administrateur",
+        "CANNOT_DELETE_SELF" => {
+            "Les administrateurs ne peuvent pas supprimer leur propre compte"
+        }
+        "IP_NOT_ALLOWED" => {
+            "Les routes d'administration ne sont pas accessibles depuis cette adresse"
+        }
+        "FEATURE_DISABLED" => "Cette fonctionnalité est désactivée sur ce serveur",
+        "NOT_FOUND" => "Introuvable",
+        "METHOD_NOT_ALLOWED" => "Méthode non autorisée",
+        "INTERNAL_ERROR" => "Erreur interne du serveur",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::HeaderValue;
+
+    fn negotiated(accept_language: &str) -> Language {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT_LANGUAGE,
+            HeaderValue::from_str(accept_language).unwrap(),
+        );
+        negotiate(&headers)
+    }
+
+    #[test]
+    fn the_highest_q_value_wins() {
+        assert_eq!(negotiated("en;q=0.5, fr;q=0.9, de;q=0.7"), Language::French);
+        assert_eq!(negotiated("fr;q=0.3, de"), Language::German);
+        assert_eq!(negotiated("de, fr"), Language::German);
+        assert_eq!(negotiated("fr;q=0, de;q=0.1"), Language::German);
+    }
+
+    #[test]
+    fn regional_tags_get_their_language() {
+        assert_eq!(negotiated("de-AT"), Language::German);
+        assert_eq!(negotiated("fr-CA;q=0.8, en-US;q=0.5"), Language::French);
+    }
+
+    #[test]
+    fn english_is_the_fallback() {
+        assert_eq!(negotiate(&HeaderMap::new()), Language::English);
+        assert_eq!(negotiated("ja, zh-CN;q=0.9"), Language::English);
+        assert_eq!(negotiated("de;q=0"), Language::English);
+        assert_eq!(negotiated("de;q=lots"), Language::English);
+    }
+}
@@ -0,0 +1,275 @@
+use crate::{
+    body,
+    error::Error,
+    repository::timed,
+    validation::{self, Validate},
+    Result, WebResult,
+};
+use bytes::Bytes;
+use mongodb::{
+    bson::{doc, spec::BinarySubtype, Binary, DateTime},
+    options::IndexOptions,
+    Collection, IndexModel,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{future::Future, time::Duration};
+use warp::{
+    http::{HeaderName, HeaderValue, Method, StatusCode},
+    hyper::{self, Body},
+    path::FullPath,
+    reject,
+    reply::Response,
+    Filter, Rejection,
+};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Set on responses replayed from an earlier request with the same key.
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+const MAX_KEY_LENGTH: usize = 255;
+/// How long a key and its response are kept. A TTL index rather than a
+/// capped collection bounds the collection, since MongoDB allows neither
+/// TTL indexes nor deletes on capped collections.
+const RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A request made with an `Idempotency-Key`, and once it has completed the
+/// response it got. The response is kept as sent, so routes whose responses
+/// carry session tokens refuse keys with [`Idempotency::refuse`] instead.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    /// Method and path, such as `POST /api/v1/signup`; keys are only
+    /// unique per route.
+    pub route: String,
+    pub key: String,
+    /// SHA-256 of the request body.
+    pub request_hash: String,
+    /// `None` while the first request with the key is still running.
+    pub response: Option<StoredResponse>,
+    pub created_at: DateTime,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Binary,
+}
+
+pub async fn create_indexes(
+    collection: &Collection<IdempotencyRecord>,
+) -> mongodb::error::Result<()> {
+    let key_index = IndexModel::builder()
+        .keys(doc! {"route": 1, "key": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    let ttl_index = IndexModel::builder()
+        .keys(doc! {"created_at": 1})
+        .options(IndexOptions::builder().expire_after(RETENTION).build())
+        .build();
+    collection
+        .create_indexes(vec![key_index, ttl_index], None)
+        .await?;
+    Ok(())
+}
+
+/// The `Idempotency-Key` a request came with, if any; see [`Idempotency::run`].
+pub struct Idempotency {
+    records: Collection<IdempotencyRecord>,
+    claim: Option<Claim>,
+}
+
+struct Claim {
+    route: String,
+    key: String,
+    request_hash: String,
+}
+
+/// How a POST route opts in to `Idempotency-Key`: in place of
+/// `validated_json()`, this takes the validated body together with the
+/// request's [`Idempotency`], and the handler runs its work through
+/// [`Idempotency::run`]. A body that fails to parse or validate is
+/// rejected before the key is looked at, so it never uses one up.
+pub fn validated_json<T>(
+    records: Collection<IdempotencyRecord>,
+) -> impl Filter<Extract = (Idempotency, T), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Validate + Send,
+{
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER))
+        .and(body::json_bytes())
+        .and_then(
+            move |method: Method, path: FullPath, key: Option<String>, bytes: Bytes| {
+                let records = records.clone();
+                async move {
+                    let body: T = body::parse(&bytes).map_err(reject::custom)?;
+                    validation::validate(&body).map_err(reject::custom)?;
+                    let claim = match key {
+                        None => None,
+                        Some(key) if !is_valid_key(&key) => {
+                            return Err(reject::custom(Error::InvalidIdempotencyKeyError))
+                        }
+                        Some(key) => Some(Claim {
+                            route: format!("{} {}", method, path.as_str()),
+                            key,
+                            request_hash: format!("{:x}", Sha256::digest(&bytes)),
+                        }),
+                    };
+                    Ok::<_, Rejection>((Idempotency { records, claim }, body))
+                }
+            },
+        )
+        .untuple_one()
+}
+
+impl Idempotency {
+    /// Refuses a request that came with a key, for routes whose response
+    /// must not be stored, such as one that issues session tokens.
+    pub fn refuse(&self) -> Result<()> {
+        match self.claim {
+            Some(_) => Err(Error::IdempotencyKeyNotAllowedError),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs `handler` unless the key was used before. The first request
+    /// with a key claims it, so a concurrent duplicate gets
+    /// `IDEMPOTENCY_KEY_IN_USE` instead of running alongside it. Once it
+    /// completes its response is stored and replayed to later requests
+    /// with the same key and body; a different body gets
+    /// `IDEMPOTENCY_KEY_REUSED`. Requests that fail are not stored and
+    /// give the key back, so they can be retried with it.
+    pub async fn run<F>(self, handler: F) -> WebResult<Response>
+    where
+        F: Future<Output = WebResult<Response>>,
+    {
+        let Some(claim) = self.claim else {
+            return handler.await;
+        };
+        if let Some(stored) = claim_key(&self.records, &claim)
+            .await
+            .map_err(reject::custom)?
+        {
+            return Ok(replay(stored));
+        }
+
+        let response = match handler.await {
+            Ok(response) => response,
+            Err(rejection) => {
+                release(&self.records, &claim).await;
+                return Err(rejection);
+            }
+        };
+        let (parts, body) = response.into_parts();
+        let body = match hyper::body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(e) => {
+                release(&self.records, &claim).await;
+                return Err(reject::custom(Error::DatabaseError(Box::new(e))));
+            }
+        };
+        let stored = StoredResponse {
+            status: parts.status.as_u16(),
+            headers: parts
+                .headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_owned()))
+                })
+                .collect(),
+            body: Binary {
+                subtype: BinarySubtype::Generic,
+                bytes: body.to_vec(),
+            },
+        };
+        let saved = store(&self.records, &claim, &stored).await;
+        if let Err(e) = saved {
+            tracing::error!(
+                "storing the response for an idempotency key failed: {:?}",
+                e
+            );
+            release(&self.records, &claim).await;
+        }
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+}
+
+/// Claims the key for this request, or returns the response stored for it.
+async fn claim_key(
+    records: &Collection<IdempotencyRecord>,
+    claim: &Claim,
+) -> Result<Option<StoredResponse>> {
+    let record = IdempotencyRecord {
+        route: claim.route.clone(),
+        key: claim.key.clone(),
+        request_hash: claim.request_hash.clone(),
+        response: None,
+        created_at: DateTime::now(),
+    };
+    match timed(records.insert_one(&record, None)).await {
+        Ok(_) => return Ok(None),
+        Err(Error::DuplicateKeyError) => {}
+        Err(e) => return Err(e),
+    }
+
+    let existing =
+        timed(records.find_one(doc! {"route": &claim.route, "key": &claim.key}, None)).await?;
+    match existing {
+        Some(existing) if existing.request_hash != claim.request_hash => {
+            Err(Error::IdempotencyKeyReusedError)
+        }
+        Some(IdempotencyRecord {
+            response: Some(stored),
+            ..
+        }) => Ok(Some(stored)),
+        // Still running, or expired between the insert and the lookup.
+        _ => Err(Error::IdempotencyKeyInUseError),
+    }
+}
+
+async fn store(
+    records: &Collection<IdempotencyRecord>,
+    claim: &Claim,
+    stored: &StoredResponse,
+) -> Result<()> {
+    let stored = mongodb::bson::to_bson(stored).map_err(Error::from)?;
+    timed(records.update_one(
+        doc! {"route": &claim.route, "key": &claim.key},
+        doc! {"$set": {"response": stored}},
+        None,
+    ))
+    .await?;
+    Ok(())
+}
+
+async fn release(records: &Collection<IdempotencyRecord>, claim: &Claim) {
+    let released = timed(records.delete_one(
+        doc! {"route": &claim.route, "key": &claim.key, "response": null},
+        None,
+    ))
+    .await;
+    if let Err(e) = released {
+        tracing::error!("giving back an idempotency key failed: {:?}", e);
+    }
+}
+
+fn replay(stored: StoredResponse) -> Response {
+    let mut response = Response::new(Body::from(stored.body.bytes));
+    *response.status_mut() = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+    let headers = response.headers_mut();
+    for (name, value) in stored.headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            headers.append(name, value);
+        }
+    }
+    headers.insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+    response
+}
+
+fn is_valid_key(key: &str) -> bool {
+    (1..=MAX_KEY_LENGTH).contains(&key.len()) && key.bytes().all(|b| b.is_ascii_graphic())
+}
@@ -0,0 +1,344 @@
+use crate::{
+    auth::{AuthContext, Claims, Role},
+    error::{Error, DUPLICATE_KEY_ERROR},
+    password,
+    repository::timed,
+    users,
+    validation::Validator,
+    User, WebResult,
+};
+use bytes::Bytes;
+use mongodb::{
+    bson::doc,
+    error::{BulkWriteFailure, ErrorKind},
+    options::InsertManyOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use utoipa::ToSchema;
+use warp::{reject, reply, Reply};
+
+pub const MAX_IMPORT_RECORDS: usize = 10_000;
+const BATCH_SIZE: usize = 500;
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+#[derive(Deserialize, ToSchema)]
+pub struct ImportRecord {
+    email: String,
+    role: String,
+    /// An existing bcrypt hash, instead of `pw`.
+    pw_hash: Option<String>,
+    pw: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportStatus {
+    Created,
+    Skipped,
+    Failed,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ImportOutcome {
+    index: usize,
+    email: Option<String>,
+    status: ImportStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ImportReport {
+    created: usize,
+    skipped: usize,
+    failed: usize,
+    results: Vec<ImportOutcome>,
+}
+
+enum Password {
+    Plain(String),
+    Hash(String),
+}
+
+/// A record that passed validation, waiting for its batch to be inserted.
+struct Candidate {
+    index: usize,
+    email: String,
+    role: Role,
+    password: Password,
+}
+
+/// Splits the body into one JSON value per record, from either a JSON
+/// array or newline-delimited JSON.
+fn parse_records(content_type: Option<&str>, body: &[u8]) -> Result<Vec<serde_json::Value>, Error> {
+    let is_ndjson = content_type.is_some_and(|t| t.starts_with(NDJSON_CONTENT_TYPE));
+    let records: Vec<serde_json::Value> = if is_ndjson {
+        body.split(|b| *b == b'\n')
+            .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+            .map(|line| serde_json::from_slice(line).unwrap_or(serde_json::Value::Null))
+            .collect()
+    } else {
+        serde_json::from_slice(body).map_err(|_| Error::InvalidImportError)?
+    };
+    if records.len() > MAX_IMPORT_RECORDS {
+        return Err(Error::ImportTooLargeError);
+    }
+    Ok(records)
+}
+
+/// bcrypt hashes are 60 characters in modular crypt format.
+fn is_bcrypt_hash(value: &str) -> bool {
+    value.len() == 60
+        && ["$2a$", "$2b$", "$2y$"]
+            .iter()
+            .any(|p| value.starts_with(p))
+}
+
+fn validate(
+    context: &AuthContext,
+    index: usize,
+    value: serde_json::Value,
+) -> Result<Candidate, String> {
+    let record: ImportRecord =
+        serde_json::from_value(value).map_err(|e| format!("malformed record: {}", e))?;
+
+    let email = users::normalize_email(&record.email);
+    let mut validator = Validator::new();
+    validator.email("email", &email);
+    if let Some(pw) = &record.pw {
+        validator.password("pw", pw);
+    }
+    if let Err(Error::ValidationError(errors)) = validator.finish() {
+        let reasons: Vec<String> = errors
+            .iter()
+            .flat_map(|(field, messages)| messages.iter().map(move |m| format!("{} {}", field, m)))
+            .collect();
+        return Err(reasons.join(", "));
+    }
+
+    let role = Role::from_str(&record.role)
+        .ok()
+        .filter(|role| context.roles().is_assignable(role))
+        .ok_or_else(|| format!("unknown role {}", record.role))?;
+
+    let password = match (record.pw, record.pw_hash) {
+        (Some(pw), None) => Password::Plain(pw),
+        (None, Some(pw_hash)) if is_bcrypt_hash(&pw_hash) => Password::Hash(pw_hash),
+        (None, Some(_)) => return Err("pw_hash is not a bcrypt hash".to_string()),
+        _ => return Err("exactly one of pw and pw_hash is required".to_string()),
+    };
+
+    Ok(Candidate {
+        index,
+        email,
+        role,
+        password,
+    })
+}
+
+/// Imports accounts for a migration. Records are validated one by one and
+/// reported individually; an email that already exists is skipped, which
+/// makes a retry of a partially applied import safe.
+#[utoipa::path(
+    post,
+    path = "/users/import",
+    tag = "users",
+    request_body(
+        content = Vec<ImportRecord>,
+        description = "At most 10000 records, as a JSON array or, with `Content-Type: \
+                       application/x-ndjson`, one record per line"
+    ),
+    responses(
+        (status = 200, description = "What happened to each record", body = ImportReport),
+        (status = 400, description = "Malformed body or too many records", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 413, description = "Body over `MAX_UPLOAD_BYTES`", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn import_users_handler(
+    _claims: Claims,
+    context: AuthContext,
+    users_collection: Collection<User>,
+    content_type: Option<String>,
+    body: Bytes,
+) -> WebResult<impl Reply> {
+    let records = parse_records(content_type.as_deref(), &body).map_err(reject::custom)?;
+
+    let mut outcomes: HashMap<usize, ImportOutcome> = HashMap::new();
+    let mut seen = HashSet::new();
+    let mut candidates = Vec::new();
+    for (index, value) in records.into_iter().enumerate() {
+        match validate(&context, index, value) {
+            Ok(candidate) if !seen.insert(candidate.email.clone()) => {
+                outcomes.insert(
+                    index,
+                    outcome(
+                        index,
+                        Some(candidate.email),
+                        ImportStatus::Skipped,
+                        "duplicate email in import",
+                    ),
+                );
+            }
+            Ok(candidate) => candidates.push(candidate),
+            Err(reason) => {
+                outcomes.insert(index, outcome(index, None, ImportStatus::Failed, &reason));
+            }
+        }
+    }
+
+    while !candidates.is_empty() {
+        let batch: Vec<Candidate> = candidates
+            .drain(..BATCH_SIZE.min(candidates.len()))
+            .collect();
+        import_batch(&users_collection, batch, &mut outcomes).await?;
+    }
+
+    let mut results: Vec<ImportOutcome> = outcomes.into_values().collect();
+    results.sort_by_key(|o| o.index);
+    let count = |status| results.iter().filter(|o| o.status == status).count();
+    Ok(reply::json(&ImportReport {
+        created: count(ImportStatus::Created),
+        skipped: count(ImportStatus::Skipped),
+        failed: count(ImportStatus::Failed),
+        results,
+    }))
+}
+
+fn outcome(
+    index: usize,
+    email: Option<String>,
+    status: ImportStatus,
+    reason: &str,
+) -> ImportOutcome {
+    ImportOutcome {
+        index,
+        email,
+        status,
+        reason: (status != ImportStatus::Created).then(|| reason.to_string()),
+    }
+}
+
+async fn import_batch(
+    users_collection: &Collection<User>,
+    batch: Vec<Candidate>,
+    outcomes: &mut HashMap<usize, ImportOutcome>,
+) -> WebResult<()> {
+    let emails: Vec<&str> = batch.iter().map(|c| c.email.as_str()).collect();
+    let mut cursor = timed(users_collection.find(doc! {"email_lower": {"$in": emails}}, None))
+        .await
+        .map_err(reject::custom)?;
+    let mut existing = HashSet::new();
+    while timed(cursor.advance()).await.map_err(reject::custom)? {
+        let user: User = cursor
+            .deserialize_current()
+            .map_err(|e| reject::custom(Error::from(e)))?;
+        existing.insert(users::normalize_email(&user.email));
+    }
+
+    let (duplicates, pending): (Vec<_>, Vec<_>) =
+        batch.into_iter().partition(|c| existing.contains(&c.email));
+    for candidate in duplicates {
+        let index = candidate.index;
+        outcomes.insert(
+            index,
+            outcome(
+                index,
+                Some(candidate.email),
+                ImportStatus::Skipped,
+                "email already exists",
+            ),
+        );
+    }
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    // Password hashing is deliberately slow; keep it off the async worker
+    // threads.
+    let hashed = tokio::task::spawn_blocking(move || {
+        pending
+            .into_iter()
+            .map(|c| {
+                let pw = match c.password {
+                    Password::Plain(pw) => password::hash(&pw).ok(),
+                    Password::Hash(pw_hash) => Some(pw_hash),
+                };
+                let user = pw.map(|pw| User::new(c.email.clone(), pw, &c.role));
+                (c.index, c.email, user)
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(|e| reject::custom(Error::PasswordHashingError(Box::new(e))))?;
+
+    let mut inserted = Vec::new();
+    let mut users = Vec::new();
+    for (index, email, user) in hashed {
+        match user {
+            Some(user) => {
+                inserted.push((index, email));
+                users.push(user);
+            }
+            None => {
+                outcomes.insert(
+                    index,
+                    outcome(
+                        index,
+                        Some(email),
+                        ImportStatus::Failed,
+                        "password hashing failed",
+                    ),
+                );
+            }
+        }
+    }
+    if users.is_empty() {
+        return Ok(());
+    }
+
+    let options = InsertManyOptions::builder().ordered(false).build();
+    let write_errors = match timed(
+        users::documents(users_collection).insert_many(users.iter().map(User::document), options),
+    )
+    .await
+    {
+        Ok(_) => Vec::new(),
+        // Per-document failures are reported per row; the driver error is
+        // kept as the source of `DatabaseError`.
+        Err(Error::DatabaseError(cause)) => match cause
+            .downcast_ref::<mongodb::error::Error>()
+            .map(|e| &*e.kind)
+        {
+            Some(ErrorKind::BulkWrite(BulkWriteFailure {
+                write_errors: Some(errors),
+                write_concern_error: None,
+                ..
+            })) => errors.clone(),
+            _ => return Err(reject::custom(Error::DatabaseError(cause))),
+        },
+        Err(e) => return Err(reject::custom(e)),
+    };
+
+    let mut failures = HashMap::new();
+    for error in &write_errors {
+        // A concurrent signup or import took the email after our lookup.
+        let failure = if error.code == DUPLICATE_KEY_ERROR {
+            (ImportStatus::Skipped, "email already exists")
+        } else {
+            (ImportStatus::Failed, "insert failed")
+        };
+        failures.insert(error.index, failure);
+    }
+    for (position, (index, email)) in inserted.into_iter().enumerate() {
+        let (status, reason) = failures
+            .get(&position)
+            .copied()
+            .unwrap_or((ImportStatus::Created, ""));
+        outcomes.insert(index, outcome(index, Some(email), status, reason));
+    }
+    Ok(())
+}
@@ -0,0 +1,175 @@
+use crate::{
+    auth::{hash_token, random_token, Claims},
+    error::Error,
+    repository::timed,
+    transaction::Abort,
+    WebResult,
+};
+use mongodb::{
+    bson::{doc, DateTime},
+    options::{FindOneAndUpdateOptions, IndexOptions},
+    ClientSession, Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::ToSchema;
+use warp::{http::StatusCode, reject, reply, Reply};
+
+const CODE_LENGTH: usize = 16;
+const DEFAULT_EXPIRY_DAYS: u64 = 30;
+const MAX_EXPIRY_DAYS: u64 = 365;
+const MAX_USES: u32 = 10_000;
+/// How long an expired invite is kept before the TTL index removes it, so
+/// signups with it still get `INVITE_EXPIRED` rather than `INVALID_INVITE`
+/// for a while.
+const EXPIRED_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// An invite code for signing up while `REQUIRE_INVITE` is on. Like other
+/// one-time tokens only its SHA-256 is stored.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Invite {
+    pub code_hash: String,
+    /// uid of the admin who minted it.
+    pub created_by: String,
+    pub max_uses: u32,
+    pub uses: u32,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateInviteRequest {
+    /// How many signups the code admits, 1 (the default) to 10000.
+    pub max_uses: Option<u32>,
+    /// Days until the code expires, at most 365 (default 30).
+    pub expires_in_days: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CreateInviteResponse {
+    /// The code to hand out, shown only this once.
+    pub code: String,
+    pub max_uses: u32,
+    pub expires_at: Option<String>,
+}
+
+pub async fn create_indexes(collection: &Collection<Invite>) -> mongodb::error::Result<()> {
+    let code_index = IndexModel::builder()
+        .keys(doc! {"code_hash": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    let ttl_index = IndexModel::builder()
+        .keys(doc! {"expires_at": 1})
+        .options(
+            IndexOptions::builder()
+                .expire_after(EXPIRED_RETENTION)
+                .build(),
+        )
+        .build();
+    collection
+        .create_indexes(vec![code_index, ttl_index], None)
+        .await?;
+    Ok(())
+}
+
+/// Uses up one signup of `code`, as part of the signup's transaction. The
+/// use is counted in the same update that checks `uses < max_uses`, so
+/// concurrent signups can never take a code past its limit. Refusals say
+/// why a code was refused.
+pub async fn consume(
+    invites: &Collection<Invite>,
+    code: &str,
+    session: &mut ClientSession,
+) -> std::result::Result<(), Abort> {
+    let code_hash = hash_token(code.trim());
+    let consumed = invites
+        .find_one_and_update_with_session(
+            doc! {
+                "code_hash": &code_hash,
+                "expires_at": {"$gt": DateTime::now()},
+                "$expr": {"$lt": ["$uses", "$max_uses"]},
+            },
+            doc! {"$inc": {"uses": 1}},
+            FindOneAndUpdateOptions::default(),
+            session,
+        )
+        .await?;
+    if consumed.is_some() {
+        return Ok(());
+    }
+
+    let invite = invites
+        .find_one_with_session(doc! {"code_hash": &code_hash}, None, session)
+        .await?;
+    Err(Abort::Refused(match invite {
+        None => Error::InvalidInviteError,
+        Some(invite) if invite.expires_at <= DateTime::now() => Error::InviteExpiredError,
+        Some(_) => Error::InviteExhaustedError,
+    }))
+}
+
+/// Gives back a use taken by [`consume`] for a signup that then failed
+/// outside its transaction, or without one on a standalone server.
+pub async fn release(
+    invites: &Collection<Invite>,
+    code: &str,
+    session: &mut ClientSession,
+) -> std::result::Result<(), Abort> {
+    invites
+        .update_one_with_session(
+            doc! {"code_hash": hash_token(code.trim()), "uses": {"$gt": 0}},
+            doc! {"$inc": {"uses": -1}},
+            None,
+            session,
+        )
+        .await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/invites",
+    tag = "account",
+    request_body = CreateInviteRequest,
+    responses(
+        (status = 201, description = "The invite code, shown only this once", body = CreateInviteResponse),
+        (status = 400, description = "`max_uses` or `expires_in_days` out of range", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn create_invite_handler(
+    claims: Claims,
+    invites: Collection<Invite>,
+    body: CreateInviteRequest,
+) -> WebResult<impl Reply> {
+    let max_uses = body.max_uses.unwrap_or(1);
+    let expires_in_days = body.expires_in_days.unwrap_or(DEFAULT_EXPIRY_DAYS);
+    if !(1..=MAX_USES).contains(&max_uses) || !(1..=MAX_EXPIRY_DAYS).contains(&expires_in_days) {
+        return Err(reject::custom(Error::InvalidInviteRequestError));
+    }
+
+    let code = random_token(CODE_LENGTH);
+    let expires_at = DateTime::now()
+        .saturating_add_duration(Duration::from_secs(expires_in_days * 24 * 60 * 60));
+    let invite = Invite {
+        code_hash: hash_token(&code),
+        created_by: claims.sub,
+        max_uses,
+        uses: 0,
+        created_at: DateTime::now(),
+        expires_at,
+    };
+    timed(invites.insert_one(&invite, None))
+        .await
+        .map_err(reject::custom)?;
+
+    Ok(reply::with_status(
+        reply::json(&CreateInviteResponse {
+            code,
+            max_uses,
+            expires_at: expires_at.try_to_rfc3339_string().ok(),
+        }),
+        StatusCode::CREATED,
+    ))
+}
@@ -0,0 +1,181 @@
+use crate::{config, error::Error, throttle::client_ip};
+use std::{fmt, net::IpAddr, str::FromStr};
+use warp::{reject, Filter, Rejection};
+
+/// An address range in CIDR notation, such as `10.8.0.0/16` or
+/// `fd00::/8`. A bare address is a range of one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Dual-stack listeners see IPv4 clients as `::ffff:a.b.c.d`.
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let (network, prefix) = match s.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = network.parse().map_err(|_| ())?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| ())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(());
+        }
+        Ok(IpRange {
+            network: network.to_canonical(),
+            prefix,
+        })
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Refuses requests from outside `ADMIN_IP_ALLOWLIST` with 403, unless
+/// `enabled` is false or the allowlist is empty. It looks at the same
+/// address as the rate limiters, so `X-Forwarded-For` is only honored with
+/// `TRUST_PROXY`, and then only the entry our own proxy appended. Goes
+/// before anything that reads the token, so other sources learn nothing
+/// about it.
+pub fn check(
+    enabled: bool,
+    trust_proxy: bool,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    check_if(move || enabled, trust_proxy)
+}
+
+/// Like [`check`], but asks `enabled` on each request, for routes whose
+/// privileges depend on role definitions that can change while the server
+/// runs.
+pub fn check_if(
+    enabled: impl Fn() -> bool + Clone + Send + Sync + 'static,
+    trust_proxy: bool,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    client_ip(trust_proxy)
+        .and_then(move |ip: Option<IpAddr>| {
+            let enabled = enabled();
+            async move {
+                if !enabled || allows(config::admin_ip_allowlist(), ip) {
+                    Ok(())
+                } else {
+                    Err(reject::custom(Error::IpNotAllowedError))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Whether `allowlist` lets `ip` in. An empty one lets anyone in, even a
+/// client without an address.
+fn allows(allowlist: &[IpRange], ip: Option<IpAddr>) -> bool {
+    if allowlist.is_empty() {
+        return true;
+    }
+    ip.is_some_and(|ip| allowlist.iter().any(|range| range.contains(ip)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::RemoteAddr;
+
+    fn ranges(ranges: &[&str]) -> Vec<IpRange> {
+        ranges.iter().map(|range| range.parse().unwrap()).collect()
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    /// The address `check` judges a request from `remote` by.
+    async fn client(
+        remote: &str,
+        forwarded_for: Option<&str>,
+        trust_proxy: bool,
+    ) -> Option<IpAddr> {
+        let mut request = warp::test::request().extension(RemoteAddr(remote.parse().unwrap()));
+        if let Some(forwarded_for) = forwarded_for {
+            request = request.header("x-forwarded-for", forwarded_for);
+        }
+        request.filter(&client_ip(trust_proxy)).await.unwrap()
+    }
+
+    #[test]
+    fn ipv4_ranges_match_their_addresses() {
+        let allowlist = ranges(&["10.8.0.0/16", "192.0.2.7"]);
+        assert!(allows(&allowlist, ip("10.8.255.1")));
+        assert!(allows(&allowlist, ip("192.0.2.7")));
+        assert!(!allows(&allowlist, ip("10.9.0.1")));
+        assert!(!allows(&allowlist, ip("192.0.2.8")));
+        // How a dual-stack listener reports an IPv4 client.
+        assert!(allows(&allowlist, ip("::ffff:10.8.0.1")));
+    }
+
+    #[test]
+    fn ipv6_ranges_match_their_addresses() {
+        let allowlist = ranges(&["fd00:1234::/32", "2001:db8::1"]);
+        assert!(allows(&allowlist, ip("fd00:1234:ffff::1")));
+        assert!(allows(&allowlist, ip("2001:db8::1")));
+        assert!(!allows(&allowlist, ip("fd00:1235::1")));
+        assert!(!allows(&allowlist, ip("2001:db8::2")));
+        assert!(!allows(&allowlist, ip("10.8.0.1")));
+    }
+
+    #[test]
+    fn an_empty_allowlist_lets_anyone_in() {
+        assert!(allows(&[], ip("203.0.113.9")));
+        assert!(allows(&[], None));
+        assert!(!allows(&ranges(&["10.0.0.0/8"]), None));
+    }
+
+    #[test]
+    fn invalid_ranges_are_refused() {
+        for range in ["10.0.0.0/33", "fd00::/129", "10.0.0", "10.0.0.0/x", ""] {
+            assert!(range.parse::<IpRange>().is_err(), "{}", range);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_forged_forwarded_for_gets_nowhere() {
+        let allowlist = ranges(&["10.8.0.0/16"]);
+
+        // Without a proxy the header is the client's own, and ignored.
+        let ip = client("203.0.113.9:1000", Some("10.8.0.1"), false).await;
+        assert!(!allows(&allowlist, ip));
+
+        // Behind one, only the entry our proxy appended counts; whatever
+        // the client sent is to its left.
+        let ip = client("127.0.0.1:1000", Some("10.8.0.1, 203.0.113.9"), true).await;
+        assert!(!allows(&allowlist, ip));
+        let ip = client("127.0.0.1:1000", Some("203.0.113.9, 10.8.0.1"), true).await;
+        assert!(allows(&allowlist, ip));
+        let ip = client("[::1]:1000", Some("fd00::1, 10.8.3.4"), true).await;
+        assert!(allows(&allowlist, ip));
+    }
+}
@@ -0,0 +1,207 @@
+use crate::error::Error;
+use std::sync::Arc;
+use utoipa::openapi::{path::PathItemType, OpenApi};
+use warp::{
+    filters::{path::FullPath, BoxedFilter},
+    http::Method,
+    reject,
+    reply::Response,
+    Filter,
+};
+
+/// A route's path as segments, `None` standing for a parameter such as
+/// `{uid}`, with the methods it is served for.
+struct Pattern {
+    segments: Vec<Option<String>>,
+    methods: Vec<Method>,
+}
+
+impl Pattern {
+    fn matches(&self, segments: &[&str]) -> bool {
+        self.segments.len() == segments.len()
+            && self
+                .segments
+                .iter()
+                .zip(segments)
+                .all(|(pattern, segment)| match pattern {
+                    Some(literal) => literal == segment,
+                    None => !segment.is_empty(),
+                })
+    }
+}
+
+/// Every path the server has routes for and the methods they answer, so a
+/// request no route took can be told apart as an unknown path (404) or a
+/// known one with the wrong method (405).
+#[derive(Clone)]
+pub struct KnownPaths(Arc<Vec<Pattern>>);
+
+impl KnownPaths {
+    /// The paths of `specs`, which must be the documents as served, and
+    /// the `undocumented` routes they leave out.
+    pub fn new(specs: &[&OpenApi], undocumented: &[(String, Method)]) -> Self {
+        let documented = specs
+            .iter()
+            .flat_map(|spec| &spec.paths.paths)
+            .map(|(path, item)| pattern(path, item.operations.keys().map(method).collect()));
+        let undocumented = undocumented
+            .iter()
+            .map(|(path, method)| pattern(path, vec![method.clone()]));
+        KnownPaths(Arc::new(documented.chain(undocumented).collect()))
+    }
+
+    /// Every known path, parameters written `{}`, with its methods.
+    #[cfg(test)]
+    pub(crate) fn paths(&self) -> Vec<(String, Vec<Method>)> {
+        self.0
+            .iter()
+            .map(|pattern| (pattern.to_string(), pattern.methods.clone()))
+            .collect()
+    }
+
+    /// The methods `path` is served for, or `None` for an unknown path.
+    fn allowed(&self, path: &str) -> Option<Vec<&Method>> {
+        let segments = split(path);
+        let mut methods: Vec<&Method> = Vec::new();
+        for pattern in self.0.iter().filter(|pattern| pattern.matches(&segments)) {
+            for method in &pattern.methods {
+                if !methods.contains(&method) {
+                    methods.push(method);
+                }
+            }
+        }
+        (!methods.is_empty()).then_some(methods)
+    }
+}
+
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for segment in &self.segments {
+            write!(f, "/{}", segment.as_deref().unwrap_or("{}"))?;
+        }
+        Ok(())
+    }
+}
+
+fn pattern(path: &str, methods: Vec<Method>) -> Pattern {
+    let segments = split(path)
+        .into_iter()
+        .map(|segment| {
+            let parameter = segment.starts_with('{') && segment.ends_with('}');
+            (!parameter).then(|| segment.to_string())
+        })
+        .collect();
+    Pattern { segments, methods }
+}
+
+fn split(path: &str) -> Vec<&str> {
+    let path = path.strip_suffix('/').unwrap_or(path);
+    path.split('/').skip(1).collect()
+}
+
+pub(crate) fn method(item: &PathItemType) -> Method {
+    match item {
+        PathItemType::Get => Method::GET,
+        PathItemType::Post => Method::POST,
+        PathItemType::Put => Method::PUT,
+        PathItemType::Delete => Method::DELETE,
+        PathItemType::Options => Method::OPTIONS,
+        PathItemType::Head => Method::HEAD,
+        PathItemType::Patch => Method::PATCH,
+        PathItemType::Trace => Method::TRACE,
+        PathItemType::Connect => Method::CONNECT,
+    }
+}
+
+/// Goes last, after every route and before `recover`, and only ever
+/// rejects: with `MethodNotAllowedError` when `known` has the path but not
+/// the method, with `RouteNotFoundError` when it hasn't the path, and
+/// otherwise with a plain not found, so the rejection of the route that
+/// took the path is the one answered.
+pub fn fallback(known: KnownPaths) -> BoxedFilter<(Response,)> {
+    warp::method()
+        .and(warp::path::full())
+        .and_then(move |method: Method, path: FullPath| {
+            let known = known.clone();
+            async move {
+                let rejection = match known.allowed(path.as_str()) {
+                    None => reject::custom(Error::RouteNotFoundError),
+                    Some(methods) if !methods.contains(&&method) => {
+                        let allow = methods
+                            .iter()
+                            .map(|method| method.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        reject::custom(Error::MethodNotAllowedError { allow })
+                    }
+                    Some(_) => reject::not_found(),
+                };
+                Err::<Response, _>(rejection)
+            }
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support;
+    use serde_json::Value;
+    use warp::http::{header::ALLOW, StatusCode};
+
+    /// The status, `Allow` header and error code answering `method` on
+    /// `path`.
+    async fn send(
+        vars: &[(&str, &str)],
+        method: &str,
+        path: &str,
+    ) -> (StatusCode, Option<String>, Value) {
+        let app = test_support::offline_app_with(vars).await;
+        let response = warp::test::request()
+            .method(method)
+            .path(path)
+            .reply(&crate::routes(app))
+            .await;
+        let allow = response
+            .headers()
+            .get(ALLOW)
+            .map(|v| v.to_str().unwrap().to_owned());
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        (response.status(), allow, body["code"].clone())
+    }
+
+    #[tokio::test]
+    async fn a_known_path_with_the_wrong_method_is_405() {
+        let (status, allow, code) = send(&[], "GET", "/api/v1/login").await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(allow.as_deref(), Some("POST"));
+        assert_eq!(code, "METHOD_NOT_ALLOWED");
+    }
+
+    #[tokio::test]
+    async fn a_path_parameter_matches_any_segment() {
+        let (status, allow, _) = send(&[], "POST", "/api/v1/users/some-uid").await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        let mut allow: Vec<String> = allow.unwrap().split(", ").map(str::to_owned).collect();
+        allow.sort();
+        assert_eq!(allow, ["DELETE", "GET", "PUT"]);
+    }
+
+    #[tokio::test]
+    async fn an_unknown_path_is_404_whatever_the_method() {
+        for method in ["GET", "POST"] {
+            let (status, allow, code) = send(&[], method, "/api/v1/nonexistent").await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert_eq!(allow, None);
+            assert_eq!(code, "NOT_FOUND");
+        }
+    }
+
+    #[tokio::test]
+    async fn unprefixed_paths_are_only_known_with_legacy_routes() {
+        let (status, _, _) = send(&[], "GET", "/login").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, allow, _) = send(&[("LEGACY_ROUTES", "true")], "GET", "/login").await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(allow.as_deref(), Some("POST"));
+    }
+}
@@ -0,0 +1,1957 @@
+#![recursion_limit = "256"]
+
+use audit::{AuditAction, AuditEvent};
+use auth::{create_csrf_token, create_jwt, create_password_change_jwt, AuthContext, Claims, Role};
+use captcha::Captcha;
+use config::Config;
+use error::Error::*;
+use events::AdminEventKind;
+use futures_util::FutureExt;
+use idempotency::Idempotency;
+use invites::Invite;
+use known_paths::KnownPaths;
+use lockout::LoginLockout;
+use mailer::Mailer;
+use mongodb::{
+    bson::{doc, uuid, DateTime},
+    Collection,
+};
+use password::Verification;
+use repository::{timed, UserRepo};
+use routes::AppState;
+use serde::{Deserialize, Serialize};
+use server::ListenAddr;
+use sessions::{ClientInfo, Session};
+use std::{future::Future, io, sync::OnceLock};
+use transaction::Transactions;
+use two_factor::{PendingLogin, TwoFactorRequiredResponse};
+use utoipa::{openapi::OpenApi, ToSchema};
+use validation::{Validate, Validator};
+use warp::{
+    filters::BoxedFilter,
+    http::{
+        header::{LOCATION, SET_COOKIE},
+        Method, StatusCode,
+    },
+    reject, reply,
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+use webhooks::WebhookEvent;
+
+pub mod access_log;
+pub mod admin_sessions;
+pub mod apikeys;
+pub mod audit;
+pub mod auth;
+pub mod avatars;
+pub mod body;
+pub mod captcha;
+pub mod circuit_breaker;
+pub mod compression;
+pub mod config;
+pub mod email_change;
+pub mod error;
+pub mod etag;
+pub mod events;
+pub mod export;
+pub mod features;
+pub mod frontend;
+pub mod github;
+pub mod google;
+pub mod guest;
+pub mod health;
+pub mod i18n;
+pub mod idempotency;
+pub mod import;
+pub mod invites;
+pub mod ip_allowlist;
+pub mod known_paths;
+pub mod lockout;
+pub mod magic_link;
+pub mod mailer;
+pub mod maintenance;
+pub mod metrics;
+pub mod oauth;
+pub mod openapi;
+pub mod orgs;
+pub mod pagination;
+pub mod password;
+pub mod password_reset;
+pub mod ratelimit;
+pub mod repository;
+pub mod request_id;
+pub mod role_batch;
+pub mod roles;
+pub mod routes;
+pub mod scopes;
+pub mod security_headers;
+pub mod seed;
+pub mod server;
+pub mod server_timing;
+pub mod sessions;
+pub mod sockets;
+pub mod stats;
+pub mod sweep;
+pub mod telemetry;
+#[cfg(test)]
+mod test_support;
+pub mod throttle;
+pub mod timeout;
+pub mod token_exchange;
+pub mod transaction;
+pub mod two_factor;
+pub mod users;
+pub mod validation;
+pub mod verification;
+pub mod webhooks;
+
+type Result<T> = std::result::Result<T, error::Error>;
+type WebResult<T> = std::result::Result<T, Rejection>;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct User {
+    pub uid: String,
+    /// Empty for guests, whose documents have no `email`, so that the
+    /// partial unique index leaves them out.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub email: String,
+    /// `email` as `users::normalize_email` has it, kept in sync for lookups
+    /// by email and case-insensitive prefix search.
+    #[serde(default)]
+    pub email_lower: Option<String>,
+    /// Optional handle that can be used instead of the email to log in.
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Lowercased `username`, which is what the unique index is on.
+    #[serde(default)]
+    pub username_lower: Option<String>,
+    /// bcrypt hash. Left out whenever a `User` is serialized, so one that
+    /// ends up in a response cannot leak it; writes to the collection go
+    /// through [`User::document`] instead. Empty for guests.
+    #[serde(default, skip_serializing)]
+    pub pw: String,
+    /// Hashes of the latest passwords, newest first, for
+    /// [`password::recently_used`]. Kept out of serialization like `pw`.
+    #[serde(default, skip_serializing)]
+    pub pw_history: Vec<String>,
+    pub role: String,
+    /// False while an admin has suspended the account.
+    #[serde(default = "default_true")]
+    pub active: bool,
+    /// Documents created before verification existed have no such field and
+    /// are treated as verified.
+    #[serde(default = "default_true")]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub verification_token_hash: Option<String>,
+    /// The address an unconfirmed `POST /me/email-change` moves the account
+    /// to. `email` stays the one to sign in with until it is confirmed.
+    #[serde(default)]
+    pub pending_email: Option<String>,
+    #[serde(default)]
+    pub email_change_token_hash: Option<String>,
+    #[serde(default)]
+    pub email_change_expires_at: Option<DateTime>,
+    /// Encrypted TOTP secret, present once the user has started enrollment.
+    #[serde(default)]
+    pub totp_secret: Option<String>,
+    #[serde(default)]
+    pub totp_enabled: bool,
+    /// The time step of the last TOTP code accepted, so that no code is
+    /// accepted twice, nor one older than it.
+    #[serde(default)]
+    pub totp_last_step: Option<i64>,
+    /// Bumped by `/logout-all` to invalidate every outstanding access token.
+    #[serde(default)]
+    pub token_version: u32,
+    /// Absent on accounts created before the field existed.
+    #[serde(default)]
+    pub created_at: Option<DateTime>,
+    /// Last change to the account itself, such as its password, profile or
+    /// role. Sign-ins and token bookkeeping don't count.
+    #[serde(default)]
+    pub updated_at: Option<DateTime>,
+    /// Counts the changes that move `updated_at`. It is the `ETag` of
+    /// `GET /users/{uid}` and `/me`, which updates must send back in
+    /// `If-Match`; see [`etag::if_match`].
+    #[serde(default)]
+    pub version: i64,
+    /// Time of the latest successful password check, and the one before it.
+    #[serde(default)]
+    pub last_login_at: Option<DateTime>,
+    #[serde(default)]
+    pub previous_login_at: Option<DateTime>,
+    /// uid of the admin who last changed `role`, and when.
+    #[serde(default)]
+    pub role_changed_by: Option<String>,
+    #[serde(default)]
+    pub role_changed_at: Option<DateTime>,
+    /// Set when an admin deletes the account; the document is purged once
+    /// the retention period has passed.
+    #[serde(default)]
+    pub deleted_at: Option<DateTime>,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+    /// Name of the uploaded image in the `AvatarStore`, if any.
+    #[serde(default)]
+    pub avatar_file: Option<String>,
+    #[serde(default)]
+    pub bio: Option<String>,
+    /// When an admin banned the account; `None` when it is not banned.
+    #[serde(default)]
+    pub banned_at: Option<DateTime>,
+    /// When the ban ends; `None` with `banned_at` set for a permanent ban.
+    #[serde(default)]
+    pub banned_until: Option<DateTime>,
+    /// Why, for moderators. Only shown to the user with `SHOW_BAN_REASON`.
+    #[serde(default)]
+    pub ban_reason: Option<String>,
+    /// Password logins only get a token for changing the password until it
+    /// has been changed, e.g. after a credential leak.
+    #[serde(default)]
+    pub must_change_password: bool,
+}
+
+impl User {
+    /// A fresh, verified account; callers override what differs.
+    pub fn new(email: String, pw: String, role: &Role) -> Self {
+        let now = DateTime::now();
+        let pw_history = if pw.is_empty() || config::password_history() == 0 {
+            Vec::new()
+        } else {
+            vec![pw.clone()]
+        };
+        User {
+            uid: uuid::Uuid::new().to_string(),
+            email_lower: Some(users::normalize_email(&email)),
+            email,
+            username: None,
+            username_lower: None,
+            pw,
+            pw_history,
+            role: role.to_string(),
+            active: true,
+            email_verified: true,
+            verification_token_hash: None,
+            pending_email: None,
+            email_change_token_hash: None,
+            email_change_expires_at: None,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_last_step: None,
+            token_version: 0,
+            created_at: Some(now),
+            updated_at: Some(now),
+            version: 0,
+            last_login_at: None,
+            previous_login_at: None,
+            role_changed_by: None,
+            role_changed_at: None,
+            deleted_at: None,
+            display_name: None,
+            avatar_url: None,
+            avatar_file: None,
+            bio: None,
+            banned_at: None,
+            banned_until: None,
+            ban_reason: None,
+            must_change_password: false,
+        }
+    }
+
+    /// A [`Role::Guest`] account, with no email or password, for trying the
+    /// app out before signing up.
+    pub fn guest() -> Self {
+        User {
+            email_lower: None,
+            ..User::new(String::new(), String::new(), &Role::Guest)
+        }
+    }
+
+    /// Whether a ban is in force; one whose `banned_until` has passed no
+    /// longer counts, even before it is lifted.
+    pub fn is_banned(&self) -> bool {
+        self.banned_at.is_some()
+            && self
+                .banned_until
+                .is_none_or(|until| until > DateTime::now())
+    }
+
+    /// What to insert or replace in the users collection, see
+    /// [`users::documents`].
+    pub fn document(&self) -> UserDocument<'_> {
+        UserDocument {
+            user: self,
+            pw: &self.pw,
+            pw_history: &self.pw_history,
+        }
+    }
+}
+
+/// A `User` as stored, with the password hash that `User` itself never
+/// serializes.
+#[derive(Serialize)]
+pub struct UserDocument<'a> {
+    #[serde(flatten)]
+    user: &'a User,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    pw: &'a str,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    pw_history: &'a [String],
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// `identifier` is the account's email or username. Older clients send it
+/// as `email`, which is still accepted.
+#[derive(Deserialize, ToSchema)]
+pub struct LoginRequest {
+    #[serde(alias = "email", deserialize_with = "crate::users::normalized_email")]
+    pub identifier: String,
+    pub pw: String,
+    /// Keep the session for `REMEMBER_ME_TTL_DAYS` (default 30) instead of
+    /// 7 days, for personal devices.
+    #[serde(default)]
+    pub remember_me: bool,
+}
+
+/// Only rules out blank input, so bcrypt never runs on empty strings; the
+/// real check is the password comparison.
+impl Validate for LoginRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.non_empty("identifier", &self.identifier);
+        validator.non_empty("pw", &self.pw);
+    }
+}
+
+/// How access tokens are presented, per RFC 6750.
+pub const TOKEN_TYPE: &str = "Bearer";
+
+#[derive(Serialize, ToSchema)]
+pub struct LoginResponse {
+    /// Short-lived access token, sent as `Authorization: Bearer <token>`.
+    pub token: String,
+    /// Always `Bearer`.
+    #[schema(value_type = String, example = "Bearer")]
+    pub token_type: &'static str,
+    /// Seconds until `token` expires.
+    #[schema(example = 3600)]
+    pub expires_in: u64,
+    pub refresh_token: String,
+}
+
+/// The answer to a correct password for an account that must change it
+/// first. `password_change_token` is only accepted by `PUT /me/password`.
+#[derive(Serialize, ToSchema)]
+pub struct PasswordChangeRequiredResponse {
+    pub password_change_required: bool,
+    pub password_change_token: String,
+    /// Seconds until `password_change_token` expires.
+    #[schema(example = 300)]
+    pub expires_in: u64,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RefreshResponse {
+    pub token: String,
+    /// Always `Bearer`.
+    #[schema(value_type = String, example = "Bearer")]
+    pub token_type: &'static str,
+    /// Seconds until `token` expires.
+    #[schema(example = 3600)]
+    pub expires_in: u64,
+    pub refresh_token: String,
+}
+
+/// Public view of a `User`, without credentials. Clients should ignore
+/// fields they don't know so new ones can be added here without a version
+/// bump; existing fields are never renamed or removed.
+#[derive(Serialize, ToSchema)]
+pub struct UserResponse {
+    pub uid: String,
+    pub email: String,
+    pub username: Option<String>,
+    pub role: String,
+    pub active: bool,
+    pub email_verified: bool,
+    /// The address an email change waits to be confirmed for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_email: Option<String>,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub last_login_at: Option<String>,
+    pub previous_login_at: Option<String>,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub bio: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<String>,
+    /// Whether a ban is in force.
+    pub banned: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub banned_until: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ban_reason: Option<String>,
+    pub must_change_password: bool,
+}
+
+impl From<User> for UserResponse {
+    fn from(user: User) -> Self {
+        let banned = user.is_banned();
+        let pending_email = user.pending_email.filter(|_| {
+            user.email_change_expires_at
+                .is_some_and(|expires_at| expires_at > DateTime::now())
+        });
+        UserResponse {
+            uid: user.uid,
+            email: user.email,
+            username: user.username,
+            role: user.role,
+            active: user.active,
+            email_verified: user.email_verified,
+            pending_email,
+            created_at: user.created_at.and_then(|c| c.try_to_rfc3339_string().ok()),
+            updated_at: user.updated_at.and_then(|u| u.try_to_rfc3339_string().ok()),
+            last_login_at: user
+                .last_login_at
+                .and_then(|l| l.try_to_rfc3339_string().ok()),
+            previous_login_at: user
+                .previous_login_at
+                .and_then(|p| p.try_to_rfc3339_string().ok()),
+            display_name: user.display_name,
+            avatar_url: user.avatar_url,
+            bio: user.bio,
+            deleted_at: user.deleted_at.and_then(|d| d.try_to_rfc3339_string().ok()),
+            banned,
+            banned_until: user
+                .banned_until
+                .and_then(|b| b.try_to_rfc3339_string().ok()),
+            ban_reason: user.ban_reason,
+            must_change_password: user.must_change_password,
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ChangePasswordRequest {
+    pub old_pw: String,
+    pub new_pw: String,
+}
+
+/// Public signups always get `Role::User`. Older clients still send a
+/// `role`, which is ignored like any other unknown field.
+#[derive(Deserialize, ToSchema)]
+pub struct SignupRequest {
+    #[serde(deserialize_with = "crate::users::normalized_email")]
+    pub email: String,
+    pub pw: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Required with `REQUIRE_INVITE=true`, ignored otherwise.
+    #[serde(default)]
+    pub invite_code: Option<String>,
+    /// The token from the CAPTCHA widget. Required when `CAPTCHA_PROVIDER`
+    /// is set, ignored otherwise.
+    #[serde(default)]
+    pub captcha_token: Option<String>,
+}
+
+/// The account `/signup` created, as a `UserResponse`. With `SIGNUP_LOGIN=true` it is also
+/// signed in, and the fields of a `LoginResponse` are included.
+#[derive(Serialize, ToSchema)]
+pub struct SignupResponse {
+    #[serde(flatten)]
+    pub user: UserResponse,
+    #[serde(flatten)]
+    pub session: Option<LoginResponse>,
+}
+
+/// What a sign-in answers: tokens, or a pending login to finish at
+/// `/login/2fa` when the account has two-factor authentication. Only
+/// describes the response for the API docs.
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+pub enum LoginResult {
+    Tokens(LoginResponse),
+    TwoFactorRequired(TwoFactorRequiredResponse),
+    PasswordChangeRequired(PasswordChangeRequiredResponse),
+}
+
+impl Validate for SignupRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.email("email", &self.email);
+        validator.password("pw", &self.pw);
+        if let Some(username) = &self.username {
+            validator.username("username", username);
+        }
+    }
+}
+
+/// The whole filter tree served on the main port, errors included: the API
+/// under `API_PREFIX` (and unprefixed with `LEGACY_ROUTES`), probes, the
+/// JWKS document, the API docs, `/metrics` unless it has its own port, the
+/// frontend, the CORS layer and the security headers.
+pub fn routes(deps: AppState) -> BoxedFilter<(Response,)> {
+    let config = &deps.config;
+    let api = routes::api_routes(&config.api_prefix, &deps);
+    // The unprefixed paths from before `/api/v1`, kept for clients that
+    // have not moved yet.
+    let api = if config.legacy_routes {
+        api.or(routes::api_routes("", &deps)).unify().boxed()
+    } else {
+        api
+    };
+    let api_docs = openapi::spec(&config.api_prefix, config.metrics_port.is_none());
+    let routes = api
+        .or(routes::fixed_routes(&deps))
+        .unify()
+        .or(routes::docs_routes(&api_docs))
+        .unify()
+        .boxed();
+    // With `METRICS_PORT` set, `/metrics` is only served on that port.
+    let routes = match config.metrics_port {
+        Some(_) => routes,
+        None => routes.or(routes::metrics_route()).unify().boxed(),
+    };
+    let routes = match &config.static_dir {
+        Some(dir) => frontend::with_frontend(routes, dir, &config.api_prefix),
+        None => routes,
+    };
+    let routes = routes
+        .or(known_paths::fallback(known_paths(config, &api_docs)))
+        .unify();
+    let routes = timeout::check().and(routes).boxed();
+    let routes = routes
+        .recover(error::handle_rejection)
+        .map(Reply::into_response)
+        .boxed();
+    // Wrapped around `recover` so error responses carry the CORS headers
+    // too; the second `recover` answers disallowed origins.
+    let routes = match config.cors() {
+        Some(cors) => routes
+            .with(cors)
+            .map(Reply::into_response)
+            .recover(error::handle_rejection)
+            .map(Reply::into_response)
+            .boxed(),
+        None => routes,
+    };
+    security_headers::with_security_headers(routes, config.security_headers.clone())
+}
+
+/// The paths served by [`routes`], for telling 404 from 405.
+fn known_paths(config: &Config, api_docs: &OpenApi) -> KnownPaths {
+    let legacy_docs = openapi::spec("", false);
+    let mut specs = vec![api_docs];
+    let mut prefixes = vec![config.api_prefix.as_str()];
+    if config.legacy_routes {
+        specs.push(&legacy_docs);
+        prefixes.push("");
+    }
+    // The docs themselves and the WebSocket upgrade aren't documented.
+    let mut undocumented = vec![
+        ("/api-docs".to_string(), Method::GET),
+        ("/api-docs/openapi.json".to_string(), Method::GET),
+    ];
+    for prefix in prefixes {
+        undocumented.push((format!("{}/ws", prefix), Method::GET));
+    }
+    KnownPaths::new(&specs, &undocumented)
+}
+
+/// Binds the main port from `deps.config`, where port 0 picks a free one,
+/// or its unix socket, and returns the bound address with the future that serves [`routes`]
+/// there until `shutdown` resolves and in-flight requests have finished.
+pub fn start_server(
+    deps: AppState,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<(ListenAddr, impl Future<Output = ()>)> {
+    let addr = deps.config.listen_addr();
+    let mode = deps.config.unix_socket_mode;
+    let tls = deps.config.tls.clone();
+    let trust_proxy = deps.trust_proxy;
+    server::bind_listen(routes(deps), addr, mode, tls, trust_proxy, shutdown)
+}
+
+#[utoipa::path(
+    post,
+    path = "/signup",
+    tag = "account",
+    request_body = SignupRequest,
+    params(
+        ("Idempotency-Key" = Option<String>, Header,
+            description = "Retrying with the same key and body replays the first response instead of signing up again; refused with `SIGNUP_LOGIN=true`"),
+    ),
+    responses(
+        (status = 201, description = "Account created; a verification email was sent",
+            body = SignupResponse,
+            headers(("Location" = String, description = "The new account, `/users/{uid}`"))),
+        (status = 400, description = "Malformed `Idempotency-Key`, or one sent with `SIGNUP_LOGIN=true`", body = ErrorResponse),
+        (status = 403, description = "Invite code missing, invalid, expired or used up, or the CAPTCHA failed",
+            body = ErrorResponse),
+        (status = 409, description = "Email or username already registered, or a request with the same `Idempotency-Key` still in progress", body = ErrorResponse),
+        (status = 422, description = "Invalid email, password or username, or an `Idempotency-Key` used before with a different body", body = ErrorResponse),
+        (status = 429, description = "Rate limited", body = ErrorResponse),
+        (status = 503, description = "The CAPTCHA provider is unavailable", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all)]
+#[allow(clippy::too_many_arguments)]
+pub async fn signup_handler(
+    captcha: Captcha,
+    mailer: Mailer,
+    users: UserRepo,
+    users_collection: Collection<User>,
+    transactions: Transactions,
+    context: AuthContext,
+    sessions_collection: Collection<Session>,
+    invites_collection: Collection<Invite>,
+    client: ClientInfo,
+    idempotency: Idempotency,
+    body: SignupRequest,
+) -> WebResult<Response> {
+    // The response would carry the new session's tokens.
+    if config::signup_login() {
+        idempotency.refuse().map_err(reject::custom)?;
+    }
+    idempotency
+        .run(signup(
+            captcha,
+            mailer,
+            users,
+            users_collection,
+            transactions,
+            context,
+            sessions_collection,
+            invites_collection,
+            client,
+            body,
+        ))
+        .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn signup(
+    captcha: Captcha,
+    mailer: Mailer,
+    users: UserRepo,
+    users_collection: Collection<User>,
+    transactions: Transactions,
+    context: AuthContext,
+    sessions_collection: Collection<Session>,
+    invites_collection: Collection<Invite>,
+    client: ClientInfo,
+    body: SignupRequest,
+) -> WebResult<Response> {
+    // First, so that bots learn nothing, not even whether an email is
+    // registered. A replay of an idempotent signup doesn't get here and
+    // needs no fresh token.
+    captcha
+        .verify(body.captcha_token.as_deref(), client.ip.as_deref())
+        .await
+        .map_err(reject::custom)?;
+
+    let existing_user = users.find_by_email(&body.email).await?;
+
+    if existing_user.is_some() {
+        return Err(reject::custom(UserAlreadyExistsError));
+    }
+    if let Some(username) = &body.username {
+        if users.find_by_username(username).await?.is_some() {
+            return Err(reject::custom(UsernameTakenError));
+        }
+    }
+
+    let invite_code = match (config::require_invite(), body.invite_code) {
+        (false, _) => None,
+        (true, None) => return Err(reject::custom(InviteRequiredError)),
+        (true, Some(code)) => Some(code),
+    };
+
+    let hashed_pw = password::hash(&body.pw).map_err(reject::custom)?;
+    let (verification_token, verification_token_hash) = verification::create_verification_token();
+    let new_user = User {
+        email_verified: false,
+        verification_token_hash: Some(verification_token_hash),
+        username_lower: body.username.as_deref().map(str::to_lowercase),
+        username: body.username,
+        ..User::new(body.email, hashed_pw, &Role::User)
+    };
+
+    // The invite use and the account are written in one transaction, so a
+    // signup that fails never uses up the code. The pre-checks above are
+    // racy; the unique indexes settle concurrent signups for the same
+    // address or username.
+    let inserted = transactions
+        .run(
+            (
+                &invites_collection,
+                &users_collection,
+                &invite_code,
+                &new_user,
+            ),
+            |session, (invites_collection, users_collection, invite_code, new_user)| {
+                async move {
+                    if let Some(code) = invite_code {
+                        invites::consume(invites_collection, code, session).await?;
+                    }
+                    users::documents(users_collection)
+                        .insert_one_with_session(new_user.document(), None, session)
+                        .await?;
+                    Ok(())
+                }
+                .boxed()
+            },
+        )
+        .await;
+    if let Err(e) = inserted {
+        // Without transactions the invite may have been used up before the
+        // insert failed.
+        let refused_invite = matches!(
+            e,
+            InvalidInviteError | InviteExpiredError | InviteExhaustedError
+        );
+        if !transactions.atomic() && !refused_invite {
+            release_invite(&transactions, &invites_collection, &invite_code).await;
+        }
+        return Err(reject::custom(match e {
+            DuplicateKeyError => UserAlreadyExistsError,
+            other => other,
+        }));
+    }
+
+    // Without the email the account could never be verified, so undo the
+    // signup and let the client retry it.
+    if verification::send_verification_email(mailer.as_ref(), &new_user.email, &verification_token)
+        .await
+        .is_err()
+    {
+        transactions
+            .run(
+                (
+                    &invites_collection,
+                    &users_collection,
+                    &invite_code,
+                    &new_user.uid,
+                ),
+                |session, (invites_collection, users_collection, invite_code, uid)| {
+                    async move {
+                        users_collection
+                            .delete_one_with_session(doc! {"uid": &**uid}, None, session)
+                            .await?;
+                        if let Some(code) = invite_code {
+                            invites::release(invites_collection, code, session).await?;
+                        }
+                        Ok(())
+                    }
+                    .boxed()
+                },
+            )
+            .await
+            .map_err(reject::custom)?;
+        return Err(reject::custom(EmailDeliveryError));
+    }
+
+    metrics::record_signup();
+    audit::record(
+        AuditEvent::new(AuditAction::Signup, &client)
+            .actor(&new_user.uid)
+            .target(&new_user.uid),
+    );
+    events::publish(AdminEventKind::Signup, Some(&new_user.uid));
+    webhooks::dispatch(WebhookEvent::UserSignedUp, &new_user, None);
+    let session = if config::signup_login() {
+        let session =
+            start_session(&context, &sessions_collection, &new_user, &client, false).await?;
+        audit_login(&client, &new_user, "signup");
+        Some(session)
+    } else {
+        None
+    };
+    let token = session.as_ref().map(|session| session.token.clone());
+    let location = format!("{}/users/{}", config::api_prefix(), new_user.uid);
+    let response = reply::json(&SignupResponse {
+        user: UserResponse::from(new_user),
+        session,
+    });
+    let response = reply::with_header(response, LOCATION, location);
+    let mut response = reply::with_status(response, StatusCode::CREATED).into_response();
+    if let Some(token) = token {
+        set_session_cookies(&context, &mut response, &token, false);
+    }
+    Ok(response)
+}
+
+/// Gives back the use of `invite_code` taken by a signup that failed.
+async fn release_invite(
+    transactions: &Transactions,
+    invites_collection: &Collection<Invite>,
+    invite_code: &Option<String>,
+) {
+    let Some(code) = invite_code else {
+        return;
+    };
+    let released = transactions
+        .run(
+            (invites_collection, code),
+            |session, (invites_collection, code)| {
+                invites::release(invites_collection, code, session).boxed()
+            },
+        )
+        .await;
+    if let Err(e) = released {
+        tracing::error!("giving back an invite use failed: {:?}", e);
+    }
+}
+
+/// A hash of a random password with the configured parameters, which
+/// `login_handler` checks against when the identifier is unknown so that
+/// answer takes as long as a wrong password. Computed once; `main` calls
+/// this at startup so the first such login is not slower.
+pub fn dummy_password_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH
+        .get_or_init(|| password::hash(&auth::random_token(32)).expect("hashing a password failed"))
+}
+
+/// Checks `pw` against `user`'s password, or against
+/// [`dummy_password_hash`] when no account matched, so that how long a
+/// login takes does not reveal whether the email or username is
+/// registered: either way it is one verification. An unusable stored hash
+/// is logged by `check` and counts as a wrong password.
+fn check_login_password(user: Option<&User>, pw: &str) -> Verification {
+    let hash = match user {
+        Some(user) => &user.pw,
+        None => dummy_password_hash(),
+    };
+    match password::check(pw, hash) {
+        Ok(verification) if user.is_some() => verification,
+        _ => Verification::Mismatch,
+    }
+}
+
+/// The account `identifier` signs in to. Deleted accounts are left out,
+/// though they keep their email until they are purged.
+async fn find_login_account(users: &UserRepo, identifier: &str) -> Result<Option<User>> {
+    Ok(users
+        .find_by_login(identifier)
+        .await?
+        .filter(|user| user.deleted_at.is_none()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/login",
+    tag = "login",
+    request_body = LoginRequest,
+    responses(
+        (status = 200, description = "Signed in, a second factor is required, or the password must be changed first",
+            body = LoginResult),
+        (status = 403, description = "Wrong credentials, unverified email or disabled account",
+            body = ErrorResponse),
+        (status = 415, description = "Body neither JSON nor form-encoded", body = ErrorResponse),
+        (status = 429, description = "Too many attempts", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all)]
+pub async fn login_handler(
+    context: AuthContext,
+    lockout: LoginLockout,
+    users: UserRepo,
+    sessions_collection: Collection<Session>,
+    pending_logins_collection: Collection<PendingLogin>,
+    client: ClientInfo,
+    body: LoginRequest,
+) -> WebResult<impl Reply> {
+    let user = find_login_account(&users, &body.identifier).await?;
+    // One count per account, whether it is addressed by email or username.
+    let lockout_key = user.as_ref().map_or_else(
+        || body.identifier.clone(),
+        |user| users::normalize_email(&user.email),
+    );
+    if let Err(e) = lockout.check(&lockout_key).await {
+        if matches!(e, AccountLockedError) {
+            audit_login_failure(&client, user.as_ref(), "account locked");
+        }
+        return Err(reject::custom(e));
+    }
+
+    let verification = check_login_password(user.as_ref(), &body.pw);
+    if let Some(user_data) = user {
+        if verification != Verification::Mismatch {
+            lockout.reset(&lockout_key).await.map_err(reject::custom)?;
+            if !user_data.active {
+                audit_login_failure(&client, Some(&user_data), "account deactivated");
+                return Err(reject::custom(AccountDisabledError));
+            }
+            if user_data.is_banned() {
+                audit_login_failure(&client, Some(&user_data), "account banned");
+                return Err(reject::custom(users::ban_error(&user_data)));
+            }
+            if user_data.banned_at.is_some() {
+                users::lift_expired_ban(&users, &user_data, &client)
+                    .await
+                    .map_err(reject::custom)?;
+            }
+            if !user_data.email_verified {
+                audit_login_failure(&client, Some(&user_data), "email not verified");
+                return Err(reject::custom(EmailNotVerifiedError));
+            }
+            access_log::set_uid(&user_data.uid);
+            metrics::record_login(true);
+            // Recorded before the second factor, so a user with 2FA still
+            // sees that someone got their password right.
+            users::record_login(&users, &user_data.uid);
+            if verification == Verification::Outdated {
+                users::upgrade_password_hash(&users, &user_data, &body.pw);
+            }
+            if user_data.totp_enabled {
+                return two_factor::start_pending_login(
+                    &pending_logins_collection,
+                    &user_data,
+                    body.remember_me,
+                )
+                .await;
+            }
+
+            issue_session(
+                &context,
+                &sessions_collection,
+                &user_data,
+                &client,
+                body.remember_me,
+                "password",
+            )
+            .await
+        } else {
+            audit_login_failure(&client, Some(&user_data), "wrong password");
+            metrics::record_login(false);
+            if lockout
+                .record_failure(&lockout_key)
+                .await
+                .map_err(reject::custom)?
+            {
+                events::publish(AdminEventKind::LockedOut, Some(&user_data.uid));
+            }
+            Err(reject::custom(WrongCredentialsError))
+        }
+    } else {
+        audit_login_failure(&client, None, "unknown account");
+        metrics::record_login(false);
+        if lockout
+            .record_failure(&lockout_key)
+            .await
+            .map_err(reject::custom)?
+        {
+            events::publish(AdminEventKind::LockedOut, None);
+        }
+        Err(reject::custom(WrongCredentialsError))
+    }
+}
+
+/// Audits a sign-in by `user` that has issued tokens, made with `method`,
+/// and announces it on `GET /events`, along with `new_device` when it
+/// comes from somewhere new.
+pub fn audit_login(client: &ClientInfo, user: &User, method: &str) {
+    let event = AuditEvent::new(AuditAction::LoginSucceeded, client)
+        .actor(&user.uid)
+        .target(&user.uid)
+        .detail(method);
+    audit::flag_new_device(&event);
+    audit::record(event);
+    events::publish(AdminEventKind::Login, Some(&user.uid));
+}
+
+/// Audits a failed sign-in. The submitted identifier is left out, since
+/// users sometimes type their password into it.
+pub fn audit_login_failure(client: &ClientInfo, user: Option<&User>, reason: &str) {
+    let event = AuditEvent::new(AuditAction::LoginFailed, client).detail(reason);
+    audit::record(match user {
+        Some(user) => event.target(&user.uid),
+        None => event,
+    });
+}
+
+/// Creates an access token and refresh session for a fully authenticated
+/// user, setting the auth cookies when cookie auth is enabled, and audits
+/// the sign-in as made with `method`. With `remember_me` the session's
+/// refresh tokens last `REMEMBER_ME_TTL_DAYS`; the access token's lifetime
+/// is the same either way. An account that must change its password gets
+/// only a [`PasswordChangeRequiredResponse`] instead, whichever way it
+/// signed in.
+pub async fn issue_session(
+    context: &AuthContext,
+    sessions_collection: &Collection<Session>,
+    user: &User,
+    client: &ClientInfo,
+    remember_me: bool,
+    method: &str,
+) -> WebResult<reply::Response> {
+    if user.must_change_password {
+        return password_change_required(context, user);
+    }
+    let response =
+        session_response(context, sessions_collection, user, client, remember_me).await?;
+    audit_login(client, user, method);
+    Ok(response)
+}
+
+/// [`start_session`]'s tokens as the response, with the auth cookies.
+async fn session_response(
+    context: &AuthContext,
+    sessions_collection: &Collection<Session>,
+    user: &User,
+    client: &ClientInfo,
+    remember_me: bool,
+) -> WebResult<reply::Response> {
+    let session = start_session(context, sessions_collection, user, client, remember_me).await?;
+    let mut response = reply::json(&session).into_response();
+    set_session_cookies(context, &mut response, &session.token, remember_me);
+    Ok(response)
+}
+
+fn password_change_required(context: &AuthContext, user: &User) -> WebResult<reply::Response> {
+    let access = create_password_change_jwt(
+        context,
+        &user.uid,
+        &context
+            .roles()
+            .resolve(&user.uid, &user.role)
+            .map_err(reject::custom)?,
+        user.token_version,
+    )
+    .map_err(reject::custom)?;
+    Ok(reply::json(&PasswordChangeRequiredResponse {
+        password_change_required: true,
+        password_change_token: access.token,
+        expires_in: access.expires_in,
+    })
+    .into_response())
+}
+
+/// The tokens of [`issue_session`], for responses that carry more.
+#[tracing::instrument(skip_all, fields(uid = %user.uid))]
+pub async fn start_session(
+    context: &AuthContext,
+    sessions_collection: &Collection<Session>,
+    user: &User,
+    client: &ClientInfo,
+    remember_me: bool,
+) -> WebResult<LoginResponse> {
+    // Every way of signing in ends here, deactivated and banned accounts
+    // included, as do accounts that must change their password: those only
+    // get the token for that, from `issue_session`.
+    if !user.active {
+        return Err(reject::custom(AccountDisabledError));
+    }
+    if user.is_banned() {
+        return Err(reject::custom(users::ban_error(user)));
+    }
+    if user.must_change_password {
+        return Err(reject::custom(PasswordChangeRequiredError));
+    }
+    let access = create_jwt(
+        context,
+        &user.uid,
+        &context
+            .roles()
+            .resolve(&user.uid, &user.role)
+            .map_err(reject::custom)?,
+        user.token_version,
+    )
+    .map_err(reject::custom)?;
+
+    let refresh_token = sessions::start(
+        sessions_collection,
+        &user.uid,
+        client,
+        &access.jti,
+        remember_me,
+    )
+    .await
+    .map_err(reject::custom)?;
+
+    Ok(LoginResponse {
+        token: access.token,
+        token_type: TOKEN_TYPE,
+        expires_in: access.expires_in,
+        refresh_token,
+    })
+}
+
+fn set_session_cookies(
+    context: &AuthContext,
+    response: &mut reply::Response,
+    token: &str,
+    remember_me: bool,
+) {
+    if context.cookie_auth() {
+        let headers = response.headers_mut();
+        headers.append(SET_COOKIE, context.session_cookie(token));
+        headers.append(
+            SET_COOKIE,
+            context.csrf_cookie(
+                &create_csrf_token(),
+                sessions::refresh_token_ttl(remember_me),
+            ),
+        );
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/refresh",
+    tag = "sessions",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, description = "New tokens; the presented refresh token is used up", body = RefreshResponse),
+        (status = 401, description = "Unknown, expired or reused refresh token", body = ErrorResponse),
+    )
+)]
+pub async fn refresh_handler(
+    context: AuthContext,
+    users_collection: Collection<User>,
+    sessions_collection: Collection<Session>,
+    client: ClientInfo,
+    body: RefreshRequest,
+) -> WebResult<impl Reply> {
+    let session = sessions::consume(&sessions_collection, &body.refresh_token)
+        .await
+        .map_err(reject::custom)?;
+
+    let user = timed(users_collection.find_one(users::active(doc! {"uid": &session.uid}), None))
+        .await
+        .map_err(reject::custom)?
+        .ok_or_else(|| reject::custom(InvalidRefreshTokenError))?;
+    if !user.active {
+        return Err(reject::custom(AccountDisabledError));
+    }
+    if user.is_banned() {
+        return Err(reject::custom(users::ban_error(&user)));
+    }
+
+    let access = create_jwt(
+        &context,
+        &user.uid,
+        &context
+            .roles()
+            .resolve(&user.uid, &user.role)
+            .map_err(reject::custom)?,
+        user.token_version,
+    )
+    .map_err(reject::custom)?;
+    let refresh_token =
+        sessions::continue_family(&sessions_collection, session, &client, &access.jti)
+            .await
+            .map_err(reject::custom)?;
+
+    let mut response = reply::json(&RefreshResponse {
+        token: access.token.clone(),
+        token_type: TOKEN_TYPE,
+        expires_in: access.expires_in,
+        refresh_token,
+    })
+    .into_response();
+    if context.cookie_auth() {
+        response
+            .headers_mut()
+            .insert(SET_COOKIE, context.session_cookie(&access.token));
+    }
+    Ok(response)
+}
+
+#[utoipa::path(
+    post,
+    path = "/logout",
+    tag = "sessions",
+    responses(
+        (status = 200, description = "Token revoked and its session ended", body = String),
+        (status = 401, description = "Missing or invalid token", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn logout_handler(
+    context: AuthContext,
+    claims: Claims,
+    sessions_collection: Collection<Session>,
+) -> WebResult<impl Reply> {
+    context.revoke(&claims).await.map_err(reject::custom)?;
+
+    // The refresh token issued with this access token, and every other
+    // link of its rotation family, must not mint new access tokens.
+    let session = timed(
+        sessions_collection.find_one(doc! {"uid": &claims.sub, "access_jti": &claims.jti}, None),
+    )
+    .await
+    .map_err(reject::custom)?;
+    if let Some(session) = session {
+        timed(sessions_collection.delete_many(
+            doc! {"uid": &claims.sub, "family_id": &session.family_id},
+            None,
+        ))
+        .await
+        .map_err(reject::custom)?;
+    }
+
+    let mut response =
+        reply::with_status("Logged out successfully", StatusCode::OK).into_response();
+    if context.cookie_auth() {
+        let headers = response.headers_mut();
+        headers.append(SET_COOKIE, context.clear_session_cookie());
+        headers.append(SET_COOKIE, context.clear_csrf_cookie());
+    }
+    Ok(response)
+}
+
+#[utoipa::path(
+    post,
+    path = "/logout-all",
+    tag = "sessions",
+    responses(
+        (status = 200, description = "Every token and session of the account invalidated", body = String),
+        (status = 401, description = "Missing or invalid token", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn logout_all_handler(
+    context: AuthContext,
+    claims: Claims,
+    sessions_collection: Collection<Session>,
+) -> WebResult<impl Reply> {
+    context
+        .bump_token_version(&claims.sub)
+        .await
+        .map_err(reject::custom)?;
+    timed(sessions_collection.delete_many(doc! {"uid": &claims.sub}, None))
+        .await
+        .map_err(reject::custom)?;
+
+    let mut response =
+        reply::with_status("Logged out on all devices", StatusCode::OK).into_response();
+    if context.cookie_auth() {
+        let headers = response.headers_mut();
+        headers.append(SET_COOKIE, context.clear_session_cookie());
+        headers.append(SET_COOKIE, context.clear_csrf_cookie());
+    }
+    Ok(response)
+}
+
+#[utoipa::path(
+    get,
+    path = "/.well-known/jwks.json",
+    tag = "keys",
+    responses((status = 200, description = "Public keys for RS256 tokens; empty with HMAC", body = auth::JwkSet))
+)]
+pub async fn jwks_handler(context: AuthContext) -> WebResult<impl Reply> {
+    Ok(reply::json(&context.jwks()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/user",
+    tag = "account",
+    responses(
+        (status = 200, description = "Greeting", body = String),
+        (status = 401, description = "Missing or invalid token or API key", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []), ("api_key" = []))
+)]
+pub async fn user_handler(claims: Claims) -> WebResult<impl Reply> {
+    Ok(format!("Hello User {}", claims.sub))
+}
+
+#[utoipa::path(
+    get,
+    path = "/welcome",
+    tag = "account",
+    responses((status = 200, description = "Greeting for the user or a guest", body = String)),
+    security((), ("bearer_auth" = []))
+)]
+pub async fn welcome_handler(claims: Option<Claims>) -> WebResult<impl Reply> {
+    match claims {
+        Some(claims) => Ok(format!("Hello User {}", claims.sub)),
+        None => Ok("Hello Guest".to_string()),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/me",
+    tag = "profile",
+    responses(
+        (status = 200, description = "The caller's account", body = UserResponse,
+            headers(("ETag" = String, description = "The account's version, for `If-Match`"))),
+        (status = 401, description = "Missing or invalid token", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn me_handler(
+    claims: Claims,
+    users_collection: Collection<User>,
+) -> WebResult<impl Reply> {
+    let user = timed(users_collection.find_one(doc! {"uid": &claims.sub}, None))
+        .await
+        .map_err(reject::custom)?
+        .ok_or_else(|| reject::custom(UserNotFoundError))?;
+
+    let version = user.version;
+    Ok(etag::with_etag(
+        reply::json(&UserResponse::from(user)),
+        version,
+    ))
+}
+
+/// Changes the caller's password. Every existing session and access token is
+/// invalidated so a stolen session can't outlive the change; the response
+/// carries fresh tokens for the caller. Also takes the token a login hands
+/// out when the password must be changed; that change gets no tokens, so
+/// the user then signs in normally, second factor included.
+#[utoipa::path(
+    put,
+    path = "/me/password",
+    tag = "profile",
+    request_body = ChangePasswordRequest,
+    responses(
+        (status = 200, description = "Password changed; fresh tokens", body = LoginResponse),
+        (status = 204, description = "Password changed with a `password_change_token`"),
+        (status = 403, description = "Wrong `old_pw`, or a `remember_me` session with \
+                                        `REQUIRE_FRESH_LOGIN`", body = ErrorResponse),
+        (status = 422, description = "New password too short, one of the recent passwords, or the same \
+                                        as a password that must change", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn change_password_handler(
+    claims: Claims,
+    context: AuthContext,
+    users_collection: Collection<User>,
+    sessions_collection: Collection<Session>,
+    client: ClientInfo,
+    body: ChangePasswordRequest,
+) -> WebResult<impl Reply> {
+    // Support acting as a user must not be able to take over the account.
+    if claims.impersonator.is_some() {
+        return Err(reject::custom(ImpersonationForbiddenError));
+    }
+    sessions::require_fresh_login(&sessions_collection, &claims)
+        .await
+        .map_err(reject::custom)?;
+    let user = timed(users_collection.find_one(doc! {"uid": &claims.sub}, None))
+        .await
+        .map_err(reject::custom)?
+        .ok_or_else(|| reject::custom(UserNotFoundError))?;
+
+    let is_password_correct = password::verify(&body.old_pw, &user.pw).map_err(reject::custom)?;
+    if !is_password_correct {
+        return Err(reject::custom(WrongCredentialsError));
+    }
+
+    let mut validator = Validator::new();
+    validator.password("new_pw", &body.new_pw);
+    if user.must_change_password && body.new_pw == body.old_pw {
+        validator.fail("new_pw", "must differ from the current password");
+    }
+    validator.finish().map_err(reject::custom)?;
+    if password::recently_used(&body.new_pw, &user) {
+        return Err(reject::custom(PasswordRecentlyUsedError));
+    }
+
+    let hashed_pw = password::hash(&body.new_pw).map_err(reject::custom)?;
+    timed(users_collection.update_one(
+        doc! {"uid": &user.uid},
+        doc! {
+            "$set": {
+                "pw": &hashed_pw,
+                "must_change_password": false,
+                "updated_at": DateTime::now(),
+            },
+            "$push": password::history_push(&hashed_pw),
+            "$inc": {"version": 1},
+        },
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?;
+    audit::record(
+        AuditEvent::new(AuditAction::PasswordChanged, &client)
+            .actor(&user.uid)
+            .target(&user.uid),
+    );
+
+    timed(sessions_collection.delete_many(doc! {"uid": &user.uid}, None))
+        .await
+        .map_err(reject::custom)?;
+    let user = context
+        .bump_token_version(&user.uid)
+        .await
+        .map_err(reject::custom)?;
+
+    if claims.purpose.is_some() {
+        return Ok(reply::with_status(reply(), StatusCode::NO_CONTENT).into_response());
+    }
+    session_response(&context, &sessions_collection, &user, &client, false).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin",
+    tag = "account",
+    responses(
+        (status = 200, description = "Greeting", body = String),
+        (status = 401, description = "Missing or invalid token", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn admin_handler(claims: Claims) -> WebResult<impl Reply> {
+    Ok(format!("Hello Admin {}", claims.sub))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        repository::{InMemoryUserRepository, UserRepository},
+        server::RemoteAddr,
+        test_support,
+    };
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use warp::test::RequestBuilder;
+
+    /// Sends `request` through the whole filter tree from a client at
+    /// 192.0.2.1, returning the status and the body as JSON.
+    async fn send(app: &AppState, request: RequestBuilder) -> (StatusCode, Value) {
+        let response = request
+            .extension(RemoteAddr(([192, 0, 2, 1], 40000).into()))
+            .reply(&routes(app.clone()))
+            .await;
+        let body = serde_json::from_slice(response.body()).unwrap_or(Value::Null);
+        (response.status(), body)
+    }
+
+    fn post(path: &str, body: &Value) -> RequestBuilder {
+        warp::test::request()
+            .method("POST")
+            .path(&format!("/api/v1{}", path))
+            .json(body)
+    }
+
+    /// Creates an account for `email` with `pw` in `app`'s database.
+    async fn create_user(app: &AppState, email: &str, pw: &str) {
+        let hash = password::hash(pw).unwrap();
+        let user = User::new(email.to_string(), hash, &Role::User);
+        app.users.insert_one(&user, None).await.unwrap();
+    }
+
+    /// The error body's status and code.
+    fn error(body: &Value) -> (u64, &str) {
+        (
+            body["status"].as_u64().unwrap(),
+            body["code"].as_str().unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn a_malformed_login_body_is_invalid_body() {
+        let app = test_support::offline_app().await;
+        let request = warp::test::request()
+            .method("POST")
+            .path("/api/v1/login")
+            .header("content-type", "application/json")
+            .body("{\"identifier\":");
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error(&body), (400, "INVALID_BODY"));
+    }
+
+    /// `POST /login` with `identifier` and `pw` as JSON and as a form, with
+    /// the request ids, which always differ, left out of the answers.
+    async fn login_as_json_and_as_form(
+        app: &AppState,
+        identifier: &str,
+        pw: &str,
+    ) -> [(StatusCode, Value); 2] {
+        let as_json = post("/login", &json!({"identifier": identifier, "pw": pw}));
+        let form = serde_urlencoded::to_string([("identifier", identifier), ("pw", pw)]).unwrap();
+        let as_form = warp::test::request()
+            .method("POST")
+            .path("/api/v1/login")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(form);
+        let mut answers = [send(app, as_json).await, send(app, as_form).await];
+        for (_, body) in &mut answers {
+            if let Some(body) = body.as_object_mut() {
+                body.remove("request_id");
+            }
+        }
+        answers
+    }
+
+    #[tokio::test]
+    async fn a_login_form_is_refused_like_the_same_json() {
+        let app = test_support::offline_app().await;
+        let [json, form] = login_as_json_and_as_form(&app, "a@example.com", "").await;
+        assert_eq!(json.0, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(json, form);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn a_login_form_is_answered_like_the_same_json() {
+        let app = test_support::app().await;
+        create_user(&app, "a@example.com", "a long password").await;
+
+        let [json, form] = login_as_json_and_as_form(&app, "a@example.com", "the wrong one").await;
+        assert_eq!(json.0, StatusCode::FORBIDDEN);
+        assert_eq!(json, form);
+
+        // Tokens differ from one login to the next; the rest must not.
+        let [json, form] =
+            login_as_json_and_as_form(&app, "a@example.com", "a long password").await;
+        assert_eq!(json.0, StatusCode::OK);
+        assert_eq!(json.0, form.0);
+        for field in ["token_type", "expires_in"] {
+            assert_eq!(json.1[field], form.1[field], "{}", field);
+        }
+        assert!(form.1["token"].is_string());
+        assert!(form.1["refresh_token"].is_string());
+    }
+
+    /// An offline app whose accounts are `repo`, with one deleted account
+    /// for `a@example.com`.
+    async fn app_with_a_deleted_account(repo: Arc<InMemoryUserRepository>) -> AppState {
+        let mut user = User::new(
+            "a@example.com".to_string(),
+            password::hash("a long password").unwrap(),
+            &Role::User,
+        );
+        user.deleted_at = Some(DateTime::now());
+        repo.insert(&user).await.unwrap();
+        let app = test_support::offline_app().await;
+        AppState {
+            user_repo: repo,
+            ..app
+        }
+    }
+
+    #[tokio::test]
+    async fn a_deleted_account_cannot_be_signed_in_to() {
+        let repo = Arc::new(InMemoryUserRepository::new());
+        let app = app_with_a_deleted_account(repo.clone()).await;
+        for identifier in ["a@example.com", "A@Example.com"] {
+            let account = find_login_account(&app.user_repo, identifier)
+                .await
+                .unwrap();
+            assert!(account.is_none(), "{}", identifier);
+        }
+        assert!(repo.find_by_email("a@example.com").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn a_deleted_account_keeps_its_email_reserved() {
+        let repo = Arc::new(InMemoryUserRepository::new());
+        let app = app_with_a_deleted_account(repo.clone()).await;
+        let request = post(
+            "/signup",
+            &json!({"email": "A@example.com", "pw": "another long password"}),
+        );
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(error(&body), (409, "USER_ALREADY_EXISTS"));
+
+        let again = User::new("a@example.com".to_string(), String::new(), &Role::User);
+        assert!(matches!(repo.insert(&again).await, Err(DuplicateKeyError)));
+    }
+
+    #[tokio::test]
+    async fn a_blank_login_password_fails_validation() {
+        let app = test_support::offline_app().await;
+        let request = post("/login", &json!({"identifier": "a@example.com", "pw": ""}));
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error(&body), (422, "VALIDATION_FAILED"));
+    }
+
+    #[tokio::test]
+    async fn an_invalid_signup_email_fails_validation() {
+        let app = test_support::offline_app().await;
+        let request = post(
+            "/signup",
+            &json!({"email": "nope", "pw": "a long password"}),
+        );
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error(&body), (422, "VALIDATION_FAILED"));
+    }
+
+    #[tokio::test]
+    async fn a_short_signup_password_fails_validation() {
+        let app = test_support::offline_app().await;
+        let request = post("/signup", &json!({"email": "a@example.com", "pw": "short"}));
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error(&body), (422, "VALIDATION_FAILED"));
+    }
+
+    #[tokio::test]
+    async fn a_wrong_field_type_or_an_empty_body_is_invalid_body() {
+        let app = test_support::offline_app().await;
+        for path in ["/login", "/signup"] {
+            let wrong_type = post(
+                path,
+                &json!({"identifier": "a@example.com", "email": "a@example.com", "pw": 123}),
+            );
+            let empty = warp::test::request()
+                .method("POST")
+                .path(&format!("/api/v1{}", path))
+                .header("content-type", "application/json");
+            for request in [wrong_type, empty] {
+                let (status, body) = send(&app, request).await;
+                assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+                assert_eq!(error(&body), (400, "INVALID_BODY"), "{}", path);
+                assert!(body["message"].is_string(), "{}", path);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn every_invalid_field_is_reported_at_once() {
+        let app = test_support::offline_app().await;
+        let request = post(
+            "/signup",
+            &json!({"email": "nope", "pw": "short", "username": "a b"}),
+        );
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error(&body), (422, "VALIDATION_FAILED"));
+        let errors = body["errors"].as_object().unwrap();
+        let mut fields: Vec<&str> = errors.keys().map(String::as_str).collect();
+        fields.sort();
+        assert_eq!(fields, ["email", "pw", "username"]);
+        assert!(errors.values().all(|messages| messages[0].is_string()));
+
+        let request = post("/login", &json!({"identifier": "", "pw": ""}));
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let errors = body["errors"].as_object().unwrap();
+        assert!(errors.contains_key("identifier") && errors.contains_key("pw"));
+    }
+
+    #[tokio::test]
+    async fn a_signup_without_a_password_is_invalid_body() {
+        let app = test_support::offline_app().await;
+        let request = post("/signup", &json!({"email": "a@example.com"}));
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error(&body), (400, "INVALID_BODY"));
+    }
+
+    #[tokio::test]
+    async fn warp_rejections_get_codes_too() {
+        let app = test_support::offline_app().await;
+        let (status, body) = send(&app, warp::test::request().path("/no/such/path")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(error(&body), (404, "NOT_FOUND"));
+
+        let (status, body) = send(&app, warp::test::request().path("/api/v1/login")).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(error(&body), (405, "METHOD_NOT_ALLOWED"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn a_wrong_password_is_wrong_credentials() {
+        let app = test_support::app().await;
+        create_user(&app, "a@example.com", "the right password").await;
+        let request = post(
+            "/login",
+            &json!({"identifier": "a@example.com", "pw": "the wrong password"}),
+        );
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(error(&body), (403, "WRONG_CREDENTIALS"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn a_corrupt_stored_role_is_a_server_error() {
+        let app = test_support::app().await;
+        let hash = password::hash("a long password").unwrap();
+        let mut user = User::new("a@example.com".to_string(), hash, &Role::User);
+        user.role = "Superuser".to_string();
+        app.users.insert_one(&user, None).await.unwrap();
+        let request = post(
+            "/login",
+            &json!({"identifier": "a@example.com", "pw": "a long password"}),
+        );
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error(&body), (500, "INVALID_ROLE_DATA"));
+    }
+
+    #[test]
+    fn a_login_checks_one_password_whether_or_not_the_account_exists() {
+        let hash = password::hash("a long password").unwrap();
+        let user = User::new("a@example.com".to_string(), hash, &Role::User);
+        let logins = [
+            (Some(&user), "a long password", Verification::Match),
+            (Some(&user), "another password", Verification::Mismatch),
+            (None, "a long password", Verification::Mismatch),
+        ];
+        for (account, pw, expected) in logins {
+            let before = password::CHECKS.with(|checks| checks.get());
+            assert!(check_login_password(account, pw) == expected);
+            assert_eq!(password::CHECKS.with(|checks| checks.get()) - before, 1);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn an_unknown_account_is_wrong_credentials() {
+        let app = test_support::app().await;
+        let request = post(
+            "/login",
+            &json!({"identifier": "nobody@example.com", "pw": "a password"}),
+        );
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(error(&body), (403, "WRONG_CREDENTIALS"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn a_taken_email_is_user_already_exists() {
+        let app = test_support::app().await;
+        create_user(&app, "a@example.com", "a long password").await;
+        let request = post(
+            "/signup",
+            &json!({"email": "A@example.com", "pw": "another long password"}),
+        );
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(error(&body), (409, "USER_ALREADY_EXISTS"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn a_signup_creates_the_account() {
+        let app = test_support::app().await;
+        let request = post(
+            "/signup",
+            &json!({"email": "New@Example.com", "pw": "a long password", "role": "Admin"}),
+        );
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["email"], "new@example.com");
+        assert_eq!(body["role"], "User");
+
+        let user = app
+            .users
+            .find_one(doc! {"uid": body["uid"].as_str().unwrap()}, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(password::verify("a long password", &user.pw).unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn a_refresh_token_stops_working_at_logout() {
+        let app = test_support::app().await;
+        create_user(&app, "a@example.com", "a long password").await;
+        let request = post(
+            "/login",
+            &json!({"identifier": "a@example.com", "pw": "a long password"}),
+        );
+        let (status, login) = send(&app, request).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let request = post("/logout", &json!({})).header(
+            "authorization",
+            format!("Bearer {}", login["token"].as_str().unwrap()),
+        );
+        let (status, _) = send(&app, request).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let request = post(
+            "/refresh",
+            &json!({"refresh_token": login["refresh_token"]}),
+        );
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(error(&body), (401, "INVALID_REFRESH_TOKEN"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn roles_with_privileged_scopes_cannot_be_impersonated() {
+        let app = test_support::app().await;
+        let admin = User::new("admin@example.com".to_string(), String::new(), &Role::Admin);
+        app.users.insert_one(&admin, None).await.unwrap();
+        let token = auth::create_jwt(&app.auth_context, &admin.uid, &Role::Admin, 0)
+            .unwrap()
+            .token;
+        app.auth_context.roles().insert(roles::RoleDefinition {
+            name: "support".to_string(),
+            permissions: vec![scopes::USERS_READ.to_string()],
+        });
+        let support = Role::Custom("support".to_string());
+        let target = User::new("support@example.com".to_string(), String::new(), &support);
+        app.users.insert_one(&target, None).await.unwrap();
+
+        let request = post(&format!("/users/{}/impersonate", target.uid), &json!({}))
+            .header("authorization", format!("Bearer {}", token));
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(error(&body), (403, "CANNOT_IMPERSONATE_ADMIN"));
+    }
+
+    /// Whether `body` carries a password hash or a field named for one.
+    fn leaks_a_password_hash(body: &str) -> bool {
+        ["\"pw\"", "\"pw_history\"", "$2b$", "$argon2"]
+            .iter()
+            .any(|needle| body.contains(needle))
+    }
+
+    /// An account with both kinds of hash, current and in its history.
+    fn user_with_hashes() -> User {
+        let argon2 = password::hash("a long password").unwrap();
+        let bcrypt = bcrypt::hash("an older password", 4).unwrap();
+        assert!(argon2.starts_with("$argon2") && bcrypt.starts_with("$2b$"));
+        User {
+            pw_history: vec![argon2.clone(), bcrypt],
+            ..User::new("a@example.com".to_string(), argon2, &Role::User)
+        }
+    }
+
+    #[test]
+    fn no_user_body_carries_a_password_hash() {
+        let user = user_with_hashes();
+        let response = || UserResponse::from(user.clone());
+        let session = LoginResponse {
+            token: "token".to_string(),
+            token_type: "Bearer",
+            expires_in: 3600,
+            refresh_token: "refresh".to_string(),
+        };
+        let bodies = [
+            serde_json::to_string(&user).unwrap(),
+            serde_json::to_string(&response()).unwrap(),
+            serde_json::to_string(&vec![response(), response()]).unwrap(),
+            serde_json::to_string(&pagination::Page {
+                items: vec![response()],
+                next_cursor: None,
+                has_more: false,
+            })
+            .unwrap(),
+            serde_json::to_string(&SignupResponse {
+                user: response(),
+                session: None,
+            })
+            .unwrap(),
+            serde_json::to_string(&SignupResponse {
+                user: response(),
+                session: Some(session),
+            })
+            .unwrap(),
+        ];
+        for body in bodies {
+            assert!(!leaks_a_password_hash(&body), "{}", body);
+        }
+        // While the stored document has them.
+        let stored = serde_json::to_string(&user.document()).unwrap();
+        assert!(leaks_a_password_hash(&stored));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn the_personal_export_carries_no_password_hash() {
+        let app = test_support::app().await;
+        let user = user_with_hashes();
+        users::documents(&app.users)
+            .insert_one(user.document(), None)
+            .await
+            .unwrap();
+        let token = create_jwt(&app.auth_context, &user.uid, &Role::User, 0).unwrap();
+        let request = warp::test::request()
+            .path("/api/v1/me/export")
+            .header("authorization", format!("Bearer {}", token.token));
+        let response = request
+            .extension(RemoteAddr(([192, 0, 2, 1], 40000).into()))
+            .reply(&routes(app.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = String::from_utf8_lossy(response.body());
+        assert!(body.contains("a@example.com"));
+        assert!(!leaks_a_password_hash(&body), "{}", body);
+    }
+
+    /// Serves an offline app on a free port of 127.0.0.1 until `stopped`.
+    async fn serve_on_a_free_port(
+        stopped: tokio::sync::oneshot::Receiver<()>,
+    ) -> (std::net::SocketAddr, tokio::task::JoinHandle<()>) {
+        let app =
+            test_support::offline_app_with(&[("BIND_ADDR", "127.0.0.1"), ("PORT", "0")]).await;
+        let (addr, server) = start_server(app, async {
+            stopped.await.ok();
+        })
+        .unwrap();
+        let ListenAddr::Tcp(addr) = addr else {
+            panic!("bound a unix socket");
+        };
+        (addr, tokio::spawn(server))
+    }
+
+    #[tokio::test]
+    async fn the_server_answers_on_the_port_it_bound() {
+        let (stop, stopped) = tokio::sync::oneshot::channel();
+        let (addr, server) = serve_on_a_free_port(stopped).await;
+        assert_ne!(addr.port(), 0);
+
+        let response = reqwest::get(format!("http://{}/health", addr))
+            .await
+            .unwrap();
+        // Up, though its database is not.
+        assert_eq!(response.status().as_u16(), 503);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["mongo"], "down");
+
+        stop.send(()).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), server)
+            .await
+            .expect("the server did not shut down")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn two_servers_get_ports_of_their_own() {
+        let (stop_first, stopped) = tokio::sync::oneshot::channel();
+        let (first, _) = serve_on_a_free_port(stopped).await;
+        let (stop_second, stopped) = tokio::sync::oneshot::channel();
+        let (second, _) = serve_on_a_free_port(stopped).await;
+        assert_ne!(first.port(), second.port());
+        stop_first.send(()).unwrap();
+        stop_second.send(()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_port_in_use_is_an_error() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port().to_string();
+        let app =
+            test_support::offline_app_with(&[("BIND_ADDR", "127.0.0.1"), ("PORT", &port)]).await;
+        assert!(start_server(app, std::future::pending()).is_err());
+    }
+}
@@ -0,0 +1,419 @@
+use crate::{error::Error, repository::timed, Result};
+use mongodb::{
+    bson::{doc, DateTime},
+    options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument},
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, str::FromStr, time::Duration};
+use warp::Filter;
+
+pub const DEFAULT_MAX_FAILURES: u32 = 5;
+const LOCK_DURATION: Duration = Duration::from_secs(15 * 60);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+/// How long an attempt claimed under [`FailurePolicy::Delay`] keeps the
+/// next one waiting while its password is checked. The outcome replaces
+/// it: a failure with the next delay, a correct password by clearing it.
+const ATTEMPT_HOLD: Duration = Duration::from_secs(10);
+
+/// What repeated wrong passwords for an account lead to, from
+/// `LOGIN_FAILURE_POLICY`.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum FailurePolicy {
+    /// `lockout`, the default: refuse the account for `LOCK_DURATION`
+    /// after `LOGIN_MAX_FAILURES` failures.
+    #[default]
+    Lockout,
+    /// `delay`: make each attempt after a failure wait longer, see
+    /// [`delay_after`]. Nobody can lock the owner out by guessing, while
+    /// guessing itself gets slow.
+    Delay,
+}
+
+impl FromStr for FailurePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<FailurePolicy, ()> {
+        match s {
+            "lockout" => Ok(FailurePolicy::Lockout),
+            "delay" => Ok(FailurePolicy::Delay),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How long after the last of `failures` consecutive failures the next
+/// attempt may be evaluated: 1s after the first, doubling each time, up to
+/// `MAX_DELAY`.
+pub fn delay_after(failures: u32) -> Duration {
+    match failures {
+        0 => Duration::ZERO,
+        n => Duration::from_secs(1u64 << (n - 1).min(6)).min(MAX_DELAY),
+    }
+}
+
+/// Consecutive failed logins for one account. Records are keyed by its
+/// email, or by the submitted identifier when no account matches, so
+/// unknown addresses lock out the same way registered ones do.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LoginAttempt {
+    pub email: String,
+    pub failures: u32,
+    pub locked_until: Option<DateTime>,
+    /// Set under [`FailurePolicy::Delay`]: no attempt is evaluated before
+    /// then.
+    #[serde(default)]
+    pub next_attempt_at: Option<DateTime>,
+    pub expires_at: DateTime,
+}
+
+#[derive(Clone)]
+pub struct LoginLockout {
+    attempts: Collection<LoginAttempt>,
+    max_failures: u32,
+    policy: FailurePolicy,
+}
+
+impl LoginLockout {
+    /// Locks out (or, under [`FailurePolicy::Delay`], slows down) an
+    /// account after `max_failures` consecutive failures; see
+    /// `Config::login_max_failures`.
+    pub fn new(
+        attempts: Collection<LoginAttempt>,
+        max_failures: u32,
+        policy: FailurePolicy,
+    ) -> Self {
+        LoginLockout {
+            attempts,
+            max_failures,
+            policy,
+        }
+    }
+
+    pub async fn create_indexes(&self) -> mongodb::error::Result<()> {
+        let email_index = IndexModel::builder()
+            .keys(doc! {"email": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        let ttl_index = IndexModel::builder()
+            .keys(doc! {"expires_at": 1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(0))
+                    .build(),
+            )
+            .build();
+        self.attempts
+            .create_indexes(vec![email_index, ttl_index], None)
+            .await?;
+        Ok(())
+    }
+
+    /// Refuses a login for `email` while it is locked, or under
+    /// [`FailurePolicy::Delay`] while its wait since the last failure has
+    /// not passed, with the seconds left as `Retry-After`.
+    pub async fn check(&self, email: &str) -> Result<()> {
+        if self.policy == FailurePolicy::Delay {
+            return self.claim_attempt(email).await;
+        }
+        let locked = timed(self.attempts.find_one(
+            doc! {"email": email, "locked_until": {"$gt": DateTime::now()}},
+            None,
+        ))
+        .await?;
+        match locked {
+            Some(_) => Err(Error::AccountLockedError),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes the one attempt `email` may make now, so that concurrent
+    /// attempts can't all slip through before the first failure is
+    /// recorded: the claim and the check that the wait is over are one
+    /// update, which holds off the next attempt for `ATTEMPT_HOLD`.
+    async fn claim_attempt(&self, email: &str) -> Result<()> {
+        let now = DateTime::now();
+        let claim = self.attempts.find_one_and_update(
+            doc! {
+                "email": email,
+                "$or": [
+                    {"next_attempt_at": {"$lte": now}},
+                    {"next_attempt_at": null},
+                ],
+            },
+            doc! {
+                "$set": {"next_attempt_at": now.saturating_add_duration(ATTEMPT_HOLD)},
+                "$setOnInsert": {
+                    "failures": 0,
+                    "locked_until": null,
+                    "expires_at": now.saturating_add_duration(LOCK_DURATION),
+                },
+            },
+            FindOneAndUpdateOptions::builder().upsert(true).build(),
+        );
+        match timed(claim).await {
+            Ok(_) => Ok(()),
+            // The record exists, but its wait is not over, so the upsert
+            // collided with it on the unique email.
+            Err(Error::DuplicateKeyError) => {
+                let attempt = timed(self.attempts.find_one(doc! {"email": email}, None)).await?;
+                let wait = attempt
+                    .and_then(|a| retry_after(&a, DateTime::now()))
+                    .unwrap_or(Duration::from_secs(1));
+                Err(Error::TooManyRequestsError {
+                    retry_after_secs: wait.as_secs() + u64::from(wait.subsec_nanos() > 0),
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Counts a failed login and locks the email once the threshold is hit,
+    /// returning whether this failure locked it. The counter lapses after
+    /// `LOCK_DURATION` without further failures. Under
+    /// [`FailurePolicy::Delay`] nothing is ever locked; the failure just
+    /// starts a longer wait.
+    pub async fn record_failure(&self, email: &str) -> Result<bool> {
+        let now = DateTime::now();
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let attempt = timed(self.attempts.find_one_and_update(
+            doc! {"email": email},
+            doc! {
+                "$inc": {"failures": 1},
+                "$set": {"expires_at": now.saturating_add_duration(LOCK_DURATION)},
+                "$setOnInsert": {"locked_until": null},
+            },
+            options,
+        ))
+        .await?;
+        if self.policy == FailurePolicy::Delay {
+            // The claim's hold keeps other attempts out until this lands.
+            let failures = attempt.map_or(1, |a| a.failures);
+            timed(self.attempts.update_one(
+                doc! {"email": email},
+                doc! {"$set": {"next_attempt_at": now.saturating_add_duration(delay_after(failures))}},
+                None,
+            ))
+            .await?;
+            return Ok(false);
+        }
+
+        let locked = attempt.is_some_and(|a| a.failures >= self.max_failures);
+        if locked {
+            let locked_until = now.saturating_add_duration(LOCK_DURATION);
+            timed(self.attempts.update_one(
+                doc! {"email": email},
+                doc! {"$set": {
+                    "failures": 0,
+                    "locked_until": locked_until,
+                    "expires_at": locked_until,
+                }},
+                None,
+            ))
+            .await?;
+        }
+        Ok(locked)
+    }
+
+    /// How many identifiers are locked out right now.
+    pub async fn locked_count(&self) -> Result<u64> {
+        timed(
+            self.attempts
+                .count_documents(doc! {"locked_until": {"$gt": DateTime::now()}}, None),
+        )
+        .await
+    }
+
+    /// Clears the failures of `email`, after a correct password.
+    pub async fn reset(&self, email: &str) -> Result<()> {
+        timed(self.attempts.delete_one(doc! {"email": email}, None)).await?;
+        Ok(())
+    }
+}
+
+/// How much longer `attempt` has to wait at `now`, if at all.
+fn retry_after(attempt: &LoginAttempt, now: DateTime) -> Option<Duration> {
+    let until = attempt.next_attempt_at?;
+    let wait = until.timestamp_millis() - now.timestamp_millis();
+    (wait > 0).then(|| Duration::from_millis(wait as u64))
+}
+
+pub fn with_lockout(
+    lockout: LoginLockout,
+) -> impl Filter<Extract = (LoginLockout,), Error = Infallible> + Clone {
+    warp::any().map(move || lockout.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    async fn lockout(max_failures: u32) -> LoginLockout {
+        with_policy(max_failures, FailurePolicy::Lockout).await
+    }
+
+    async fn with_policy(max_failures: u32, policy: FailurePolicy) -> LoginLockout {
+        let lockout = LoginLockout::new(
+            test_support::database().await.collection("login_attempts"),
+            max_failures,
+            policy,
+        );
+        lockout.create_indexes().await.unwrap();
+        lockout
+    }
+
+    fn failed(failures: u32, millis_ago: i64) -> LoginAttempt {
+        let now = DateTime::now().timestamp_millis();
+        LoginAttempt {
+            email: "a@example.com".to_string(),
+            failures,
+            locked_until: None,
+            next_attempt_at: Some(DateTime::from_millis(
+                now - millis_ago + delay_after(failures).as_millis() as i64,
+            )),
+            expires_at: DateTime::from_millis(now + 60_000),
+        }
+    }
+
+    #[test]
+    fn the_delay_doubles_up_to_a_minute() {
+        let schedule: Vec<u64> = (0..10).map(|n| delay_after(n).as_secs()).collect();
+        assert_eq!(schedule, [0, 1, 2, 4, 8, 16, 32, 60, 60, 60]);
+        assert_eq!(delay_after(u32::MAX), MAX_DELAY);
+    }
+
+    #[test]
+    fn an_attempt_waits_out_the_delay_since_the_last_failure() {
+        let now = DateTime::now();
+        let wait = retry_after(&failed(3, 1000), now).unwrap();
+        assert!(wait > Duration::from_secs(2) && wait <= Duration::from_secs(3));
+        // Once the delay has passed, the next attempt is evaluated, and a
+        // correct password then signs in.
+        assert_eq!(retry_after(&failed(3, 4000), now), None);
+        let mut never_failed = failed(0, 0);
+        never_failed.next_attempt_at = None;
+        assert_eq!(retry_after(&never_failed, now), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn too_fast_a_retry_is_told_when_to_come_back() {
+        let lockout = with_policy(3, FailurePolicy::Delay).await;
+        for _ in 0..5 {
+            assert!(!lockout.record_failure("a@example.com").await.unwrap());
+        }
+        match lockout.check("a@example.com").await {
+            Err(Error::TooManyRequestsError { retry_after_secs }) => {
+                assert!(
+                    (15..=16).contains(&retry_after_secs),
+                    "{}",
+                    retry_after_secs
+                )
+            }
+            _ => panic!("expected TooManyRequestsError"),
+        }
+        // Never a lock, however many failures.
+        assert_eq!(lockout.locked_count().await.unwrap(), 0);
+        assert!(lockout.check("b@example.com").await.is_ok());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn a_correct_password_ends_the_delay() {
+        let lockout = with_policy(3, FailurePolicy::Delay).await;
+        lockout.record_failure("a@example.com").await.unwrap();
+        assert!(lockout.check("a@example.com").await.is_err());
+        lockout.reset("a@example.com").await.unwrap();
+        assert!(lockout.check("a@example.com").await.is_ok());
+        // The schedule starts over at one second.
+        lockout.record_failure("a@example.com").await.unwrap();
+        match lockout.check("a@example.com").await {
+            Err(Error::TooManyRequestsError { retry_after_secs }) => {
+                assert_eq!(retry_after_secs, 1)
+            }
+            _ => panic!("expected TooManyRequestsError"),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn concurrent_attempts_get_one_turn_between_delays() {
+        let lockout = with_policy(3, FailurePolicy::Delay).await;
+        let checks = (0..10).map(|_| lockout.check("a@example.com"));
+        let results = futures_util::future::join_all(checks).await;
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results
+            .iter()
+            .all(|r| matches!(r, Ok(()) | Err(Error::TooManyRequestsError { .. }))));
+
+        // Once the admitted attempt fails and its delay passes, again only
+        // one of a burst is let through.
+        lockout.record_failure("a@example.com").await.unwrap();
+        let passed = DateTime::from_millis(DateTime::now().timestamp_millis() - 1000);
+        lockout
+            .attempts
+            .update_one(
+                doc! {"email": "a@example.com"},
+                doc! {"$set": {"next_attempt_at": passed}},
+                None,
+            )
+            .await
+            .unwrap();
+        let checks = (0..10).map(|_| lockout.check("a@example.com"));
+        let results = futures_util::future::join_all(checks).await;
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn locks_after_the_last_allowed_failure() {
+        let lockout = lockout(3).await;
+        assert!(!lockout.record_failure("a@example.com").await.unwrap());
+        assert!(!lockout.record_failure("a@example.com").await.unwrap());
+        assert!(lockout.check("a@example.com").await.is_ok());
+        assert!(lockout.record_failure("a@example.com").await.unwrap());
+        assert!(matches!(
+            lockout.check("a@example.com").await,
+            Err(Error::AccountLockedError)
+        ));
+        // Addresses are counted apart.
+        assert!(lockout.check("b@example.com").await.is_ok());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn a_correct_password_resets_the_count() {
+        let lockout = lockout(3).await;
+        lockout.record_failure("a@example.com").await.unwrap();
+        lockout.record_failure("a@example.com").await.unwrap();
+        lockout.reset("a@example.com").await.unwrap();
+        assert!(!lockout.record_failure("a@example.com").await.unwrap());
+        assert!(!lockout.record_failure("a@example.com").await.unwrap());
+        assert!(lockout.check("a@example.com").await.is_ok());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn the_lock_lifts_when_its_window_ends() {
+        let lockout = lockout(1).await;
+        assert!(lockout.record_failure("a@example.com").await.unwrap());
+        assert!(lockout.check("a@example.com").await.is_err());
+
+        let ended = DateTime::from_millis(DateTime::now().timestamp_millis() - 1000);
+        lockout
+            .attempts
+            .update_one(
+                doc! {"email": "a@example.com"},
+                doc! {"$set": {"locked_until": ended}},
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(lockout.check("a@example.com").await.is_ok());
+        assert_eq!(lockout.locked_count().await.unwrap(), 0);
+    }
+}
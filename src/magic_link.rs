@@ -0,0 +1,199 @@
+use crate::{
+    auth::{hash_token, random_token, AuthContext},
+    config,
+    error::Error,
+    issue_session,
+    mailer::Mailer,
+    ratelimit::RateLimiter,
+    repository::timed,
+    sessions::{ClientInfo, Session},
+    two_factor::{self, PendingLogin},
+    users, User, WebResult,
+};
+use mongodb::{
+    bson::{doc, DateTime},
+    options::IndexOptions,
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+use warp::{http::StatusCode, reject, reply, Reply};
+
+const MAGIC_TOKEN_LENGTH: usize = 48;
+const MAGIC_LINK_EXPIRY: Duration = Duration::from_secs(10 * 60);
+/// At most this many links are sent to one address per window, so the
+/// endpoint can't be used to flood someone's inbox.
+pub const MAGIC_LINKS_PER_EMAIL: u64 = 3;
+pub const MAGIC_LINK_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MagicLink {
+    pub token_hash: String,
+    pub uid: String,
+    pub email: String,
+    pub expires_at: DateTime,
+    pub used: bool,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct MagicLinkRequest {
+    #[serde(deserialize_with = "crate::users::normalized_email")]
+    pub email: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MagicLinkConfirm {
+    /// The token from the emailed link.
+    pub token: String,
+}
+
+pub async fn create_indexes(collection: &Collection<MagicLink>) -> mongodb::error::Result<()> {
+    let index = IndexModel::builder()
+        .keys(doc! {"expires_at": 1})
+        .options(
+            IndexOptions::builder()
+                .expire_after(Duration::from_secs(0))
+                .build(),
+        )
+        .build();
+    collection.create_index(index, None).await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/login/magic",
+    tag = "login",
+    request_body = MagicLinkRequest,
+    responses(
+        (status = 200, description = "Sent if the account exists; the same either way", body = String),
+        (status = 429, description = "Too many links for this address", body = ErrorResponse),
+    )
+)]
+pub async fn request_magic_link_handler(
+    mailer: Mailer,
+    limiter: RateLimiter,
+    users_collection: Collection<User>,
+    magic_links: Collection<MagicLink>,
+    body: MagicLinkRequest,
+) -> WebResult<impl Reply> {
+    // Limiting by the submitted address applies to registered and unknown
+    // emails alike, so a 429 reveals nothing about the account.
+    limiter
+        .hit(format!("email:{}", body.email))
+        .map_err(reject::custom)?;
+
+    let user = timed(users_collection.find_one(users::active(users::by_email(&body.email)), None))
+        .await
+        .map_err(reject::custom)?;
+
+    if let Some(user) = user {
+        let token = random_token(MAGIC_TOKEN_LENGTH);
+        timed(magic_links.insert_one(
+            MagicLink {
+                token_hash: hash_token(&token),
+                uid: user.uid.clone(),
+                email: user.email.clone(),
+                expires_at: DateTime::now().saturating_add_duration(MAGIC_LINK_EXPIRY),
+                used: false,
+            },
+            None,
+        ))
+        .await
+        .map_err(reject::custom)?;
+
+        // A delivery failure is only logged: reporting it would reveal that
+        // the account exists.
+        let body = format!(
+            "Sign in by visiting {}/login/magic/confirm?token={}",
+            config::api_prefix(),
+            token
+        );
+        if mailer
+            .send(&user.email, "Your sign-in link", &body)
+            .await
+            .is_err()
+        {
+            tracing::error!("magic link email for {} was not sent", user.uid);
+        }
+    }
+
+    Ok(reply::with_status(
+        "If the account exists, a sign-in link has been sent",
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/login/magic/confirm",
+    tag = "login",
+    params(MagicLinkConfirm),
+    responses(
+        (status = 200, description = "Signed in, a second factor is required, or the password must be changed first",
+            body = LoginResult),
+        (status = 401, description = "Unknown, used or expired link", body = ErrorResponse),
+        (status = 403, description = "Account disabled", body = ErrorResponse),
+    )
+)]
+pub async fn confirm_magic_link_handler(
+    context: AuthContext,
+    users_collection: Collection<User>,
+    sessions_collection: Collection<Session>,
+    pending_logins: Collection<PendingLogin>,
+    magic_links: Collection<MagicLink>,
+    client: ClientInfo,
+    query: MagicLinkConfirm,
+) -> WebResult<impl Reply> {
+    let link = timed(magic_links.find_one_and_update(
+        doc! {
+            "token_hash": hash_token(&query.token),
+            "used": false,
+            "expires_at": {"$gt": DateTime::now()},
+        },
+        doc! {"$set": {"used": true}},
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?
+    .ok_or_else(|| reject::custom(Error::InvalidMagicLinkError))?;
+
+    // The link only proves control of the address it was sent to; if the
+    // account's email has changed since, it no longer applies.
+    let mut user = timed(users_collection.find_one(
+        users::active(doc! {"uid": &link.uid, "email": &link.email}),
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?
+    .ok_or_else(|| reject::custom(Error::InvalidMagicLinkError))?;
+
+    if !user.email_verified {
+        timed(users_collection.update_one(
+            doc! {"uid": &user.uid},
+            doc! {
+                "$set": {"email_verified": true, "updated_at": DateTime::now()},
+                "$inc": {"version": 1},
+            },
+            None,
+        ))
+        .await
+        .map_err(reject::custom)?;
+        user.email_verified = true;
+    }
+
+    if user.totp_enabled {
+        return two_factor::start_pending_login(&pending_logins, &user, false).await;
+    }
+    issue_session(
+        &context,
+        &sessions_collection,
+        &user,
+        &client,
+        false,
+        "magic link",
+    )
+    .await
+}
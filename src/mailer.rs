@@ -0,0 +1,134 @@
+use crate::{error::Error, Result};
+use async_trait::async_trait;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use std::{convert::Infallible, env, sync::Arc};
+use warp::Filter;
+
+/// Transport for transactional mail such as verification and reset links.
+#[async_trait]
+pub trait EmailSender: Send + Sync {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<()>;
+}
+
+pub type Mailer = Arc<dyn EmailSender>;
+
+/// Shortest run of letters and digits taken for a token when logging.
+/// Every emailed token is longer, and ordinary words rarely are.
+const MIN_REDACTED_LENGTH: usize = 16;
+
+/// Sends through `smtp` (see `Config::smtp`) and falls back to
+/// `LogSender` without it, so local development needs no mail server.
+pub fn new(smtp: Option<SmtpSender>) -> Mailer {
+    match smtp {
+        Some(smtp) => Arc::new(smtp),
+        None => {
+            tracing::warn!("SMTP_HOST is not set: emails are only logged, and not delivered");
+            Arc::new(LogSender)
+        }
+    }
+}
+
+pub fn with_mailer(mailer: Mailer) -> impl Filter<Extract = (Mailer,), Error = Infallible> + Clone {
+    warp::any().map(move || mailer.clone())
+}
+
+#[derive(Clone)]
+pub struct SmtpSender {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpSender {
+    /// Reads `SMTP_HOST`, with `SMTP_USER`, `SMTP_PASS` and `SMTP_FROM`
+    /// (default `SMTP_USER`). `None` without `SMTP_HOST`, or when a setting
+    /// is missing or invalid, which is recorded in `problems`.
+    pub(crate) fn from_env(problems: &mut Vec<String>) -> Option<Self> {
+        let host = env::var("SMTP_HOST").ok().filter(|host| !host.is_empty())?;
+        let mut required = |name: &str| {
+            let value = env::var(name).ok().filter(|v| !v.is_empty());
+            if value.is_none() {
+                problems.push(format!("{} must be set when SMTP_HOST is set", name));
+            }
+            value
+        };
+        let (user, pass) = (required("SMTP_USER"), required("SMTP_PASS"));
+        let from = env::var("SMTP_FROM")
+            .ok()
+            .or_else(|| user.clone())
+            .and_then(|from| {
+                from.parse::<Mailbox>()
+                    .map_err(|_| {
+                        problems.push(format!(
+                            "SMTP_FROM (or SMTP_USER) must be a valid email address, got {:?}",
+                            from
+                        ))
+                    })
+                    .ok()
+            });
+        let relay = AsyncSmtpTransport::<Tokio1Executor>::relay(&host)
+            .map_err(|_| problems.push(format!("SMTP_HOST {:?} is not a valid relay host", host)))
+            .ok();
+
+        let (relay, user, pass, from) = (relay?, user?, pass?, from?);
+        Some(SmtpSender {
+            transport: relay.credentials(Credentials::new(user, pass)).build(),
+            from,
+        })
+    }
+}
+
+#[async_trait]
+impl EmailSender for SmtpSender {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<()> {
+        let to = to.parse().map_err(|_| Error::EmailDeliveryError)?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .body(body.to_string())
+            .map_err(|_| Error::EmailDeliveryError)?;
+
+        self.transport.send(message).await.map_err(|e| {
+            tracing::error!("sending email failed: {}", e);
+            Error::EmailDeliveryError
+        })?;
+        Ok(())
+    }
+}
+
+/// Logs messages instead of sending them, with the tokens they carry
+/// redacted: logs are read and kept by more people than a mailbox.
+pub struct LogSender;
+
+#[async_trait]
+impl EmailSender for LogSender {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<()> {
+        tracing::info!(%to, %subject, body = %redact(body), "email not delivered");
+        Ok(())
+    }
+}
+
+/// `body` with every run of at least [`MIN_REDACTED_LENGTH`] letters and
+/// digits, such as a reset token or the one in a link, replaced.
+fn redact(body: &str) -> String {
+    let mut redacted = String::with_capacity(body.len());
+    let mut run = String::new();
+    for c in body.chars().chain(std::iter::once(' ')) {
+        if c.is_ascii_alphanumeric() {
+            run.push(c);
+            continue;
+        }
+        if run.len() >= MIN_REDACTED_LENGTH {
+            redacted.push_str("[redacted]");
+        } else {
+            redacted.push_str(&run);
+        }
+        run.clear();
+        redacted.push(c);
+    }
+    redacted.pop();
+    redacted
+}
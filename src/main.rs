@@ -1,168 +1,576 @@
-use auth::{create_jwt, with_auth, Role};
-use bcrypt::{hash, verify, DEFAULT_COST};
-use dotenv::dotenv;
-use error::Error::*;
-use mongodb::{
-    bson::{doc, uuid},
-    options::ClientOptions,
-    Client, Collection,
+use clap::{Parser, Subcommand};
+use mongodb::{Client, Collection};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use rust_warp_jwt::{
+    apikeys::{self, ApiKey},
+    audit::{self, AuditEvent},
+    auth::{AuthContext, RevokedToken},
+    avatars::AvatarStore,
+    config::{Config, LogFormat},
+    error::{self, Error},
+    export,
+    health::{self, connect_to_mongo, wait_for_mongo, PingLatencies, Readiness},
+    idempotency::{self, IdempotencyRecord},
+    invites::{self, Invite},
+    lockout::{LoginAttempt, LoginLockout},
+    magic_link::{self, MagicLink},
+    mailer,
+    maintenance::{Maintenance, MaintenanceMode},
+    oauth::{self, FederatedIdentity, OAuthState},
+    orgs::{self, Membership, Organization},
+    password_reset::{self, PasswordReset},
+    ratelimit::RateLimiter,
+    repository::MongoUserRepository,
+    roles::{RoleDefinition, RoleRegistry},
+    routes::{self, AppState},
+    seed::{self, Fixtures},
+    server,
+    sessions::{self, Session},
+    sweep, telemetry,
+    throttle::LoginThrottle,
+    timeout,
+    transaction::Transactions,
+    two_factor::{self, PendingLogin},
+    users::{self, AdminCreated, UserData},
+    verification::{self, VerificationResend},
+    webhooks::{self, WebhookDelivery},
+    User,
 };
-use serde::{Deserialize, Serialize};
-use std::convert::Infallible;
-use std::env;
-use warp::{http::StatusCode, reject, reply, Filter, Rejection, Reply};
-
-mod auth;
-mod error;
+use std::{
+    fmt,
+    io::{self, BufRead},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use warp::{Filter, Reply};
 
-type Result<T> = std::result::Result<T, error::Error>;
-type WebResult<T> = std::result::Result<T, Rejection>;
 type MongoDbClient = Client;
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct User {
-    pub uid: String,
-    pub email: String,
-    pub pw: String,
-    pub role: String,
+/// Serves the API, configured from the environment; see the README.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Deserialize)]
-pub struct LoginRequest {
-    pub email: String,
-    pub pw: String,
+#[derive(Subcommand)]
+enum Command {
+    /// Create an admin account in the configured database and print its
+    /// uid, for deployments that have none yet.
+    CreateAdmin {
+        #[arg(long)]
+        email: String,
+        #[arg(long, required_unless_present = "password_stdin")]
+        password: Option<String>,
+        /// Read the password from the first line of stdin, keeping it out
+        /// of the shell history and process list.
+        #[arg(long, conflicts_with = "password")]
+        password_stdin: bool,
+        /// Promote the account if the email is already registered, and
+        /// set its password.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Create or reset the accounts in a JSON fixtures file, for local
+    /// development and demos.
+    Seed {
+        /// Fixtures of the form `{"users": [{"email": ..., "password": ...,
+        /// "role": ..., "username": ...}]}`; a built-in admin and three
+        /// users when left out.
+        path: Option<PathBuf>,
+        /// Seed even though the database has accounts the fixtures don't
+        /// describe, such as a production one would.
+        #[arg(long)]
+        force: bool,
+    },
 }
 
-#[derive(Serialize)]
-pub struct LoginResponse {
-    pub token: String,
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let started = Instant::now();
+    let config = Arc::new(Config::from_env().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }));
+    let tracer = telemetry::init().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    init_tracing(config.log_format, tracer.as_ref());
+    match cli.command {
+        None => serve(config, started).await,
+        Some(Command::CreateAdmin {
+            email,
+            password,
+            force,
+            ..
+        }) => {
+            let password = match password {
+                Some(password) => password,
+                // clap requires `--password-stdin` without `--password`.
+                None => read_password_line().unwrap_or_else(|e| {
+                    eprintln!("reading the password from stdin failed: {}", e);
+                    std::process::exit(1);
+                }),
+            };
+            create_admin(&config, &email, &password, force).await
+        }
+        Some(Command::Seed { path, force }) => seed(&config, path.as_deref(), force).await,
+    }
+    if let Some(tracer) = tracer {
+        telemetry::shutdown(tracer);
+    }
 }
 
-#[derive(Deserialize)]
-pub struct SignupRequest {
-    pub email: String,
-    pub pw: String,
-    pub role: String,
+fn read_password_line() -> io::Result<String> {
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
 }
 
-#[tokio::main]
-async fn main() {
-    let client = connect_to_mongo().await.expect("MongoDB connection failed");
-    let users_collection_pointer = client.database("my_app").collection::<User>("users");
-
-    let login_route = warp::path!("login")
-        .and(warp::post())
-        .and(with_collection(users_collection_pointer.clone()))
-        .and(warp::body::json())
-        .and_then(login_handler);
-
-    let user_route = warp::path!("user")
-        .and(with_auth(Role::User))
-        .and_then(user_handler);
-
-    let admin_route = warp::path!("admin")
-        .and(with_auth(Role::Admin))
-        .and_then(admin_handler);
-
-    let signup_route = warp::path!("signup")
-        .and(warp::post())
-        .and(with_collection(users_collection_pointer.clone()))
-        .and(warp::body::json())
-        .and_then(signup_handler);
-
-    let routes = login_route
-        .or(signup_route)
-        .or(user_route)
-        .or(admin_route)
-        .recover(error::handle_rejection);
-
-    warp::serve(routes).run(([0, 0, 0, 0], 8000)).await;
+/// `create-admin`: connects like the server does, then prints the uid of
+/// the new or promoted admin, or exits with 1 and the reason.
+async fn create_admin(config: &Config, email: &str, password: &str, force: bool) {
+    let (client, users_collection) = open_users(config).await;
+    let created = users::create_admin(&users_collection, email, password, force).await;
+    client.shutdown().await;
+    match created {
+        Ok(AdminCreated::Created(uid)) => println!("{}", uid),
+        Ok(AdminCreated::Promoted(uid)) => {
+            eprintln!("{} was already registered and is now an admin", email);
+            println!("{}", uid);
+        }
+        Err(Error::UserAlreadyExistsError) => {
+            eprintln!(
+                "{} is already registered; pass --force to make it an admin",
+                email
+            );
+            std::process::exit(1);
+        }
+        Err(Error::ValidationError(errors)) => {
+            for (field, messages) in errors {
+                eprintln!("{} {}", field, messages.join(", "));
+            }
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("creating the admin failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `seed`: loads the fixtures and seeds the configured database with them,
+/// exiting with 1 and the reason on any problem.
+async fn seed(config: &Config, path: Option<&Path>, force: bool) {
+    let fixtures = Fixtures::load(path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let (client, users_collection) = open_users(config).await;
+    let roles = RoleRegistry::load(
+        &client
+            .database(&config.mongo_db_name)
+            .collection::<RoleDefinition>("roles"),
+    )
+    .await
+    .expect("Loading role definitions failed");
+    let problems = fixtures.problems(&roles);
+    if !problems.is_empty() {
+        for problem in problems {
+            eprintln!("{}", problem);
+        }
+        std::process::exit(1);
+    }
+    let seeded = seed::seed(&users_collection, &fixtures, force).await;
+    client.shutdown().await;
+    match seeded {
+        Ok(seeded) => println!(
+            "created {} and reset {} accounts",
+            seeded.created, seeded.updated
+        ),
+        Err(Error::UserAlreadyExistsError) => {
+            eprintln!(
+                "the database has accounts the fixtures don't describe; pass --force to seed it anyway"
+            );
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("seeding failed: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
-pub async fn connect_to_mongo() -> mongodb::error::Result<MongoDbClient> {
-    dotenv().ok();
+/// Connects like the server does, for the subcommands, and returns the
+/// users collection with its indexes in place.
+async fn open_users(config: &Config) -> (MongoDbClient, Collection<User>) {
+    let client = connect_to_mongo(&config.mongo_uri, config.db_op_timeout)
+        .await
+        .expect("MongoDB connection failed");
+    wait_for_mongo(&client, &config.mongo_db_name, config.mongo_connect_timeout)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        });
+    let users_collection = client
+        .database(&config.mongo_db_name)
+        .collection::<User>(&config.users_collection);
+    users::create_indexes(&users_collection)
+        .await
+        .expect("Creating users indexes failed, check for duplicate emails");
+    (client, users_collection)
+}
 
-    let username =
-        env::var("MONGO_INITDB_ROOT_USERNAME").expect("MONGO_INITDB_ROOT_USERNAME must be set");
-    let password =
-        env::var("MONGO_INITDB_ROOT_PASSWORD").expect("MONGO_INITDB_ROOT_PASSWORD must be set");
-    let mongo_uri = format!("mongodb://{}:{}@localhost:27017", username, password);
+/// Runs the HTTP server until a shutdown signal.
+async fn serve(config: Arc<Config>, started: Instant) {
+    rust_warp_jwt::dummy_password_hash();
+    let client = connect_to_mongo(&config.mongo_uri, config.db_op_timeout)
+        .await
+        .expect("MongoDB connection failed");
+    wait_for_mongo(&client, &config.mongo_db_name, config.mongo_connect_timeout)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        });
+    let transactions = Transactions::detect(client.clone()).await;
+    let db = client.database(&config.mongo_db_name);
+    let users_collection_pointer = db.collection::<User>(&config.users_collection);
+    users::create_indexes(&users_collection_pointer)
+        .await
+        .expect("Creating users indexes failed, check for duplicate emails");
+    users::backfill_email_lower(&users_collection_pointer)
+        .await
+        .expect("Backfilling users.email_lower failed");
+    let sessions_collection_pointer = db.collection::<Session>("sessions");
+    sessions::hash_plaintext_tokens(&sessions_collection_pointer)
+        .await
+        .expect("Hashing plaintext refresh tokens failed");
+    sessions::create_indexes(&sessions_collection_pointer)
+        .await
+        .expect("Creating sessions indexes failed");
+    let revoked_tokens_collection_pointer = db.collection::<RevokedToken>("revoked_tokens");
 
-    let mut client_options = ClientOptions::parse(&mongo_uri).await?;
-    client_options.app_name = Some("MyApp".to_string());
+    let roles_collection_pointer = db.collection::<RoleDefinition>("roles");
 
-    Client::with_options(client_options)
-}
+    let api_keys_collection_pointer = db.collection::<ApiKey>("api_keys");
+    apikeys::create_indexes(&api_keys_collection_pointer)
+        .await
+        .expect("Creating api_keys indexes failed");
 
-fn with_collection(
-    collection: Collection<User>,
-) -> impl Filter<Extract = (Collection<User>,), Error = Infallible> + Clone {
-    warp::any().map(move || collection.clone())
-}
+    let password_resets_collection_pointer = db.collection::<PasswordReset>("password_resets");
+    password_reset::create_indexes(&password_resets_collection_pointer)
+        .await
+        .expect("Creating password_resets indexes failed");
 
-pub async fn signup_handler(
-    users_collection: Collection<User>,
-    body: SignupRequest,
-) -> WebResult<impl Reply> {
-    let existing_user = users_collection
-        .find_one(doc! {"email": &body.email}, None)
+    let verification_resends_collection_pointer =
+        db.collection::<VerificationResend>("verification_resends");
+    verification::create_indexes(&verification_resends_collection_pointer)
         .await
-        .map_err(|_| reject::custom(DatabaseError))?;
+        .expect("Creating verification_resends indexes failed");
 
-    if existing_user.is_some() {
-        return Err(reject::custom(UserAlreadyExistsError));
+    let pending_logins_collection_pointer = db.collection::<PendingLogin>("pending_logins");
+    two_factor::create_indexes(&pending_logins_collection_pointer)
+        .await
+        .expect("Creating pending_logins indexes failed");
+
+    let federated_identities_collection_pointer =
+        db.collection::<FederatedIdentity>("federated_identities");
+    let oauth_states_collection_pointer = db.collection::<OAuthState>("oauth_states");
+    oauth::create_indexes(
+        &federated_identities_collection_pointer,
+        &oauth_states_collection_pointer,
+    )
+    .await
+    .expect("Creating federated_identities indexes failed");
+
+    let magic_links_collection_pointer = db.collection::<MagicLink>("magic_links");
+    magic_link::create_indexes(&magic_links_collection_pointer)
+        .await
+        .expect("Creating magic_links indexes failed");
+
+    let invites_collection_pointer = db.collection::<Invite>("invites");
+    invites::create_indexes(&invites_collection_pointer)
+        .await
+        .expect("Creating invites indexes failed");
+
+    let idempotency_keys_collection_pointer =
+        db.collection::<IdempotencyRecord>("idempotency_keys");
+    idempotency::create_indexes(&idempotency_keys_collection_pointer)
+        .await
+        .expect("Creating idempotency_keys indexes failed");
+
+    let organizations_collection_pointer = db.collection::<Organization>("organizations");
+    let memberships_collection_pointer = db.collection::<Membership>("memberships");
+    orgs::create_indexes(
+        &organizations_collection_pointer,
+        &memberships_collection_pointer,
+    )
+    .await
+    .expect("Creating organizations indexes failed");
+
+    let audit_log_collection_pointer = db.collection::<AuditEvent>("audit_log");
+    audit::create_indexes(&audit_log_collection_pointer)
+        .await
+        .expect("Creating audit_log indexes failed");
+    audit::init(audit_log_collection_pointer.clone());
+
+    let webhook_deliveries_collection_pointer =
+        db.collection::<WebhookDelivery>("webhook_deliveries");
+    webhooks::create_indexes(&webhook_deliveries_collection_pointer)
+        .await
+        .expect("Creating webhook_deliveries indexes failed");
+    webhooks::init(
+        config.webhooks.clone(),
+        webhook_deliveries_collection_pointer.clone(),
+    );
+
+    let login_attempts_collection_pointer = db.collection::<LoginAttempt>("login_attempts");
+    let login_lockout = LoginLockout::new(
+        login_attempts_collection_pointer.clone(),
+        config.login_max_failures,
+        config.login_failure_policy,
+    );
+    login_lockout
+        .create_indexes()
+        .await
+        .expect("Creating login_attempts indexes failed");
+
+    let role_registry = RoleRegistry::load(&roles_collection_pointer)
+        .await
+        .expect("Loading role definitions failed");
+    users::bootstrap_admin(&users_collection_pointer)
+        .await
+        .expect("Creating the bootstrap admin failed");
+    let mailer = mailer::new(config.smtp.clone());
+    let captcha = config.captcha.clone();
+    let avatar_store = AvatarStore::from_env()
+        .await
+        .expect("Creating the avatar directory failed");
+    let trust_proxy = config.trust_proxy;
+    let auth_context = AuthContext::new(
+        config.jwt.clone(),
+        revoked_tokens_collection_pointer.clone(),
+        role_registry,
+        config.totp_cipher.clone(),
+        users_collection_pointer.clone(),
+        trust_proxy,
+    );
+    auth_context
+        .create_indexes()
+        .await
+        .expect("Creating revoked_tokens indexes failed");
+
+    let user_data = UserData {
+        sessions: sessions_collection_pointer.clone(),
+        password_resets: password_resets_collection_pointer.clone(),
+        pending_logins: pending_logins_collection_pointer.clone(),
+        magic_links: magic_links_collection_pointer.clone(),
+        federated_identities: federated_identities_collection_pointer.clone(),
+        api_keys: api_keys_collection_pointer.clone(),
+        memberships: memberships_collection_pointer.clone(),
+        transactions: transactions.clone(),
+    };
+
+    let purge_task = users::spawn_purge(
+        auth_context.clone(),
+        users_collection_pointer.clone(),
+        user_data.clone(),
+        config.user_retention,
+        config.guest_max_age,
+    );
+    let sweep_task = sweep::spawn(
+        vec![
+            revoked_tokens_collection_pointer.clone_with_type(),
+            sessions_collection_pointer.clone_with_type(),
+            password_resets_collection_pointer.clone_with_type(),
+            verification_resends_collection_pointer.clone_with_type(),
+            pending_logins_collection_pointer.clone_with_type(),
+            magic_links_collection_pointer.clone_with_type(),
+            oauth_states_collection_pointer.clone_with_type(),
+            login_attempts_collection_pointer.clone_with_type(),
+        ],
+        config.sweep_interval,
+    );
+    // `wait_for_mongo` has succeeded by now.
+    let readiness = Readiness::default();
+    readiness.set(true);
+    let ping_latencies = PingLatencies::default();
+    let readiness_task =
+        health::spawn_readiness_check(db.clone(), readiness.clone(), ping_latencies.clone());
+
+    let maintenance = Maintenance::new(config.maintenance_mode);
+    if config.maintenance_mode != MaintenanceMode::Off {
+        tracing::warn!("starting in maintenance mode");
     }
 
-    let hashed_pw =
-        hash(body.pw, DEFAULT_COST).map_err(|_| reject::custom(PasswordHashingError))?;
+    if config.unix_socket.is_some() && !trust_proxy {
+        tracing::warn!(
+            "LISTEN_UNIX_SOCKET without TRUST_PROXY: clients have no address, so rate limits are shared and logins are not throttled"
+        );
+    }
+    let login_throttle = LoginThrottle::new(trust_proxy);
+    login_throttle.spawn_cleanup();
+    let signup_limiter = RateLimiter::new(
+        config.rate_limit_requests,
+        config.rate_limit_window,
+        trust_proxy,
+    );
+    signup_limiter.spawn_cleanup();
+    let magic_link_limiter = RateLimiter::new(
+        magic_link::MAGIC_LINKS_PER_EMAIL,
+        magic_link::MAGIC_LINK_WINDOW,
+        trust_proxy,
+    );
+    magic_link_limiter.spawn_cleanup();
+    let export_limiter = RateLimiter::new(1, export::PERSONAL_EXPORT_WINDOW, trust_proxy);
+    export_limiter.spawn_cleanup();
 
-    let new_user = User {
-        uid: uuid::Uuid::new().to_string(),
-        email: body.email,
-        pw: hashed_pw,
-        role: body.role,
+    let sockets = auth_context.sockets().clone();
+    let state = AppState {
+        auth_context,
+        user_repo: Arc::new(MongoUserRepository::new(users_collection_pointer.clone())),
+        users: users_collection_pointer,
+        sessions: sessions_collection_pointer,
+        pending_logins: pending_logins_collection_pointer,
+        magic_links: magic_links_collection_pointer,
+        password_resets: password_resets_collection_pointer,
+        verification_resends: verification_resends_collection_pointer,
+        federated_identities: federated_identities_collection_pointer,
+        oauth_states: oauth_states_collection_pointer,
+        api_keys: api_keys_collection_pointer,
+        invites: invites_collection_pointer,
+        idempotency_keys: idempotency_keys_collection_pointer,
+        organizations: organizations_collection_pointer,
+        memberships: memberships_collection_pointer,
+        roles: roles_collection_pointer,
+        audit_log: audit_log_collection_pointer,
+        webhook_deliveries: webhook_deliveries_collection_pointer,
+        stats_cache: Default::default(),
+        sockets,
+        user_data,
+        transactions,
+        mailer,
+        captcha,
+        avatar_store,
+        oauth_providers: config.oauth_providers.clone(),
+        login_throttle,
+        login_lockout,
+        signup_limiter,
+        magic_link_limiter,
+        export_limiter,
+        trust_proxy,
+        db,
+        started,
+        readiness: readiness.clone(),
+        ping_latencies,
+        maintenance,
+        config: config.clone(),
     };
 
-    users_collection
-        .insert_one(new_user, None)
-        .await
-        .map_err(|_| reject::custom(DatabaseError))?;
+    let (stop_accepting, stopped) = tokio::sync::watch::channel(());
+    let shutdown = move || {
+        let mut stopped = stopped.clone();
+        async move {
+            stopped.changed().await.ok();
+        }
+    };
+    if let Some(port) = config.http_redirect_port {
+        let addr = SocketAddr::new(config.bind_addr, port);
+        let (_, redirect) = server::bind_https_redirect(addr, config.port, shutdown())
+            .unwrap_or_else(|e| exit_bind_failed(addr, e));
+        tokio::spawn(redirect);
+    }
+    if let Some(port) = config.metrics_port {
+        let addr = SocketAddr::new(config.bind_addr, port);
+        let metrics_routes = timeout::check()
+            .and(routes::metrics_route())
+            .recover(error::handle_rejection)
+            .map(Reply::into_response)
+            .boxed();
+        let (addr, metrics_server) =
+            server::bind(metrics_routes, addr, None, trust_proxy, shutdown())
+                .unwrap_or_else(|e| exit_bind_failed(addr, e));
+        tracing::info!("serving metrics on {}", addr);
+        tokio::spawn(metrics_server);
+    }
+    let (addr, server) = rust_warp_jwt::start_server(state, shutdown())
+        .unwrap_or_else(|e| exit_bind_failed(config.listen_addr(), e));
+    tracing::info!("listening on {}", addr);
+    tokio::pin!(server);
+    tokio::select! {
+        _ = &mut server => {}
+        _ = shutdown_signal() => {
+            tracing::info!(
+                "shutdown: no longer accepting connections, draining in-flight requests for up to {}s",
+                config.shutdown_drain.as_secs()
+            );
+            // Stop the health check first so it cannot mark us ready again.
+            readiness_task.abort();
+            readiness.set(false);
+            stop_accepting.send(()).ok();
+            match tokio::time::timeout(config.shutdown_drain, &mut server).await {
+                Ok(()) => tracing::info!("shutdown: all requests finished"),
+                Err(_) => tracing::warn!("shutdown: drain period over, dropping remaining requests"),
+            }
+        }
+    }
 
-    Ok(reply::with_status(
-        "User created successfully",
-        StatusCode::CREATED,
-    ))
+    tracing::info!("shutdown: stopping background tasks");
+    purge_task.abort();
+    purge_task.await.ok();
+    sweep_task.abort();
+    sweep_task.await.ok();
+    readiness_task.abort();
+    readiness_task.await.ok();
+    tracing::info!("shutdown: closing MongoDB connections");
+    client.shutdown().await;
+    tracing::info!("shutdown: done");
 }
 
-pub async fn login_handler(
-    users_collection: Collection<User>,
-    body: LoginRequest,
-) -> WebResult<impl Reply> {
-    let user = users_collection
-        .find_one(doc! {"email": &body.email}, None)
-        .await
-        .map_err(|_| reject::custom(DatabaseError))?;
-
-    if let Some(user_data) = user {
-        let is_password_correct = verify(&body.pw, &user_data.pw)
-            .map_err(|_| reject::custom(PasswordVerificationError))?;
-
-        if is_password_correct {
-            let token = create_jwt(&user_data.uid, &Role::from_str(&user_data.role))
-                .map_err(|e| reject::custom(e))?;
-            Ok(reply::json(&LoginResponse { token }))
-        } else {
-            Err(reject::custom(WrongCredentialsError))
-        }
-    } else {
-        Err(reject::custom(WrongCredentialsError))
+/// Logs to stderr at the levels in `RUST_LOG` (default `info`), as text or
+/// as one JSON object per line. JSON events carry their request span's
+/// fields, such as `request_id` and `uid`. With a `tracer`, the same spans
+/// are also exported over OTLP.
+fn init_tracing(format: LogFormat, tracer: Option<&SdkTracerProvider>) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracer.map(telemetry::layer));
+    let logs = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => subscriber.with(logs).init(),
+        LogFormat::Json => subscriber.with(logs.json().with_span_list(false)).init(),
     }
 }
 
-pub async fn user_handler(uid: String) -> WebResult<impl Reply> {
-    Ok(format!("Hello User {}", uid))
+fn exit_bind_failed(addr: impl fmt::Display, error: io::Error) -> ! {
+    tracing::error!("binding {} failed: {}", addr, error);
+    std::process::exit(1);
 }
 
-pub async fn admin_handler(uid: String) -> WebResult<impl Reply> {
-    Ok(format!("Hello Admin {}", uid))
+/// Resolves on Ctrl-C, or on SIGTERM (what docker and Kubernetes send) on
+/// unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("installing SIGTERM handler failed");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c()
+        .await
+        .expect("installing Ctrl-C handler failed");
 }
@@ -0,0 +1,184 @@
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::Claims,
+    error::Error,
+    sessions::ClientInfo,
+    WebResult,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::Infallible,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use utoipa::ToSchema;
+use warp::{http::Method, reject, reply, Filter, Rejection, Reply};
+
+/// How the server starts, from `MAINTENANCE_MODE`: `off`, `on`, or
+/// `writes_only` to keep serving reads.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum MaintenanceMode {
+    #[default]
+    Off,
+    On,
+    WritesOnly,
+}
+
+impl FromStr for MaintenanceMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<MaintenanceMode, ()> {
+        match s {
+            "off" => Ok(MaintenanceMode::Off),
+            "on" => Ok(MaintenanceMode::On),
+            "writes_only" => Ok(MaintenanceMode::WritesOnly),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether the API is refusing requests with 503 `MAINTENANCE`, shared by
+/// every route and switched at runtime with `POST /admin/maintenance`.
+#[derive(Clone, Default)]
+pub struct Maintenance {
+    enabled: Arc<AtomicBool>,
+    writes_only: Arc<AtomicBool>,
+}
+
+impl Maintenance {
+    pub fn new(mode: MaintenanceMode) -> Self {
+        let maintenance = Maintenance::default();
+        maintenance.set(
+            mode != MaintenanceMode::Off,
+            mode == MaintenanceMode::WritesOnly,
+        );
+        maintenance
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn writes_only(&self) -> bool {
+        self.writes_only.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, enabled: bool, writes_only: bool) {
+        // Stored first so a request never sees the mode switch on with the
+        // previous `writes_only`.
+        self.writes_only
+            .store(enabled && writes_only, Ordering::Relaxed);
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Whether a request with `method` is turned away right now.
+    fn refuses(&self, method: &Method) -> bool {
+        let read = matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS);
+        self.is_enabled() && !(read && self.writes_only())
+    }
+}
+
+/// Rejects with `MaintenanceError` while maintenance mode is on, except
+/// for reads when it is `writes_only`. Goes before anything that reads the
+/// token or the database.
+pub fn check(maintenance: Maintenance) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and_then(move |method: Method| {
+            let maintenance = maintenance.clone();
+            async move {
+                if maintenance.refuses(&method) {
+                    Err(reject::custom(Error::MaintenanceError))
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .untuple_one()
+}
+
+pub fn with_maintenance(
+    maintenance: Maintenance,
+) -> impl Filter<Extract = (Maintenance,), Error = Infallible> + Clone {
+    warp::any().map(move || maintenance.clone())
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct MaintenanceRequest {
+    pub enabled: bool,
+    /// Keep answering `GET`, `HEAD` and `OPTIONS` requests; only with
+    /// `enabled`.
+    #[serde(default)]
+    pub writes_only: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MaintenanceResponse {
+    pub enabled: bool,
+    pub writes_only: bool,
+}
+
+impl From<&Maintenance> for MaintenanceResponse {
+    fn from(maintenance: &Maintenance) -> Self {
+        MaintenanceResponse {
+            enabled: maintenance.is_enabled(),
+            writes_only: maintenance.writes_only(),
+        }
+    }
+}
+
+/// Whether maintenance mode is on.
+#[utoipa::path(
+    get,
+    path = "/admin/maintenance",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The current mode", body = MaintenanceResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn maintenance_handler(
+    _claims: Claims,
+    maintenance: Maintenance,
+) -> WebResult<impl Reply> {
+    Ok(reply::json(&MaintenanceResponse::from(&maintenance)))
+}
+
+/// Turns maintenance mode on or off for this instance. While it is on,
+/// every route but the probes, `/metrics`, the docs and this one answers
+/// 503 `MAINTENANCE` with `Retry-After`, and `/readyz` reports not ready.
+/// The switch is not persisted: a restart goes back to `MAINTENANCE_MODE`.
+#[utoipa::path(
+    post,
+    path = "/admin/maintenance",
+    tag = "admin",
+    request_body = MaintenanceRequest,
+    responses(
+        (status = 200, description = "The new mode", body = MaintenanceResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn set_maintenance_handler(
+    claims: Claims,
+    maintenance: Maintenance,
+    client: ClientInfo,
+    body: MaintenanceRequest,
+) -> WebResult<impl Reply> {
+    maintenance.set(body.enabled, body.writes_only);
+    let detail = match (maintenance.is_enabled(), maintenance.writes_only()) {
+        (false, _) => "off",
+        (true, false) => "on",
+        (true, true) => "writes_only",
+    };
+    tracing::warn!(actor = %claims.sub, mode = detail, "maintenance mode changed");
+    audit::record(
+        AuditEvent::new(AuditAction::MaintenanceChanged, &client)
+            .actor(&claims.sub)
+            .detail(detail),
+    );
+    Ok(reply::json(&MaintenanceResponse::from(&maintenance)))
+}
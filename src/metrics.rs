@@ -0,0 +1,243 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use std::{cell::Cell, convert::Infallible, future::Future, sync::LazyLock, time::Duration};
+use warp::{
+    http::{header::CONTENT_TYPE, Method, StatusCode},
+    reply, Filter, Reply,
+};
+
+/// Label for requests no route matched, so probing random paths cannot
+/// create new series.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+tokio::task_local! {
+    static ROUTE: Cell<&'static str>;
+}
+
+struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    db_duration: Histogram,
+    circuit_state: IntGauge,
+    in_flight: IntGauge,
+    logins: IntCounterVec,
+    signups: IntCounter,
+    swept: IntCounterVec,
+    user_cache: IntCounterVec,
+    timeouts: IntCounterVec,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let registry = Registry::new();
+    let requests = IntCounterVec::new(
+        Opts::new("http_requests_total", "HTTP requests answered"),
+        &["method", "route", "status"],
+    )
+    .expect("valid metric");
+    let request_duration = HistogramVec::new(
+        HistogramOpts::new(
+            "http_request_duration_seconds",
+            "Time taken to answer HTTP requests",
+        ),
+        &["method", "route"],
+    )
+    .expect("valid metric");
+    let db_duration = Histogram::with_opts(HistogramOpts::new(
+        "db_operation_duration_seconds",
+        "Time taken by MongoDB operations made through the repository layer",
+    ))
+    .expect("valid metric");
+    let circuit_state = IntGauge::new(
+        "db_circuit_breaker_state",
+        "The MongoDB circuit breaker: 0 closed, 1 half-open, 2 open",
+    )
+    .expect("valid metric");
+    let in_flight = IntGauge::new("http_requests_in_flight", "HTTP requests being handled")
+        .expect("valid metric");
+    let logins = IntCounterVec::new(
+        Opts::new("logins_total", "Password logins by result"),
+        &["result"],
+    )
+    .expect("valid metric");
+    let signups =
+        IntCounter::new("signups_total", "Accounts created through /signup").expect("valid metric");
+    let swept = IntCounterVec::new(
+        Opts::new(
+            "expired_documents_removed_total",
+            "Expired documents deleted by the background sweep",
+        ),
+        &["collection"],
+    )
+    .expect("valid metric");
+
+    registry
+        .register(Box::new(requests.clone()))
+        .expect("unique metric");
+    registry
+        .register(Box::new(request_duration.clone()))
+        .expect("unique metric");
+    registry
+        .register(Box::new(db_duration.clone()))
+        .expect("unique metric");
+    registry
+        .register(Box::new(circuit_state.clone()))
+        .expect("unique metric");
+    registry
+        .register(Box::new(in_flight.clone()))
+        .expect("unique metric");
+    registry
+        .register(Box::new(logins.clone()))
+        .expect("unique metric");
+    registry
+        .register(Box::new(signups.clone()))
+        .expect("unique metric");
+    let user_cache = IntCounterVec::new(
+        Opts::new(
+            "user_cache_lookups_total",
+            "Account checks on authenticated requests, by whether the cache answered",
+        ),
+        &["result"],
+    )
+    .expect("valid metric");
+    registry
+        .register(Box::new(swept.clone()))
+        .expect("unique metric");
+    registry
+        .register(Box::new(user_cache.clone()))
+        .expect("unique metric");
+    let timeouts = IntCounterVec::new(
+        Opts::new(
+            "http_request_timeouts_total",
+            "Requests answered 504 for running out of time",
+        ),
+        &["route"],
+    )
+    .expect("valid metric");
+    registry
+        .register(Box::new(timeouts.clone()))
+        .expect("unique metric");
+    Metrics {
+        registry,
+        requests,
+        request_duration,
+        db_duration,
+        circuit_state,
+        in_flight,
+        logins,
+        signups,
+        swept,
+        user_cache,
+        timeouts,
+    }
+});
+
+/// Labels the current request with its route `pattern`, such as
+/// `/users/{uid}`, rather than the raw path, which would give every user
+/// their own series. Goes right after the route's `warp::path!`.
+pub fn route(pattern: &'static str) -> impl Filter<Extract = (), Error = Infallible> + Clone {
+    warp::any()
+        .map(move || {
+            ROUTE.try_with(|route| route.set(pattern)).ok();
+        })
+        .untuple_one()
+}
+
+/// Runs `future`, the handling of one request, counting it as in flight
+/// and returning the route it was labelled with.
+pub async fn scope<F: Future>(future: F) -> (&'static str, F::Output) {
+    let _in_flight = InFlight::start();
+    ROUTE
+        .scope(Cell::new(UNMATCHED_ROUTE), async move {
+            let output = future.await;
+            (ROUTE.with(Cell::get), output)
+        })
+        .await
+}
+
+/// Decrements the in-flight gauge on drop, so requests abandoned by their
+/// client are not counted forever.
+struct InFlight;
+
+impl InFlight {
+    fn start() -> InFlight {
+        METRICS.in_flight.inc();
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        METRICS.in_flight.dec();
+    }
+}
+
+/// Records a finished request.
+pub fn observe(method: &Method, route: &str, status: StatusCode, latency: Duration) {
+    METRICS
+        .requests
+        .with_label_values(&[method.as_str(), route, status.as_str()])
+        .inc();
+    METRICS
+        .request_duration
+        .with_label_values(&[method.as_str(), route])
+        .observe(latency.as_secs_f64());
+}
+
+/// Records a finished MongoDB operation.
+pub fn observe_db(elapsed: Duration) {
+    METRICS.db_duration.observe(elapsed.as_secs_f64());
+}
+
+pub fn set_circuit_state(state: i64) {
+    METRICS.circuit_state.set(state);
+}
+
+pub fn record_login(success: bool) {
+    let result = if success { "success" } else { "failure" };
+    METRICS.logins.with_label_values(&[result]).inc();
+}
+
+pub fn record_signup() {
+    METRICS.signups.inc();
+}
+
+pub fn record_user_cache(hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    METRICS.user_cache.with_label_values(&[result]).inc();
+}
+
+/// Counts a timed-out request under the route it is labelled with so far.
+pub fn record_timeout() {
+    let route = ROUTE.try_with(Cell::get).unwrap_or(UNMATCHED_ROUTE);
+    METRICS.timeouts.with_label_values(&[route]).inc();
+}
+
+pub fn record_swept(collection: &str, removed: u64) {
+    METRICS
+        .swept
+        .with_label_values(&[collection])
+        .inc_by(removed);
+}
+
+/// Every metric in the Prometheus text format.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "probes",
+    responses((status = 200, description = "Prometheus text exposition format", body = String))
+)]
+pub async fn metrics_handler() -> Result<impl Reply, Infallible> {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder
+        .encode(&METRICS.registry.gather(), &mut body)
+        .expect("encoding metrics into a Vec cannot fail");
+    Ok(reply::with_header(
+        body,
+        CONTENT_TYPE,
+        encoder.format_type().to_string(),
+    ))
+}
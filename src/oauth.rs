@@ -0,0 +1,409 @@
+use crate::{
+    auth::{constant_time_eq, cookie_value, hash_token, random_token, AuthContext, Claims, Role},
+    error::Error,
+    github::GitHubProvider,
+    google::GoogleProvider,
+    issue_session, password,
+    repository::timed,
+    sessions::{ClientInfo, Session},
+    two_factor::{self, PendingLogin},
+    users, Result, User, WebResult,
+};
+use async_trait::async_trait;
+use mongodb::{
+    bson::{doc, DateTime},
+    options::IndexOptions,
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, convert::Infallible, env, sync::Arc, time::Duration};
+use utoipa::IntoParams;
+use warp::{
+    http::{
+        header::{HeaderMap, HeaderValue, SET_COOKIE},
+        Uri,
+    },
+    reject, Filter, Reply,
+};
+
+const STATE_COOKIE: &str = "oauth_state";
+const STATE_LENGTH: usize = 32;
+const STATE_EXPIRY: Duration = Duration::from_secs(10 * 60);
+const UNUSABLE_PASSWORD_LENGTH: usize = 64;
+
+/// An account at an external identity provider. Providers must only return
+/// an email address they have verified.
+pub struct ExternalIdentity {
+    pub external_id: String,
+    pub email: String,
+}
+
+#[async_trait]
+pub trait OAuthProvider: Send + Sync {
+    /// URL of the provider's consent screen for this login attempt.
+    fn authorize_url(&self, state: &str) -> String;
+
+    /// Exchanges the callback's authorization code for the token that
+    /// `fetch_identity` needs.
+    async fn exchange_code(&self, code: &str) -> Result<String>;
+
+    async fn fetch_identity(&self, token: &str) -> Result<ExternalIdentity>;
+}
+
+/// Links an external account to a local user. One user may have any number
+/// of these, but each `(provider, external_id)` belongs to a single user.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FederatedIdentity {
+    pub provider: String,
+    pub external_id: String,
+    pub uid: String,
+    pub email: String,
+    pub created_at: DateTime,
+}
+
+/// Server-side half of the `state` parameter. The other half is the
+/// `oauth_state` cookie, which ties the callback to the browser that started
+/// the flow.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OAuthState {
+    pub state_hash: String,
+    pub provider: String,
+    /// Set when a logged-in user started the flow to link another provider.
+    pub link_uid: Option<String>,
+    pub expires_at: DateTime,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+}
+
+/// Providers enabled by `OAUTH_PROVIDERS`, a comma-separated list such as
+/// `google,github`.
+#[derive(Clone, Default)]
+pub struct OAuthProviders {
+    providers: Arc<HashMap<String, Arc<dyn OAuthProvider>>>,
+}
+
+impl OAuthProviders {
+    /// Reads `OAUTH_PROVIDERS` and each listed provider's settings,
+    /// recording unknown providers and missing settings in `problems`.
+    pub(crate) fn from_env(problems: &mut Vec<String>) -> Self {
+        let configured = env::var("OAUTH_PROVIDERS").unwrap_or_else(|_| {
+            // Deployments that configured Google before the providers list
+            // existed keep working without setting it.
+            if env::var("GOOGLE_CLIENT_ID").is_ok() {
+                "google".to_string()
+            } else {
+                String::new()
+            }
+        });
+
+        let mut providers: HashMap<String, Arc<dyn OAuthProvider>> = HashMap::new();
+        for name in configured
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+        {
+            let provider: Option<Arc<dyn OAuthProvider>> = match name {
+                "google" => GoogleProvider::from_env(problems).map(|p| Arc::new(p) as _),
+                "github" => GitHubProvider::from_env(problems).map(|p| Arc::new(p) as _),
+                other => {
+                    problems.push(format!(
+                        "OAUTH_PROVIDERS contains unknown provider {:?}",
+                        other
+                    ));
+                    None
+                }
+            };
+            if let Some(provider) = provider {
+                providers.insert(name.to_string(), provider);
+            }
+        }
+        OAuthProviders {
+            providers: Arc::new(providers),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<Arc<dyn OAuthProvider>> {
+        self.providers.get(name).cloned()
+    }
+}
+
+pub fn with_providers(
+    providers: OAuthProviders,
+) -> impl Filter<Extract = (OAuthProviders,), Error = Infallible> + Clone {
+    warp::any().map(move || providers.clone())
+}
+
+pub async fn create_indexes(
+    identities: &Collection<FederatedIdentity>,
+    states: &Collection<OAuthState>,
+) -> mongodb::error::Result<()> {
+    let identity_index = IndexModel::builder()
+        .keys(doc! {"provider": 1, "external_id": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    identities.create_index(identity_index, None).await?;
+
+    let state_index = IndexModel::builder()
+        .keys(doc! {"expires_at": 1})
+        .options(
+            IndexOptions::builder()
+                .expire_after(Duration::from_secs(0))
+                .build(),
+        )
+        .build();
+    states.create_index(state_index, None).await?;
+    Ok(())
+}
+
+fn state_cookie(state: &str) -> HeaderValue {
+    // Lax rather than Strict: the callback is a top-level navigation coming
+    // back from the provider, and Strict cookies would not be sent on it.
+    HeaderValue::from_str(&format!(
+        "{}={}; HttpOnly; Secure; SameSite=Lax; Path=/auth; Max-Age={}",
+        STATE_COOKIE,
+        state,
+        STATE_EXPIRY.as_secs()
+    ))
+    .expect("state is a valid header value")
+}
+
+fn clear_state_cookie() -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "{}=; HttpOnly; Secure; SameSite=Lax; Path=/auth; Max-Age=0",
+        STATE_COOKIE
+    ))
+    .expect("cookie is a valid header value")
+}
+
+async fn link_identity(
+    identities: &Collection<FederatedIdentity>,
+    provider: &str,
+    identity: &ExternalIdentity,
+    uid: &str,
+) -> Result<()> {
+    timed(identities.insert_one(
+        FederatedIdentity {
+            provider: provider.to_owned(),
+            external_id: identity.external_id.clone(),
+            uid: uid.to_owned(),
+            email: identity.email.clone(),
+            created_at: DateTime::now(),
+        },
+        None,
+    ))
+    .await
+    .map_err(|e| match e {
+        Error::DuplicateKeyError => Error::IdentityAlreadyLinkedError,
+        other => other,
+    })?;
+    Ok(())
+}
+
+async fn find_user(users_collection: &Collection<User>, uid: &str) -> Result<User> {
+    timed(users_collection.find_one(users::active(doc! {"uid": uid}), None))
+        .await?
+        .ok_or(Error::UserNotFoundError)
+}
+
+/// Maps an external identity to a local user. A linking flow attaches it to
+/// `link_uid`; a login uses an existing link, then a user with the same
+/// email (linking it), and finally creates a new user.
+async fn resolve_user(
+    users_collection: &Collection<User>,
+    identities: &Collection<FederatedIdentity>,
+    provider: &str,
+    identity: &ExternalIdentity,
+    link_uid: Option<String>,
+) -> Result<User> {
+    let linked = timed(identities.find_one(
+        doc! {"provider": provider, "external_id": &identity.external_id},
+        None,
+    ))
+    .await?;
+
+    if let Some(link_uid) = link_uid {
+        match linked {
+            Some(linked) if linked.uid != link_uid => {
+                return Err(Error::IdentityAlreadyLinkedError)
+            }
+            Some(_) => {}
+            None => link_identity(identities, provider, identity, &link_uid).await?,
+        }
+        return find_user(users_collection, &link_uid).await;
+    }
+
+    if let Some(linked) = linked {
+        return find_user(users_collection, &linked.uid).await;
+    }
+
+    let existing = timed(users_collection.find_one(users::by_email(&identity.email), None)).await?;
+    let user = match existing {
+        // The address stays reserved while a deleted account can be restored.
+        Some(user) if user.deleted_at.is_some() => return Err(Error::EmailAlreadyInUseError),
+        Some(mut user) => {
+            timed(users_collection.update_one(
+                doc! {"uid": &user.uid},
+                doc! {
+                    "$set": {"email_verified": true, "updated_at": DateTime::now()},
+                    "$inc": {"version": 1},
+                },
+                None,
+            ))
+            .await?;
+            user.email_verified = true;
+            user
+        }
+        None => {
+            // Nobody knows this password, so the account can only sign in
+            // through a provider until the user sets one via password reset.
+            let pw = password::hash(&random_token(UNUSABLE_PASSWORD_LENGTH))?;
+            let user = User::new(users::normalize_email(&identity.email), pw, &Role::User);
+            timed(users::documents(users_collection).insert_one(user.document(), None)).await?;
+            user
+        }
+    };
+    link_identity(identities, provider, identity, &user.uid).await?;
+    Ok(user)
+}
+
+#[utoipa::path(
+    get,
+    path = "/auth/{provider}",
+    tag = "login",
+    params(("provider" = String, Path, description = "`google` or `github`")),
+    responses(
+        (status = 302, description = "Redirect to the provider's consent page"),
+        (status = 404, description = "Provider not configured", body = ErrorResponse),
+    ),
+    security((), ("bearer_auth" = []))
+)]
+pub async fn oauth_login_handler(
+    provider_name: String,
+    providers: OAuthProviders,
+    claims: Option<Claims>,
+    states: Collection<OAuthState>,
+) -> WebResult<impl Reply> {
+    let provider = providers
+        .get(&provider_name)
+        .ok_or_else(reject::not_found)?;
+
+    let state = random_token(STATE_LENGTH);
+    timed(states.insert_one(
+        OAuthState {
+            state_hash: hash_token(&state),
+            provider: provider_name,
+            link_uid: claims.map(|claims| claims.sub),
+            expires_at: DateTime::now().saturating_add_duration(STATE_EXPIRY),
+        },
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?;
+
+    let location: Uri = provider
+        .authorize_url(&state)
+        .parse()
+        .map_err(|_| reject::custom(Error::OAuthProviderError))?;
+    let mut response = warp::redirect::found(location).into_response();
+    response
+        .headers_mut()
+        .insert(SET_COOKIE, state_cookie(&state));
+    Ok(response)
+}
+
+#[utoipa::path(
+    get,
+    path = "/auth/{provider}/callback",
+    tag = "login",
+    params(("provider" = String, Path, description = "`google` or `github`"), CallbackQuery),
+    responses(
+        (status = 200, description = "Signed in, a second factor is required, or the password must be changed first",
+            body = LoginResult),
+        (status = 400, description = "Missing or mismatched state or code", body = ErrorResponse),
+        (status = 409, description = "External account linked to another user", body = ErrorResponse),
+        (status = 502, description = "The provider failed", body = ErrorResponse),
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn oauth_callback_handler(
+    provider_name: String,
+    providers: OAuthProviders,
+    context: AuthContext,
+    users_collection: Collection<User>,
+    sessions_collection: Collection<Session>,
+    pending_logins: Collection<PendingLogin>,
+    identities: Collection<FederatedIdentity>,
+    states: Collection<OAuthState>,
+    client: ClientInfo,
+    query: CallbackQuery,
+    headers: HeaderMap,
+) -> WebResult<impl Reply> {
+    let provider = providers
+        .get(&provider_name)
+        .ok_or_else(reject::not_found)?;
+
+    // The state must match the cookie set on this browser by
+    // `oauth_login_handler`, otherwise a forged callback could log the
+    // victim into an attacker's account.
+    let expected = cookie_value(&headers, STATE_COOKIE);
+    let (code, state) = match (query.code, query.state, expected) {
+        (Some(code), Some(state), Some(expected))
+            if constant_time_eq(state.as_bytes(), expected.as_bytes()) =>
+        {
+            (code, state)
+        }
+        _ => return Err(reject::custom(Error::OAuthCallbackError)),
+    };
+    let stored = timed(states.find_one_and_delete(
+        doc! {
+            "state_hash": hash_token(&state),
+            "provider": &provider_name,
+            "expires_at": {"$gt": DateTime::now()},
+        },
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?
+    .ok_or_else(|| reject::custom(Error::OAuthCallbackError))?;
+
+    let token = provider
+        .exchange_code(&code)
+        .await
+        .map_err(reject::custom)?;
+    let identity = provider
+        .fetch_identity(&token)
+        .await
+        .map_err(reject::custom)?;
+    let user = resolve_user(
+        &users_collection,
+        &identities,
+        &provider_name,
+        &identity,
+        stored.link_uid,
+    )
+    .await
+    .map_err(reject::custom)?;
+
+    let mut response = if user.totp_enabled {
+        two_factor::start_pending_login(&pending_logins, &user, false).await?
+    } else {
+        issue_session(
+            &context,
+            &sessions_collection,
+            &user,
+            &client,
+            false,
+            &provider_name,
+        )
+        .await?
+    };
+    response
+        .headers_mut()
+        .append(SET_COOKIE, clear_state_cookie());
+    Ok(response)
+}
@@ -0,0 +1,381 @@
+use crate::{
+    admin_sessions::{self, AdminSessionResponse, RevokedSessions, SessionFilter},
+    apikeys::{self, CreateApiKeyRequest, CreateApiKeyResponse, API_KEY_HEADER},
+    audit::{self, AuditAction, AuditEntry},
+    auth::{Jwk, JwkSet},
+    avatars::{self, AvatarUpload},
+    email_change::{self, EmailChangeRequest},
+    error::ErrorResponse,
+    events, export,
+    features::{self, Features},
+    guest::{self, GuestUpgradeRequest},
+    health::{self, HealthResponse, PoolStats},
+    import::{self, ImportOutcome, ImportRecord, ImportReport, ImportStatus},
+    invites::{self, CreateInviteRequest, CreateInviteResponse},
+    magic_link::{self, MagicLinkRequest},
+    maintenance::{self, MaintenanceRequest, MaintenanceResponse},
+    metrics,
+    orgs::{self, AddMemberRequest, CreateOrgRequest, MemberPage, MemberResponse, OrgResponse},
+    pagination::{AdminSessionPage, AuditPage, UserPage},
+    password_reset::{self, PasswordResetConfirm, PasswordResetRequest},
+    role_batch::{self, RoleBatchReport, RoleUpdate, RoleUpdateOutcome, RoleUpdateStatus},
+    roles::{self, RoleDefinition, UpdateRoleRequest},
+    sessions::{self, SessionResponse},
+    sockets::{self, NotifyRequest, NotifyResponse},
+    stats::{self, UserStats},
+    token_exchange::{self, TokenExchangeRequest, TokenExchangeResponse},
+    two_factor::{
+        self, CodeRequest, EnrollResponse, TwoFactorLoginRequest, TwoFactorRequiredResponse,
+    },
+    users::{
+        self, BanRequest, CreateUserRequest, DeleteAccountRequest, ImpersonationResponse,
+        UpdateProfileRequest, UpdateUserRequest, UpdateUserRoleRequest,
+    },
+    verification::{self, ResendVerificationRequest},
+    webhooks::{self, DeliveryEntry, DeliveryPage, WebhookEvent},
+    ChangePasswordRequest, LoginRequest, LoginResponse, LoginResult,
+    PasswordChangeRequiredResponse, RefreshRequest, RefreshResponse, SignupRequest, SignupResponse,
+    UserResponse,
+};
+use std::{convert::Infallible, sync::Arc};
+use utoipa::{
+    openapi::{
+        self,
+        path::PathItemType,
+        security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    },
+    Modify, OpenApi,
+};
+use warp::{http::header::CONTENT_TYPE, reply, Reply};
+
+/// Swagger UI is loaded from a CDN rather than bundled, so the binary does
+/// not carry its assets.
+const SWAGGER_UI_HTML: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>API docs</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    window.ui = SwaggerUIBundle({ url: "/api-docs/openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##;
+
+/// The routes below the API prefix, with paths relative to it.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        crate::login_handler,
+        two_factor::login_2fa_handler,
+        magic_link::request_magic_link_handler,
+        magic_link::confirm_magic_link_handler,
+        two_factor::enroll_handler,
+        two_factor::verify_handler,
+        two_factor::disable_handler,
+        crate::refresh_handler,
+        crate::logout_handler,
+        crate::logout_all_handler,
+        crate::oauth::oauth_login_handler,
+        crate::oauth::oauth_callback_handler,
+        sessions::list_sessions_handler,
+        sessions::delete_session_handler,
+        crate::signup_handler,
+        guest::create_guest_handler,
+        guest::upgrade_guest_handler,
+        token_exchange::exchange_token_handler,
+        verification::verify_email_handler,
+        verification::resend_verification_handler,
+        email_change::confirm_email_change_handler,
+        password_reset::request_reset_handler,
+        password_reset::confirm_reset_handler,
+        crate::user_handler,
+        crate::welcome_handler,
+        crate::me_handler,
+        users::update_profile_handler,
+        avatars::upload_avatar_handler,
+        avatars::get_avatar_handler,
+        crate::change_password_handler,
+        email_change::request_email_change_handler,
+        crate::admin_handler,
+        maintenance::maintenance_handler,
+        maintenance::set_maintenance_handler,
+        features::features_handler,
+        stats::stats_handler,
+        users::list_users_handler,
+        users::search_users_handler,
+        export::export_users_handler,
+        export::export_my_data_handler,
+        export::export_user_data_handler,
+        users::create_user_handler,
+        import::import_users_handler,
+        role_batch::batch_update_roles_handler,
+        users::get_user_handler,
+        users::update_user_handler,
+        users::delete_user_handler,
+        users::restore_user_handler,
+        users::deactivate_user_handler,
+        users::activate_user_handler,
+        users::ban_user_handler,
+        users::unban_user_handler,
+        users::delete_me_handler,
+        users::require_password_change_handler,
+        users::update_user_role_handler,
+        users::impersonate_user_handler,
+        roles::list_roles_handler,
+        roles::create_role_handler,
+        roles::update_role_handler,
+        roles::delete_role_handler,
+        apikeys::create_api_key_handler,
+        apikeys::delete_api_key_handler,
+        invites::create_invite_handler,
+        orgs::create_org_handler,
+        orgs::delete_org_handler,
+        orgs::add_member_handler,
+        orgs::list_members_handler,
+        audit::list_audit_handler,
+        admin_sessions::list_all_sessions_handler,
+        admin_sessions::revoke_sessions_handler,
+        webhooks::list_deliveries_handler,
+        sockets::notify_handler,
+        events::events_handler,
+    ),
+    components(schemas(
+        ErrorResponse,
+        LoginRequest,
+        LoginResponse,
+        LoginResult,
+        TwoFactorRequiredResponse,
+        PasswordChangeRequiredResponse,
+        TwoFactorLoginRequest,
+        MagicLinkRequest,
+        CodeRequest,
+        EnrollResponse,
+        RefreshRequest,
+        RefreshResponse,
+        SessionResponse,
+        SignupRequest,
+        SignupResponse,
+        GuestUpgradeRequest,
+        TokenExchangeRequest,
+        TokenExchangeResponse,
+        ResendVerificationRequest,
+        PasswordResetRequest,
+        PasswordResetConfirm,
+        UserResponse,
+        UpdateProfileRequest,
+        AvatarUpload,
+        ChangePasswordRequest,
+        EmailChangeRequest,
+        DeleteAccountRequest,
+        UserPage,
+        UserStats,
+        CreateUserRequest,
+        UpdateUserRequest,
+        UpdateUserRoleRequest,
+        BanRequest,
+        ImpersonationResponse,
+        ImportRecord,
+        ImportReport,
+        ImportOutcome,
+        ImportStatus,
+        RoleUpdate,
+        RoleBatchReport,
+        RoleUpdateOutcome,
+        RoleUpdateStatus,
+        RoleDefinition,
+        UpdateRoleRequest,
+        CreateApiKeyRequest,
+        CreateInviteRequest,
+        CreateInviteResponse,
+        CreateOrgRequest,
+        OrgResponse,
+        AddMemberRequest,
+        MemberResponse,
+        MemberPage,
+        NotifyRequest,
+        WebhookEvent,
+        DeliveryEntry,
+        DeliveryPage,
+        NotifyResponse,
+        CreateApiKeyResponse,
+        AuditPage,
+        AuditEntry,
+        AuditAction,
+        AdminSessionPage,
+        AdminSessionResponse,
+        SessionFilter,
+        RevokedSessions,
+        MaintenanceRequest,
+        MaintenanceResponse,
+        Features,
+    )),
+    modifiers(&SecuritySchemes, &FormBodies)
+)]
+struct ApiDoc;
+
+/// The routes at fixed paths; see `routes::fixed_routes`.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        health::health_handler,
+        health::livez_handler,
+        health::readyz_handler,
+        crate::jwks_handler,
+    ),
+    components(schemas(HealthResponse, PoolStats, JwkSet, Jwk))
+)]
+struct FixedDoc;
+
+#[derive(OpenApi)]
+#[openapi(paths(metrics::metrics_handler))]
+struct MetricsDoc;
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer_auth",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .description(Some(
+                        "The `token` from a login. With `AUTH_COOKIE=true` the `auth_token` \
+                         cookie works too.",
+                    ))
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                API_KEY_HEADER,
+                "A key from `POST /apikeys`.",
+            ))),
+        );
+    }
+}
+
+/// `POST /login` takes its body as a form too, which `utoipa::path` has
+/// no way to say.
+struct FormBodies;
+
+impl Modify for FormBodies {
+    fn modify(&self, openapi: &mut openapi::OpenApi) {
+        let body = openapi
+            .paths
+            .paths
+            .get_mut("/login")
+            .and_then(|item| item.operations.get_mut(&PathItemType::Post))
+            .and_then(|operation| operation.request_body.as_mut());
+        if let Some(body) = body {
+            if let Some(json) = body.content.get("application/json").cloned() {
+                body.content
+                    .insert("application/x-www-form-urlencoded".to_string(), json);
+            }
+        }
+    }
+}
+
+/// The OpenAPI document for the routes as served: API paths under `prefix`,
+/// and `/metrics` only when it is served on the main port.
+pub fn spec(prefix: &str, with_metrics: bool) -> openapi::OpenApi {
+    let mut spec = ApiDoc::openapi();
+    spec.paths.paths = std::mem::take(&mut spec.paths.paths)
+        .into_iter()
+        .map(|(path, item)| (format!("{}{}", prefix, path), item))
+        .collect();
+    spec.merge(FixedDoc::openapi());
+    if with_metrics {
+        spec.merge(MetricsDoc::openapi());
+    }
+    spec
+}
+
+/// `spec` as serialized at startup.
+pub async fn openapi_handler(spec: Arc<String>) -> Result<impl Reply, Infallible> {
+    Ok(reply::with_header(
+        spec.to_string(),
+        CONTENT_TYPE,
+        "application/json",
+    ))
+}
+
+/// The contents of the docs page's inline `<script>`, for its hash in the
+/// default `Content-Security-Policy`.
+pub fn swagger_ui_inline_script() -> &'static str {
+    let start = SWAGGER_UI_HTML
+        .rfind("<script>")
+        .expect("the docs page has an inline script")
+        + "<script>".len();
+    let end = SWAGGER_UI_HTML[start..]
+        .find("</script>")
+        .expect("the inline script is closed");
+    &SWAGGER_UI_HTML[start..start + end]
+}
+
+pub async fn swagger_ui_handler() -> Result<impl Reply, Infallible> {
+    Ok(reply::html(SWAGGER_UI_HTML))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support;
+    use utoipa::openapi::{OpenApi, OpenApiVersion};
+
+    /// The spec's path with its parameters written `{}`, as `KnownPaths`
+    /// writes them.
+    fn shape(path: &str) -> String {
+        path.split('/')
+            .map(|segment| match segment.starts_with('{') {
+                true => "{}",
+                false => segment,
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    #[tokio::test]
+    async fn every_served_route_is_in_the_served_spec() {
+        let app = test_support::offline_app().await;
+        let config = app.config.clone();
+        let response = warp::test::request()
+            .path("/api-docs/openapi.json")
+            .reply(&crate::routes(app))
+            .await;
+        assert_eq!(response.status(), 200);
+        let served: OpenApi = serde_json::from_slice(response.body()).unwrap();
+        assert!(matches!(served.openapi, OpenApiVersion::Version3));
+
+        let spec = super::spec(&config.api_prefix, config.metrics_port.is_none());
+        let known = crate::known_paths(&config, &spec).paths();
+        // The docs themselves and the WebSocket upgrade are left out.
+        let documented = known
+            .iter()
+            .filter(|(path, _)| !path.starts_with("/api-docs") && !path.ends_with("/ws"));
+        for (path, methods) in documented {
+            let item = served
+                .paths
+                .paths
+                .iter()
+                .find(|(served, _)| shape(served) == *path)
+                .map(|(_, item)| item)
+                .unwrap_or_else(|| panic!("{} is served but not documented", path));
+            for method in methods {
+                let documented = item
+                    .operations
+                    .keys()
+                    .any(|op| crate::known_paths::method(op) == method);
+                assert!(documented, "{} {} is not documented", method, path);
+            }
+        }
+    }
+}
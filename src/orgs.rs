@@ -0,0 +1,475 @@
+use crate::{
+    auth::{invalid_role_data, with_claims, AuthContext, Claims, Role},
+    error::Error,
+    repository::timed,
+    users::{self, active},
+    validation::{Validate, Validator},
+    User, WebResult,
+};
+use mongodb::{
+    bson::{doc, uuid::Uuid, DateTime},
+    options::{FindOptions, IndexOptions},
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utoipa::{IntoParams, ToSchema};
+use warp::{http::StatusCode, reject, reply, Filter, Rejection, Reply};
+
+const MAX_NAME_LENGTH: usize = 100;
+const DEFAULT_PAGE_LIMIT: u64 = 50;
+const MAX_PAGE_LIMIT: u64 = 200;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Organization {
+    pub id: String,
+    pub name: String,
+    pub created_by: String,
+    pub created_at: DateTime,
+}
+
+/// A user's role within one organization, independent of their global
+/// role and of their roles in other organizations.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Membership {
+    pub uid: String,
+    pub org_id: String,
+    /// `User` for plain members or `Admin` for those who manage the
+    /// organization.
+    pub role: String,
+    pub added_by: String,
+    pub created_at: DateTime,
+}
+
+/// The caller of an `/orgs/{org_id}/...` route, as [`with_org_role`]
+/// authorized them.
+pub struct OrgAccess {
+    pub org_id: String,
+    pub claims: Claims,
+    /// The caller's role in the organization; `Admin` for global admins.
+    pub role: Role,
+}
+
+pub async fn create_indexes(
+    organizations: &Collection<Organization>,
+    memberships: &Collection<Membership>,
+) -> mongodb::error::Result<()> {
+    let id_index = IndexModel::builder()
+        .keys(doc! {"id": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    organizations.create_index(id_index, None).await?;
+
+    let member_index = IndexModel::builder()
+        .keys(doc! {"org_id": 1, "uid": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    let uid_index = IndexModel::builder().keys(doc! {"uid": 1}).build();
+    memberships
+        .create_indexes(vec![member_index, uid_index], None)
+        .await?;
+    Ok(())
+}
+
+/// Matches the `orgs/{org_id}` prefix of a route and lets the caller
+/// through if their membership in that organization has at least
+/// `role`. Global admins pass without a membership, and guests never do.
+/// Organizations the caller is not a member of answer 404, so their ids
+/// can't be probed.
+pub fn with_org_role(
+    role: Role,
+    context: AuthContext,
+    memberships: Collection<Membership>,
+) -> impl Filter<Extract = (OrgAccess,), Error = Rejection> + Clone {
+    warp::path("orgs")
+        .and(warp::path::param::<String>())
+        .and(with_claims(context))
+        .and_then(move |org_id: String, claims: Claims| {
+            let role = role.clone();
+            let memberships = memberships.clone();
+            async move { authorize_org(&role, &memberships, org_id, claims).await }
+        })
+}
+
+async fn authorize_org(
+    required: &Role,
+    memberships: &Collection<Membership>,
+    org_id: String,
+    claims: Claims,
+) -> WebResult<OrgAccess> {
+    // Not even to organizations a guest created before `POST /orgs`
+    // refused them.
+    if !claims.role.has_permission(&Role::User) {
+        return Err(reject::custom(Error::NoPermissionError));
+    }
+    if claims.role == Role::Admin {
+        return Ok(OrgAccess {
+            org_id,
+            claims,
+            role: Role::Admin,
+        });
+    }
+    let membership =
+        timed(memberships.find_one(doc! {"org_id": &org_id, "uid": &claims.sub}, None))
+            .await
+            .map_err(reject::custom)?
+            .ok_or_else(|| reject::custom(Error::OrgNotFoundError))?;
+    let role = match parse_org_role(Some(&membership.role)) {
+        Ok(role) => role,
+        Err(_) => {
+            return Err(reject::custom(invalid_role_data(
+                &claims.sub,
+                &membership.role,
+            )))
+        }
+    };
+    if !role.has_permission(required) {
+        return Err(reject::custom(Error::NoPermissionError));
+    }
+    Ok(OrgAccess {
+        org_id,
+        claims,
+        role,
+    })
+}
+
+/// Only the two built-in roles exist within an organization.
+fn parse_org_role(role: Option<&str>) -> Result<Role, Error> {
+    match role.map(Role::from_str).transpose()? {
+        None => Ok(Role::User),
+        Some(role @ (Role::User | Role::Admin)) => Ok(role),
+        Some(Role::Guest | Role::Custom(_)) => Err(Error::InvalidRoleError),
+    }
+}
+
+async fn find_org(
+    organizations: &Collection<Organization>,
+    org_id: &str,
+) -> WebResult<Organization> {
+    timed(organizations.find_one(doc! {"id": org_id}, None))
+        .await
+        .map_err(reject::custom)?
+        .ok_or_else(|| reject::custom(Error::OrgNotFoundError))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateOrgRequest {
+    pub name: String,
+}
+
+impl Validate for CreateOrgRequest {
+    fn validate(&self, validator: &mut Validator) {
+        if validator.non_empty("name", &self.name) {
+            validator.max_length("name", &self.name, MAX_NAME_LENGTH);
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct OrgResponse {
+    pub id: String,
+    pub name: String,
+    pub created_by: String,
+    pub created_at: Option<String>,
+}
+
+impl From<Organization> for OrgResponse {
+    fn from(org: Organization) -> Self {
+        OrgResponse {
+            id: org.id,
+            name: org.name,
+            created_by: org.created_by,
+            created_at: org.created_at.try_to_rfc3339_string().ok(),
+        }
+    }
+}
+
+/// Creates an organization with the caller as its first admin.
+#[utoipa::path(
+    post,
+    path = "/orgs",
+    tag = "organizations",
+    request_body = CreateOrgRequest,
+    responses(
+        (status = 201, description = "The new organization", body = OrgResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "A guest account", body = ErrorResponse),
+        (status = 422, description = "Missing or overlong name", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn create_org_handler(
+    claims: Claims,
+    organizations: Collection<Organization>,
+    memberships: Collection<Membership>,
+    body: CreateOrgRequest,
+) -> WebResult<impl Reply> {
+    let now = DateTime::now();
+    let org = Organization {
+        id: Uuid::new().to_string(),
+        name: body.name.trim().to_string(),
+        created_by: claims.sub.clone(),
+        created_at: now,
+    };
+    timed(organizations.insert_one(&org, None))
+        .await
+        .map_err(reject::custom)?;
+    let membership = Membership {
+        uid: claims.sub.clone(),
+        org_id: org.id.clone(),
+        role: Role::Admin.to_string(),
+        added_by: claims.sub,
+        created_at: now,
+    };
+    if let Err(e) = timed(memberships.insert_one(&membership, None)).await {
+        // An organization nobody can manage would be unreachable.
+        timed(organizations.delete_one(doc! {"id": &org.id}, None))
+            .await
+            .ok();
+        return Err(reject::custom(e));
+    }
+
+    Ok(reply::with_status(
+        reply::json(&OrgResponse::from(org)),
+        StatusCode::CREATED,
+    ))
+}
+
+/// Deletes the organization together with all of its memberships.
+#[utoipa::path(
+    delete,
+    path = "/orgs/{org_id}",
+    tag = "organizations",
+    params(("org_id" = String, Path, description = "Organization id")),
+    responses(
+        (status = 204, description = "Organization deleted"),
+        (status = 403, description = "Not an admin of the organization", body = ErrorResponse),
+        (status = 404, description = "No such organization, or not a member", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn delete_org_handler(
+    access: OrgAccess,
+    organizations: Collection<Organization>,
+    memberships: Collection<Membership>,
+) -> WebResult<impl Reply> {
+    let result = timed(organizations.delete_one(doc! {"id": &access.org_id}, None))
+        .await
+        .map_err(reject::custom)?;
+    if result.deleted_count == 0 {
+        return Err(reject::custom(Error::OrgNotFoundError));
+    }
+    timed(memberships.delete_many(doc! {"org_id": &access.org_id}, None))
+        .await
+        .map_err(reject::custom)?;
+
+    Ok(reply::with_status(reply(), StatusCode::NO_CONTENT))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct AddMemberRequest {
+    /// The account to add, which must already exist.
+    pub email: String,
+    /// `User` (the default) or `Admin`.
+    pub role: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MemberResponse {
+    pub uid: String,
+    /// Absent when the account has been deleted.
+    pub email: Option<String>,
+    pub role: String,
+    pub added_by: String,
+    pub added_at: Option<String>,
+}
+
+fn member_response(membership: Membership, user: Option<&User>) -> MemberResponse {
+    MemberResponse {
+        uid: membership.uid,
+        email: user.map(|user| user.email.clone()),
+        role: membership.role,
+        added_by: membership.added_by,
+        added_at: membership.created_at.try_to_rfc3339_string().ok(),
+    }
+}
+
+/// Adds an existing account to the organization. Only its admins (and
+/// global admins) may, and only they may grant `Admin`.
+#[utoipa::path(
+    post,
+    path = "/orgs/{org_id}/members",
+    tag = "organizations",
+    params(("org_id" = String, Path, description = "Organization id")),
+    request_body = AddMemberRequest,
+    responses(
+        (status = 201, description = "The new membership", body = MemberResponse),
+        (status = 400, description = "Role other than `User` or `Admin`", body = ErrorResponse),
+        (status = 403, description = "Not an admin of the organization", body = ErrorResponse),
+        (status = 404, description = "No such organization or account, or not a member",
+            body = ErrorResponse),
+        (status = 409, description = "Already a member", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn add_member_handler(
+    access: OrgAccess,
+    organizations: Collection<Organization>,
+    memberships: Collection<Membership>,
+    users_collection: Collection<User>,
+    body: AddMemberRequest,
+) -> WebResult<impl Reply> {
+    let role = parse_org_role(body.role.as_deref()).map_err(reject::custom)?;
+    find_org(&organizations, &access.org_id).await?;
+    let user = timed(users_collection.find_one(active(users::by_email(&body.email)), None))
+        .await
+        .map_err(reject::custom)?
+        .ok_or_else(|| reject::custom(Error::UserNotFoundError))?;
+
+    let membership = Membership {
+        uid: user.uid.clone(),
+        org_id: access.org_id,
+        role: role.to_string(),
+        added_by: access.claims.sub,
+        created_at: DateTime::now(),
+    };
+    timed(memberships.insert_one(&membership, None))
+        .await
+        .map_err(|e| match e {
+            Error::DuplicateKeyError => reject::custom(Error::AlreadyMemberError),
+            other => reject::custom(other),
+        })?;
+
+    Ok(reply::with_status(
+        reply::json(&member_response(membership, Some(&user))),
+        StatusCode::CREATED,
+    ))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MembersQuery {
+    /// Starting at 1 (the default).
+    pub page: Option<u64>,
+    /// Members per page, at most 200 (default 50).
+    pub limit: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MemberPage {
+    pub members: Vec<MemberResponse>,
+    pub page: u64,
+    pub limit: u64,
+    pub total: u64,
+    pub next_page: Option<u64>,
+}
+
+/// The organization's members in the order they joined. Any member may
+/// list them.
+#[utoipa::path(
+    get,
+    path = "/orgs/{org_id}/members",
+    tag = "organizations",
+    params(("org_id" = String, Path, description = "Organization id"), MembersQuery),
+    responses(
+        (status = 200, description = "One page of members", body = MemberPage),
+        (status = 400, description = "`page` or `limit` out of range", body = ErrorResponse),
+        (status = 404, description = "No such organization, or not a member", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn list_members_handler(
+    access: OrgAccess,
+    organizations: Collection<Organization>,
+    memberships: Collection<Membership>,
+    users_collection: Collection<User>,
+    query: MembersQuery,
+) -> WebResult<impl Reply> {
+    let page = query.page.unwrap_or(1);
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if page == 0 || limit == 0 || limit > MAX_PAGE_LIMIT {
+        return Err(reject::custom(Error::InvalidPaginationError));
+    }
+    find_org(&organizations, &access.org_id).await?;
+
+    let filter = doc! {"org_id": &access.org_id};
+    let total = timed(memberships.count_documents(filter.clone(), None))
+        .await
+        .map_err(reject::custom)?;
+    let options = FindOptions::builder()
+        .sort(doc! {"created_at": 1, "uid": 1})
+        .skip((page - 1) * limit)
+        .limit(limit as i64)
+        .build();
+    let mut cursor = timed(memberships.find(filter, options))
+        .await
+        .map_err(reject::custom)?;
+    let mut page_memberships = Vec::new();
+    while timed(cursor.advance()).await.map_err(reject::custom)? {
+        let membership: Membership = cursor
+            .deserialize_current()
+            .map_err(|e| reject::custom(Error::from(e)))?;
+        page_memberships.push(membership);
+    }
+
+    let uids: Vec<&str> = page_memberships.iter().map(|m| m.uid.as_str()).collect();
+    let mut cursor = timed(users_collection.find(active(doc! {"uid": {"$in": uids}}), None))
+        .await
+        .map_err(reject::custom)?;
+    let mut accounts = HashMap::new();
+    while timed(cursor.advance()).await.map_err(reject::custom)? {
+        let user: User = cursor
+            .deserialize_current()
+            .map_err(|e| reject::custom(Error::from(e)))?;
+        accounts.insert(user.uid.clone(), user);
+    }
+
+    let members = page_memberships
+        .into_iter()
+        .map(|membership| {
+            let user = accounts.get(&membership.uid);
+            member_response(membership, user)
+        })
+        .collect();
+    Ok(reply::json(&MemberPage {
+        members,
+        page,
+        limit,
+        total,
+        next_page: (page * limit < total).then_some(page + 1),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::Client;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn guests_are_refused_before_their_membership_is_looked_up() {
+        // Never contacted: the guest is turned away first.
+        let client = Client::with_uri_str("mongodb://127.0.0.1:1/?directConnection=true")
+            .await
+            .unwrap();
+        let memberships = client.database("test").collection("memberships");
+        let guest: Claims = serde_json::from_value(json!({
+            "sub": "guest",
+            "role": "Guest",
+            "exp": usize::MAX,
+            "iat": 0,
+            "jti": "jti",
+        }))
+        .unwrap();
+
+        let rejection = authorize_org(&Role::User, &memberships, "org".to_string(), guest)
+            .await
+            .err()
+            .unwrap();
+
+        assert!(matches!(
+            rejection.find::<Error>(),
+            Some(Error::NoPermissionError)
+        ));
+    }
+}
@@ -0,0 +1,236 @@
+use crate::{
+    admin_sessions::AdminSessionResponse, audit::AuditEntry, error::Error, repository::timed,
+    Result, UserResponse,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use mongodb::{
+    bson::{self, spec::ElementType, Bson, Document},
+    options::FindOptions,
+    Collection,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use utoipa::ToSchema;
+use warp::{
+    http::HeaderValue,
+    reply::{self, Response},
+    Reply,
+};
+
+pub const DEFAULT_LIMIT: u64 = 50;
+pub const MAX_LIMIT: u64 = 200;
+
+/// One field of the order a listing is paged in, with the BSON type its
+/// values have, which a cursor must match.
+pub struct SortKey {
+    pub field: &'static str,
+    pub descending: bool,
+    pub kind: ElementType,
+}
+
+/// The order of a listing. Ends in a unique field, normally `_id`, so a
+/// cursor names exactly one position.
+pub type Order = &'static [SortKey];
+
+/// One page of a listing. Pass `next_cursor` back as `cursor` for the
+/// next one.
+#[derive(Serialize, ToSchema)]
+#[aliases(
+    UserPage = Page<UserResponse>,
+    AuditPage = Page<AuditEntry>,
+    AdminSessionPage = Page<AdminSessionResponse>
+)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Where the next page starts; null on the last page.
+    pub next_cursor: Option<String>,
+    pub has_more: bool,
+}
+
+impl<T> Page<T> {
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            next_cursor: self.next_cursor,
+            has_more: self.has_more,
+        }
+    }
+}
+
+enum Position {
+    Start,
+    After(Document),
+    /// The deprecated `?page=`, as a number of documents to skip.
+    Skip(u64),
+}
+
+/// Which page of a listing a request asks for.
+pub struct PageRequest {
+    limit: u64,
+    position: Position,
+}
+
+impl PageRequest {
+    /// From a listing's `cursor`, `page` and `limit` parameters. A limit
+    /// outside 1 to [`MAX_LIMIT`], page 0 or both a cursor and a page are
+    /// refused with `InvalidPaginationError`, and a cursor that isn't one
+    /// of `order`'s with `InvalidCursorError`.
+    pub fn new(
+        order: Order,
+        cursor: Option<&str>,
+        page: Option<u64>,
+        limit: Option<u64>,
+    ) -> Result<PageRequest> {
+        let limit = limit.unwrap_or(DEFAULT_LIMIT);
+        if limit == 0 || limit > MAX_LIMIT {
+            return Err(Error::InvalidPaginationError);
+        }
+        let position = match (cursor, page) {
+            (Some(_), Some(_)) | (None, Some(0)) => return Err(Error::InvalidPaginationError),
+            (Some(cursor), None) => Position::After(decode(order, cursor)?),
+            (None, Some(page)) => Position::Skip((page - 1) * limit),
+            (None, None) => Position::Start,
+        };
+        Ok(PageRequest { limit, position })
+    }
+}
+
+/// One page of the documents of `collection` matching `filter`, in
+/// `order`. The order should be served by an index that starts with
+/// `filter`'s fields, so each page is read straight from it however deep
+/// into the listing it is. `projection`, if any, must keep the fields of
+/// `order`.
+pub async fn find<T: DeserializeOwned>(
+    collection: &Collection<T>,
+    filter: Document,
+    order: Order,
+    request: &PageRequest,
+    projection: Option<Document>,
+) -> Result<Page<T>> {
+    let sort: Document = order
+        .iter()
+        .map(|key| (key.field.to_string(), Bson::Int32(direction(key))))
+        .collect();
+    let (filter, skip) = match &request.position {
+        Position::Start => (filter, None),
+        Position::After(cursor) => (and(filter, after(order, cursor)), None),
+        Position::Skip(skip) => (filter, Some(*skip)),
+    };
+    // One more than asked for tells whether there is a next page.
+    let options = FindOptions::builder()
+        .sort(sort)
+        .skip(skip)
+        .limit((request.limit + 1) as i64)
+        .projection(projection)
+        .build();
+    let mut cursor = timed(
+        collection
+            .clone_with_type::<Document>()
+            .find(filter, options),
+    )
+    .await?;
+    let mut documents = Vec::new();
+    while timed(cursor.advance()).await? {
+        documents.push(cursor.deserialize_current()?);
+    }
+
+    let has_more = documents.len() as u64 > request.limit;
+    documents.truncate(request.limit as usize);
+    let next_cursor = match documents.last() {
+        Some(last) if has_more => Some(encode(order, last)?),
+        _ => None,
+    };
+    let items = documents
+        .into_iter()
+        .map(|document| bson::from_document(document).map_err(Error::from))
+        .collect::<Result<_>>()?;
+    Ok(Page {
+        items,
+        next_cursor,
+        has_more,
+    })
+}
+
+/// `page` as JSON, marked with a `Deprecation` header when it was asked
+/// for with `?page=`.
+pub fn reply<T: Serialize>(page: &Page<T>, request: &PageRequest) -> Response {
+    let mut response = reply::json(page).into_response();
+    if let Position::Skip(_) = request.position {
+        response
+            .headers_mut()
+            .insert("deprecation", HeaderValue::from_static("true"));
+    }
+    response
+}
+
+fn direction(key: &SortKey) -> i32 {
+    if key.descending {
+        -1
+    } else {
+        1
+    }
+}
+
+fn and(filter: Document, other: Document) -> Document {
+    if filter.is_empty() {
+        other
+    } else {
+        bson::doc! {"$and": [filter, other]}
+    }
+}
+
+/// The documents after `cursor` in `order`: those past it in the first
+/// sort field, or equal there and past it in the next, and so on.
+fn after(order: Order, cursor: &Document) -> Document {
+    let branches: Vec<Document> = order
+        .iter()
+        .enumerate()
+        .map(|(i, key)| {
+            let mut branch: Document = order[..i]
+                .iter()
+                .map(|earlier| (earlier.field.to_string(), cursor_value(cursor, earlier)))
+                .collect();
+            let operator = if key.descending { "$lt" } else { "$gt" };
+            branch.insert(key.field, bson::doc! {operator: cursor_value(cursor, key)});
+            branch
+        })
+        .collect();
+    bson::doc! {"$or": branches}
+}
+
+fn cursor_value(cursor: &Document, key: &SortKey) -> Bson {
+    cursor.get(key.field).cloned().unwrap_or(Bson::Null)
+}
+
+/// The sort key of `last` as an opaque cursor: its BSON, base64url
+/// encoded.
+fn encode(order: Order, last: &Document) -> Result<String> {
+    let mut key = Document::new();
+    for sort_key in order {
+        let value = last.get(sort_key.field).ok_or_else(|| {
+            Error::DatabaseError(format!("sort key {} missing from a page", sort_key.field).into())
+        })?;
+        key.insert(sort_key.field, value.clone());
+    }
+    let mut bytes = Vec::new();
+    key.to_writer(&mut bytes).map_err(Error::from)?;
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// Reads a cursor made by [`encode`] for `order`. Anything else, such as
+/// a cursor edited by hand or from another listing, is an
+/// `InvalidCursorError` rather than a query that may fail or match
+/// nothing.
+fn decode(order: Order, cursor: &str) -> Result<Document> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(cursor)
+        .map_err(|_| Error::InvalidCursorError)?;
+    let key = Document::from_reader(bytes.as_slice()).map_err(|_| Error::InvalidCursorError)?;
+    let matches = key.len() == order.len()
+        && key.iter().zip(order).all(|((field, value), sort_key)| {
+            field == sort_key.field && value.element_type() == sort_key.kind
+        });
+    if !matches {
+        return Err(Error::InvalidCursorError);
+    }
+    Ok(key)
+}
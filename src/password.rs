@@ -0,0 +1,201 @@
+use crate::{config, error::Error, Result, User};
+use argon2::{
+    password_hash::{
+        self, rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Algorithm, Argon2, Params, Version,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use mongodb::bson::{doc, Document};
+use sha2::Sha256;
+
+const ARGON2ID_PREFIX: &str = "$argon2id$";
+const BCRYPT_PREFIXES: [&str; 3] = ["$2a$", "$2b$", "$2y$"];
+
+/// How a password compared to its stored hash.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Mismatch,
+    Match,
+    /// Correct, but the hash predates Argon2id or the current pepper and
+    /// should be replaced with a fresh `hash`.
+    Outdated,
+}
+
+fn argon2(params: Params) -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+/// With `PASSWORD_PEPPER` set, what gets hashed is the HMAC-SHA256 of the
+/// password keyed by the pepper, so a dump of the users collection alone is
+/// not enough to crack it. Base64 keeps it within bcrypt's 72 bytes.
+fn peppered(password: &str, pepper: &str) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(pepper.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(password.as_bytes());
+    STANDARD.encode(mac.finalize().into_bytes())
+}
+
+/// Hashes a new password with Argon2id, in PHC string format, peppered if
+/// a pepper is configured. Every new hash goes through here, with the
+/// `ARGON2_*` parameters, which the hash records.
+pub fn hash(password: &str) -> Result<String> {
+    hash_with(password, config::argon2_params())
+}
+
+fn hash_with(password: &str, params: Params) -> Result<String> {
+    let secret = match config::password_pepper() {
+        Some(pepper) => peppered(password, pepper),
+        None => password.to_owned(),
+    };
+    let salt = SaltString::generate(&mut OsRng);
+    argon2(params)
+        .hash_password(secret.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| Error::PasswordHashingError(Box::new(e)))
+}
+
+/// Whether `password` matches the stored hash; see `check`.
+pub fn verify(password: &str, hash: &str) -> Result<bool> {
+    Ok(check(password, hash)? != Verification::Mismatch)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Calls to `check` on this thread, for tests that count them.
+    pub static CHECKS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Checks `password` against a stored Argon2id or bcrypt hash. Hashes made
+/// before the pepper was configured still match, as `Outdated`.
+pub fn check(password: &str, hash: &str) -> Result<Verification> {
+    #[cfg(test)]
+    CHECKS.with(|checks| checks.set(checks.get() + 1));
+    let current_scheme = hash.starts_with(ARGON2ID_PREFIX);
+    let pepper = config::password_pepper();
+    if let Some(pepper) = pepper {
+        if verify_secret(&peppered(password, pepper), hash)? {
+            return Ok(if current_scheme {
+                Verification::Match
+            } else {
+                Verification::Outdated
+            });
+        }
+    }
+    if verify_secret(password, hash)? {
+        return Ok(if current_scheme && pepper.is_none() {
+            Verification::Match
+        } else {
+            Verification::Outdated
+        });
+    }
+    Ok(Verification::Mismatch)
+}
+
+/// Whether `password` is one of the last `PASSWORD_HISTORY` passwords of
+/// `user`, the current one included. Each is checked with a full
+/// verification, so with the defaults a password change spends up to five
+/// times as long hashing. A remembered hash that is unusable is skipped.
+pub fn recently_used(password: &str, user: &User) -> bool {
+    let mut hashes: Vec<&str> = Vec::new();
+    // `pw` comes first: it may have been set, or re-hashed at a login,
+    // without being pushed to the history.
+    for hash in std::iter::once(&user.pw).chain(&user.pw_history) {
+        if hashes.len() == config::password_history() {
+            break;
+        }
+        if !hash.is_empty() && !hashes.contains(&hash.as_str()) {
+            hashes.push(hash);
+        }
+    }
+    hashes
+        .into_iter()
+        .any(|hash| verify(password, hash).unwrap_or(false))
+}
+
+/// The `$push` that makes `hash` the newest entry of `pw_history`, dropping
+/// those beyond `PASSWORD_HISTORY`.
+pub fn history_push(hash: &str) -> Document {
+    doc! {"pw_history": {
+        "$each": [hash],
+        "$position": 0,
+        "$slice": config::password_history() as i64,
+    }}
+}
+
+/// Tells the scheme apart by the hash's prefix. A hash that is corrupted
+/// or in an unknown scheme is logged and never matches.
+fn verify_secret(secret: &str, hash: &str) -> Result<bool> {
+    let verified = if hash.starts_with(ARGON2ID_PREFIX) {
+        verify_argon2(secret, hash)
+    } else if is_bcrypt(hash) {
+        bcrypt::verify(secret, hash).map_err(|e| e.to_string())
+    } else {
+        Err("unknown hash scheme".to_string())
+    };
+    verified.map_err(|e| {
+        Error::PasswordVerificationError(format!("stored password hash is unusable: {}", e).into())
+    })
+}
+
+fn verify_argon2(secret: &str, hash: &str) -> std::result::Result<bool, String> {
+    let parsed = PasswordHash::new(hash).map_err(|e| e.to_string())?;
+    if parsed.hash.is_none() {
+        return Err("the hash output is missing".to_string());
+    }
+    // Verification takes the parameters from `parsed`, so hashes made
+    // before the `ARGON2_*` settings changed keep matching.
+    match argon2(config::argon2_params()).verify_password(secret.as_bytes(), &parsed) {
+        Ok(()) => Ok(true),
+        Err(password_hash::Error::Password) => Ok(false),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+pub fn is_bcrypt(hash: &str) -> bool {
+    BCRYPT_PREFIXES
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, TEST_JWT_SECRET};
+
+    /// Parameters as `Config::from_env` reads them from `vars`.
+    fn params(vars: &[(&str, &str)]) -> Params {
+        let mut all = vec![
+            ("JWT_SECRET", TEST_JWT_SECRET),
+            ("MONGO_URI", "mongodb://localhost:27017"),
+        ];
+        all.extend_from_slice(vars);
+        test_support::config(&all).unwrap().argon2_params
+    }
+
+    #[test]
+    fn the_stored_hash_records_the_configured_parameters() {
+        let params = params(&[
+            ("ARGON2_MEMORY_KIB", "8"),
+            ("ARGON2_ITERATIONS", "1"),
+            ("ARGON2_PARALLELISM", "1"),
+        ]);
+        let hash = hash_with("a long password", params).unwrap();
+
+        let stored = Params::try_from(&PasswordHash::new(&hash).unwrap()).unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=8,t=1,p=1$"), "{}", hash);
+        assert_eq!(
+            (stored.m_cost(), stored.t_cost(), stored.p_cost()),
+            (8, 1, 1)
+        );
+    }
+
+    #[test]
+    fn hashes_made_with_other_parameters_still_verify() {
+        let cheap = Params::new(8, 1, 1, None).unwrap();
+        let hash = hash_with("a long password", cheap).unwrap();
+        assert!(verify("a long password", &hash).unwrap());
+        assert!(!verify("another password", &hash).unwrap());
+    }
+}
@@ -0,0 +1,193 @@
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{hash_token, random_token},
+    error::Error,
+    mailer::Mailer,
+    password,
+    repository::timed,
+    sessions::{ClientInfo, Session},
+    users,
+    validation::Validator,
+    User, WebResult,
+};
+use mongodb::{
+    bson::{doc, DateTime},
+    options::IndexOptions,
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::ToSchema;
+use warp::{http::StatusCode, reject, reply, Reply};
+
+const RESET_TOKEN_LENGTH: usize = 48;
+const RESET_TOKEN_EXPIRY: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PasswordReset {
+    pub token_hash: String,
+    pub uid: String,
+    pub expires_at: DateTime,
+    pub used: bool,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PasswordResetRequest {
+    #[serde(deserialize_with = "crate::users::normalized_email")]
+    pub email: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PasswordResetConfirm {
+    /// The token from the reset email.
+    pub token: String,
+    pub pw: String,
+}
+
+pub async fn create_indexes(collection: &Collection<PasswordReset>) -> mongodb::error::Result<()> {
+    let index = IndexModel::builder()
+        .keys(doc! {"expires_at": 1})
+        .options(
+            IndexOptions::builder()
+                .expire_after(Duration::from_secs(0))
+                .build(),
+        )
+        .build();
+    collection.create_index(index, None).await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/password-reset/request",
+    tag = "account",
+    request_body = PasswordResetRequest,
+    responses(
+        (status = 200, description = "Sent if the account exists; the same either way", body = String),
+    )
+)]
+pub async fn request_reset_handler(
+    mailer: Mailer,
+    users_collection: Collection<User>,
+    resets_collection: Collection<PasswordReset>,
+    body: PasswordResetRequest,
+) -> WebResult<impl Reply> {
+    let user = timed(users_collection.find_one(users::active(users::by_email(&body.email)), None))
+        .await
+        .map_err(reject::custom)?;
+
+    // Respond identically whether or not the account exists so the endpoint
+    // can't be used to enumerate registered emails.
+    if let Some(user) = user {
+        let token = random_token(RESET_TOKEN_LENGTH);
+        let reset = PasswordReset {
+            token_hash: hash_token(&token),
+            uid: user.uid.clone(),
+            expires_at: DateTime::now().saturating_add_duration(RESET_TOKEN_EXPIRY),
+            used: false,
+        };
+        timed(resets_collection.insert_one(reset, None))
+            .await
+            .map_err(reject::custom)?;
+        // A delivery failure is only logged: reporting it would reveal that
+        // the account exists.
+        let body = format!("Use this token to reset your password: {}", token);
+        if mailer
+            .send(&user.email, "Reset your password", &body)
+            .await
+            .is_err()
+        {
+            tracing::error!("password reset email for {} was not sent", user.uid);
+        }
+    }
+
+    Ok(reply::with_status(
+        "If the account exists, a password reset email has been sent",
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/password-reset/confirm",
+    tag = "account",
+    request_body = PasswordResetConfirm,
+    responses(
+        (status = 200, description = "Password changed", body = String),
+        (status = 400, description = "Unknown, used or expired token", body = ErrorResponse),
+        (status = 422, description = "Password too short, or one of the recent passwords",
+            body = ErrorResponse),
+    )
+)]
+pub async fn confirm_reset_handler(
+    users_collection: Collection<User>,
+    sessions_collection: Collection<Session>,
+    resets_collection: Collection<PasswordReset>,
+    client: ClientInfo,
+    body: PasswordResetConfirm,
+) -> WebResult<impl Reply> {
+    let mut validator = Validator::new();
+    validator.password("pw", &body.pw);
+    validator.finish().map_err(reject::custom)?;
+
+    let usable = doc! {
+        "token_hash": hash_token(&body.token),
+        "used": false,
+        "expires_at": {"$gt": DateTime::now()},
+    };
+    // Looked up without using the token up first, so that a recently used
+    // password can be refused and another one tried with the same token.
+    let reset = timed(resets_collection.find_one(usable.clone(), None))
+        .await
+        .map_err(reject::custom)?
+        .ok_or_else(|| reject::custom(Error::InvalidResetTokenError))?;
+    let user = timed(users_collection.find_one(doc! {"uid": &reset.uid}, None))
+        .await
+        .map_err(reject::custom)?
+        .ok_or_else(|| reject::custom(Error::InvalidResetTokenError))?;
+    if password::recently_used(&body.pw, &user) {
+        return Err(reject::custom(Error::PasswordRecentlyUsedError));
+    }
+
+    // Marking the token used in the same operation that finds it makes the
+    // token single-use even under concurrent confirmations.
+    timed(resets_collection.find_one_and_update(usable, doc! {"$set": {"used": true}}, None))
+        .await
+        .map_err(reject::custom)?
+        .ok_or_else(|| reject::custom(Error::InvalidResetTokenError))?;
+
+    let hashed_pw = password::hash(&body.pw).map_err(reject::custom)?;
+
+    let result = timed(users_collection.update_one(
+        doc! {"uid": &reset.uid},
+        doc! {
+            "$set": {
+                "pw": &hashed_pw,
+                "must_change_password": false,
+                "updated_at": DateTime::now(),
+            },
+            "$push": password::history_push(&hashed_pw),
+            "$inc": {"version": 1},
+        },
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?;
+    if result.matched_count == 0 {
+        return Err(reject::custom(Error::InvalidResetTokenError));
+    }
+    audit::record(
+        AuditEvent::new(AuditAction::PasswordChanged, &client)
+            .target(&reset.uid)
+            .detail("password reset"),
+    );
+
+    timed(sessions_collection.delete_many(doc! {"uid": &reset.uid}, None))
+        .await
+        .map_err(reject::custom)?;
+
+    Ok(reply::with_status(
+        "Password updated successfully",
+        StatusCode::OK,
+    ))
+}
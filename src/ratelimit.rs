@@ -0,0 +1,202 @@
+use crate::{auth::AuthContext, error::Error, throttle::client_ip, Result};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use warp::{
+    filters::header::headers_cloned,
+    http::{HeaderMap, HeaderValue},
+    reject,
+    reply::{self, Reply},
+    Filter, Rejection,
+};
+
+pub const LIMIT_HEADER: &str = "x-ratelimit-limit";
+pub const REMAINING_HEADER: &str = "x-ratelimit-remaining";
+pub const RESET_HEADER: &str = "x-ratelimit-reset";
+
+struct Window {
+    started: Instant,
+    count: u64,
+}
+
+/// Fixed-window request limiter shared by every route it is attached to.
+#[derive(Clone)]
+pub struct RateLimiter {
+    windows: Arc<Mutex<HashMap<String, Window>>>,
+    limit: u64,
+    window: Duration,
+    trust_proxy: bool,
+}
+
+/// Outcome of a request that was let through, used to fill in the
+/// `X-RateLimit-*` headers.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitStatus {
+    pub limit: u64,
+    pub remaining: u64,
+    /// Seconds until the current window ends.
+    pub reset: u64,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u64, window: Duration, trust_proxy: bool) -> Self {
+        RateLimiter {
+            windows: Arc::default(),
+            limit,
+            window,
+            trust_proxy,
+        }
+    }
+
+    /// Counts one request for `key`, for callers that limit on something
+    /// other than the requester, such as a target email address.
+    pub fn hit(&self, key: String) -> Result<RateLimitStatus> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().expect("rate limiter lock poisoned");
+        let window = windows.entry(key).or_insert(Window {
+            started: now,
+            count: 0,
+        });
+        if now.duration_since(window.started) >= self.window {
+            window.started = now;
+            window.count = 0;
+        }
+
+        let elapsed = now.duration_since(window.started);
+        let reset = self.window.saturating_sub(elapsed).as_secs().max(1);
+        if window.count >= self.limit {
+            return Err(Error::RateLimitExceededError {
+                limit: self.limit,
+                reset,
+            });
+        }
+        window.count += 1;
+        Ok(RateLimitStatus {
+            limit: self.limit,
+            remaining: self.limit - window.count,
+            reset,
+        })
+    }
+
+    fn prune(&self) {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().expect("rate limiter lock poisoned");
+        windows.retain(|_, window| now.duration_since(window.started) < self.window);
+    }
+
+    pub fn spawn_cleanup(&self) {
+        let limiter = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(limiter.window);
+            loop {
+                interval.tick().await;
+                limiter.prune();
+            }
+        });
+    }
+}
+
+/// Counts the request against `limiter`, keyed by the uid of a signed,
+/// unexpired token when one is present and by client IP otherwise. The
+/// token is only decoded for the key; the route's own auth filter does the
+/// lookups and checks, once. Place it after the path and method filters so
+/// unmatched routes don't consume the budget, and pass the extracted status
+/// to `with_headers` once the handler has run.
+pub fn with_rate_limit(
+    limiter: RateLimiter,
+    context: AuthContext,
+) -> impl Filter<Extract = (RateLimitStatus,), Error = Rejection> + Clone {
+    let uid = headers_cloned().map(move |headers: HeaderMap| context.token_subject(&headers));
+    uid.and(client_ip(limiter.trust_proxy)).and_then(
+        move |uid: Option<String>, ip: Option<IpAddr>| {
+            let limiter = limiter.clone();
+            async move {
+                let key = match (uid, ip) {
+                    (Some(uid), _) => format!("uid:{}", uid),
+                    (None, Some(ip)) => format!("ip:{}", ip),
+                    (None, None) => "unknown".to_string(),
+                };
+                limiter.hit(key).map_err(reject::custom)
+            }
+        },
+    )
+}
+
+pub fn with_limiter(
+    limiter: RateLimiter,
+) -> impl Filter<Extract = (RateLimiter,), Error = Infallible> + Clone {
+    warp::any().map(move || limiter.clone())
+}
+
+pub fn with_headers<R: Reply>(status: RateLimitStatus, reply: R) -> reply::Response {
+    let mut response = reply.into_response();
+    insert_headers(
+        response.headers_mut(),
+        status.limit,
+        status.remaining,
+        status.reset,
+    );
+    response
+}
+
+pub fn insert_headers(headers: &mut warp::http::HeaderMap, limit: u64, remaining: u64, reset: u64) {
+    headers.insert(LIMIT_HEADER, HeaderValue::from(limit));
+    headers.insert(REMAINING_HEADER, HeaderValue::from(remaining));
+    headers.insert(RESET_HEADER, HeaderValue::from(reset));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{create_jwt, Role},
+        server::RemoteAddr,
+        test_support,
+    };
+
+    /// Whether a request from `ip` with `token`, if any, gets through.
+    async fn allowed(
+        limiter: &RateLimiter,
+        context: &AuthContext,
+        ip: [u8; 4],
+        token: Option<&str>,
+    ) -> bool {
+        let mut request = warp::test::request().extension(RemoteAddr((ip, 40000).into()));
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        request
+            .filter(&with_rate_limit(limiter.clone(), context.clone()))
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn a_token_keys_the_limit_without_touching_the_database() {
+        // The database is unreachable, so a lookup would fail the request
+        // or fall back to keying by address.
+        let app = test_support::offline_app().await;
+        let context = &app.auth_context;
+        let token = create_jwt(context, "uid", &Role::User, 0).unwrap().token;
+        let limiter = RateLimiter::new(1, Duration::from_secs(60), false);
+
+        assert!(allowed(&limiter, context, [192, 0, 2, 1], Some(&token)).await);
+        assert!(!allowed(&limiter, context, [192, 0, 2, 2], Some(&token)).await);
+        assert!(allowed(&limiter, context, [192, 0, 2, 2], None).await);
+    }
+
+    #[tokio::test]
+    async fn an_invalid_token_is_keyed_by_address() {
+        let app = test_support::offline_app().await;
+        let context = &app.auth_context;
+        let limiter = RateLimiter::new(1, Duration::from_secs(60), false);
+
+        assert!(allowed(&limiter, context, [192, 0, 2, 1], Some("garbage")).await);
+        assert!(!allowed(&limiter, context, [192, 0, 2, 1], Some("other garbage")).await);
+        assert!(allowed(&limiter, context, [192, 0, 2, 2], Some("garbage")).await);
+    }
+}
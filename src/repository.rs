@@ -0,0 +1,402 @@
+use crate::{
+    circuit_breaker, config, error::Error, etag::IfMatch, metrics, server_timing, users, Result,
+    User,
+};
+use async_trait::async_trait;
+use mongodb::{
+    bson::{doc, DateTime},
+    Collection,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Gives up on a MongoDB `operation` after `DB_OP_TIMEOUT_MS` with
+/// `DatabaseTimeoutError`, so a hung database turns into quick 503s
+/// instead of requests that pile up waiting. Dropping the operation leaves
+/// it to the server; a write may still happen after the timeout. The time
+/// it took goes to the metrics and `Server-Timing`. While the circuit
+/// breaker is open the operation isn't sent at all and fails with
+/// `DatabaseUnavailableError`.
+pub async fn timed<T>(operation: impl Future<Output = mongodb::error::Result<T>>) -> Result<T> {
+    let breaker = circuit_breaker::global();
+    if !breaker.allow() {
+        return Err(Error::DatabaseUnavailableError);
+    }
+    // Boxed because driver futures are large, and carrying them inline
+    // through every handler's state overflows worker stacks in debug builds.
+    let started = Instant::now();
+    let result = tokio::time::timeout(config::db_op_timeout(), Box::pin(operation)).await;
+    let elapsed = started.elapsed();
+    metrics::observe_db(elapsed);
+    server_timing::record_db(elapsed);
+    let result = match result {
+        Ok(result) => result.map_err(Error::from),
+        Err(_) => {
+            tracing::warn!(
+                "MongoDB operation timed out after {}ms",
+                config::db_op_timeout().as_millis()
+            );
+            Err(Error::DatabaseTimeoutError)
+        }
+    };
+    breaker.record(!matches!(
+        result,
+        Err(Error::DatabaseTimeoutError | Error::DatabaseUnavailableError)
+    ));
+    result
+}
+
+/// Storage for `User` accounts, so handlers can run without MongoDB.
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    /// The account with this email in any case, deleted accounts included
+    /// since their email stays reserved.
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>>;
+    /// The account with this username in any case.
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>>;
+    /// The account whose email or username is `identifier`, as one lookup.
+    async fn find_by_login(&self, identifier: &str) -> Result<Option<User>>;
+    async fn find_by_uid(&self, uid: &str) -> Result<Option<User>>;
+    /// Fails with `DuplicateKeyError` when the email, username or uid is
+    /// taken.
+    async fn insert(&self, user: &User) -> Result<()>;
+    /// Replaces the stored account with the same uid, if there is one.
+    async fn update(&self, user: &User) -> Result<()>;
+    /// Replaces the stored account with `user` at the next version, unless
+    /// the stored version is not one `if_match` allows, which fails with
+    /// `PreconditionFailedError` so that a write based on a stale read
+    /// doesn't overwrite the one that went first.
+    async fn update_if_match(&self, user: &User, if_match: &IfMatch) -> Result<User>;
+    async fn delete(&self, uid: &str) -> Result<()>;
+    /// Moves `last_login_at` to `previous_login_at` and stamps the current
+    /// time.
+    async fn record_login(&self, uid: &str) -> Result<()>;
+    /// Swaps the password hash for an equivalent one, unless it is no
+    /// longer `current`. Not a change to the account, so `updated_at` and
+    /// the sessions stay as they are.
+    async fn replace_password_hash(&self, uid: &str, current: &str, new: &str) -> Result<()>;
+    /// Lifts the account's ban if it has run out, and says whether it did.
+    /// A ban that has since been extended or made permanent stays.
+    async fn clear_expired_ban(&self, uid: &str) -> Result<bool>;
+}
+
+pub type UserRepo = Arc<dyn UserRepository>;
+
+/// The `users` collection, relying on its unique `email` and `uid`
+/// indexes.
+pub struct MongoUserRepository {
+    collection: Collection<User>,
+}
+
+impl MongoUserRepository {
+    pub fn new(collection: Collection<User>) -> Self {
+        MongoUserRepository { collection }
+    }
+}
+
+#[async_trait]
+impl UserRepository for MongoUserRepository {
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>> {
+        timed(self.collection.find_one(users::by_email(email), None)).await
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>> {
+        timed(self.collection.find_one(users::by_username(username), None)).await
+    }
+
+    async fn find_by_login(&self, identifier: &str) -> Result<Option<User>> {
+        let filter = doc! {"$or": [users::by_email(identifier), users::by_username(identifier)]};
+        timed(self.collection.find_one(filter, None)).await
+    }
+
+    async fn find_by_uid(&self, uid: &str) -> Result<Option<User>> {
+        timed(self.collection.find_one(doc! {"uid": uid}, None)).await
+    }
+
+    async fn insert(&self, user: &User) -> Result<()> {
+        timed(users::documents(&self.collection).insert_one(user.document(), None)).await?;
+        Ok(())
+    }
+
+    async fn update(&self, user: &User) -> Result<()> {
+        timed(users::documents(&self.collection).replace_one(
+            doc! {"uid": &user.uid},
+            user.document(),
+            None,
+        ))
+        .await?;
+        Ok(())
+    }
+
+    async fn update_if_match(&self, user: &User, if_match: &IfMatch) -> Result<User> {
+        let updated = next_version(user);
+        let result = timed(users::documents(&self.collection).replace_one(
+            if_match.apply(doc! {"uid": &user.uid}),
+            updated.document(),
+            None,
+        ))
+        .await?;
+        if result.matched_count == 0 {
+            return Err(match self.find_by_uid(&user.uid).await? {
+                Some(_) => Error::PreconditionFailedError,
+                None => Error::UserNotFoundError,
+            });
+        }
+        Ok(updated)
+    }
+
+    async fn delete(&self, uid: &str) -> Result<()> {
+        timed(self.collection.delete_one(doc! {"uid": uid}, None)).await?;
+        Ok(())
+    }
+
+    async fn record_login(&self, uid: &str) -> Result<()> {
+        let update = vec![doc! {"$set": {
+            "previous_login_at": "$last_login_at",
+            "last_login_at": DateTime::now(),
+        }}];
+        timed(self.collection.update_one(doc! {"uid": uid}, update, None)).await?;
+        Ok(())
+    }
+
+    async fn replace_password_hash(&self, uid: &str, current: &str, new: &str) -> Result<()> {
+        timed(self.collection.update_one(
+            doc! {"uid": uid, "pw": current},
+            doc! {"$set": {"pw": new}},
+            None,
+        ))
+        .await?;
+        Ok(())
+    }
+
+    async fn clear_expired_ban(&self, uid: &str) -> Result<bool> {
+        let result = timed(self.collection.update_one(
+            doc! {"uid": uid, "banned_until": {"$lte": DateTime::now()}},
+            doc! {
+                "$unset": {"banned_at": "", "banned_until": "", "ban_reason": ""},
+                "$set": {"updated_at": DateTime::now()},
+                "$inc": {"version": 1},
+            },
+            None,
+        ))
+        .await?;
+        Ok(result.modified_count > 0)
+    }
+}
+
+/// Accounts kept in memory, keyed by uid, for tests and local experiments.
+/// Emails are unique regardless of case, just as with the Mongo lookups.
+#[derive(Default)]
+pub struct InMemoryUserRepository {
+    users: Mutex<HashMap<String, User>>,
+}
+
+impl InMemoryUserRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn users(&self) -> std::sync::MutexGuard<'_, HashMap<String, User>> {
+        self.users
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// `user` as written by an update: one version on, changed now.
+fn next_version(user: &User) -> User {
+    User {
+        version: user.version + 1,
+        updated_at: Some(DateTime::now()),
+        ..user.clone()
+    }
+}
+
+/// Whether two accounts share an email or username, ignoring case.
+fn conflicts(a: &User, b: &User) -> bool {
+    users::normalize_email(&a.email) == users::normalize_email(&b.email)
+        || (a.username_lower.is_some() && a.username_lower == b.username_lower)
+}
+
+#[async_trait]
+impl UserRepository for InMemoryUserRepository {
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>> {
+        let email = users::normalize_email(email);
+        Ok(self
+            .users()
+            .values()
+            .find(|u| users::normalize_email(&u.email) == email)
+            .cloned())
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>> {
+        let username = username.to_lowercase();
+        Ok(self
+            .users()
+            .values()
+            .find(|u| u.username_lower.as_ref() == Some(&username))
+            .cloned())
+    }
+
+    async fn find_by_login(&self, identifier: &str) -> Result<Option<User>> {
+        match self.find_by_email(identifier).await? {
+            Some(user) => Ok(Some(user)),
+            None => self.find_by_username(identifier).await,
+        }
+    }
+
+    async fn find_by_uid(&self, uid: &str) -> Result<Option<User>> {
+        Ok(self.users().get(uid).cloned())
+    }
+
+    async fn insert(&self, user: &User) -> Result<()> {
+        let mut users = self.users();
+        let taken = users.values().any(|u| conflicts(u, user));
+        if users.contains_key(&user.uid) || taken {
+            return Err(Error::DuplicateKeyError);
+        }
+        users.insert(user.uid.clone(), user.clone());
+        Ok(())
+    }
+
+    async fn update(&self, user: &User) -> Result<()> {
+        let mut users = self.users();
+        let taken = users
+            .values()
+            .any(|u| u.uid != user.uid && conflicts(u, user));
+        if taken {
+            return Err(Error::DuplicateKeyError);
+        }
+        if let Some(stored) = users.get_mut(&user.uid) {
+            *stored = user.clone();
+        }
+        Ok(())
+    }
+
+    async fn update_if_match(&self, user: &User, if_match: &IfMatch) -> Result<User> {
+        let mut users = self.users();
+        let taken = users
+            .values()
+            .any(|u| u.uid != user.uid && conflicts(u, user));
+        if taken {
+            return Err(Error::DuplicateKeyError);
+        }
+        let stored = users.get_mut(&user.uid).ok_or(Error::UserNotFoundError)?;
+        if !if_match.matches(stored.version) {
+            return Err(Error::PreconditionFailedError);
+        }
+        *stored = next_version(user);
+        Ok(stored.clone())
+    }
+
+    async fn delete(&self, uid: &str) -> Result<()> {
+        self.users().remove(uid);
+        Ok(())
+    }
+
+    async fn record_login(&self, uid: &str) -> Result<()> {
+        if let Some(user) = self.users().get_mut(uid) {
+            user.previous_login_at = user.last_login_at.take();
+            user.last_login_at = Some(DateTime::now());
+        }
+        Ok(())
+    }
+
+    async fn replace_password_hash(&self, uid: &str, current: &str, new: &str) -> Result<()> {
+        if let Some(user) = self.users().get_mut(uid) {
+            if user.pw == current {
+                user.pw = new.to_owned();
+            }
+        }
+        Ok(())
+    }
+
+    async fn clear_expired_ban(&self, uid: &str) -> Result<bool> {
+        let mut users = self.users();
+        let Some(user) = users.get_mut(uid) else {
+            return Ok(false);
+        };
+        if user
+            .banned_until
+            .is_none_or(|until| until > DateTime::now())
+        {
+            return Ok(false);
+        }
+        user.banned_at = None;
+        user.banned_until = None;
+        user.ban_reason = None;
+        user.updated_at = Some(DateTime::now());
+        user.version += 1;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::Role, test_support};
+
+    fn user(email: &str) -> User {
+        User::new(email.to_string(), String::new(), &Role::User)
+    }
+
+    /// What both implementations must agree on.
+    async fn check_repository(repo: &dyn UserRepository) {
+        // Signups store emails lowercased; lookups ignore case.
+        let mut first = user("someone@example.com");
+        first.username = Some("someone".to_string());
+        first.username_lower = Some("someone".to_string());
+        repo.insert(&first).await.unwrap();
+
+        assert!(matches!(
+            repo.insert(&user("someone@example.com")).await,
+            Err(Error::DuplicateKeyError)
+        ));
+        let found = repo.find_by_email("SOMEONE@example.com").await.unwrap();
+        assert_eq!(found.map(|u| u.uid), Some(first.uid.clone()));
+        let found = repo.find_by_login("SomeOne").await.unwrap();
+        assert_eq!(found.map(|u| u.uid), Some(first.uid.clone()));
+
+        let mut second = user("other@example.com");
+        repo.insert(&second).await.unwrap();
+        second.email = "someone@example.com".to_string();
+        second.email_lower = Some(users::normalize_email(&second.email));
+        assert!(matches!(
+            repo.update(&second).await,
+            Err(Error::DuplicateKeyError)
+        ));
+
+        repo.delete(&first.uid).await.unwrap();
+        assert!(repo.find_by_uid(&first.uid).await.unwrap().is_none());
+        repo.insert(&user("someone@example.com")).await.unwrap();
+
+        // Two signups racing for one address: the unique index, not an
+        // earlier lookup, decides, so exactly one of them gets it.
+        let (one, other) = (user("racing@example.com"), user("racing@example.com"));
+        let (a, b) = tokio::join!(repo.insert(&one), repo.insert(&other));
+        assert_eq!(a.is_ok() as u8 + b.is_ok() as u8, 1);
+        assert!(matches!(a.and(b), Err(Error::DuplicateKeyError)));
+    }
+
+    #[tokio::test]
+    async fn the_in_memory_repository_keeps_emails_unique() {
+        check_repository(&InMemoryUserRepository::new()).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn the_mongo_repository_keeps_emails_unique() {
+        let users = test_support::database().await.collection("users");
+        users::create_indexes(&users).await.unwrap();
+        check_repository(&MongoUserRepository::new(users.clone())).await;
+        let stored = users
+            .count_documents(doc! {"email": "racing@example.com"}, None)
+            .await
+            .unwrap();
+        assert_eq!(stored, 1);
+    }
+}
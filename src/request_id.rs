@@ -0,0 +1,111 @@
+use mongodb::bson::uuid::Uuid;
+use std::future::Future;
+use tracing::{Instrument, Span};
+use warp::http::HeaderMap;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Longest `X-Request-Id` taken from a proxy; longer ones are replaced.
+const MAX_INCOMING_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The ID of the request being handled by the current task, if any.
+/// Handlers, `handle_rejection` and the request span all read it from here,
+/// so each of them reports the same value the client sees in
+/// `X-Request-Id`.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// The `X-Request-Id` our proxy assigned to a request with `headers`.
+/// Only honored with `TRUST_PROXY`, since anyone else could send one.
+pub fn incoming(headers: &HeaderMap, trust_proxy: bool) -> Option<String> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .filter(|_| trust_proxy)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// Runs `future`, the handling of one request, under a request ID, which
+/// is returned alongside its output. That is `incoming`, the ID our proxy
+/// assigned, when it is up to `MAX_INCOMING_LENGTH` printable ASCII
+/// characters without spaces, and a new UUID otherwise, so nothing a
+/// client sends can break up log lines.
+pub async fn scope<F: Future>(incoming: Option<&str>, future: F) -> (String, F::Output) {
+    let id = match incoming {
+        Some(id) if is_acceptable(id) => id.to_owned(),
+        _ => Uuid::new().to_string(),
+    };
+    let output = REQUEST_ID.scope(id.clone(), future).await;
+    (id, output)
+}
+
+fn is_acceptable(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_INCOMING_LENGTH && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// `tokio::spawn`, keeping the current request ID and span for the spawned
+/// task's events.
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let id = current();
+    let future = future.instrument(Span::current());
+    tokio::spawn(async move {
+        match id {
+            Some(id) => REQUEST_ID.scope(id, future).await,
+            None => future.await,
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::HeaderValue;
+
+    fn headers(id: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_str(id).unwrap());
+        headers
+    }
+
+    /// The ID a request with `headers` is handled under, checking that the
+    /// handler sees the same one.
+    async fn id_for(headers: &HeaderMap, trust_proxy: bool) -> String {
+        let incoming = incoming(headers, trust_proxy);
+        let (id, seen) = scope(incoming.as_deref(), async { current() }).await;
+        assert_eq!(seen.as_deref(), Some(id.as_str()));
+        id
+    }
+
+    fn is_uuid(id: &str) -> bool {
+        Uuid::parse_str(id).is_ok()
+    }
+
+    #[tokio::test]
+    async fn a_proxy_request_id_is_passed_through() {
+        assert_eq!(id_for(&headers("proxy-id-1"), true).await, "proxy-id-1");
+    }
+
+    #[tokio::test]
+    async fn an_unacceptable_request_id_is_replaced() {
+        let too_long = "a".repeat(MAX_INCOMING_LENGTH + 1);
+        for id in ["", "two words", "tab\there", too_long.as_str()] {
+            let replaced = id_for(&headers(id), true).await;
+            assert!(is_uuid(&replaced), "{:?} became {:?}", id, replaced);
+        }
+    }
+
+    #[tokio::test]
+    async fn without_trust_proxy_the_header_is_ignored() {
+        let id = id_for(&headers("proxy-id-1"), false).await;
+        assert_ne!(id, "proxy-id-1");
+        assert!(is_uuid(&id));
+        assert!(is_uuid(&id_for(&HeaderMap::new(), true).await));
+    }
+}
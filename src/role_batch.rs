@@ -0,0 +1,242 @@
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{AuthContext, Claims, Role},
+    error::{Chain, Error},
+    repository::timed,
+    sessions::{self, ClientInfo, Session},
+    users,
+    webhooks::{self, WebhookEvent},
+    User, WebResult,
+};
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, DateTime},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use utoipa::ToSchema;
+use warp::{reject, reply, Reply};
+
+pub const MAX_ROLE_UPDATES: usize = 1000;
+
+#[derive(Deserialize, ToSchema)]
+pub struct RoleUpdate {
+    uid: String,
+    role: String,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RoleUpdateStatus {
+    Updated,
+    /// The user already had the role.
+    Unchanged,
+    NotFound,
+    InvalidRole,
+    /// Admins can't change their own role in a batch.
+    SkippedSelfDemotion,
+    /// Applying it would have left no admin.
+    LastAdmin,
+    /// The uid appeared earlier in the batch, which decides its role.
+    Duplicate,
+    /// The database refused the update; retrying may help.
+    Failed,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RoleUpdateOutcome {
+    index: usize,
+    uid: String,
+    status: RoleUpdateStatus,
+    /// The role the user had before, when one was found.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_role: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RoleBatchReport {
+    updated: usize,
+    results: Vec<RoleUpdateOutcome>,
+}
+
+/// A change that passed every check, waiting for its role's update.
+struct Change {
+    index: usize,
+    user: User,
+}
+
+/// Changes the role of many users at once. Every entry is checked before
+/// anything is written and reported individually, so one bad entry
+/// doesn't keep the others from being applied. Users getting the same role
+/// are updated together.
+#[utoipa::path(
+    post,
+    path = "/users/roles:batch",
+    tag = "users",
+    request_body(content = Vec<RoleUpdate>, description = "At most 1000 updates"),
+    responses(
+        (status = 200, description = "What happened to each update", body = RoleBatchReport),
+        (status = 400, description = "Malformed body", body = ErrorResponse),
+        (status = 403, description = "Not an admin, or a `remember_me` session with \
+                                        `REQUIRE_FRESH_LOGIN`", body = ErrorResponse),
+        (status = 413, description = "More than 1000 updates, or a body over `MAX_UPLOAD_BYTES`", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn batch_update_roles_handler(
+    claims: Claims,
+    context: AuthContext,
+    users_collection: Collection<User>,
+    sessions_collection: Collection<Session>,
+    client: ClientInfo,
+    updates: Vec<RoleUpdate>,
+) -> WebResult<impl Reply> {
+    if updates.len() > MAX_ROLE_UPDATES {
+        return Err(reject::custom(Error::RoleBatchTooLargeError));
+    }
+    sessions::require_fresh_login(&sessions_collection, &claims)
+        .await
+        .map_err(reject::custom)?;
+
+    let mut results: Vec<RoleUpdateOutcome> = updates
+        .iter()
+        .enumerate()
+        .map(|(index, update)| RoleUpdateOutcome {
+            index,
+            uid: update.uid.clone(),
+            status: RoleUpdateStatus::Failed,
+            previous_role: None,
+        })
+        .collect();
+
+    let mut seen = HashSet::new();
+    let mut valid = Vec::new();
+    for (index, update) in updates.iter().enumerate() {
+        let role = Role::from_str(&update.role)
+            .ok()
+            .filter(|role| context.roles().is_assignable(role));
+        results[index].status = if users::validate_uid(&update.uid).is_err() {
+            RoleUpdateStatus::NotFound
+        } else if !seen.insert(update.uid.as_str()) {
+            RoleUpdateStatus::Duplicate
+        } else if let Some(role) = role {
+            valid.push((index, role));
+            continue;
+        } else {
+            RoleUpdateStatus::InvalidRole
+        };
+    }
+
+    let uids: Vec<&str> = valid
+        .iter()
+        .map(|(index, _)| updates[*index].uid.as_str())
+        .collect();
+    let cursor = timed(users_collection.find(doc! {"uid": {"$in": uids}}, None))
+        .await
+        .map_err(reject::custom)?;
+    let mut found: HashMap<String, User> = timed(cursor.try_collect::<Vec<User>>())
+        .await
+        .map_err(reject::custom)?
+        .into_iter()
+        .map(|user| (user.uid.clone(), user))
+        .collect();
+
+    // Demoting the only admin who can sign in would leave nobody able to
+    // manage roles, so demotions stop while one such admin would remain.
+    let mut admins = timed(users_collection.count_documents(users::usable_admins(), None))
+        .await
+        .map_err(reject::custom)?;
+
+    let mut changes: BTreeMap<String, Vec<Change>> = BTreeMap::new();
+    for (index, role) in valid {
+        let uid = &updates[index].uid;
+        let Some(user) = found.remove(uid) else {
+            results[index].status = RoleUpdateStatus::NotFound;
+            continue;
+        };
+        results[index].previous_role = Some(user.role.clone());
+        let role = role.to_string();
+        results[index].status = if user.role == role {
+            RoleUpdateStatus::Unchanged
+        } else if *uid == claims.sub {
+            RoleUpdateStatus::SkippedSelfDemotion
+        } else if users::is_usable_admin(&user) && admins <= 1 {
+            RoleUpdateStatus::LastAdmin
+        } else {
+            if users::is_usable_admin(&user) {
+                admins -= 1;
+            }
+            changes
+                .entry(role)
+                .or_default()
+                .push(Change { index, user });
+            continue;
+        };
+    }
+
+    let mut updated = 0;
+    for (role, mut changes) in changes {
+        let uids: Vec<&str> = changes.iter().map(|c| c.user.uid.as_str()).collect();
+        let now = DateTime::now();
+        let result = timed(users_collection.update_many(
+            doc! {"uid": {"$in": uids}},
+            doc! {
+                "$set": {
+                    "role": &role,
+                    "role_changed_by": &claims.sub,
+                    "role_changed_at": now,
+                    "updated_at": now,
+                },
+                "$inc": {"version": 1},
+            },
+            None,
+        ))
+        .await;
+        if let Err(e) = result {
+            tracing::error!(
+                "changing the role of {} users to {} failed: {}",
+                changes.len(),
+                role,
+                Chain(&e)
+            );
+            continue;
+        }
+
+        // The count above can't see demotions made concurrently by other
+        // requests, so each group checks again once it is written.
+        if changes.iter().any(|c| users::is_usable_admin(&c.user))
+            && !users::admin_remains(&users_collection)
+                .await
+                .map_err(reject::custom)?
+        {
+            let (demoted, kept): (Vec<Change>, Vec<Change>) = changes
+                .into_iter()
+                .partition(|c| users::is_usable_admin(&c.user));
+            for Change { index, user } in demoted {
+                users::restore_role(&users_collection, &user)
+                    .await
+                    .map_err(reject::custom)?;
+                results[index].status = RoleUpdateStatus::LastAdmin;
+            }
+            changes = kept;
+        }
+
+        for Change { index, mut user } in changes {
+            let previous = std::mem::replace(&mut user.role, role.clone());
+            user.version += 1;
+            context.forget_user(&user.uid);
+            audit::record(
+                AuditEvent::new(AuditAction::RoleChanged, &client)
+                    .actor(&claims.sub)
+                    .target(&user.uid)
+                    .detail(&format!("{} -> {}", previous, role)),
+            );
+            webhooks::dispatch(WebhookEvent::UserRoleChanged, &user, Some(&previous));
+            results[index].status = RoleUpdateStatus::Updated;
+            updated += 1;
+        }
+    }
+
+    Ok(reply::json(&RoleBatchReport { updated, results }))
+}
@@ -0,0 +1,263 @@
+use crate::{
+    apikeys::ApiKey,
+    auth::{invalid_role_data, AuthContext, Claims, Role},
+    error::Error,
+    repository::timed,
+    Result, User, WebResult,
+};
+use mongodb::{bson::doc, options::IndexOptions, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use utoipa::ToSchema;
+use warp::{http::StatusCode, reject, reply, Reply};
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct RoleDefinition {
+    pub name: String,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateRoleRequest {
+    pub permissions: Vec<String>,
+}
+
+/// In-process copy of the `roles` collection, loaded at startup and kept in
+/// sync by the role management handlers so authorization never hits MongoDB.
+#[derive(Clone, Default)]
+pub struct RoleRegistry {
+    roles: Arc<RwLock<HashMap<String, Vec<String>>>>,
+}
+
+impl RoleRegistry {
+    pub async fn load(collection: &Collection<RoleDefinition>) -> mongodb::error::Result<Self> {
+        let index = IndexModel::builder()
+            .keys(doc! {"name": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        collection.create_index(index, None).await?;
+
+        let registry = RoleRegistry::default();
+        let mut cursor = collection.find(None, None).await?;
+        while cursor.advance().await? {
+            registry.insert(cursor.deserialize_current()?);
+        }
+        Ok(registry)
+    }
+
+    /// Maps the role name stored for `uid` to a `Role`. A name that doesn't
+    /// parse or isn't defined in the registry is logged and refused with
+    /// `InvalidRoleDataError`.
+    pub fn resolve(&self, uid: &str, name: &str) -> Result<Role> {
+        match Role::from_str(name) {
+            Ok(role) if self.is_known(&role) => Ok(role),
+            _ => Err(invalid_role_data(uid, name)),
+        }
+    }
+
+    pub fn is_known(&self, role: &Role) -> bool {
+        match role {
+            Role::Custom(name) => self.contains(name),
+            _ => true,
+        }
+    }
+
+    /// Whether accounts, API keys and fixtures may be given `role`. Only
+    /// `POST /guest` makes guests, since they are purged when they get old.
+    pub fn is_assignable(&self, role: &Role) -> bool {
+        *role != Role::Guest && self.is_known(role)
+    }
+
+    pub fn has_permission(&self, role: &Role, permission: &str) -> bool {
+        if *role == Role::Admin {
+            return true;
+        }
+        let roles = self.roles.read().expect("role registry lock poisoned");
+        roles
+            .get(&role.to_string())
+            .map(|permissions| permissions.iter().any(|p| p == permission))
+            .unwrap_or(false)
+    }
+
+    /// The permissions defined for `role`; none for the built-in roles.
+    pub fn permissions(&self, role: &Role) -> Vec<String> {
+        let roles = self.roles.read().expect("role registry lock poisoned");
+        roles.get(&role.to_string()).cloned().unwrap_or_default()
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        let roles = self.roles.read().expect("role registry lock poisoned");
+        roles.contains_key(name)
+    }
+
+    pub(crate) fn insert(&self, definition: RoleDefinition) {
+        let mut roles = self.roles.write().expect("role registry lock poisoned");
+        roles.insert(definition.name, definition.permissions);
+    }
+
+    fn remove(&self, name: &str) {
+        let mut roles = self.roles.write().expect("role registry lock poisoned");
+        roles.remove(name);
+    }
+}
+
+/// Custom roles can't take a built-in role's name, in any case.
+fn validate_role_name(name: &str) -> Result<()> {
+    match Role::from_str(name)? {
+        Role::Custom(_) => Ok(()),
+        _ => Err(Error::InvalidRoleError),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/roles",
+    tag = "roles",
+    responses(
+        (status = 200, description = "Roles defined in the database", body = Vec<RoleDefinition>),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn list_roles_handler(
+    _claims: Claims,
+    roles_collection: Collection<RoleDefinition>,
+) -> WebResult<impl Reply> {
+    let mut cursor = timed(roles_collection.find(None, None))
+        .await
+        .map_err(reject::custom)?;
+
+    let mut roles = Vec::new();
+    while timed(cursor.advance()).await.map_err(reject::custom)? {
+        roles.push(
+            cursor
+                .deserialize_current()
+                .map_err(|e| reject::custom(Error::from(e)))?,
+        );
+    }
+
+    Ok(reply::json(&roles))
+}
+
+#[utoipa::path(
+    post,
+    path = "/roles",
+    tag = "roles",
+    request_body = RoleDefinition,
+    responses(
+        (status = 201, description = "Role created", body = RoleDefinition),
+        (status = 400, description = "Invalid role name", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 409, description = "Role already exists", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn create_role_handler(
+    _claims: Claims,
+    context: AuthContext,
+    roles_collection: Collection<RoleDefinition>,
+    body: RoleDefinition,
+) -> WebResult<impl Reply> {
+    validate_role_name(&body.name).map_err(reject::custom)?;
+
+    let existing = timed(roles_collection.find_one(doc! {"name": &body.name}, None))
+        .await
+        .map_err(reject::custom)?;
+    if existing.is_some() {
+        return Err(reject::custom(Error::RoleAlreadyExistsError));
+    }
+
+    timed(roles_collection.insert_one(&body, None))
+        .await
+        .map_err(reject::custom)?;
+    context.roles().insert(body.clone());
+
+    Ok(reply::with_status(reply::json(&body), StatusCode::CREATED))
+}
+
+#[utoipa::path(
+    put,
+    path = "/roles/{name}",
+    tag = "roles",
+    params(("name" = String, Path, description = "Role name")),
+    request_body = UpdateRoleRequest,
+    responses(
+        (status = 200, description = "Role updated", body = RoleDefinition),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such role", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn update_role_handler(
+    name: String,
+    _claims: Claims,
+    context: AuthContext,
+    roles_collection: Collection<RoleDefinition>,
+    body: UpdateRoleRequest,
+) -> WebResult<impl Reply> {
+    let result = timed(roles_collection.update_one(
+        doc! {"name": &name},
+        doc! {"$set": {"permissions": &body.permissions}},
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?;
+    if result.matched_count == 0 {
+        return Err(reject::custom(Error::RoleNotFoundError));
+    }
+
+    let definition = RoleDefinition {
+        name,
+        permissions: body.permissions,
+    };
+    context.roles().insert(definition.clone());
+
+    Ok(reply::json(&definition))
+}
+
+/// Refused while any account or API key has the role, since they could no
+/// longer sign in or authenticate without it.
+#[utoipa::path(
+    delete,
+    path = "/roles/{name}",
+    tag = "roles",
+    params(("name" = String, Path, description = "Role name")),
+    responses(
+        (status = 204, description = "Role deleted"),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such role", body = ErrorResponse),
+        (status = 409, description = "Accounts or API keys still have the role", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn delete_role_handler(
+    name: String,
+    _claims: Claims,
+    context: AuthContext,
+    roles_collection: Collection<RoleDefinition>,
+    users_collection: Collection<User>,
+    api_keys: Collection<ApiKey>,
+) -> WebResult<impl Reply> {
+    let holders = timed(users_collection.count_documents(doc! {"role": &name}, None))
+        .await
+        .map_err(reject::custom)?
+        + timed(api_keys.count_documents(doc! {"role": &name}, None))
+            .await
+            .map_err(reject::custom)?;
+    if holders > 0 {
+        return Err(reject::custom(Error::RoleInUseError));
+    }
+
+    let result = timed(roles_collection.delete_one(doc! {"name": &name}, None))
+        .await
+        .map_err(reject::custom)?;
+    if result.deleted_count == 0 {
+        return Err(reject::custom(Error::RoleNotFoundError));
+    }
+    context.roles().remove(&name);
+
+    Ok(reply::with_status(reply(), StatusCode::NO_CONTENT))
+}
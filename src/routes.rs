@@ -0,0 +1,1161 @@
+use crate::{
+    admin_handler, admin_sessions,
+    apikeys::{self, with_api_key, ApiKey},
+    audit::{self, AuditEvent},
+    auth::{
+        with_any_role, with_auth, with_auth_optional, with_claims, with_password_change_claims,
+        with_scope, with_socket_claims, AuthContext, Role,
+    },
+    avatars::{self, with_avatar_store, AvatarStore},
+    body,
+    captcha::{with_captcha, Captcha},
+    change_password_handler,
+    config::{self, Config},
+    email_change, etag, events, export, features, guest,
+    health::{self, PingLatencies, Readiness},
+    idempotency::{self, IdempotencyRecord},
+    import,
+    invites::{self, Invite},
+    jwks_handler,
+    lockout::{with_lockout, LoginLockout},
+    login_handler, logout_all_handler, logout_handler,
+    magic_link::{self, MagicLink},
+    mailer::{with_mailer, Mailer},
+    maintenance::{self, with_maintenance, Maintenance},
+    me_handler, metrics,
+    oauth::{self, with_providers, FederatedIdentity, OAuthProviders, OAuthState},
+    openapi,
+    orgs::{self, with_org_role, Membership, Organization},
+    password_reset::{self, PasswordReset},
+    ratelimit::{self, with_limiter, with_rate_limit, RateLimiter},
+    refresh_handler,
+    repository::UserRepo,
+    role_batch,
+    roles::{self, RoleDefinition},
+    scopes,
+    sessions::{self, with_client_info, Session},
+    signup_handler,
+    sockets::{self, SocketRegistry},
+    stats::{self, StatsCache},
+    throttle::{with_login_throttle, LoginThrottle},
+    timeout::with_timeout,
+    token_exchange::{self, with_trusted_peers},
+    transaction::{with_transactions, Transactions},
+    two_factor::{self, PendingLogin},
+    user_handler,
+    users::{self, with_user_data, UserData},
+    validation::{validated_json, validated_json_or_form},
+    verification::{self, VerificationResend},
+    webhooks::{self, WebhookDelivery},
+    welcome_handler, User,
+};
+use mongodb::{Collection, Database};
+use std::{convert::Infallible, sync::Arc, time::Instant};
+use warp::{filters::BoxedFilter, reply::Response, Filter, Reply};
+
+/// Everything the routes hand to their handlers, built once at startup.
+#[derive(Clone)]
+pub struct AppState {
+    pub auth_context: AuthContext,
+    pub users: Collection<User>,
+    /// The same accounts behind `UserRepository`, for the handlers that
+    /// have moved to it.
+    pub user_repo: UserRepo,
+    pub sessions: Collection<Session>,
+    pub pending_logins: Collection<PendingLogin>,
+    pub magic_links: Collection<MagicLink>,
+    pub password_resets: Collection<PasswordReset>,
+    pub verification_resends: Collection<VerificationResend>,
+    pub federated_identities: Collection<FederatedIdentity>,
+    pub oauth_states: Collection<OAuthState>,
+    pub api_keys: Collection<ApiKey>,
+    pub invites: Collection<Invite>,
+    pub idempotency_keys: Collection<IdempotencyRecord>,
+    pub organizations: Collection<Organization>,
+    pub memberships: Collection<Membership>,
+    pub roles: Collection<RoleDefinition>,
+    pub audit_log: Collection<AuditEvent>,
+    pub webhook_deliveries: Collection<WebhookDelivery>,
+    pub stats_cache: StatsCache,
+    /// Open `/ws` connections, for pushing messages to a user.
+    pub sockets: SocketRegistry,
+    pub user_data: UserData,
+    pub transactions: Transactions,
+    pub mailer: Mailer,
+    pub captcha: Captcha,
+    pub avatar_store: AvatarStore,
+    pub oauth_providers: OAuthProviders,
+    pub login_throttle: LoginThrottle,
+    pub login_lockout: LoginLockout,
+    pub signup_limiter: RateLimiter,
+    pub magic_link_limiter: RateLimiter,
+    /// One personal data export per user per hour.
+    pub export_limiter: RateLimiter,
+    /// Take client addresses from `X-Forwarded-For`.
+    pub trust_proxy: bool,
+    pub db: Database,
+    pub started: Instant,
+    pub readiness: Readiness,
+    pub ping_latencies: PingLatencies,
+    pub maintenance: Maintenance,
+    pub config: Arc<Config>,
+}
+
+/// The API, every route below `prefix` (such as `/api/v1`, or `""` for the
+/// legacy unprefixed paths). Probes and the JWKS document are not part of
+/// it; see [`fixed_routes`].
+pub fn api_routes(prefix: &str, deps: &AppState) -> BoxedFilter<(Response,)> {
+    // A single `or` chain of every route nests its futures deeply enough to
+    // overflow a worker thread's stack in debug builds; boxing groups of
+    // routes keeps the nesting shallow.
+    let api = login_routes(deps)
+        .or(session_routes(deps))
+        .unify()
+        .or(account_routes(deps))
+        .unify()
+        .or(profile_routes(deps))
+        .unify()
+        .or(user_admin_routes(deps))
+        .unify()
+        .or(user_update_routes(deps))
+        .unify()
+        .or(user_moderation_routes(deps))
+        .unify()
+        .or(export_routes(deps))
+        .unify()
+        .or(role_and_key_routes(deps))
+        .unify()
+        .or(realtime_routes(deps))
+        .unify()
+        .or(org_routes(deps))
+        .unify()
+        .boxed();
+    let api = maintenance_routes(deps)
+        .or(maintenance::check(deps.maintenance.clone()).and(api))
+        .unify()
+        .boxed();
+    path_prefix(prefix).and(api).boxed()
+}
+
+/// `/admin/maintenance`, which stays reachable in maintenance mode so it
+/// can be switched off again.
+fn maintenance_routes(deps: &AppState) -> BoxedFilter<(Response,)> {
+    let get_maintenance_route = warp::path!("admin" / "maintenance")
+        .and(metrics::route("/admin/maintenance"))
+        .and(warp::get())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_maintenance(deps.maintenance.clone()))
+        .and_then(maintenance::maintenance_handler);
+
+    let set_maintenance_route = warp::path!("admin" / "maintenance")
+        .and(metrics::route("/admin/maintenance"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_maintenance(deps.maintenance.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and(body::json())
+        .and_then(maintenance::set_maintenance_handler);
+
+    get_maintenance_route
+        .or(set_maintenance_route)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// Matches and consumes the segments of `prefix`.
+fn path_prefix(prefix: &str) -> BoxedFilter<()> {
+    prefix
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |filter, segment| {
+            filter.and(warp::path(segment.to_string())).boxed()
+        })
+}
+
+/// `/metrics`, served either with the other routes or on `METRICS_PORT`.
+pub fn metrics_route() -> BoxedFilter<(Response,)> {
+    warp::path!("metrics")
+        .and(metrics::route("/metrics"))
+        .and(warp::get())
+        .and_then(metrics::metrics_handler)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// The OpenAPI document at `/api-docs/openapi.json`, and Swagger UI for it
+/// at `/api-docs`.
+pub fn docs_routes(spec: &utoipa::openapi::OpenApi) -> BoxedFilter<(Response,)> {
+    let spec = Arc::new(spec.to_json().expect("the OpenAPI document serializes"));
+    let spec_route = warp::path!("api-docs" / "openapi.json")
+        .and(metrics::route("/api-docs/openapi.json"))
+        .and(warp::get())
+        .and(warp::any().map(move || spec.clone()))
+        .and_then(openapi::openapi_handler);
+    let swagger_ui_route = warp::path!("api-docs")
+        .and(metrics::route("/api-docs"))
+        .and(warp::get())
+        .and_then(openapi::swagger_ui_handler);
+    spec_route
+        .or(swagger_ui_route)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+fn with_collection<T: Send + Sync>(
+    collection: Collection<T>,
+) -> impl Filter<Extract = (Collection<T>,), Error = Infallible> + Clone {
+    warp::any().map(move || collection.clone())
+}
+
+fn with_repo(repo: UserRepo) -> impl Filter<Extract = (UserRepo,), Error = Infallible> + Clone {
+    warp::any().map(move || repo.clone())
+}
+
+fn with_context(
+    context: AuthContext,
+) -> impl Filter<Extract = (AuthContext,), Error = Infallible> + Clone {
+    warp::any().map(move || context.clone())
+}
+
+fn login_routes(deps: &AppState) -> BoxedFilter<(Response,)> {
+    let login_route = warp::path!("login")
+        .and(metrics::route("/login"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.password_login))
+        .and(with_login_throttle(deps.login_throttle.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_lockout(deps.login_lockout.clone()))
+        .and(with_repo(deps.user_repo.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and(with_collection(deps.pending_logins.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and(validated_json_or_form())
+        .and_then(login_handler);
+
+    let login_2fa_route = warp::path!("login" / "2fa")
+        .and(metrics::route("/login/2fa"))
+        .and(warp::post())
+        .and(with_login_throttle(deps.login_throttle.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_lockout(deps.login_lockout.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and(with_collection(deps.pending_logins.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and(body::json())
+        .and_then(two_factor::login_2fa_handler);
+
+    let magic_link_request_route = warp::path!("login" / "magic")
+        .and(metrics::route("/login/magic"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.magic_link))
+        .and(with_mailer(deps.mailer.clone()))
+        .and(with_limiter(deps.magic_link_limiter.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_collection(deps.magic_links.clone()))
+        .and(body::json())
+        .and_then(magic_link::request_magic_link_handler);
+
+    let magic_link_confirm_route = warp::path!("login" / "magic" / "confirm")
+        .and(metrics::route("/login/magic/confirm"))
+        .and(warp::get())
+        .and(features::enabled(deps.config.features.magic_link))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and(with_collection(deps.pending_logins.clone()))
+        .and(with_collection(deps.magic_links.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and(warp::query::<magic_link::MagicLinkConfirm>())
+        .and_then(magic_link::confirm_magic_link_handler);
+
+    let two_factor_enroll_route = warp::path!("2fa" / "enroll")
+        .and(metrics::route("/2fa/enroll"))
+        .and(warp::post())
+        .and(with_auth(Role::User, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and_then(two_factor::enroll_handler);
+
+    let two_factor_verify_route = warp::path!("2fa" / "verify")
+        .and(metrics::route("/2fa/verify"))
+        .and(warp::post())
+        .and(with_auth(Role::User, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(body::json())
+        .and_then(two_factor::verify_handler);
+
+    let two_factor_disable_route = warp::path!("2fa" / "disable")
+        .and(metrics::route("/2fa/disable"))
+        .and(warp::post())
+        .and(with_auth(Role::User, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(body::json())
+        .and_then(two_factor::disable_handler);
+
+    login_route
+        .or(login_2fa_route)
+        .or(magic_link_request_route)
+        .or(magic_link_confirm_route)
+        .or(two_factor_enroll_route)
+        .or(two_factor_verify_route)
+        .or(two_factor_disable_route)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+fn session_routes(deps: &AppState) -> BoxedFilter<(Response,)> {
+    let refresh_route = warp::path!("refresh")
+        .and(metrics::route("/refresh"))
+        .and(warp::post())
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and(body::json())
+        .and_then(refresh_handler);
+
+    // Any signed-in account, guests included, may end its own sessions.
+    let logout_route = warp::path!("logout")
+        .and(metrics::route("/logout"))
+        .and(warp::post())
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_claims(deps.auth_context.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and_then(logout_handler);
+
+    let logout_all_route = warp::path!("logout-all")
+        .and(metrics::route("/logout-all"))
+        .and(warp::post())
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_claims(deps.auth_context.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and_then(logout_all_handler);
+
+    let oauth_login_route = warp::path!("auth" / String)
+        .and(metrics::route("/auth/{provider}"))
+        .and(warp::get())
+        .and(with_providers(deps.oauth_providers.clone()))
+        .and(with_auth_optional(deps.auth_context.clone()))
+        .and(with_collection(deps.oauth_states.clone()))
+        .and_then(oauth::oauth_login_handler);
+
+    let oauth_callback_route = warp::path!("auth" / String / "callback")
+        .and(metrics::route("/auth/{provider}/callback"))
+        .and(warp::get())
+        .and(with_providers(deps.oauth_providers.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and(with_collection(deps.pending_logins.clone()))
+        .and(with_collection(deps.federated_identities.clone()))
+        .and(with_collection(deps.oauth_states.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and(warp::query::<oauth::CallbackQuery>())
+        .and(warp::header::headers_cloned())
+        .and_then(oauth::oauth_callback_handler);
+
+    let sessions_route = warp::path!("sessions")
+        .and(metrics::route("/sessions"))
+        .and(warp::get())
+        .and(with_auth(Role::User, deps.auth_context.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and_then(sessions::list_sessions_handler);
+
+    let delete_session_route = warp::path!("sessions" / String)
+        .and(metrics::route("/sessions/{id}"))
+        .and(warp::delete())
+        .and(with_auth(Role::User, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and_then(sessions::delete_session_handler);
+
+    refresh_route
+        .or(logout_route)
+        .or(logout_all_route)
+        .or(oauth_login_route)
+        .or(oauth_callback_route)
+        .or(sessions_route)
+        .or(delete_session_route)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+fn account_routes(deps: &AppState) -> BoxedFilter<(Response,)> {
+    let signup_route = warp::path!("signup")
+        .and(metrics::route("/signup"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.signup))
+        .and(with_rate_limit(
+            deps.signup_limiter.clone(),
+            deps.auth_context.clone(),
+        ))
+        .and(
+            with_captcha(deps.captcha.clone())
+                .and(with_mailer(deps.mailer.clone()))
+                .and(with_repo(deps.user_repo.clone()))
+                .and(with_collection(deps.users.clone()))
+                .and(with_transactions(deps.transactions.clone()))
+                .and(with_context(deps.auth_context.clone()))
+                .and(with_collection(deps.sessions.clone()))
+                .and(with_collection(deps.invites.clone()))
+                .and(with_client_info(deps.trust_proxy))
+                .and(idempotency::validated_json(deps.idempotency_keys.clone()))
+                .and_then(signup_handler),
+        )
+        .map(ratelimit::with_headers)
+        // The largest future of the group; see `api_routes`.
+        .boxed();
+
+    let verify_route = warp::path!("verify")
+        .and(metrics::route("/verify"))
+        .and(warp::get())
+        .and(warp::query::<verification::VerifyQuery>())
+        .and(with_collection(deps.users.clone()))
+        .and_then(verification::verify_email_handler);
+
+    let resend_verification_route = warp::path!("verify" / "resend")
+        .and(metrics::route("/verify/resend"))
+        .and(warp::post())
+        .and(with_mailer(deps.mailer.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_collection(deps.verification_resends.clone()))
+        .and(body::json())
+        .and_then(verification::resend_verification_handler);
+
+    let confirm_email_change_route = warp::path!("email-change" / "confirm")
+        .and(metrics::route("/email-change/confirm"))
+        .and(warp::get())
+        .and(warp::query::<email_change::EmailChangeConfirmQuery>())
+        .and(with_collection(deps.users.clone()))
+        .and_then(email_change::confirm_email_change_handler);
+
+    let password_reset_request_route = warp::path!("password-reset" / "request")
+        .and(metrics::route("/password-reset/request"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.password_login))
+        .and(with_mailer(deps.mailer.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_collection(deps.password_resets.clone()))
+        .and(body::json())
+        .and_then(password_reset::request_reset_handler);
+
+    let password_reset_confirm_route = warp::path!("password-reset" / "confirm")
+        .and(metrics::route("/password-reset/confirm"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.password_login))
+        .and(with_collection(deps.users.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and(with_collection(deps.password_resets.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and(body::json())
+        .and_then(password_reset::confirm_reset_handler);
+
+    let user_route = warp::path!("user")
+        .and(metrics::route("/user"))
+        .and(
+            with_auth(Role::User, deps.auth_context.clone())
+                .or(with_api_key(
+                    Role::User,
+                    deps.auth_context.clone(),
+                    deps.api_keys.clone(),
+                ))
+                .unify(),
+        )
+        .and_then(user_handler);
+
+    let welcome_route = warp::path!("welcome")
+        .and(metrics::route("/welcome"))
+        .and(warp::get())
+        .and(with_auth_optional(deps.auth_context.clone()))
+        .and_then(welcome_handler);
+
+    let guest_route = warp::path!("guest")
+        .and(metrics::route("/guest"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.signup))
+        .and(with_rate_limit(
+            deps.signup_limiter.clone(),
+            deps.auth_context.clone(),
+        ))
+        .and(
+            with_collection(deps.users.clone())
+                .and(with_context(deps.auth_context.clone()))
+                .and(with_collection(deps.sessions.clone()))
+                .and(with_client_info(deps.trust_proxy))
+                .and_then(guest::create_guest_handler),
+        )
+        .map(ratelimit::with_headers);
+
+    let upgrade_guest_route = warp::path!("guest" / "upgrade")
+        .and(metrics::route("/guest/upgrade"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.signup))
+        .and(with_any_role(&[Role::Guest], deps.auth_context.clone()))
+        .and(with_mailer(deps.mailer.clone()))
+        .and(with_repo(deps.user_repo.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and(validated_json())
+        .and_then(guest::upgrade_guest_handler);
+
+    let token_exchange_route = warp::path!("token" / "exchange")
+        .and(metrics::route("/token/exchange"))
+        .and(warp::post())
+        .and(with_trusted_peers(deps.config.token_exchange_peers.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_repo(deps.user_repo.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and(body::json())
+        .and_then(token_exchange::exchange_token_handler);
+
+    signup_route
+        .or(verify_route)
+        .or(resend_verification_route)
+        .or(confirm_email_change_route)
+        .or(password_reset_request_route)
+        .or(password_reset_confirm_route)
+        .or(user_route)
+        .or(welcome_route)
+        .or(guest_route)
+        .or(upgrade_guest_route)
+        .or(token_exchange_route)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+fn profile_routes(deps: &AppState) -> BoxedFilter<(Response,)> {
+    let me_route = warp::path!("me")
+        .and(metrics::route("/me"))
+        .and(warp::get())
+        .and(with_auth(Role::User, deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and_then(me_handler);
+
+    let update_profile_route = warp::path!("me")
+        .and(metrics::route("/me"))
+        .and(warp::patch())
+        .and(with_auth(Role::User, deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(etag::if_match())
+        .and(body::json())
+        .and_then(users::update_profile_handler);
+
+    let upload_avatar_route = warp::path!("me" / "avatar")
+        .and(metrics::route("/me/avatar"))
+        .and(warp::post())
+        .and(with_auth(Role::User, deps.auth_context.clone()))
+        .and(with_avatar_store(deps.avatar_store.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(warp::multipart::form().max_length(avatars::MAX_FORM_BYTES))
+        .and_then(avatars::upload_avatar_handler);
+
+    let get_avatar_route = warp::path!("avatars" / String)
+        .and(metrics::route("/avatars/{uid}"))
+        .and(warp::get())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(with_avatar_store(deps.avatar_store.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and_then(avatars::get_avatar_handler);
+
+    let change_password_route = warp::path!("me" / "password")
+        .and(metrics::route("/me/password"))
+        .and(warp::put())
+        .and(with_password_change_claims(deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and(body::json())
+        .and_then(change_password_handler);
+
+    let email_change_route = warp::path!("me" / "email-change")
+        .and(metrics::route("/me/email-change"))
+        .and(warp::post())
+        .and(with_auth(Role::User, deps.auth_context.clone()))
+        .and(with_mailer(deps.mailer.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(body::json())
+        .and_then(email_change::request_email_change_handler);
+
+    let delete_me_route = warp::path!("me")
+        .and(metrics::route("/me"))
+        .and(warp::delete())
+        .and(with_auth(Role::User, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_user_data(deps.user_data.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and(body::json())
+        .and_then(users::delete_me_handler);
+
+    me_route
+        .or(update_profile_route)
+        .or(delete_me_route)
+        .or(upload_avatar_route)
+        .or(get_avatar_route)
+        .or(change_password_route)
+        .or(email_change_route)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+fn user_admin_routes(deps: &AppState) -> BoxedFilter<(Response,)> {
+    let admin_route = warp::path!("admin")
+        .and(metrics::route("/admin"))
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and_then(admin_handler);
+
+    let list_users_route = warp::path!("users")
+        .and(metrics::route("/users"))
+        .and(warp::get())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_scope(scopes::USERS_READ, deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(warp::query::<users::ListUsersQuery>())
+        .and_then(users::list_users_handler);
+
+    let search_users_route = warp::path!("users" / "search")
+        .and(metrics::route("/users/search"))
+        .and(warp::get())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_scope(scopes::USERS_READ, deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(warp::query::<users::SearchUsersQuery>())
+        .and_then(users::search_users_handler);
+
+    let create_user_route = warp::path!("users")
+        .and(metrics::route("/users"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(body::json())
+        .and_then(users::create_user_handler);
+
+    let import_users_route = warp::path!("users" / "import")
+        .and(metrics::route("/users/import"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(body::bytes(config::max_upload_bytes()))
+        .and_then(import::import_users_handler);
+
+    let get_user_route = warp::path!("users" / String)
+        .and(metrics::route("/users/{uid}"))
+        .and(warp::get())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_scope(scopes::USERS_READ, deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and_then(users::get_user_handler);
+
+    let stats_route = warp::path!("stats")
+        .and(metrics::route("/stats"))
+        .and(warp::get())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_lockout(deps.login_lockout.clone()))
+        .and(warp::any().map({
+            let cache = deps.stats_cache.clone();
+            move || cache.clone()
+        }))
+        .and_then(stats::stats_handler);
+
+    // Stays up with the rest of the admin API switched off, to show that
+    // it is.
+    let features_route = warp::path!("admin" / "features")
+        .and(metrics::route("/admin/features"))
+        .and(warp::get())
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(warp::any().map({
+            let features = deps.config.features;
+            move || features
+        }))
+        .and_then(features::features_handler);
+
+    let list_sessions_route = warp::path!("admin" / "sessions")
+        .and(metrics::route("/admin/sessions"))
+        .and(warp::get())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(warp::query::<admin_sessions::AdminSessionQuery>())
+        .and_then(admin_sessions::list_all_sessions_handler);
+
+    let revoke_sessions_route = warp::path!("admin" / "sessions")
+        .and(metrics::route("/admin/sessions"))
+        .and(warp::delete())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and(body::json())
+        .and_then(admin_sessions::revoke_sessions_handler);
+
+    admin_route
+        .or(features_route)
+        .or(list_sessions_route)
+        .or(revoke_sessions_route)
+        .or(stats_route)
+        .or(list_users_route)
+        .or(search_users_route)
+        .or(create_user_route)
+        .or(import_users_route)
+        .or(get_user_route)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+fn user_update_routes(deps: &AppState) -> BoxedFilter<(Response,)> {
+    let update_user_route = warp::path!("users" / String)
+        .and(metrics::route("/users/{uid}"))
+        .and(warp::put())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(etag::if_match())
+        .and(body::json())
+        .and_then(users::update_user_handler);
+
+    let delete_user_route = warp::path!("users" / String)
+        .and(metrics::route("/users/{uid}"))
+        .and(warp::delete())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_user_data(deps.user_data.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and_then(users::delete_user_handler);
+
+    let restore_user_route = warp::path!("users" / String / "restore")
+        .and(metrics::route("/users/{uid}/restore"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and_then(users::restore_user_handler);
+
+    let deactivate_user_route = warp::path!("users" / String / "deactivate")
+        .and(metrics::route("/users/{uid}/deactivate"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and_then(users::deactivate_user_handler);
+
+    let activate_user_route = warp::path!("users" / String / "activate")
+        .and(metrics::route("/users/{uid}/activate"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and_then(users::activate_user_handler);
+
+    let update_user_role_route = warp::path!("users" / String / "role")
+        .and(metrics::route("/users/{uid}/role"))
+        .and(warp::put())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and(etag::if_match())
+        .and(body::json())
+        .and_then(users::update_user_role_handler);
+
+    let batch_update_roles_route = warp::path!("users" / "roles:batch")
+        .and(metrics::route("/users/roles:batch"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and(body::bulk_json())
+        .and_then(role_batch::batch_update_roles_handler);
+
+    let impersonate_user_route = warp::path!("users" / String / "impersonate")
+        .and(metrics::route("/users/{uid}/impersonate"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and_then(users::impersonate_user_handler);
+
+    update_user_route
+        .or(delete_user_route)
+        .or(restore_user_route)
+        .or(deactivate_user_route)
+        .or(activate_user_route)
+        .or(update_user_role_route)
+        .or(batch_update_roles_route)
+        .or(impersonate_user_route)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+fn user_moderation_routes(deps: &AppState) -> BoxedFilter<(Response,)> {
+    let ban_user_route = warp::path!("users" / String / "ban")
+        .and(metrics::route("/users/{uid}/ban"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and(validated_json())
+        .and_then(users::ban_user_handler);
+
+    let unban_user_route = warp::path!("users" / String / "unban")
+        .and(metrics::route("/users/{uid}/unban"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and_then(users::unban_user_handler);
+
+    let require_password_change_route = warp::path!("users" / String / "require-password-change")
+        .and(metrics::route("/users/{uid}/require-password-change"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_collection(deps.sessions.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and_then(users::require_password_change_handler);
+
+    ban_user_route
+        .or(unban_user_route)
+        .or(require_password_change_route)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// Exports of account data: the admin CSV of all users and the personal
+/// data exports.
+fn export_routes(deps: &AppState) -> BoxedFilter<(Response,)> {
+    let budget = deps.config.request_timeout.max(export::EXPORT_TIMEOUT);
+    let export_users_route = warp::path!("users" / "export")
+        .and(metrics::route("/users/export"))
+        .and(with_timeout(budget))
+        .and(warp::get())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(warp::query::<export::ExportUsersQuery>())
+        .and_then(export::export_users_handler);
+
+    let export_my_data_route = warp::path!("me" / "export")
+        .and(metrics::route("/me/export"))
+        .and(with_timeout(budget))
+        .and(warp::get())
+        .and(with_rate_limit(
+            deps.export_limiter.clone(),
+            deps.auth_context.clone(),
+        ))
+        .and(
+            with_auth(Role::User, deps.auth_context.clone())
+                .and(with_collection(deps.users.clone()))
+                .and(with_user_data(deps.user_data.clone()))
+                .and(with_collection(deps.audit_log.clone()))
+                .and(with_client_info(deps.trust_proxy))
+                .and_then(export::export_my_data_handler),
+        )
+        .map(ratelimit::with_headers);
+
+    let export_user_data_route = warp::path!("users" / String / "export")
+        .and(metrics::route("/users/{uid}/export"))
+        .and(with_timeout(budget))
+        .and(warp::get())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_user_data(deps.user_data.clone()))
+        .and(with_collection(deps.audit_log.clone()))
+        .and(with_client_info(deps.trust_proxy))
+        .and_then(export::export_user_data_handler);
+
+    export_users_route
+        .or(export_my_data_route)
+        .or(export_user_data_route)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+fn role_and_key_routes(deps: &AppState) -> BoxedFilter<(Response,)> {
+    let list_roles_route = warp::path!("roles")
+        .and(metrics::route("/roles"))
+        .and(warp::get())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_collection(deps.roles.clone()))
+        .and_then(roles::list_roles_handler);
+
+    let create_role_route = warp::path!("roles")
+        .and(metrics::route("/roles"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.roles.clone()))
+        .and(body::json())
+        .and_then(roles::create_role_handler);
+
+    let update_role_route = warp::path!("roles" / String)
+        .and(metrics::route("/roles/{name}"))
+        .and(warp::put())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.roles.clone()))
+        .and(body::json())
+        .and_then(roles::update_role_handler);
+
+    let delete_role_route = warp::path!("roles" / String)
+        .and(metrics::route("/roles/{name}"))
+        .and(warp::delete())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.roles.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(with_collection(deps.api_keys.clone()))
+        .and_then(roles::delete_role_handler);
+
+    let create_api_key_route = warp::path!("apikeys")
+        .and(metrics::route("/apikeys"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and(with_collection(deps.api_keys.clone()))
+        .and(body::json())
+        .and_then(apikeys::create_api_key_handler);
+
+    let delete_api_key_route = warp::path!("apikeys" / String)
+        .and(metrics::route("/apikeys/{id}"))
+        .and(warp::delete())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_collection(deps.api_keys.clone()))
+        .and_then(apikeys::delete_api_key_handler);
+
+    let create_invite_route = warp::path!("invites")
+        .and(metrics::route("/invites"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_collection(deps.invites.clone()))
+        .and(body::json())
+        .and_then(invites::create_invite_handler);
+
+    let audit_route = warp::path!("audit")
+        .and(metrics::route("/audit"))
+        .and(warp::get())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_collection(deps.audit_log.clone()))
+        .and(warp::query::<audit::AuditQuery>())
+        .and_then(audit::list_audit_handler);
+
+    let webhook_deliveries_route = warp::path!("webhooks" / "deliveries")
+        .and(metrics::route("/webhooks/deliveries"))
+        .and(warp::get())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_collection(deps.webhook_deliveries.clone()))
+        .and(warp::query::<webhooks::DeliveriesQuery>())
+        .and_then(webhooks::list_deliveries_handler);
+
+    list_roles_route
+        .or(create_role_route)
+        .or(update_role_route)
+        .or(delete_role_route)
+        .or(create_api_key_route)
+        .or(delete_api_key_route)
+        .or(create_invite_route)
+        .or(audit_route)
+        .or(webhook_deliveries_route)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+fn realtime_routes(deps: &AppState) -> BoxedFilter<(Response,)> {
+    let with_sockets = warp::any().map({
+        let sockets = deps.sockets.clone();
+        move || sockets.clone()
+    });
+
+    // Open to guests too: a socket only carries the caller's own events.
+    let socket_route = warp::path!("ws")
+        .and(metrics::route("/ws"))
+        .and(warp::get())
+        .and(with_socket_claims(deps.auth_context.clone()))
+        .and(warp::ws())
+        .and(with_sockets.clone())
+        .and_then(sockets::socket_handler);
+
+    let notify_route = warp::path!("notify" / String)
+        .and(metrics::route("/notify/{uid}"))
+        .and(warp::post())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(with_sockets)
+        .and(body::json())
+        .and_then(sockets::notify_handler);
+
+    let events_route = warp::path!("events")
+        .and(metrics::route("/events"))
+        .and(warp::get())
+        .and(features::enabled(deps.config.features.admin_api))
+        .and(with_auth(Role::Admin, deps.auth_context.clone()))
+        .and(warp::header::optional::<u64>("last-event-id"))
+        .and_then(events::events_handler);
+
+    socket_route
+        .or(notify_route)
+        .or(events_route)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// `/orgs` and everything under `/orgs/{org_id}`, authorized by the
+/// caller's role in that organization rather than their global one. The
+/// method is matched first so only the route that can match looks up the
+/// membership.
+fn org_routes(deps: &AppState) -> BoxedFilter<(Response,)> {
+    let create_org_route = warp::path!("orgs")
+        .and(metrics::route("/orgs"))
+        .and(warp::post())
+        .and(with_auth(Role::User, deps.auth_context.clone()))
+        .and(with_collection(deps.organizations.clone()))
+        .and(with_collection(deps.memberships.clone()))
+        .and(validated_json())
+        .and_then(orgs::create_org_handler);
+
+    let delete_org_route = warp::delete()
+        .and(with_org_role(
+            Role::Admin,
+            deps.auth_context.clone(),
+            deps.memberships.clone(),
+        ))
+        .and(warp::path::end())
+        .and(metrics::route("/orgs/{org_id}"))
+        .and(with_collection(deps.organizations.clone()))
+        .and(with_collection(deps.memberships.clone()))
+        .and_then(orgs::delete_org_handler);
+
+    let add_member_route = warp::post()
+        .and(with_org_role(
+            Role::Admin,
+            deps.auth_context.clone(),
+            deps.memberships.clone(),
+        ))
+        .and(warp::path!("members"))
+        .and(metrics::route("/orgs/{org_id}/members"))
+        .and(with_collection(deps.organizations.clone()))
+        .and(with_collection(deps.memberships.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(body::json())
+        .and_then(orgs::add_member_handler);
+
+    let list_members_route = warp::get()
+        .and(with_org_role(
+            Role::User,
+            deps.auth_context.clone(),
+            deps.memberships.clone(),
+        ))
+        .and(warp::path!("members"))
+        .and(metrics::route("/orgs/{org_id}/members"))
+        .and(with_collection(deps.organizations.clone()))
+        .and(with_collection(deps.memberships.clone()))
+        .and(with_collection(deps.users.clone()))
+        .and(warp::query::<orgs::MembersQuery>())
+        .and_then(orgs::list_members_handler);
+
+    create_org_route
+        .or(delete_org_route)
+        .or(add_member_route)
+        .or(list_members_route)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// Routes whose paths do not change with the API version: the probes,
+/// which load balancers and orchestrators are configured with, and the JWKS
+/// document at its well-known location.
+pub fn fixed_routes(deps: &AppState) -> BoxedFilter<(Response,)> {
+    let health_route = warp::path!("health")
+        .and(metrics::route("/health"))
+        .and(warp::get())
+        .and(warp::any().map({
+            let db = deps.db.clone();
+            move || db.clone()
+        }))
+        .and(warp::any().map({
+            let started = deps.started;
+            move || started
+        }))
+        .and(warp::any().map({
+            let latencies = deps.ping_latencies.clone();
+            move || latencies.clone()
+        }))
+        .and(warp::any().map({
+            let degraded = deps.config.health_degraded;
+            move || degraded
+        }))
+        .and_then(health::health_handler);
+
+    let livez_route = warp::path!("livez")
+        .and(metrics::route("/livez"))
+        .and(warp::get())
+        .and_then(health::livez_handler);
+
+    let readyz_route = warp::path!("readyz")
+        .and(metrics::route("/readyz"))
+        .and(warp::get())
+        .and(warp::any().map({
+            let readiness = deps.readiness.clone();
+            move || readiness.clone()
+        }))
+        .and(with_maintenance(deps.maintenance.clone()))
+        .and_then(health::readyz_handler);
+
+    let jwks_route = warp::path!(".well-known" / "jwks.json")
+        .and(metrics::route("/.well-known/jwks.json"))
+        .and(warp::get())
+        .and(maintenance::check(deps.maintenance.clone()))
+        .and(with_context(deps.auth_context.clone()))
+        .and_then(jwks_handler);
+
+    health_route
+        .or(livez_route)
+        .or(readyz_route)
+        .or(jwks_route)
+        .map(Reply::into_response)
+        .boxed()
+}
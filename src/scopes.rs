@@ -0,0 +1,57 @@
+use crate::{auth::Role, roles::RoleRegistry};
+
+pub const PROFILE_READ: &str = "profile:read";
+pub const PROFILE_WRITE: &str = "profile:write";
+pub const USERS_READ: &str = "users:read";
+pub const USERS_WRITE: &str = "users:write";
+pub const ROLES_READ: &str = "roles:read";
+pub const ROLES_WRITE: &str = "roles:write";
+pub const AUDIT_READ: &str = "audit:read";
+
+/// What every account may do with its own data.
+const USER_SCOPES: &[&str] = &[PROFILE_READ, PROFILE_WRITE];
+
+/// Every scope defined here, all of which `Admin` tokens carry.
+const ADMIN_SCOPES: &[&str] = &[
+    PROFILE_READ,
+    PROFILE_WRITE,
+    USERS_READ,
+    USERS_WRITE,
+    ROLES_READ,
+    ROLES_WRITE,
+    AUDIT_READ,
+];
+
+/// Whether `scope` goes beyond what every account may do, so that only
+/// admins and roles granted it have it.
+pub fn is_privileged(scope: &str) -> bool {
+    !USER_SCOPES.contains(&scope)
+}
+
+/// The scopes a token for `role` carries. Custom roles get the `User`
+/// scopes plus the permissions of their definition in the role registry,
+/// so granting a role `users:read` there lets it through
+/// `with_scope("users:read")`.
+pub fn for_role(role: &Role, roles: &RoleRegistry) -> Vec<String> {
+    let builtin = match role {
+        Role::Admin => ADMIN_SCOPES,
+        Role::User | Role::Custom(_) => USER_SCOPES,
+        // Guests have no scopes until they upgrade.
+        Role::Guest => &[],
+    };
+    let mut scopes: Vec<String> = builtin.iter().map(|scope| scope.to_string()).collect();
+    for permission in roles.permissions(role) {
+        if !scopes.contains(&permission) {
+            scopes.push(permission);
+        }
+    }
+    scopes
+}
+
+/// Whether a token for `role` carries any privileged scope: always for
+/// `Admin`, and for custom roles granted one in the registry.
+pub fn is_privileged_role(role: &Role, roles: &RoleRegistry) -> bool {
+    for_role(role, roles)
+        .iter()
+        .any(|scope| is_privileged(scope))
+}
@@ -0,0 +1,147 @@
+use crate::openapi;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use warp::{
+    filters::BoxedFilter,
+    http::{
+        header::{
+            HeaderName, CONTENT_SECURITY_POLICY, CONTENT_TYPE, REFERRER_POLICY,
+            STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+        },
+        HeaderValue,
+    },
+    reply::Response,
+    Filter,
+};
+
+pub const DEFAULT_HSTS: &str = "max-age=31536000; includeSubDomains";
+pub const DEFAULT_FRAME_OPTIONS: &str = "DENY";
+pub const DEFAULT_REFERRER_POLICY: &str = "no-referrer";
+
+/// Same-origin only, except for the Swagger UI assets on the CDN and the
+/// docs page's one inline script, allowed by its hash.
+pub fn default_content_security_policy() -> String {
+    let script = openapi::swagger_ui_inline_script();
+    let hash = STANDARD.encode(Sha256::digest(script.as_bytes()));
+    format!(
+        "default-src 'self'; script-src 'self' https://unpkg.com 'sha256-{}'; \
+         style-src 'self' 'unsafe-inline' https://unpkg.com; img-src 'self' data:; \
+         frame-ancestors 'none'",
+        hash
+    )
+}
+
+/// The hardening headers added to every response, errors included. Each
+/// one a handler already set is left alone.
+#[derive(Clone)]
+pub struct SecurityHeaders {
+    /// `Strict-Transport-Security`, only with TLS or `FORCE_HSTS`.
+    pub hsts: Option<HeaderValue>,
+    pub frame_options: Option<HeaderValue>,
+    pub referrer_policy: Option<HeaderValue>,
+    /// Only sent with HTML, the docs page and the frontend.
+    pub content_security_policy: Option<HeaderValue>,
+}
+
+impl SecurityHeaders {
+    fn apply(&self, mut response: Response) -> Response {
+        let html = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/html"));
+        let nosniff = HeaderValue::from_static("nosniff");
+        let headers: [(HeaderName, Option<&HeaderValue>); 5] = [
+            (X_CONTENT_TYPE_OPTIONS, Some(&nosniff)),
+            (STRICT_TRANSPORT_SECURITY, self.hsts.as_ref()),
+            (X_FRAME_OPTIONS, self.frame_options.as_ref()),
+            (REFERRER_POLICY, self.referrer_policy.as_ref()),
+            (
+                CONTENT_SECURITY_POLICY,
+                self.content_security_policy.as_ref().filter(|_| html),
+            ),
+        ];
+        for (name, value) in headers {
+            if let Some(value) = value {
+                response
+                    .headers_mut()
+                    .entry(name)
+                    .or_insert_with(|| value.clone());
+            }
+        }
+        response
+    }
+}
+
+/// Adds `headers` to whatever `routes` answers. Goes outside `recover`, so
+/// error responses get them too.
+pub fn with_security_headers(
+    routes: BoxedFilter<(Response,)>,
+    headers: SecurityHeaders,
+) -> BoxedFilter<(Response,)> {
+    routes
+        .map(move |response: Response| headers.apply(response))
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use warp::http::{HeaderMap, StatusCode};
+
+    async fn get(vars: &[(&str, &str)], path: &str) -> (StatusCode, HeaderMap) {
+        let app = test_support::offline_app_with(vars).await;
+        let response = warp::test::request()
+            .path(path)
+            .reply(&crate::routes(app))
+            .await;
+        (response.status(), response.headers().clone())
+    }
+
+    #[tokio::test]
+    async fn successes_and_errors_alike_are_hardened() {
+        for (path, status) in [
+            ("/livez", StatusCode::OK),
+            ("/no/such/path", StatusCode::NOT_FOUND),
+            ("/api/v1/me", StatusCode::UNAUTHORIZED),
+        ] {
+            let (answered, headers) = get(&[], path).await;
+            assert_eq!(answered, status, "{}", path);
+            assert_eq!(headers[X_CONTENT_TYPE_OPTIONS], "nosniff", "{}", path);
+            assert_eq!(headers[X_FRAME_OPTIONS], DEFAULT_FRAME_OPTIONS, "{}", path);
+            assert_eq!(
+                headers[REFERRER_POLICY], DEFAULT_REFERRER_POLICY,
+                "{}",
+                path
+            );
+            // Plain HTTP, and not HTML.
+            assert!(!headers.contains_key(STRICT_TRANSPORT_SECURITY), "{}", path);
+            assert!(!headers.contains_key(CONTENT_SECURITY_POLICY), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn force_hsts_sends_hsts_without_tls() {
+        let (_, headers) = get(&[("FORCE_HSTS", "true")], "/no/such/path").await;
+        assert_eq!(headers[STRICT_TRANSPORT_SECURITY], DEFAULT_HSTS);
+    }
+
+    #[tokio::test]
+    async fn the_docs_page_gets_a_content_security_policy() {
+        let (status, headers) = get(&[], "/api-docs").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            headers[CONTENT_SECURITY_POLICY],
+            default_content_security_policy().as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn headers_can_be_changed_or_turned_off() {
+        let vars = [("X_FRAME_OPTIONS", "SAMEORIGIN"), ("REFERRER_POLICY", "")];
+        let (_, headers) = get(&vars, "/livez").await;
+        assert_eq!(headers[X_FRAME_OPTIONS], "SAMEORIGIN");
+        assert!(!headers.contains_key(REFERRER_POLICY));
+    }
+}
@@ -0,0 +1,150 @@
+use crate::{
+    auth::Role,
+    error::Error,
+    password,
+    roles::RoleRegistry,
+    users::{self, by_email, normalize_email},
+    validation::{Validate, Validator},
+    Result, User,
+};
+use mongodb::{
+    bson::{doc, DateTime},
+    Collection,
+};
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+/// Used by `seed` when no fixtures file is given.
+const DEFAULT_FIXTURES: &str = include_str!("../fixtures/seed.json");
+
+/// Accounts for a development or demo database, as read by the `seed`
+/// command.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fixtures {
+    pub users: Vec<FixtureUser>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FixtureUser {
+    pub email: String,
+    /// In plaintext; fixtures are for development only.
+    pub password: String,
+    #[serde(default = "default_role")]
+    pub role: String,
+    pub username: Option<String>,
+}
+
+fn default_role() -> String {
+    Role::User.to_string()
+}
+
+impl Validate for FixtureUser {
+    fn validate(&self, validator: &mut Validator) {
+        validator.email("email", &self.email);
+        validator.password("password", &self.password);
+        if let Some(username) = &self.username {
+            validator.username("username", username);
+        }
+    }
+}
+
+impl Fixtures {
+    /// Reads the JSON fixtures at `path`, or the built-in ones.
+    pub fn load(path: Option<&Path>) -> std::result::Result<Fixtures, String> {
+        let json = match path {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|e| format!("reading {} failed: {}", path.display(), e))?,
+            None => DEFAULT_FIXTURES.to_string(),
+        };
+        serde_json::from_str(&json).map_err(|e| format!("invalid fixtures: {}", e))
+    }
+
+    /// Every problem with the fixtures, each naming the entry it is in.
+    pub fn problems(&self, roles: &RoleRegistry) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (i, user) in self.users.iter().enumerate() {
+            let mut validator = Validator::new();
+            user.validate(&mut validator);
+            if !Role::from_str(&user.role).is_ok_and(|role| roles.is_assignable(&role)) {
+                validator.fail("role", "is not a role accounts can be given");
+            }
+            if let Err(Error::ValidationError(errors)) = validator.finish() {
+                for (field, messages) in errors {
+                    problems.push(format!("users[{}].{} {}", i, field, messages.join(", ")));
+                }
+            }
+        }
+        problems
+    }
+}
+
+/// How many fixture accounts [`seed`] created and how many it updated.
+#[derive(Default)]
+pub struct Seeded {
+    pub created: usize,
+    pub updated: usize,
+}
+
+/// Makes the accounts in `fixtures`, which should have no
+/// [`Fixtures::problems`], exist with their password, role and username.
+/// Accounts are matched by email, so seeding again only resets them. Refuses
+/// with `UserAlreadyExistsError` while the database holds any account the
+/// fixtures don't describe, which a production database would, unless
+/// `force` is set.
+pub async fn seed(
+    users_collection: &Collection<User>,
+    fixtures: &Fixtures,
+    force: bool,
+) -> Result<Seeded> {
+    let emails: Vec<String> = fixtures
+        .users
+        .iter()
+        .map(|user| normalize_email(&user.email))
+        .collect();
+    let others = users_collection
+        .count_documents(doc! {"email_lower": {"$nin": &emails}}, None)
+        .await?;
+    if others > 0 && !force {
+        return Err(Error::UserAlreadyExistsError);
+    }
+
+    let mut seeded = Seeded::default();
+    for (fixture, email) in fixtures.users.iter().zip(emails) {
+        let pw = password::hash(&fixture.password)?;
+        let role = Role::from_str(&fixture.role)?;
+        let existing = users_collection.find_one(by_email(&email), None).await?;
+        match existing {
+            Some(user) => {
+                users_collection
+                    .update_one(
+                        doc! {"uid": &user.uid},
+                        doc! {
+                            "$set": {
+                                "pw": pw,
+                                "role": role.to_string(),
+                                "username": &fixture.username,
+                                "username_lower": fixture.username.as_ref().map(|u| u.to_lowercase()),
+                                "updated_at": DateTime::now(),
+                            },
+                            "$inc": {"version": 1},
+                        },
+                        None,
+                    )
+                    .await?;
+                seeded.updated += 1;
+            }
+            None => {
+                let mut user = User::new(email, pw, &role);
+                user.username_lower = fixture.username.as_ref().map(|u| u.to_lowercase());
+                user.username = fixture.username.clone();
+                users::documents(users_collection)
+                    .insert_one(user.document(), None)
+                    .await?;
+                seeded.created += 1;
+            }
+        }
+    }
+    Ok(seeded)
+}
@@ -0,0 +1,113 @@
+use std::{cell::Cell, future::Future, time::Duration};
+use warp::http::HeaderValue;
+
+pub const SERVER_TIMING_HEADER: &str = "server-timing";
+
+tokio::task_local! {
+    static DB_TIME: Cell<Duration>;
+}
+
+/// Runs `future`, the handling of one request, returning the time it spent
+/// in MongoDB operations along with its output.
+pub async fn scope<F: Future>(future: F) -> (Duration, F::Output) {
+    DB_TIME
+        .scope(Cell::new(Duration::ZERO), async move {
+            let output = future.await;
+            (DB_TIME.with(Cell::get), output)
+        })
+        .await
+}
+
+/// Adds `elapsed`, one MongoDB operation, to the current request's
+/// database time. Does nothing outside a request, such as in background
+/// tasks.
+pub fn record_db(elapsed: Duration) {
+    DB_TIME.try_with(|db| db.set(db.get() + elapsed)).ok();
+}
+
+/// `Server-Timing` for a request answered in `total`, `db` of it in MongoDB
+/// and the rest in the server itself. Operations that ran concurrently
+/// each count in full, so `db` can exceed `total`, leaving `app` at 0.
+pub fn header(db: Duration, total: Duration) -> HeaderValue {
+    let value = format!(
+        "db;dur={:.1}, app;dur={:.1}, total;dur={:.1}",
+        millis(db),
+        millis(total.saturating_sub(db)),
+        millis(total)
+    );
+    HeaderValue::from_str(&value).expect("Server-Timing is ASCII")
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{health, repository::timed};
+    use mongodb::bson::{doc, Document};
+    use std::collections::HashMap;
+
+    /// The durations of a `Server-Timing` value, by metric name.
+    fn parse(header: &HeaderValue) -> HashMap<String, f64> {
+        header
+            .to_str()
+            .unwrap()
+            .split(", ")
+            .map(|metric| {
+                let (name, duration) = metric.split_once(";dur=").unwrap();
+                (name.to_owned(), duration.parse().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_header_splits_the_total_into_db_and_app() {
+        let timings = parse(&header(
+            Duration::from_micros(12_300),
+            Duration::from_micros(17_000),
+        ));
+        assert_eq!(timings["db"], 12.3);
+        assert_eq!(timings["app"], 4.7);
+        assert_eq!(timings["total"], 17.0);
+        assert_eq!(timings.len(), 3);
+    }
+
+    #[test]
+    fn concurrent_database_time_leaves_app_at_zero() {
+        let timings = parse(&header(
+            Duration::from_millis(30),
+            Duration::from_millis(20),
+        ));
+        assert_eq!(timings["app"], 0.0);
+    }
+
+    #[tokio::test]
+    async fn a_scope_adds_up_its_database_time() {
+        let (db, ()) = scope(async {
+            record_db(Duration::from_millis(5));
+            record_db(Duration::from_millis(7));
+        })
+        .await;
+        assert_eq!(db, Duration::from_millis(12));
+        // Outside a request it is dropped.
+        record_db(Duration::from_millis(1));
+    }
+
+    #[tokio::test]
+    async fn repository_calls_count_as_database_time() {
+        // Nothing listens on port 1, so the operation fails once the
+        // server selection timeout has passed.
+        let client = health::connect_to_mongo(
+            "mongodb://127.0.0.1:1/?directConnection=true",
+            Duration::from_millis(50),
+        )
+        .await
+        .unwrap();
+        let users = client.database("test").collection::<Document>("users");
+        let (db, result) = scope(timed(users.find_one(doc! {}, None))).await;
+        assert!(result.is_err());
+        assert!(db >= Duration::from_millis(50), "{:?}", db);
+    }
+}
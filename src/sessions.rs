@@ -0,0 +1,445 @@
+use crate::{
+    auth::{create_refresh_token, hash_token, AuthContext, Claims, REFRESH_TOKEN_EXPIRY},
+    config,
+    error::Error,
+    repository::timed,
+    throttle::client_ip,
+    Result, WebResult,
+};
+use mongodb::{
+    bson::{doc, uuid, DateTime, Document},
+    options::IndexOptions,
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, time::Duration};
+use utoipa::ToSchema;
+use warp::{http::StatusCode, reject, reply, Filter, Rejection, Reply};
+
+/// One refresh token in a rotation chain, stored as its SHA-256 so a copy
+/// of the database holds no usable tokens. Lookups go through the hash, so
+/// no comparison ever sees the secret itself. Every refresh marks the presented
+/// token used and adds a successor with the same `family_id`; used tokens are
+/// kept until they expire so a replay can be recognised. The family id is
+/// what users see as the session id.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub uid: String,
+    pub family_id: String,
+    pub token_hash: String,
+    pub used: bool,
+    pub expires_at: DateTime,
+    /// When the family was started by a login.
+    pub created_at: DateTime,
+    /// When this link of the chain was issued.
+    pub last_used: DateTime,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// `jti` of the access token issued alongside this refresh token.
+    pub access_jti: Option<String>,
+    /// Started by a login with `remember_me`, so its refresh tokens last
+    /// [`config::remember_me_ttl`] instead of [`REFRESH_TOKEN_EXPIRY`].
+    #[serde(default)]
+    pub remember_me: bool,
+}
+
+/// Where a login or refresh came from, recorded on the session.
+#[derive(Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SessionResponse {
+    pub id: String,
+    pub created_at: Option<String>,
+    pub last_used: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// Whether this is the session the request was made with.
+    pub current: bool,
+    /// Whether it was started with `remember_me`.
+    pub remember_me: bool,
+}
+
+/// Hashes refresh tokens stored in plaintext by releases that kept them in
+/// a `refresh_token` field, turning each into a session of its own. This
+/// runs at startup rather than when each token is next used, both so the
+/// plaintext does not linger until then and because the unique
+/// `token_hash` index cannot be built while several documents lack one.
+#[tracing::instrument(skip_all)]
+pub async fn hash_plaintext_tokens(collection: &Collection<Session>) -> mongodb::error::Result<()> {
+    let collection = collection.clone_with_type::<Document>();
+    let mut cursor = collection
+        .find(doc! {"refresh_token": {"$type": "string"}}, None)
+        .await?;
+    let mut upgraded = 0;
+    while cursor.advance().await? {
+        let legacy = cursor.deserialize_current()?;
+        let (Ok(token), Ok(expires_at)) = (
+            legacy.get_str("refresh_token"),
+            legacy.get_datetime("expires_at"),
+        ) else {
+            continue;
+        };
+        let issued_at = DateTime::from_millis(
+            expires_at.timestamp_millis() - REFRESH_TOKEN_EXPIRY.as_millis() as i64,
+        );
+        collection
+            .update_one(
+                doc! {"_id": legacy.get("_id")},
+                doc! {
+                    "$set": {
+                        "token_hash": hash_token(token),
+                        "family_id": uuid::Uuid::new().to_string(),
+                        "used": false,
+                        "created_at": issued_at,
+                        "last_used": issued_at,
+                    },
+                    "$unset": {"refresh_token": ""},
+                },
+                None,
+            )
+            .await?;
+        upgraded += 1;
+    }
+    if upgraded > 0 {
+        tracing::info!("hashed {} plaintext refresh tokens", upgraded);
+    }
+    Ok(())
+}
+
+pub async fn create_indexes(collection: &Collection<Session>) -> mongodb::error::Result<()> {
+    let token_index = IndexModel::builder()
+        .keys(doc! {"token_hash": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    let ttl_index = IndexModel::builder()
+        .keys(doc! {"expires_at": 1})
+        .options(
+            IndexOptions::builder()
+                .expire_after(Duration::from_secs(0))
+                .build(),
+        )
+        .build();
+    let family_index = IndexModel::builder().keys(doc! {"family_id": 1}).build();
+    // Serves `GET /admin/sessions`, which lists active sessions most
+    // recently used first.
+    let activity_index = IndexModel::builder()
+        .keys(doc! {"used": 1, "last_used": -1, "_id": -1})
+        .build();
+    collection
+        .create_indexes(
+            vec![token_index, ttl_index, family_index, activity_index],
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+pub fn with_client_info(
+    trust_proxy: bool,
+) -> impl Filter<Extract = (ClientInfo,), Error = Rejection> + Clone {
+    client_ip(trust_proxy)
+        .and(warp::header::optional::<String>("user-agent"))
+        .map(
+            |ip: Option<IpAddr>, user_agent: Option<String>| ClientInfo {
+                ip: ip.map(|ip| ip.to_string()),
+                user_agent,
+            },
+        )
+}
+
+/// How long the refresh tokens of a session last.
+pub fn refresh_token_ttl(remember_me: bool) -> Duration {
+    if remember_me {
+        config::remember_me_ttl()
+    } else {
+        REFRESH_TOKEN_EXPIRY
+    }
+}
+
+async fn insert_token(
+    sessions_collection: &Collection<Session>,
+    uid: &str,
+    family_id: String,
+    created_at: DateTime,
+    client: &ClientInfo,
+    access_jti: &str,
+    remember_me: bool,
+) -> Result<String> {
+    let refresh_token = create_refresh_token();
+    let now = DateTime::now();
+    timed(sessions_collection.insert_one(
+        Session {
+            uid: uid.to_owned(),
+            family_id,
+            token_hash: hash_token(&refresh_token),
+            used: false,
+            expires_at: now.saturating_add_duration(refresh_token_ttl(remember_me)),
+            created_at,
+            last_used: now,
+            ip: client.ip.clone(),
+            user_agent: client.user_agent.clone(),
+            access_jti: Some(access_jti.to_owned()),
+            remember_me,
+        },
+        None,
+    ))
+    .await?;
+    Ok(refresh_token)
+}
+
+/// Starts a new session family for `uid` and returns its first refresh
+/// token. With `remember_me` the family's tokens are long-lived.
+#[tracing::instrument(skip_all)]
+pub async fn start(
+    sessions_collection: &Collection<Session>,
+    uid: &str,
+    client: &ClientInfo,
+    access_jti: &str,
+    remember_me: bool,
+) -> Result<String> {
+    let family_id = uuid::Uuid::new().to_string();
+    insert_token(
+        sessions_collection,
+        uid,
+        family_id,
+        DateTime::now(),
+        client,
+        access_jti,
+        remember_me,
+    )
+    .await
+}
+
+/// Marks a refresh token used and returns its session so the caller can
+/// issue a successor with `continue_family`. Presenting a token that was
+/// already rotated revokes the whole family, since either the client or an
+/// attacker holds a stale copy.
+#[tracing::instrument(skip_all)]
+pub async fn consume(
+    sessions_collection: &Collection<Session>,
+    refresh_token: &str,
+) -> Result<Session> {
+    let token_hash = hash_token(refresh_token);
+    let current = timed(sessions_collection.find_one_and_update(
+        doc! {
+            "token_hash": &token_hash,
+            "used": false,
+            "expires_at": {"$gt": DateTime::now()},
+        },
+        doc! {"$set": {"used": true}},
+        None,
+    ))
+    .await?;
+    if let Some(session) = current {
+        return Ok(session);
+    }
+
+    let reused =
+        timed(sessions_collection.find_one(doc! {"token_hash": &token_hash, "used": true}, None))
+            .await?;
+    match reused {
+        Some(session) => {
+            timed(sessions_collection.delete_many(doc! {"family_id": &session.family_id}, None))
+                .await?;
+            Err(Error::RefreshTokenReuseError)
+        }
+        None => Err(Error::InvalidRefreshTokenError),
+    }
+}
+
+/// Issues the refresh token that replaces `previous` in its family.
+pub async fn continue_family(
+    sessions_collection: &Collection<Session>,
+    previous: Session,
+    client: &ClientInfo,
+    access_jti: &str,
+) -> Result<String> {
+    insert_token(
+        sessions_collection,
+        &previous.uid,
+        previous.family_id,
+        previous.created_at,
+        client,
+        access_jti,
+        previous.remember_me,
+    )
+    .await
+}
+
+/// Refuses, with `REQUIRE_FRESH_LOGIN` on, a sensitive change made with
+/// the access token of a `remember_me` session: a device that stays signed
+/// in for weeks should not be enough to take over an account. Tokens that
+/// belong to no session, such as API keys, are let through.
+pub async fn require_fresh_login(
+    sessions_collection: &Collection<Session>,
+    claims: &Claims,
+) -> Result<()> {
+    if !config::require_fresh_login() {
+        return Ok(());
+    }
+    let remembered = timed(sessions_collection.find_one(
+        doc! {"uid": &claims.sub, "access_jti": &claims.jti, "remember_me": true},
+        None,
+    ))
+    .await?;
+    match remembered {
+        Some(_) => Err(Error::FreshLoginRequiredError),
+        None => Ok(()),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/sessions",
+    tag = "sessions",
+    responses(
+        (status = 200, description = "The caller's active sessions", body = Vec<SessionResponse>),
+        (status = 401, description = "Missing or invalid token", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn list_sessions_handler(
+    claims: Claims,
+    sessions_collection: Collection<Session>,
+) -> WebResult<impl Reply> {
+    // Exactly one unused token exists per live family, so these documents
+    // are the user's active sessions.
+    let mut cursor = timed(sessions_collection.find(
+        doc! {
+            "uid": &claims.sub,
+            "used": false,
+            "expires_at": {"$gt": DateTime::now()},
+        },
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?;
+
+    let mut sessions = Vec::new();
+    while timed(cursor.advance()).await.map_err(reject::custom)? {
+        let session: Session = cursor
+            .deserialize_current()
+            .map_err(|e| reject::custom(Error::from(e)))?;
+        sessions.push(SessionResponse {
+            current: session.access_jti.as_deref() == Some(claims.jti.as_str()),
+            id: session.family_id,
+            created_at: session.created_at.try_to_rfc3339_string().ok(),
+            last_used: session.last_used.try_to_rfc3339_string().ok(),
+            ip: session.ip,
+            user_agent: session.user_agent,
+            remember_me: session.remember_me,
+        });
+    }
+
+    Ok(reply::json(&sessions))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/sessions/{id}",
+    tag = "sessions",
+    params(("id" = String, Path, description = "Session id")),
+    responses(
+        (status = 204, description = "Session ended"),
+        (status = 404, description = "No such session", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn delete_session_handler(
+    id: String,
+    claims: Claims,
+    context: AuthContext,
+    sessions_collection: Collection<Session>,
+) -> WebResult<impl Reply> {
+    // Matching on uid as well as the family id keeps other users' sessions
+    // out of reach even if an id is guessed.
+    let current = timed(sessions_collection.find_one(
+        doc! {"uid": &claims.sub, "family_id": &id, "used": false},
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?;
+
+    let result =
+        timed(sessions_collection.delete_many(doc! {"uid": &claims.sub, "family_id": &id}, None))
+            .await
+            .map_err(reject::custom)?;
+    if result.deleted_count == 0 {
+        return Err(reject::custom(Error::SessionNotFoundError));
+    }
+
+    if let Some(jti) = current.and_then(|session| session.access_jti) {
+        context.revoke_jti(&jti).await.map_err(reject::custom)?;
+    }
+
+    Ok(reply::with_status(reply(), StatusCode::NO_CONTENT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    async fn sessions() -> Collection<Session> {
+        let sessions = test_support::database().await.collection("sessions");
+        create_indexes(&sessions).await.unwrap();
+        sessions
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn each_refresh_token_is_replaced_by_the_next() {
+        let sessions = sessions().await;
+        let client = ClientInfo::default();
+        let first = start(&sessions, "uid", &client, "jti-1", false)
+            .await
+            .unwrap();
+
+        let session = consume(&sessions, &first).await.unwrap();
+        let second = continue_family(&sessions, session, &client, "jti-2")
+            .await
+            .unwrap();
+        let session = consume(&sessions, &second).await.unwrap();
+
+        assert_eq!(session.uid, "uid");
+        assert_eq!(session.access_jti.as_deref(), Some("jti-2"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn replaying_a_rotated_token_ends_the_family() {
+        let sessions = sessions().await;
+        let client = ClientInfo::default();
+        let stolen = start(&sessions, "uid", &client, "jti-1", false)
+            .await
+            .unwrap();
+        let session = consume(&sessions, &stolen).await.unwrap();
+        let current = continue_family(&sessions, session, &client, "jti-2")
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            consume(&sessions, &stolen).await,
+            Err(Error::RefreshTokenReuseError)
+        ));
+        // The legitimate holder is signed out too.
+        assert!(matches!(
+            consume(&sessions, &current).await,
+            Err(Error::InvalidRefreshTokenError)
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn an_unknown_refresh_token_is_invalid() {
+        let sessions = sessions().await;
+        assert!(matches!(
+            consume(&sessions, "no such token").await,
+            Err(Error::InvalidRefreshTokenError)
+        ));
+    }
+}
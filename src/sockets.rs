@@ -0,0 +1,304 @@
+use crate::{auth::Claims, error::Error, WebResult};
+use chrono::Utc;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use utoipa::ToSchema;
+use warp::{
+    reject, reply,
+    ws::{Message, WebSocket, Ws},
+    Reply,
+};
+
+/// How often the server pings an idle socket.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// A socket the client has sent nothing on, pongs included, for this long
+/// is considered dead and closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+/// Open sockets allowed per account, so one client cannot pile up
+/// connections.
+const MAX_SOCKETS_PER_USER: usize = 16;
+/// The close code for sockets the server ends because their token stopped
+/// being valid.
+const POLICY_VIOLATION: u16 = 1008;
+
+/// What the server sends over a socket, tagged by `type`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage<'a> {
+    Notification { message: &'a str },
+    Pong,
+    Echo { data: serde_json::Value },
+    Error { message: &'a str },
+}
+
+/// What a client may send: `{"type": "ping"}` as an application-level
+/// heartbeat, or `{"type": "echo", "data": ...}` to get `data` back.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Ping,
+    Echo { data: serde_json::Value },
+}
+
+/// The open sockets of each account, by uid. Each socket is served by its
+/// own task, which owns the receiving end of its channel; dropping that
+/// closes the sender here, which is how disconnected sockets are told
+/// apart.
+#[derive(Clone, Default)]
+pub struct SocketRegistry {
+    sockets: Arc<RwLock<HashMap<String, Vec<UnboundedSender<Message>>>>>,
+}
+
+impl SocketRegistry {
+    /// Sends `message` to every open socket of `uid` and returns how many
+    /// that was.
+    pub fn push(&self, uid: &str, message: &ServerMessage) -> usize {
+        let text = serde_json::to_string(message).expect("socket messages serialize");
+        let sockets = self.sockets.read().expect("socket registry lock poisoned");
+        sockets.get(uid).map_or(0, |senders| {
+            senders
+                .iter()
+                .filter(|sender| sender.send(Message::text(text.clone())).is_ok())
+                .count()
+        })
+    }
+
+    /// Registers a socket for `uid` unless it already has
+    /// `MAX_SOCKETS_PER_USER` open. The count and the registration happen
+    /// under one lock, so concurrent upgrades can't add up to more.
+    fn try_register(&self, uid: &str) -> Option<UnboundedReceiver<Message>> {
+        let mut sockets = self.sockets.write().expect("socket registry lock poisoned");
+        let senders = sockets.entry(uid.to_string()).or_default();
+        senders.retain(|sender| !sender.is_closed());
+        if senders.len() >= MAX_SOCKETS_PER_USER {
+            return None;
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        senders.push(sender);
+        Some(receiver)
+    }
+
+    /// Closes every open socket of `uid`, for when its tokens stop being
+    /// accepted.
+    pub fn disconnect(&self, uid: &str) {
+        let mut sockets = self.sockets.write().expect("socket registry lock poisoned");
+        sockets.remove(uid);
+    }
+
+    /// Drops the closed senders of `uid`, and `uid` itself once it has no
+    /// sockets left.
+    fn prune(&self, uid: &str) {
+        let mut sockets = self.sockets.write().expect("socket registry lock poisoned");
+        if let Some(senders) = sockets.get_mut(uid) {
+            senders.retain(|sender| !sender.is_closed());
+            if senders.is_empty() {
+                sockets.remove(uid);
+            }
+        }
+    }
+}
+
+/// Upgrades to a WebSocket that receives this account's notifications.
+/// The socket is closed when the token it was opened with expires, and
+/// when the account is signed out everywhere, banned or otherwise changed
+/// so that its tokens stop being accepted; clients reconnect with a fresh
+/// token.
+pub async fn socket_handler(
+    claims: Claims,
+    ws: Ws,
+    registry: SocketRegistry,
+) -> WebResult<impl Reply> {
+    let outbox = registry
+        .try_register(&claims.sub)
+        .ok_or_else(|| reject::custom(Error::TooManySocketsError))?;
+    Ok(ws.on_upgrade(move |socket| serve_socket(socket, claims, outbox, registry)))
+}
+
+async fn serve_socket(
+    socket: WebSocket,
+    claims: Claims,
+    mut outbox: UnboundedReceiver<Message>,
+    registry: SocketRegistry,
+) {
+    let (mut tx, mut rx) = socket.split();
+    let mut heartbeat =
+        tokio::time::interval_at(tokio::time::Instant::now() + PING_INTERVAL, PING_INTERVAL);
+    let mut last_seen = Instant::now();
+    let lifetime = Duration::from_secs(
+        (claims.exp as u64).saturating_sub(Utc::now().timestamp().max(0) as u64),
+    );
+    let expiry = tokio::time::sleep(lifetime);
+    tokio::pin!(expiry);
+
+    loop {
+        let outgoing = tokio::select! {
+            incoming = rx.next() => match incoming {
+                Some(Ok(message)) if message.is_close() => break,
+                Some(Ok(message)) => {
+                    last_seen = Instant::now();
+                    match message.to_str() {
+                        Ok(text) => reply_to(text),
+                        // Pongs only count as a sign of life; pings are
+                        // answered by the WebSocket layer itself.
+                        Err(()) => continue,
+                    }
+                }
+                Some(Err(_)) | None => break,
+            },
+            queued = outbox.recv() => match queued {
+                Some(message) => message,
+                // The registry let go of this socket: see `disconnect`.
+                None => {
+                    tx.send(Message::close_with(POLICY_VIOLATION, "signed out")).await.ok();
+                    break;
+                }
+            },
+            _ = &mut expiry => {
+                tx.send(Message::close_with(POLICY_VIOLATION, "token expired")).await.ok();
+                break;
+            }
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > IDLE_TIMEOUT {
+                    break;
+                }
+                Message::ping(Vec::new())
+            }
+        };
+        if tx.send(outgoing).await.is_err() {
+            break;
+        }
+    }
+
+    drop(outbox);
+    registry.prune(&claims.sub);
+}
+
+fn reply_to(text: &str) -> Message {
+    let reply = match serde_json::from_str(text) {
+        Ok(ClientMessage::Ping) => ServerMessage::Pong,
+        Ok(ClientMessage::Echo { data }) => ServerMessage::Echo { data },
+        Err(_) => ServerMessage::Error {
+            message: "expected {\"type\": \"ping\"} or {\"type\": \"echo\", \"data\": ...}",
+        },
+    };
+    Message::text(serde_json::to_string(&reply).expect("socket messages serialize"))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NotifyRequest {
+    pub message: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct NotifyResponse {
+    /// How many of the user's sockets the message went to; 0 when they have
+    /// none open.
+    pub delivered: usize,
+}
+
+/// Pushes `message` to the open `/ws` sockets of `uid`, as a
+/// `{"type": "notification"}` message.
+#[utoipa::path(
+    post,
+    path = "/notify/{uid}",
+    tag = "users",
+    request_body = NotifyRequest,
+    params(("uid" = String, Path, description = "The user to notify")),
+    responses(
+        (status = 200, description = "Message pushed", body = NotifyResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn notify_handler(
+    uid: String,
+    _claims: Claims,
+    registry: SocketRegistry,
+    body: NotifyRequest,
+) -> WebResult<impl Reply> {
+    let delivered = registry.push(
+        &uid,
+        &ServerMessage::Notification {
+            message: &body.message,
+        },
+    );
+    Ok(reply::json(&NotifyResponse { delivered }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use warp::{test::WsClient, Filter};
+
+    fn claims(lifetime: i64) -> Claims {
+        serde_json::from_value(json!({
+            "sub": "someone",
+            "role": "User",
+            "exp": Utc::now().timestamp() + lifetime,
+            "iat": Utc::now().timestamp(),
+            "jti": "jti",
+        }))
+        .unwrap()
+    }
+
+    async fn open(registry: &SocketRegistry, lifetime: i64) -> WsClient {
+        let registry = registry.clone();
+        let route = warp::ws()
+            .and_then(move |ws| socket_handler(claims(lifetime), ws, registry.clone()))
+            .recover(crate::error::handle_rejection);
+        warp::test::ws().handshake(route).await.unwrap()
+    }
+
+    async fn assert_closed(client: &mut WsClient) {
+        tokio::time::timeout(Duration::from_secs(5), client.recv_closed())
+            .await
+            .expect("the socket was not closed")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn closes_when_the_token_expires() {
+        let registry = SocketRegistry::default();
+        let mut client = open(&registry, 1).await;
+        assert_closed(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn closes_on_disconnect() {
+        let registry = SocketRegistry::default();
+        let mut client = open(&registry, 3600).await;
+        client.send_text(r#"{"type": "ping"}"#).await;
+        let pong = client.recv().await.unwrap();
+        assert_eq!(pong.to_str(), Ok(r#"{"type":"pong"}"#));
+
+        registry.disconnect("someone");
+        assert_closed(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn refuses_sockets_beyond_the_limit() {
+        let registry = SocketRegistry::default();
+        let mut clients = Vec::new();
+        for _ in 0..MAX_SOCKETS_PER_USER {
+            clients.push(open(&registry, 3600).await);
+        }
+        let route = warp::ws()
+            .and_then({
+                let registry = registry.clone();
+                move |ws| socket_handler(claims(3600), ws, registry.clone())
+            })
+            .recover(crate::error::handle_rejection);
+        assert!(warp::test::ws().handshake(route).await.is_err());
+
+        drop(clients.pop());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        open(&registry, 3600).await;
+    }
+}
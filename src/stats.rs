@@ -0,0 +1,171 @@
+use crate::{auth::Claims, lockout::LoginLockout, repository::timed, User, WebResult};
+use mongodb::{
+    bson::{doc, Bson, DateTime, Document},
+    Collection,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use utoipa::ToSchema;
+use warp::{reject, reply, Reply};
+
+const CACHE_TTL: Duration = Duration::from_secs(60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The latest `UserStats` and when they were computed, shared by every
+/// request.
+pub type StatsCache = Arc<RwLock<Option<(Instant, UserStats)>>>;
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct UserStats {
+    /// Accounts that are not deleted.
+    pub total: u64,
+    /// `total` broken down by role name.
+    pub by_role: BTreeMap<String, u64>,
+    /// Accounts created in the last 24 hours, 7 days and 30 days, deleted
+    /// ones included.
+    pub signups_last_24h: u64,
+    pub signups_last_7d: u64,
+    pub signups_last_30d: u64,
+    /// Accounts an admin has deactivated, deleted ones excluded.
+    pub deactivated: u64,
+    /// Soft-deleted accounts awaiting their purge.
+    pub deleted: u64,
+    /// Login identifiers currently locked out after failed attempts. Unknown
+    /// emails lock out too, so this can exceed the number of accounts.
+    pub locked: u64,
+    pub computed_at: Option<String>,
+}
+
+fn ago(duration: Duration) -> DateTime {
+    DateTime::from_millis(DateTime::now().timestamp_millis() - duration.as_millis() as i64)
+}
+
+/// 1 for documents matching `condition`, for summing in a `$group`.
+fn one_if(condition: Document) -> Document {
+    doc! {"$cond": [condition, 1, 0]}
+}
+
+/// A count from an aggregation result, which MongoDB returns as whichever
+/// integer type fits.
+fn count(document: &Document, key: &str) -> u64 {
+    match document.get(key) {
+        Some(Bson::Int32(n)) => *n as u64,
+        Some(Bson::Int64(n)) => *n as u64,
+        _ => 0,
+    }
+}
+
+async fn aggregate(
+    users_collection: &Collection<User>,
+    pipeline: Vec<Document>,
+) -> crate::Result<Vec<Document>> {
+    let mut cursor = timed(users_collection.aggregate(pipeline, None)).await?;
+    let mut documents = Vec::new();
+    while timed(cursor.advance()).await? {
+        documents.push(cursor.deserialize_current()?);
+    }
+    Ok(documents)
+}
+
+/// One pass over the users grouped by role, plus one over the last 30 days
+/// of signups through the `created_at` index.
+async fn compute(
+    users_collection: &Collection<User>,
+    lockout: &LoginLockout,
+) -> crate::Result<UserStats> {
+    let deleted = doc! {"$gt": ["$deleted_at", null]};
+    let roles = aggregate(
+        users_collection,
+        vec![doc! {"$group": {
+            "_id": "$role",
+            "total": {"$sum": one_if(doc! {"$not": [deleted.clone()]})},
+            "deactivated": {"$sum": one_if(doc! {"$and": [
+                {"$eq": ["$active", false]},
+                {"$not": [deleted.clone()]},
+            ]})},
+            "deleted": {"$sum": one_if(deleted)},
+        }}],
+    )
+    .await?;
+
+    let day = ago(DAY);
+    let week = ago(DAY * 7);
+    let month = ago(DAY * 30);
+    let signups = aggregate(
+        users_collection,
+        vec![
+            doc! {"$match": {"created_at": {"$gte": month}}},
+            doc! {"$group": {
+                "_id": null,
+                "day": {"$sum": one_if(doc! {"$gte": ["$created_at", day]})},
+                "week": {"$sum": one_if(doc! {"$gte": ["$created_at", week]})},
+                "month": {"$sum": 1},
+            }},
+        ],
+    )
+    .await?;
+    let signups = signups.first().cloned().unwrap_or_default();
+
+    let mut stats = UserStats {
+        total: 0,
+        by_role: BTreeMap::new(),
+        signups_last_24h: count(&signups, "day"),
+        signups_last_7d: count(&signups, "week"),
+        signups_last_30d: count(&signups, "month"),
+        deactivated: 0,
+        deleted: 0,
+        locked: lockout.locked_count().await?,
+        computed_at: DateTime::now().try_to_rfc3339_string().ok(),
+    };
+    for role in roles {
+        let total = count(&role, "total");
+        stats.total += total;
+        stats.deactivated += count(&role, "deactivated");
+        stats.deleted += count(&role, "deleted");
+        if total > 0 {
+            let name = role.get_str("_id").unwrap_or_default().to_string();
+            *stats.by_role.entry(name).or_default() += total;
+        }
+    }
+    Ok(stats)
+}
+
+/// Account counts for dashboards. Computed at most once a minute; polling
+/// more often gets the cached numbers, with `computed_at` telling how old
+/// they are.
+#[utoipa::path(
+    get,
+    path = "/stats",
+    tag = "users",
+    responses(
+        (status = 200, description = "Account counts", body = UserStats),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn stats_handler(
+    _claims: Claims,
+    users_collection: Collection<User>,
+    lockout: LoginLockout,
+    cache: StatsCache,
+) -> WebResult<impl Reply> {
+    {
+        let cached = cache.read().expect("stats cache lock poisoned");
+        if let Some((computed, stats)) = cached.as_ref() {
+            if computed.elapsed() < CACHE_TTL {
+                return Ok(reply::json(stats));
+            }
+        }
+    }
+
+    let stats = compute(&users_collection, &lockout)
+        .await
+        .map_err(reject::custom)?;
+    let response = reply::json(&stats);
+    *cache.write().expect("stats cache lock poisoned") = Some((Instant::now(), stats));
+    Ok(response)
+}
@@ -0,0 +1,39 @@
+use crate::metrics;
+use mongodb::{
+    bson::{doc, DateTime, Document},
+    Collection,
+};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Every `interval`, deletes the documents in `collections` whose
+/// `expires_at` has passed. Their TTL indexes do this too, but MongoDB only
+/// runs its TTL monitor once a minute and skips it on secondaries or under
+/// load, so tokens, sessions and lockout entries could otherwise outlive
+/// their expiry indefinitely. A failed sweep is logged and retried on the
+/// next tick.
+pub fn spawn(collections: Vec<Collection<Document>>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            for collection in &collections {
+                sweep(collection).await;
+            }
+        }
+    })
+}
+
+#[tracing::instrument(skip_all, fields(collection = collection.name()))]
+async fn sweep(collection: &Collection<Document>) {
+    let deleted = collection
+        .delete_many(doc! {"expires_at": {"$lte": DateTime::now()}}, None)
+        .await;
+    match deleted {
+        Ok(result) => {
+            tracing::info!(removed = result.deleted_count, "swept expired documents");
+            metrics::record_swept(collection.name(), result.deleted_count);
+        }
+        Err(e) => tracing::error!("sweeping expired documents failed: {}", e),
+    }
+}
@@ -0,0 +1,101 @@
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    trace::{TraceContextExt, TracerProvider as _},
+    Context,
+};
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    trace::{SdkTracerProvider, Tracer},
+    Resource,
+};
+use std::env;
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+use warp::http::HeaderMap;
+
+const ENDPOINT_VAR: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+/// Reported when `OTEL_SERVICE_NAME` is not set.
+const DEFAULT_SERVICE_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Sets up exporting spans over OTLP/HTTP to `OTEL_EXPORTER_OTLP_ENDPOINT`,
+/// with the other standard `OTEL_*` variables read by the exporter. Returns
+/// `None` without it, in which case nothing is exported, no connection is
+/// ever made and `traceparent` headers are ignored.
+pub fn init() -> Result<Option<SdkTracerProvider>, String> {
+    if env::var(ENDPOINT_VAR).map_or(true, |endpoint| endpoint.is_empty()) {
+        return Ok(None);
+    }
+    let exporter = SpanExporter::builder().with_http().build().map_err(|e| {
+        format!(
+            "{} is set, but creating the exporter failed: {}",
+            ENDPOINT_VAR, e
+        )
+    })?;
+    let mut resource = Resource::builder();
+    if env::var_os("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name(DEFAULT_SERVICE_NAME);
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(Some(provider))
+}
+
+/// The `tracing` layer that turns spans into OpenTelemetry spans for
+/// `provider` to export.
+pub fn layer<S>(provider: &SdkTracerProvider) -> OpenTelemetryLayer<S, Tracer>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(DEFAULT_SERVICE_NAME))
+}
+
+/// Sends the spans still buffered and stops the exporter, so that the last
+/// requests before a shutdown are not lost.
+pub fn shutdown(provider: SdkTracerProvider) {
+    if let Err(e) = provider.shutdown() {
+        eprintln!("flushing buffered spans failed: {}", e);
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// The trace a request's W3C `traceparent` header says it is part of,
+/// for [`set_parent`].
+pub fn extract(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+/// Makes `span` part of the caller's trace, if `parent` names one, instead
+/// of starting a trace of its own.
+pub fn set_parent(span: &Span, parent: Context) {
+    if parent.span().span_context().is_valid() {
+        span.set_parent(parent).ok();
+    }
+}
+
+/// The ID of the trace the current span is exported in, when spans are
+/// exported.
+pub fn trace_id() -> Option<String> {
+    let context = Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string())
+}
@@ -0,0 +1,197 @@
+//! For tests that need a MongoDB server, found at `TEST_MONGO_URI`. They
+//! are `#[ignore]`d so that `cargo test` runs without one; CI runs them
+//! with `--include-ignored`.
+
+use crate::{
+    auth::AuthContext,
+    avatars::AvatarStore,
+    config::{Config, ConfigError},
+    health::{self, PingLatencies, Readiness},
+    lockout::LoginLockout,
+    magic_link, mailer,
+    maintenance::Maintenance,
+    ratelimit::RateLimiter,
+    repository::MongoUserRepository,
+    roles::RoleRegistry,
+    routes::AppState,
+    throttle::LoginThrottle,
+    transaction::Transactions,
+    users::UserData,
+};
+use mongodb::{bson::uuid::Uuid, Client, Database};
+use std::{
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Held while a test changes the environment.
+static ENVIRONMENT: Mutex<()> = Mutex::new(());
+
+/// Environment variables left in place while a configuration is loaded.
+const KEPT_VARS: &[&str] = &["PATH", "HOME", "TEST_MONGO_URI"];
+
+/// A fresh database of its own, so tests don't see each other's data.
+pub async fn database() -> Database {
+    fresh_database(&client().await)
+}
+
+async fn client() -> Client {
+    let uri = env::var("TEST_MONGO_URI").expect("TEST_MONGO_URI must name a MongoDB server");
+    Client::with_uri_str(uri)
+        .await
+        .expect("TEST_MONGO_URI is not a valid connection string")
+}
+
+fn fresh_database(client: &Client) -> Database {
+    client.database(&format!("test_{}", Uuid::new()))
+}
+
+/// `Config::from_env` with nothing but `vars` set, whatever the
+/// environment the tests run in.
+pub fn config(vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+    let _environment = ENVIRONMENT.lock().unwrap_or_else(|e| e.into_inner());
+    let saved: Vec<(String, String)> = env::vars().collect();
+    for (name, _) in &saved {
+        if !KEPT_VARS.contains(&name.as_str()) {
+            env::remove_var(name);
+        }
+    }
+    for (name, value) in vars {
+        env::set_var(name, value);
+    }
+    let config = Config::from_env();
+    for (name, _) in vars {
+        env::remove_var(name);
+    }
+    for (name, value) in saved {
+        env::set_var(name, value);
+    }
+    config
+}
+
+/// The defaults, with `vars` and the settings that have none. The
+/// database the routes use is the one handed to [`app`], not `MONGO_URI`.
+pub fn test_config(vars: &[(&str, &str)]) -> Config {
+    let mut all = vec![
+        ("JWT_SECRET", TEST_JWT_SECRET),
+        ("MONGO_URI", UNREACHABLE_MONGO),
+    ];
+    all.extend_from_slice(vars);
+    config(&all).expect("the test configuration is valid")
+}
+
+pub const TEST_JWT_SECRET: &str = "a secret only the tests use, long enough for HS512";
+
+/// Nothing listens on port 1, so connections are refused at once.
+const UNREACHABLE_MONGO: &str = "mongodb://127.0.0.1:1/?directConnection=true";
+
+/// The server's state, like `main` builds it, on a fresh database at
+/// `TEST_MONGO_URI` with its indexes in place.
+pub async fn app() -> AppState {
+    app_with(&[]).await
+}
+
+/// Like [`app`], configured with `vars` too.
+pub async fn app_with(vars: &[(&str, &str)]) -> AppState {
+    let client = client().await;
+    let state = state(&client, fresh_database(&client), test_config(vars)).await;
+    crate::users::create_indexes(&state.users).await.unwrap();
+    crate::sessions::create_indexes(&state.sessions)
+        .await
+        .unwrap();
+    state
+}
+
+/// The server's state on a database that can't be reached, for requests
+/// answered before one is needed.
+pub async fn offline_app() -> AppState {
+    offline_app_with(&[]).await
+}
+
+/// Like [`offline_app`], configured with `vars` too.
+pub async fn offline_app_with(vars: &[(&str, &str)]) -> AppState {
+    let client = health::connect_to_mongo(UNREACHABLE_MONGO, Duration::from_millis(100))
+        .await
+        .unwrap();
+    state(&client, client.database("offline"), test_config(vars)).await
+}
+
+async fn state(client: &Client, db: Database, config: Config) -> AppState {
+    let users = db.collection(&config.users_collection);
+    let transactions = Transactions::detect(client.clone()).await;
+    let auth_context = AuthContext::new(
+        config.jwt.clone(),
+        db.collection("revoked_tokens"),
+        RoleRegistry::default(),
+        config.totp_cipher.clone(),
+        users.clone(),
+        config.trust_proxy,
+    );
+    let user_data = UserData {
+        sessions: db.collection("sessions"),
+        password_resets: db.collection("password_resets"),
+        pending_logins: db.collection("pending_logins"),
+        magic_links: db.collection("magic_links"),
+        federated_identities: db.collection("federated_identities"),
+        api_keys: db.collection("api_keys"),
+        memberships: db.collection("memberships"),
+        transactions: transactions.clone(),
+    };
+    AppState {
+        sockets: auth_context.sockets().clone(),
+        auth_context,
+        user_repo: Arc::new(MongoUserRepository::new(users.clone())),
+        users,
+        sessions: db.collection("sessions"),
+        pending_logins: db.collection("pending_logins"),
+        magic_links: db.collection("magic_links"),
+        password_resets: db.collection("password_resets"),
+        verification_resends: db.collection("verification_resends"),
+        federated_identities: db.collection("federated_identities"),
+        oauth_states: db.collection("oauth_states"),
+        api_keys: db.collection("api_keys"),
+        invites: db.collection("invites"),
+        idempotency_keys: db.collection("idempotency_keys"),
+        organizations: db.collection("organizations"),
+        memberships: db.collection("memberships"),
+        roles: db.collection("roles"),
+        audit_log: db.collection("audit_log"),
+        webhook_deliveries: db.collection("webhook_deliveries"),
+        stats_cache: Default::default(),
+        user_data,
+        transactions,
+        mailer: mailer::new(config.smtp.clone()),
+        captcha: config.captcha.clone(),
+        avatar_store: AvatarStore::from_env().await.unwrap(),
+        oauth_providers: config.oauth_providers.clone(),
+        login_throttle: LoginThrottle::new(config.trust_proxy),
+        login_lockout: LoginLockout::new(
+            db.collection("login_attempts"),
+            config.login_max_failures,
+            config.login_failure_policy,
+        ),
+        signup_limiter: RateLimiter::new(
+            config.rate_limit_requests,
+            config.rate_limit_window,
+            config.trust_proxy,
+        ),
+        magic_link_limiter: RateLimiter::new(
+            magic_link::MAGIC_LINKS_PER_EMAIL,
+            magic_link::MAGIC_LINK_WINDOW,
+            config.trust_proxy,
+        ),
+        export_limiter: RateLimiter::new(
+            1,
+            crate::export::PERSONAL_EXPORT_WINDOW,
+            config.trust_proxy,
+        ),
+        trust_proxy: config.trust_proxy,
+        db,
+        started: Instant::now(),
+        readiness: Readiness::default(),
+        ping_latencies: PingLatencies::default(),
+        maintenance: Maintenance::new(config.maintenance_mode),
+        config: Arc::new(config),
+    }
+}
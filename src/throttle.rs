@@ -0,0 +1,171 @@
+use crate::{error::Error, server::RemoteAddr};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use warp::{reject, Filter, Rejection};
+
+const LOGIN_ATTEMPT_LIMIT: usize = 10;
+const LOGIN_ATTEMPT_WINDOW: Duration = Duration::from_secs(60);
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// Extracts the client address, preferring `X-Forwarded-For` when
+/// `trust_proxy` is set. The rightmost entry is used because it is the one
+/// appended by our own proxy; entries to its left are client-controlled.
+pub fn client_ip(
+    trust_proxy: bool,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone {
+    warp::ext::optional::<RemoteAddr>()
+        .and(warp::header::optional::<String>(FORWARDED_FOR_HEADER))
+        .map(
+            move |remote: Option<RemoteAddr>, forwarded_for: Option<String>| {
+                let forwarded = forwarded_for
+                    .filter(|_| trust_proxy)
+                    .and_then(|value| value.rsplit(',').next()?.trim().parse().ok());
+                forwarded.or_else(|| remote.map(|RemoteAddr(addr)| addr.ip()))
+            },
+        )
+}
+
+/// Sliding-window limit on login attempts per source IP, kept in memory.
+#[derive(Clone)]
+pub struct LoginThrottle {
+    attempts: Arc<Mutex<HashMap<IpAddr, VecDeque<Instant>>>>,
+    trust_proxy: bool,
+}
+
+impl LoginThrottle {
+    pub fn new(trust_proxy: bool) -> Self {
+        LoginThrottle {
+            attempts: Arc::default(),
+            trust_proxy,
+        }
+    }
+
+    /// Records an attempt from `ip`, returning the number of seconds until
+    /// the next attempt is allowed if the limit has been reached.
+    fn hit(&self, ip: IpAddr) -> Option<u64> {
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().expect("login throttle lock poisoned");
+        let window = attempts.entry(ip).or_default();
+        while window
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= LOGIN_ATTEMPT_WINDOW)
+        {
+            window.pop_front();
+        }
+
+        if window.len() >= LOGIN_ATTEMPT_LIMIT {
+            let oldest = *window.front().expect("window is full");
+            let retry_after = LOGIN_ATTEMPT_WINDOW.saturating_sub(now.duration_since(oldest));
+            return Some(retry_after.as_secs().max(1));
+        }
+        window.push_back(now);
+        None
+    }
+
+    /// Drops addresses with no attempts left in the window so the map only
+    /// holds recently active clients.
+    fn prune(&self) {
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().expect("login throttle lock poisoned");
+        attempts.retain(|_, window| {
+            window
+                .back()
+                .is_some_and(|t| now.duration_since(*t) < LOGIN_ATTEMPT_WINDOW)
+        });
+    }
+
+    pub fn spawn_cleanup(&self) {
+        let throttle = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(LOGIN_ATTEMPT_WINDOW);
+            loop {
+                interval.tick().await;
+                throttle.prune();
+            }
+        });
+    }
+}
+
+/// Rejects with `TooManyRequestsError` once a client exceeds the login
+/// attempt limit. Requests without a known address are not limited.
+pub fn with_login_throttle(
+    throttle: LoginThrottle,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    client_ip(throttle.trust_proxy)
+        .and_then(move |ip: Option<IpAddr>| {
+            let throttle = throttle.clone();
+            async move {
+                match ip.and_then(|ip| throttle.hit(ip)) {
+                    Some(retry_after) => Err(reject::custom(Error::TooManyRequestsError {
+                        retry_after_secs: retry_after,
+                    })),
+                    None => Ok(()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    async fn attempt(throttle: &LoginThrottle, from: &str) -> Result<(), Rejection> {
+        let addr: SocketAddr = from.parse().unwrap();
+        warp::test::request()
+            .extension(RemoteAddr(addr))
+            .filter(&with_login_throttle(throttle.clone()))
+            .await
+    }
+
+    #[tokio::test]
+    async fn a_burst_is_cut_off_at_the_limit() {
+        let throttle = LoginThrottle::new(false);
+        for _ in 0..LOGIN_ATTEMPT_LIMIT {
+            assert!(attempt(&throttle, "192.0.2.1:1000").await.is_ok());
+        }
+        let rejection = attempt(&throttle, "192.0.2.1:1000").await.unwrap_err();
+        match rejection.find::<Error>() {
+            Some(Error::TooManyRequestsError { retry_after_secs }) => {
+                assert!((1..=60).contains(retry_after_secs))
+            }
+            _ => panic!("expected TooManyRequestsError"),
+        }
+    }
+
+    #[tokio::test]
+    async fn addresses_are_limited_apart() {
+        let throttle = LoginThrottle::new(false);
+        for _ in 0..LOGIN_ATTEMPT_LIMIT + 1 {
+            attempt(&throttle, "192.0.2.1:1000").await.ok();
+        }
+        assert!(attempt(&throttle, "192.0.2.1:2000").await.is_err());
+        for _ in 0..LOGIN_ATTEMPT_LIMIT {
+            assert!(attempt(&throttle, "192.0.2.2:1000").await.is_ok());
+            assert!(attempt(&throttle, "[2001:db8::1]:1000").await.is_ok());
+        }
+    }
+
+    #[test]
+    fn pruning_forgets_addresses_whose_window_has_passed() {
+        let throttle = LoginThrottle::new(false);
+        let idle: IpAddr = "192.0.2.1".parse().unwrap();
+        let active: IpAddr = "192.0.2.2".parse().unwrap();
+        throttle.attempts.lock().unwrap().insert(
+            idle,
+            VecDeque::from([Instant::now() - LOGIN_ATTEMPT_WINDOW]),
+        );
+        assert_eq!(throttle.hit(active), None);
+
+        throttle.prune();
+
+        let attempts = throttle.attempts.lock().unwrap();
+        assert!(!attempts.contains_key(&idle));
+        assert!(attempts.contains_key(&active));
+    }
+}
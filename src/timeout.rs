@@ -0,0 +1,77 @@
+use crate::{config, error::Error};
+use std::{
+    cell::Cell,
+    convert::Infallible,
+    future::{poll_fn, Future},
+    pin::pin,
+    task::Poll,
+    time::Duration,
+};
+use tokio::time::{sleep_until, Instant};
+use warp::{reject, Filter, Rejection};
+
+tokio::task_local! {
+    static BUDGET: Cell<Duration>;
+}
+
+/// Marks the copy of a request that ran out of time, which the server
+/// sends through the routes again so [`check`] can answer it.
+#[derive(Clone, Copy)]
+pub struct TimedOut;
+
+/// Gives the current request `budget` instead of `REQUEST_TIMEOUT_MS`,
+/// still counted from its arrival. Goes right after the route's
+/// `warp::path!`, like `metrics::route`.
+pub fn with_timeout(budget: Duration) -> impl Filter<Extract = (), Error = Infallible> + Clone {
+    warp::any()
+        .map(move || {
+            BUDGET.try_with(|current| current.set(budget)).ok();
+        })
+        .untuple_one()
+}
+
+/// Runs `future`, the handling of one request, until it finishes or the
+/// request's budget runs out, which drops it and gives `None`. The budget is
+/// `REQUEST_TIMEOUT_MS` unless the route sets its own with
+/// [`with_timeout`].
+///
+/// Only the wait for the response is bounded, reading the request body
+/// included: a streamed response body, such as the CSV export's, is sent
+/// after `future` has finished and can take as long as it takes.
+pub async fn scope<F: Future>(future: F) -> Option<F::Output> {
+    let started = Instant::now();
+    BUDGET
+        .scope(Cell::new(config::request_timeout()), async move {
+            let mut future = pin!(future);
+            let mut budget = BUDGET.with(Cell::get);
+            let mut deadline = pin!(sleep_until(started + budget));
+            poll_fn(|cx| {
+                if let Poll::Ready(output) = future.as_mut().poll(cx) {
+                    return Poll::Ready(Some(output));
+                }
+                // The route's `with_timeout` runs in the poll above.
+                let current = BUDGET.with(Cell::get);
+                if current != budget {
+                    budget = current;
+                    deadline.as_mut().reset(started + budget);
+                }
+                deadline.as_mut().poll(cx).map(|()| None)
+            })
+            .await
+        })
+        .await
+}
+
+/// Rejects a request marked [`TimedOut`] with `RequestTimeoutError` and
+/// lets every other through. Goes before all routes and inside `recover`,
+/// so a timeout is answered like any other error.
+pub fn check() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::ext::optional::<TimedOut>()
+        .and_then(|timed_out: Option<TimedOut>| async move {
+            match timed_out {
+                Some(TimedOut) => Err(reject::custom(Error::RequestTimeoutError)),
+                None => Ok(()),
+            }
+        })
+        .untuple_one()
+}
@@ -0,0 +1,269 @@
+use crate::{
+    audit::{self, AuditAction, AuditEvent},
+    auth::{create_exchanged_jwt, AuthContext, Role},
+    error::Error,
+    repository::UserRepo,
+    sessions::ClientInfo,
+    users, WebResult, TOKEN_TYPE,
+};
+use jsonwebtoken::{
+    dangerous_insecure_decode, decode, errors::ErrorKind, Algorithm, DecodingKey, Validation,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    convert::Infallible,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use utoipa::ToSchema;
+use warp::{reject, reply, Filter, Reply};
+
+/// One entry of the `TOKEN_EXCHANGE_PEERS` file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PeerConfig {
+    /// The `iss` its tokens carry.
+    issuer: String,
+    algorithm: Algorithm,
+    /// For the HMAC algorithms.
+    secret: Option<String>,
+    /// PEM file, for the RSA and ECDSA algorithms.
+    public_key_path: Option<PathBuf>,
+    /// Required as the `aud` of its tokens when set.
+    audience: Option<String>,
+    /// The claim holding our uid of the user the token is for.
+    #[serde(default = "default_uid_claim")]
+    uid_claim: String,
+    /// The roles it may ask for; `Admin` only when listed.
+    roles: Vec<String>,
+}
+
+fn default_uid_claim() -> String {
+    "sub".to_string()
+}
+
+/// A service whose tokens may be exchanged for ours.
+struct TrustedPeer {
+    issuer: String,
+    algorithm: Algorithm,
+    key: DecodingKey<'static>,
+    audience: Option<String>,
+    uid_claim: String,
+    roles: Vec<Role>,
+}
+
+/// The peers from `TOKEN_EXCHANGE_PEERS`; none when it isn't set, so
+/// every exchange is refused.
+#[derive(Clone, Default)]
+pub struct TrustedPeers(Arc<Vec<TrustedPeer>>);
+
+impl TrustedPeers {
+    /// Reads the JSON list of peers at `path`, with their keys.
+    pub fn load(path: &Path) -> std::result::Result<TrustedPeers, String> {
+        let json = fs::read_to_string(path)
+            .map_err(|e| format!("reading {} failed: {}", path.display(), e))?;
+        let configs: Vec<PeerConfig> = serde_json::from_str(&json)
+            .map_err(|e| format!("invalid TOKEN_EXCHANGE_PEERS file: {}", e))?;
+        let mut peers: Vec<TrustedPeer> = Vec::new();
+        for config in configs {
+            if peers.iter().any(|peer| peer.issuer == config.issuer) {
+                return Err(format!(
+                    "TOKEN_EXCHANGE_PEERS lists issuer {} twice",
+                    config.issuer
+                ));
+            }
+            let roles = config
+                .roles
+                .iter()
+                .map(|role| {
+                    Role::from_str(role).map_err(|_| {
+                        format!(
+                            "TOKEN_EXCHANGE_PEERS: {} lists invalid role {:?}",
+                            config.issuer, role
+                        )
+                    })
+                })
+                .collect::<std::result::Result<_, _>>()?;
+            peers.push(TrustedPeer {
+                key: decoding_key(&config)?,
+                roles,
+                issuer: config.issuer,
+                algorithm: config.algorithm,
+                audience: config.audience,
+                uid_claim: config.uid_claim,
+            });
+        }
+        Ok(TrustedPeers(Arc::new(peers)))
+    }
+
+    fn find(&self, issuer: &str) -> Option<&TrustedPeer> {
+        self.0.iter().find(|peer| peer.issuer == issuer)
+    }
+}
+
+fn decoding_key(config: &PeerConfig) -> std::result::Result<DecodingKey<'static>, String> {
+    let problem = |what: &str| format!("TOKEN_EXCHANGE_PEERS: {} {}", config.issuer, what);
+    match config.algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => match &config.secret {
+            Some(secret) if !secret.is_empty() => {
+                Ok(DecodingKey::from_secret(secret.as_bytes()).into_static())
+            }
+            _ => Err(problem("needs a secret")),
+        },
+        algorithm => {
+            let path = config
+                .public_key_path
+                .as_ref()
+                .ok_or_else(|| problem("needs a public_key_path"))?;
+            let pem = fs::read(path)
+                .map_err(|e| problem(&format!("key {} is unreadable: {}", path.display(), e)))?;
+            let key = match algorithm {
+                Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&pem),
+                _ => DecodingKey::from_rsa_pem(&pem),
+            };
+            key.map(DecodingKey::into_static)
+                .map_err(|e| problem(&format!("key {} is invalid: {}", path.display(), e)))
+        }
+    }
+}
+
+pub fn with_trusted_peers(
+    peers: TrustedPeers,
+) -> impl Filter<Extract = (TrustedPeers,), Error = Infallible> + Clone {
+    warp::any().map(move || peers.clone())
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TokenExchangeRequest {
+    /// A token from a trusted peer for the user to act as.
+    pub subject_token: String,
+    /// The role the new token should have, which the peer must be allowed
+    /// to ask for.
+    pub role: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TokenExchangeResponse {
+    pub token: String,
+    /// Always `Bearer`.
+    #[schema(value_type = String, example = "Bearer")]
+    pub token_type: &'static str,
+    /// Seconds until `token` expires, always fewer than the subject token
+    /// had left.
+    pub expires_in: u64,
+}
+
+#[derive(Deserialize)]
+struct Issuer {
+    iss: Option<String>,
+}
+
+/// Verifies `token` with its peer's key. Peer tokens get no leeway: past
+/// their `exp` nothing shorter-lived could be issued for them anyway.
+fn verify(peer: &TrustedPeer, token: &str) -> std::result::Result<Map<String, Value>, Error> {
+    let mut validation = Validation::new(peer.algorithm);
+    validation.leeway = 0;
+    validation.iss = Some(peer.issuer.clone());
+    if let Some(audience) = &peer.audience {
+        validation.set_audience(&[audience]);
+    }
+    decode::<Map<String, Value>>(token, &peer.key, &validation)
+        .map(|decoded| decoded.claims)
+        .map_err(|e| match e.kind() {
+            ErrorKind::ExpiredSignature => Error::JWTTokenExpiredError,
+            _ => Error::JWTTokenError,
+        })
+}
+
+/// Lets an internal service that has already authenticated a user call
+/// the API as them. The subject token must be signed by a peer listed in
+/// `TOKEN_EXCHANGE_PEERS`, carry an `exp`, and name one of our users; the
+/// token issued for it has the requested role, which the peer must be
+/// allowed and the user able to act as, records the peer as `source`, and
+/// expires before the subject token does.
+#[utoipa::path(
+    post,
+    path = "/token/exchange",
+    tag = "account",
+    request_body = TokenExchangeRequest,
+    responses(
+        (status = 200, description = "A token for the user", body = TokenExchangeResponse),
+        (status = 401, description = "Subject token invalid or expired", body = ErrorResponse),
+        (status = 403, description = "Issuer or role not allowed, or the account is disabled or banned",
+            body = ErrorResponse),
+        (status = 404, description = "No such user", body = ErrorResponse),
+    )
+)]
+pub async fn exchange_token_handler(
+    peers: TrustedPeers,
+    context: AuthContext,
+    users: UserRepo,
+    client: ClientInfo,
+    body: TokenExchangeRequest,
+) -> WebResult<impl Reply> {
+    // The issuer is only read unverified to pick the key; `verify` then
+    // checks it along with the signature.
+    let issuer = dangerous_insecure_decode::<Issuer>(&body.subject_token)
+        .map_err(|_| reject::custom(Error::JWTTokenError))?
+        .claims
+        .iss
+        .ok_or_else(|| reject::custom(Error::TokenExchangeRefusedError))?;
+    let peer = peers
+        .find(&issuer)
+        .ok_or_else(|| reject::custom(Error::TokenExchangeRefusedError))?;
+    let claims = verify(peer, &body.subject_token).map_err(reject::custom)?;
+    let (Some(exp), Some(uid)) = (
+        claims.get("exp").and_then(Value::as_i64),
+        claims.get(&peer.uid_claim).and_then(Value::as_str),
+    ) else {
+        return Err(reject::custom(Error::JWTTokenError));
+    };
+
+    let role =
+        Role::from_str(&body.role).map_err(|_| reject::custom(Error::TokenExchangeRefusedError))?;
+    if !peer.roles.contains(&role) || !context.roles().is_assignable(&role) {
+        return Err(reject::custom(Error::TokenExchangeRefusedError));
+    }
+    let user = users
+        .find_by_uid(uid)
+        .await?
+        .filter(|user| user.deleted_at.is_none())
+        .ok_or_else(|| reject::custom(Error::UserNotFoundError))?;
+    if !user.active {
+        return Err(reject::custom(Error::AccountDisabledError));
+    }
+    if user.is_banned() {
+        return Err(reject::custom(users::ban_error(&user)));
+    }
+    // A peer acts for the user, so it gets no more than the user has.
+    let user_role = context
+        .roles()
+        .resolve(&user.uid, &user.role)
+        .map_err(reject::custom)?;
+    if !user_role.has_permission(&role) {
+        return Err(reject::custom(Error::TokenExchangeRefusedError));
+    }
+
+    let access = create_exchanged_jwt(
+        &context,
+        &user.uid,
+        &role,
+        user.token_version,
+        exp,
+        &peer.issuer,
+    )
+    .map_err(reject::custom)?;
+    audit::record(
+        AuditEvent::new(AuditAction::TokenExchanged, &client)
+            .actor(&user.uid)
+            .target(&user.uid)
+            .detail(&format!("{} as {}", peer.issuer, role)),
+    );
+    Ok(reply::json(&TokenExchangeResponse {
+        token: access.token,
+        token_type: TOKEN_TYPE,
+        expires_in: access.expires_in,
+    }))
+}
@@ -0,0 +1,184 @@
+use crate::{error::Error, Result};
+use futures_util::{future::BoxFuture, FutureExt};
+use mongodb::{bson::doc, Client, ClientSession};
+use std::convert::Infallible;
+use warp::Filter;
+
+/// Why a [`Transactions::run`] callback stopped: a database error, which
+/// the transaction is retried for when MongoDB labels it transient, or a
+/// refusal such as a used-up invite, which aborts it for good.
+pub enum Abort {
+    Database(mongodb::error::Error),
+    Refused(Error),
+}
+
+impl From<mongodb::error::Error> for Abort {
+    fn from(error: mongodb::error::Error) -> Self {
+        Abort::Database(error)
+    }
+}
+
+impl From<Error> for Abort {
+    fn from(error: Error) -> Self {
+        Abort::Refused(error)
+    }
+}
+
+/// Runs groups of writes that belong together, such as deleting a user
+/// along with their sessions, in a MongoDB transaction. Only replica sets
+/// and sharded clusters have transactions; against a standalone server the
+/// writes run one after another in a plain session, so a crash or error
+/// part way leaves the earlier ones in place.
+#[derive(Clone)]
+pub struct Transactions {
+    client: Client,
+    supported: bool,
+}
+
+impl Transactions {
+    /// Asks the server whether it is part of a replica set or sharded
+    /// cluster. A failed check counts as no, which is always safe.
+    pub async fn detect(client: Client) -> Self {
+        let hello = client
+            .database("admin")
+            .run_command(doc! {"hello": 1}, None)
+            .await;
+        let supported = match hello {
+            Ok(hello) => hello.contains_key("setName") || hello.get_str("msg") == Ok("isdbgrid"),
+            Err(e) => {
+                tracing::warn!("checking for transaction support failed: {}", e);
+                false
+            }
+        };
+        if !supported {
+            tracing::info!(
+                "MongoDB is a standalone server; multi-document writes run without transactions"
+            );
+        }
+        Transactions { client, supported }
+    }
+
+    /// Whether [`run`](Self::run) makes its writes all-or-nothing.
+    pub fn atomic(&self) -> bool {
+        self.supported
+    }
+
+    /// Calls `callback` with a session that every write in it must use,
+    /// inside a transaction where there are transactions. The transaction
+    /// commits when `callback` succeeds and is aborted when it fails; on a
+    /// transient error, like a write conflict or a replica set election, it
+    /// is started over, so `callback` may run more than once and must not
+    /// have effects outside the database.
+    pub async fn run<T, C, F>(&self, mut context: C, mut callback: F) -> Result<T>
+    where
+        C: Send,
+        F: for<'a> FnMut(
+                &'a mut ClientSession,
+                &'a mut C,
+            ) -> BoxFuture<'a, std::result::Result<T, Abort>>
+            + Send,
+        T: Send,
+    {
+        let mut session = self.client.start_session(None).await?;
+        if !self.supported {
+            return match callback(&mut session, &mut context).await {
+                Ok(value) => Ok(value),
+                Err(Abort::Database(e)) => Err(e.into()),
+                Err(Abort::Refused(e)) => Err(e),
+            };
+        }
+        session
+            .with_transaction(
+                (context, callback),
+                |session, (context, callback)| {
+                    async move {
+                        match callback(session, context).await {
+                            Ok(value) => Ok(Ok(value)),
+                            Err(Abort::Database(e)) => Err(e),
+                            Err(Abort::Refused(e)) => {
+                                session.abort_transaction().await?;
+                                Ok(Err(e))
+                            }
+                        }
+                    }
+                    .boxed()
+                },
+                None,
+            )
+            .await?
+    }
+}
+
+pub fn with_transactions(
+    transactions: Transactions,
+) -> impl Filter<Extract = (Transactions,), Error = Infallible> + Clone {
+    warp::any().map(move || transactions.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use mongodb::bson::Document;
+
+    /// Inserts `{_id: 1}` into `first`, then `{_id: 2}` into `second`,
+    /// which already has one, so the second write fails.
+    #[tokio::test]
+    #[ignore = "needs a MongoDB replica set at TEST_MONGO_URI"]
+    async fn a_failed_write_rolls_back_the_ones_before_it() {
+        let app = test_support::app().await;
+        let (db, transactions) = (app.db, app.transactions);
+        assert!(
+            transactions.atomic(),
+            "TEST_MONGO_URI is a standalone server"
+        );
+        db.create_collection("first", None).await.unwrap();
+        let first = db.collection::<Document>("first");
+        let second = db.collection::<Document>("second");
+        second.insert_one(doc! {"_id": 2}, None).await.unwrap();
+
+        let result = transactions
+            .run((first.clone(), second), |session, (first, second)| {
+                async move {
+                    first
+                        .insert_one_with_session(doc! {"_id": 1}, None, session)
+                        .await?;
+                    second
+                        .insert_one_with_session(doc! {"_id": 2}, None, session)
+                        .await?;
+                    Ok(())
+                }
+                .boxed()
+            })
+            .await;
+        assert!(matches!(result, Err(Error::DuplicateKeyError)));
+        assert_eq!(first.count_documents(doc! {}, None).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a MongoDB replica set at TEST_MONGO_URI"]
+    async fn a_refusal_rolls_back_the_writes_before_it() {
+        let app = test_support::app().await;
+        let (db, transactions) = (app.db, app.transactions);
+        assert!(
+            transactions.atomic(),
+            "TEST_MONGO_URI is a standalone server"
+        );
+        db.create_collection("first", None).await.unwrap();
+        let first = db.collection::<Document>("first");
+
+        let result: Result<()> = transactions
+            .run(first.clone(), |session, first| {
+                async move {
+                    first
+                        .insert_one_with_session(doc! {"_id": 1}, None, session)
+                        .await?;
+                    Err(Abort::Refused(Error::UserNotFoundError))
+                }
+                .boxed()
+            })
+            .await;
+        assert!(matches!(result, Err(Error::UserNotFoundError)));
+        assert_eq!(first.count_documents(doc! {}, None).await.unwrap(), 0);
+    }
+}
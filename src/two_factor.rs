@@ -0,0 +1,548 @@
+use crate::{
+    audit_login_failure,
+    auth::{constant_time_eq, hash_token, random_token, AuthContext, Claims},
+    error::Error,
+    events::{self, AdminEventKind},
+    issue_session,
+    lockout::LoginLockout,
+    repository::timed,
+    sessions::{ClientInfo, Session},
+    users, Result, User, WebResult,
+};
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use mongodb::{
+    bson::{doc, DateTime},
+    options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument},
+    Collection, IndexModel,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::{env, time::Duration};
+use url::Url;
+use utoipa::ToSchema;
+use warp::{reject, reply, Reply};
+
+const TOTP_ISSUER: &str = "MyApp";
+const TOTP_SECRET_LENGTH: usize = 20;
+const TOTP_STEP_SECONDS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// Number of steps either side of the current one that are still accepted,
+/// to tolerate clock drift between server and authenticator.
+const TOTP_WINDOW: i64 = 1;
+const NONCE_LENGTH: usize = 12;
+const PENDING_TOKEN_LENGTH: usize = 48;
+const PENDING_LOGIN_EXPIRY: Duration = Duration::from_secs(5 * 60);
+/// Codes one pending login may be tried with before it is used up, so its
+/// six digits can't be guessed within `PENDING_LOGIN_EXPIRY`.
+const MAX_CODE_ATTEMPTS: u32 = 5;
+
+/// Encrypts TOTP secrets at rest with AES-256-GCM using the key from
+/// `TOTP_ENCRYPTION_KEY` (32 bytes, base64 encoded).
+#[derive(Clone)]
+pub struct TotpCipher {
+    cipher: Aes256Gcm,
+}
+
+impl TotpCipher {
+    /// Returns `None` when no key is configured, which leaves 2FA disabled,
+    /// or when the key is not usable, which is recorded in `problems`.
+    pub(crate) fn from_env(problems: &mut Vec<String>) -> Option<Self> {
+        let encoded = env::var("TOTP_ENCRYPTION_KEY")
+            .ok()
+            .filter(|v| !v.is_empty())?;
+        match STANDARD.decode(encoded) {
+            Ok(key) if key.len() == 32 => Some(TotpCipher {
+                cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+            }),
+            Ok(_) => {
+                problems.push("TOTP_ENCRYPTION_KEY must decode to 32 bytes".to_string());
+                None
+            }
+            Err(_) => {
+                problems.push("TOTP_ENCRYPTION_KEY must be valid base64".to_string());
+                None
+            }
+        }
+    }
+
+    fn encrypt(&self, secret: &[u8]) -> Result<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.cipher
+                .encrypt(&nonce, secret)
+                .map_err(|_| Error::TwoFactorUnavailableError)?,
+        );
+        Ok(STANDARD.encode(sealed))
+    }
+
+    fn decrypt(&self, sealed: &str) -> Result<Vec<u8>> {
+        let sealed = STANDARD
+            .decode(sealed)
+            .map_err(|_| Error::TwoFactorUnavailableError)?;
+        if sealed.len() < NONCE_LENGTH {
+            return Err(Error::TwoFactorUnavailableError);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| Error::TwoFactorUnavailableError)
+    }
+}
+
+/// A login that passed the password check and is waiting for a TOTP code.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub token_hash: String,
+    pub uid: String,
+    pub expires_at: DateTime,
+    /// The login asked for `remember_me`, which the session gets once the
+    /// code is confirmed.
+    #[serde(default)]
+    pub remember_me: bool,
+    /// Codes tried so far, counted before each is checked.
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TwoFactorRequiredResponse {
+    pub two_factor_required: bool,
+    pub pending_token: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct EnrollResponse {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CodeRequest {
+    /// The current six digit code.
+    pub code: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TwoFactorLoginRequest {
+    pub pending_token: String,
+    pub code: String,
+}
+
+pub async fn create_indexes(collection: &Collection<PendingLogin>) -> mongodb::error::Result<()> {
+    let index = IndexModel::builder()
+        .keys(doc! {"expires_at": 1})
+        .options(
+            IndexOptions::builder()
+                .expire_after(Duration::from_secs(0))
+                .build(),
+        )
+        .build();
+    collection.create_index(index, None).await?;
+    Ok(())
+}
+
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    binary % 10u32.pow(TOTP_DIGITS)
+}
+
+/// The time step `code` belongs to, if it is one of those in the window
+/// around `now_step`.
+fn verify_code(secret: &[u8], code: &str, now_step: i64) -> Option<i64> {
+    (now_step - TOTP_WINDOW..=now_step + TOTP_WINDOW).find(|&step| {
+        let expected = format!(
+            "{:0width$}",
+            hotp(secret, step as u64),
+            width = TOTP_DIGITS as usize
+        );
+        constant_time_eq(expected.as_bytes(), code.trim().as_bytes())
+    })
+}
+
+/// Accepts `code` for `user` and records its time step, refusing it when
+/// that step is at or below the last one accepted, so an intercepted code
+/// can't be replayed while it is still in the window. The step is claimed
+/// with a conditional update, so two requests with one code can't both win.
+async fn check_code(
+    context: &AuthContext,
+    users_collection: &Collection<User>,
+    user: &User,
+    code: &str,
+) -> Result<()> {
+    let sealed = user
+        .totp_secret
+        .as_deref()
+        .ok_or(Error::TwoFactorNotEnrolledError)?;
+    let secret = context.totp_cipher()?.decrypt(sealed)?;
+    let now_step = DateTime::now().timestamp_millis() / 1000 / TOTP_STEP_SECONDS;
+    let step = verify_code(&secret, code, now_step).ok_or(Error::InvalidTotpCodeError)?;
+    claim_step(users_collection, &user.uid, step).await
+}
+
+/// Records `step` as the last one accepted for `uid`, unless it is at or
+/// below the last one already.
+async fn claim_step(users_collection: &Collection<User>, uid: &str, step: i64) -> Result<()> {
+    let claimed = timed(users_collection.update_one(
+        doc! {"uid": uid, "totp_last_step": {"$not": {"$gte": step}}},
+        doc! {"$set": {"totp_last_step": step}},
+        None,
+    ))
+    .await?;
+    if claimed.matched_count == 0 {
+        return Err(Error::InvalidTotpCodeError);
+    }
+    Ok(())
+}
+
+async fn find_user(users_collection: &Collection<User>, uid: &str) -> Result<User> {
+    timed(users_collection.find_one(users::active(doc! {"uid": uid}), None))
+        .await?
+        .ok_or(Error::UserNotFoundError)
+}
+
+/// Starts a pending login for a user with 2FA enabled and returns the reply
+/// `login_handler` sends in place of the access token.
+pub async fn start_pending_login(
+    pending_logins: &Collection<PendingLogin>,
+    user: &User,
+    remember_me: bool,
+) -> WebResult<reply::Response> {
+    if !user.active {
+        return Err(reject::custom(Error::AccountDisabledError));
+    }
+    let pending_token = random_token(PENDING_TOKEN_LENGTH);
+    timed(pending_logins.insert_one(
+        PendingLogin {
+            token_hash: hash_token(&pending_token),
+            uid: user.uid.clone(),
+            expires_at: DateTime::now().saturating_add_duration(PENDING_LOGIN_EXPIRY),
+            remember_me,
+            attempts: 0,
+        },
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?;
+
+    Ok(reply::json(&TwoFactorRequiredResponse {
+        two_factor_required: true,
+        pending_token,
+    })
+    .into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/2fa/enroll",
+    tag = "two-factor",
+    responses(
+        (status = 200, description = "Secret to add to an authenticator app", body = EnrollResponse),
+        (status = 409, description = "2FA already enabled", body = ErrorResponse),
+        (status = 503, description = "2FA is not configured on this server", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn enroll_handler(
+    claims: Claims,
+    context: AuthContext,
+    users_collection: Collection<User>,
+) -> WebResult<impl Reply> {
+    let cipher = context.totp_cipher().map_err(reject::custom)?;
+    let user = find_user(&users_collection, &claims.sub)
+        .await
+        .map_err(reject::custom)?;
+    if user.totp_enabled {
+        return Err(reject::custom(Error::TwoFactorAlreadyEnabledError));
+    }
+
+    let mut secret = [0u8; TOTP_SECRET_LENGTH];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let sealed = cipher.encrypt(&secret).map_err(reject::custom)?;
+    timed(users_collection.update_one(
+        doc! {"uid": &user.uid},
+        doc! {
+            "$set": {
+                "totp_secret": sealed,
+                "totp_enabled": false,
+                "updated_at": DateTime::now(),
+            },
+            "$inc": {"version": 1},
+        },
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?;
+
+    let encoded = BASE32_NOPAD.encode(&secret);
+    Ok(reply::json(&EnrollResponse {
+        otpauth_uri: otpauth_uri(&user.email, &encoded),
+        secret: encoded,
+    }))
+}
+
+/// The `otpauth://` URI authenticator apps scan, labelled with `email`.
+/// The label is percent-encoded, so an address with `?`, `#` or `/` in it
+/// can't spill into the parameters.
+fn otpauth_uri(email: &str, secret: &str) -> String {
+    let mut uri = Url::parse("otpauth://totp/").expect("otpauth url is valid");
+    uri.path_segments_mut()
+        .expect("otpauth url has a path")
+        .pop_if_empty()
+        .push(&format!("{}:{}", TOTP_ISSUER, email));
+    uri.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", TOTP_ISSUER)
+        .append_pair("digits", &TOTP_DIGITS.to_string())
+        .append_pair("period", &TOTP_STEP_SECONDS.to_string());
+    uri.to_string()
+}
+
+#[utoipa::path(
+    post,
+    path = "/2fa/verify",
+    tag = "two-factor",
+    request_body = CodeRequest,
+    responses(
+        (status = 200, description = "2FA enabled", body = String),
+        (status = 400, description = "Not enrolled", body = ErrorResponse),
+        (status = 401, description = "Wrong code", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn verify_handler(
+    claims: Claims,
+    context: AuthContext,
+    users_collection: Collection<User>,
+    body: CodeRequest,
+) -> WebResult<impl Reply> {
+    let user = find_user(&users_collection, &claims.sub)
+        .await
+        .map_err(reject::custom)?;
+    check_code(&context, &users_collection, &user, &body.code)
+        .await
+        .map_err(reject::custom)?;
+
+    timed(users_collection.update_one(
+        doc! {"uid": &user.uid},
+        doc! {
+            "$set": {"totp_enabled": true, "updated_at": DateTime::now()},
+            "$inc": {"version": 1},
+        },
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?;
+
+    Ok(reply::with_status(
+        "Two-factor authentication enabled",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/2fa/disable",
+    tag = "two-factor",
+    request_body = CodeRequest,
+    responses(
+        (status = 200, description = "2FA disabled", body = String),
+        (status = 400, description = "Not enrolled", body = ErrorResponse),
+        (status = 401, description = "Wrong code", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn disable_handler(
+    claims: Claims,
+    context: AuthContext,
+    users_collection: Collection<User>,
+    body: CodeRequest,
+) -> WebResult<impl Reply> {
+    let user = find_user(&users_collection, &claims.sub)
+        .await
+        .map_err(reject::custom)?;
+    if !user.totp_enabled {
+        return Err(reject::custom(Error::TwoFactorNotEnrolledError));
+    }
+    check_code(&context, &users_collection, &user, &body.code)
+        .await
+        .map_err(reject::custom)?;
+
+    timed(users_collection.update_one(
+        doc! {"uid": &user.uid},
+        doc! {
+            "$set": {"totp_enabled": false, "updated_at": DateTime::now()},
+            "$unset": {"totp_secret": ""},
+            "$inc": {"version": 1},
+        },
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?;
+
+    Ok(reply::with_status(
+        "Two-factor authentication disabled",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Completes a login with the code from the user's authenticator. Each
+/// pending token takes at most five codes and is used up by the fifth
+/// wrong one, and wrong codes count towards the account's login lockout
+/// like wrong passwords do.
+#[utoipa::path(
+    post,
+    path = "/login/2fa",
+    tag = "login",
+    request_body = TwoFactorLoginRequest,
+    responses(
+        (status = 200, description = "Signed in, or the password must be changed first", body = LoginResult),
+        (status = 401, description = "Wrong code, or unknown, expired or used up pending token",
+            body = ErrorResponse),
+        (status = 429, description = "Too many attempts, or the account is locked", body = ErrorResponse),
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn login_2fa_handler(
+    context: AuthContext,
+    lockout: LoginLockout,
+    users_collection: Collection<User>,
+    sessions_collection: Collection<Session>,
+    pending_logins: Collection<PendingLogin>,
+    client: ClientInfo,
+    body: TwoFactorLoginRequest,
+) -> WebResult<impl Reply> {
+    let token_hash = hash_token(&body.pending_token);
+    // The attempt is counted before the code is checked, so concurrent
+    // guesses can't get past the limit either.
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let pending = timed(pending_logins.find_one_and_update(
+        doc! {
+            "token_hash": &token_hash,
+            "expires_at": {"$gt": DateTime::now()},
+            "attempts": {"$not": {"$gte": MAX_CODE_ATTEMPTS}},
+        },
+        doc! {"$inc": {"attempts": 1}},
+        options,
+    ))
+    .await
+    .map_err(reject::custom)?
+    .ok_or_else(|| reject::custom(Error::InvalidPendingTokenError))?;
+
+    let user = find_user(&users_collection, &pending.uid)
+        .await
+        .map_err(|_| reject::custom(Error::InvalidPendingTokenError))?;
+    let lockout_key = users::normalize_email(&user.email);
+    if let Err(e) = lockout.check(&lockout_key).await {
+        if matches!(e, Error::AccountLockedError) {
+            audit_login_failure(&client, Some(&user), "account locked");
+        }
+        return Err(reject::custom(e));
+    }
+    if let Err(e) = check_code(&context, &users_collection, &user, &body.code).await {
+        audit_login_failure(&client, Some(&user), "wrong two-factor code");
+        if pending.attempts >= MAX_CODE_ATTEMPTS {
+            timed(pending_logins.delete_one(doc! {"token_hash": &token_hash}, None))
+                .await
+                .map_err(reject::custom)?;
+        }
+        if lockout
+            .record_failure(&lockout_key)
+            .await
+            .map_err(reject::custom)?
+        {
+            events::publish(AdminEventKind::LockedOut, Some(&user.uid));
+        }
+        return Err(reject::custom(e));
+    }
+
+    // Deleting by hash makes the pending token single-use: a concurrent
+    // request that already consumed it sees nothing to delete.
+    let result = timed(pending_logins.delete_one(doc! {"token_hash": &token_hash}, None))
+        .await
+        .map_err(reject::custom)?;
+    if result.deleted_count == 0 {
+        return Err(reject::custom(Error::InvalidPendingTokenError));
+    }
+    lockout.reset(&lockout_key).await.map_err(reject::custom)?;
+
+    issue_session(
+        &context,
+        &sessions_collection,
+        &user,
+        &client,
+        pending.remember_me,
+        "two-factor",
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::Role, test_support};
+
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    fn code(step: i64) -> String {
+        format!("{:06}", hotp(SECRET, step as u64))
+    }
+
+    #[test]
+    fn codes_are_accepted_within_the_window_and_tell_their_step() {
+        let now = 1_000_000;
+        for step in now - TOTP_WINDOW..=now + TOTP_WINDOW {
+            assert_eq!(verify_code(SECRET, &code(step), now), Some(step));
+        }
+        assert_eq!(verify_code(SECRET, &code(now - TOTP_WINDOW - 1), now), None);
+        assert_eq!(verify_code(SECRET, &code(now + TOTP_WINDOW + 1), now), None);
+    }
+
+    #[test]
+    fn the_otpauth_label_is_percent_encoded() {
+        let uri = otpauth_uri("a b?c#d/e@example.com", "SECRET");
+        assert_eq!(
+            uri,
+            "otpauth://totp/MyApp:a%20b%3Fc%23d%2Fe@example.com\
+             ?secret=SECRET&issuer=MyApp&digits=6&period=30"
+        );
+        let parsed = Url::parse(&uri).unwrap();
+        assert_eq!(parsed.query_pairs().count(), 4);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn a_step_is_accepted_once_and_never_an_older_one() {
+        let users = test_support::database().await.collection::<User>("users");
+        let user = User::new("a@example.com".to_string(), String::new(), &Role::User);
+        users.insert_one(&user, None).await.unwrap();
+
+        claim_step(&users, &user.uid, 100).await.unwrap();
+        for replayed in [100, 99] {
+            assert!(matches!(
+                claim_step(&users, &user.uid, replayed).await,
+                Err(Error::InvalidTotpCodeError)
+            ));
+        }
+        claim_step(&users, &user.uid, 101).await.unwrap();
+    }
+}
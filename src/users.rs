@@ -0,0 +1,1707 @@
+use crate::{
+    apikeys::ApiKey,
+    audit::{self, AuditAction, AuditEvent},
+    auth::{create_impersonation_jwt, AuthContext, Claims, Role},
+    config, email_change,
+    error::{is_duplicate_key, Chain, Error},
+    etag::{self, IfMatch},
+    magic_link::MagicLink,
+    oauth::FederatedIdentity,
+    orgs::Membership,
+    pagination::{self, Order, PageRequest, SortKey},
+    password,
+    password_reset::PasswordReset,
+    repository::{timed, UserRepo},
+    request_id, scopes,
+    sessions::{self, ClientInfo, Session},
+    transaction::Transactions,
+    two_factor::PendingLogin,
+    validation::{Validate, Validator},
+    webhooks::{self, WebhookEvent},
+    Result, User, UserDocument, UserResponse, WebResult,
+};
+use futures_util::FutureExt;
+use mongodb::{
+    bson::{doc, spec::ElementType, uuid::Uuid, Bson, DateTime, Document},
+    error::ErrorKind,
+    options::{FindOneAndUpdateOptions, FindOptions, IndexOptions, ReturnDocument},
+    ClientSession, Collection, IndexModel,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{convert::Infallible, env, time::Duration};
+use tokio::task::JoinHandle;
+use utoipa::{IntoParams, ToSchema};
+use warp::{http::StatusCode, reject, reply, Filter, Reply};
+
+/// MongoDB's error code for a missing collection.
+const NAMESPACE_NOT_FOUND: i32 = 26;
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_BAN_REASON_LENGTH: usize = 200;
+const MAX_BAN_DAYS: u64 = 3650;
+const MAX_DISPLAY_NAME_LENGTH: usize = 100;
+const MAX_AVATAR_URL_LENGTH: usize = 2048;
+const MAX_BIO_LENGTH: usize = 1000;
+
+/// Unique indexes on `uid` and, where there is one, `email` and
+/// `username_lower`. These are what actually prevent duplicate accounts;
+/// handlers' lookups only give a nicer error.
+#[tracing::instrument(skip_all)]
+pub async fn create_indexes(collection: &Collection<User>) -> mongodb::error::Result<()> {
+    drop_full_email_index(collection).await?;
+    // Partial so that guests, who have no email, don't collide on it.
+    let email_index = IndexModel::builder()
+        .keys(doc! {"email": 1})
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .partial_filter_expression(doc! {"email": {"$type": "string"}})
+                .build(),
+        )
+        .build();
+    let uid_index = IndexModel::builder()
+        .keys(doc! {"uid": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    let email_lower_index = IndexModel::builder().keys(doc! {"email_lower": 1}).build();
+    let created_at_index = IndexModel::builder().keys(doc! {"created_at": 1}).build();
+    // Partial rather than sparse, since accounts without a username store
+    // `null`, which a sparse index still counts.
+    let username_index = IndexModel::builder()
+        .keys(doc! {"username_lower": 1})
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .partial_filter_expression(doc! {"username_lower": {"$type": "string"}})
+                .build(),
+        )
+        .build();
+    collection
+        .create_indexes(
+            vec![
+                email_index,
+                uid_index,
+                email_lower_index,
+                created_at_index,
+                username_index,
+            ],
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Drops the unique `email` index from before guests existed, which
+/// covered every document, so [`create_indexes`] can replace it with the
+/// partial one; MongoDB refuses to change an index's options in place.
+async fn drop_full_email_index(collection: &Collection<User>) -> mongodb::error::Result<()> {
+    let mut indexes = match collection.list_indexes(None).await {
+        Ok(indexes) => indexes,
+        // The collection doesn't exist yet.
+        Err(e) if matches!(&*e.kind, ErrorKind::Command(c) if c.code == NAMESPACE_NOT_FOUND) => {
+            return Ok(())
+        }
+        Err(e) => return Err(e),
+    };
+    while indexes.advance().await? {
+        let index = indexes.deserialize_current()?;
+        let options = index.options.unwrap_or_default();
+        if index.keys == doc! {"email": 1} && options.partial_filter_expression.is_none() {
+            if let Some(name) = options.name {
+                tracing::info!("replacing the users email index with a partial one");
+                collection.drop_index(name, None).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keeps `email_lower` the `normalize_email` form of `email`, on accounts
+/// created before it existed or before emails were normalized. `email`
+/// itself is left as it was, since two such accounts may only differ in
+/// case.
+#[tracing::instrument(skip_all)]
+pub async fn backfill_email_lower(collection: &Collection<User>) -> mongodb::error::Result<()> {
+    let normalized = doc! {"$toLower": {"$trim": {"input": "$email"}}};
+    collection
+        .update_many(
+            doc! {"$expr": {"$ne": ["$email_lower", &normalized]}},
+            vec![doc! {"$set": {"email_lower": &normalized}}],
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// The form emails are stored and compared in: trimmed and lowercased, so
+/// `" Alice@Example.com"` and `"alice@example.com"` are the same account.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// For `#[serde(deserialize_with)]` on request fields holding an email.
+pub fn normalized_email<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    String::deserialize(deserializer).map(|email| normalize_email(&email))
+}
+
+/// Filter matching the account with this email in any case, including
+/// accounts stored before emails were normalized.
+pub fn by_email(email: &str) -> Document {
+    doc! {"email_lower": normalize_email(email)}
+}
+
+/// Filter matching the account with this username in any case.
+pub fn by_username(username: &str) -> Document {
+    doc! {"username_lower": username.to_lowercase()}
+}
+
+/// Moves `last_login_at` to `previous_login_at` and stamps the current
+/// time. The write runs in the background: logins don't wait on it, at the
+/// cost of a timestamp being lost (and only logged) if the write fails.
+pub fn record_login(users: &UserRepo, uid: &str) {
+    let users = users.clone();
+    let uid = uid.to_owned();
+    request_id::spawn(async move {
+        if let Err(e) = users.record_login(&uid).await {
+            tracing::error!("recording login for {} failed: {}", uid, Chain(&e));
+        }
+    });
+}
+
+/// Replaces the hash `user` signed in with by an Argon2id hash of the same
+/// `password`, in the background like `record_login`. A password changed in
+/// the meantime is left alone.
+pub fn upgrade_password_hash(users: &UserRepo, user: &User, password: &str) {
+    let users = users.clone();
+    let uid = user.uid.clone();
+    let current = user.pw.clone();
+    let password = password.to_owned();
+    request_id::spawn(async move {
+        let upgraded = match password::hash(&password) {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                tracing::error!("rehashing the password of {} failed: {}", uid, Chain(&e));
+                return;
+            }
+        };
+        if let Err(e) = users.replace_password_hash(&uid, &current, &upgraded).await {
+            tracing::error!(
+                "storing the rehashed password of {} failed: {}",
+                uid,
+                Chain(&e)
+            );
+        }
+    });
+}
+
+/// uids are UUIDs; rejecting anything else up front keeps garbage input
+/// away from the database.
+pub(crate) fn validate_uid(uid: &str) -> Result<()> {
+    Uuid::parse_str(uid).map_err(|_| Error::InvalidUserIdError)?;
+    Ok(())
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateUserRequest {
+    pub email: String,
+}
+
+/// Moves `uid` to a new, case-normalized email address. The old address is
+/// free for a new signup as soon as this returns.
+#[tracing::instrument(skip(users_collection, email, if_match))]
+async fn change_email(
+    users_collection: &Collection<User>,
+    uid: &str,
+    email: &str,
+    if_match: &IfMatch,
+) -> Result<User> {
+    let email = normalize_email(email);
+    let mut validator = Validator::new();
+    validator.email("email", &email);
+    validator.finish()?;
+
+    let taken =
+        timed(users_collection.find_one(doc! {"email_lower": &email, "uid": {"$ne": uid}}, None))
+            .await?;
+    if taken.is_some() {
+        return Err(Error::EmailAlreadyInUseError);
+    }
+
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let user = timed(users_collection.find_one_and_update(
+        if_match.apply(doc! {"uid": uid}),
+        doc! {
+            "$set": {
+                "email": &email,
+                "email_lower": &email,
+                "updated_at": DateTime::now(),
+            },
+            "$inc": {"version": 1},
+        },
+        options,
+    ))
+    .await
+    .map_err(|e| match e {
+        Error::DuplicateKeyError => Error::EmailAlreadyInUseError,
+        other => other,
+    })?;
+    match user {
+        Some(user) => Ok(user),
+        None => Err(missed_update(users_collection, uid).await),
+    }
+}
+
+/// Why an update of `uid` filtered on its `If-Match` matched nothing: the
+/// user has changed since the client read it, or is gone.
+async fn missed_update(users_collection: &Collection<User>, uid: &str) -> Error {
+    match timed(users_collection.count_documents(doc! {"uid": uid}, None)).await {
+        Ok(0) => Error::UserNotFoundError,
+        Ok(_) => Error::PreconditionFailedError,
+        Err(e) => e,
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/users/{uid}",
+    tag = "users",
+    params(
+        ("uid" = String, Path, description = "User id"),
+        ("If-Match" = String, Header, description = "The user's `ETag`"),
+    ),
+    request_body = UpdateUserRequest,
+    responses(
+        (status = 200, description = "The updated user", body = UserResponse,
+            headers(("ETag" = String, description = "The user's new version"))),
+        (status = 400, description = "The uid is not a UUID", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such user", body = ErrorResponse),
+        (status = 409, description = "Email already in use", body = ErrorResponse),
+        (status = 412, description = "The user changed since the `ETag` was read", body = ErrorResponse),
+        (status = 428, description = "No `If-Match`", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn update_user_handler(
+    uid: String,
+    _claims: Claims,
+    users_collection: Collection<User>,
+    if_match: IfMatch,
+    body: UpdateUserRequest,
+) -> WebResult<impl Reply> {
+    validate_uid(&uid).map_err(reject::custom)?;
+    let user = change_email(&users_collection, &uid, &body.email, &if_match)
+        .await
+        .map_err(reject::custom)?;
+    let version = user.version;
+    Ok(etag::with_etag(
+        reply::json(&UserResponse::from(user)),
+        version,
+    ))
+}
+
+/// `PATCH /me` body. Each field is `None` when absent, `Some(None)` when
+/// sent as `null` (clear it) and `Some(Some(_))` when it should be set.
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateProfileRequest {
+    #[serde(default, deserialize_with = "present")]
+    pub display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub avatar_url: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub bio: Option<Option<String>>,
+}
+
+/// Marks a field as present even when its value is `null`, which plain
+/// `Option<Option<T>>` can't tell apart from a missing field.
+fn present<'de, D, T>(deserializer: D) -> std::result::Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+fn validate_length(
+    value: &Option<Option<String>>,
+    max: usize,
+    message: &'static str,
+) -> Result<()> {
+    match value {
+        Some(Some(value)) if value.chars().count() > max => {
+            Err(Error::InvalidProfileError(message))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[utoipa::path(
+    patch,
+    path = "/me",
+    tag = "profile",
+    params(("If-Match" = String, Header, description = "The account's `ETag` from `GET /me`")),
+    request_body = UpdateProfileRequest,
+    responses(
+        (status = 200, description = "The updated user", body = UserResponse,
+            headers(("ETag" = String, description = "The account's new version"))),
+        (status = 400, description = "A field is too long", body = ErrorResponse),
+        (status = 412, description = "The account changed since the `ETag` was read", body = ErrorResponse),
+        (status = 428, description = "No `If-Match`", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn update_profile_handler(
+    claims: Claims,
+    users_collection: Collection<User>,
+    if_match: IfMatch,
+    body: UpdateProfileRequest,
+) -> WebResult<impl Reply> {
+    validate_length(
+        &body.display_name,
+        MAX_DISPLAY_NAME_LENGTH,
+        "display_name must be at most 100 characters",
+    )
+    .map_err(reject::custom)?;
+    validate_length(
+        &body.avatar_url,
+        MAX_AVATAR_URL_LENGTH,
+        "avatar_url must be at most 2048 characters",
+    )
+    .map_err(reject::custom)?;
+    validate_length(
+        &body.bio,
+        MAX_BIO_LENGTH,
+        "bio must be at most 1000 characters",
+    )
+    .map_err(reject::custom)?;
+
+    let mut set = Document::new();
+    let mut unset = Document::new();
+    for (field, value) in [
+        ("display_name", body.display_name),
+        ("avatar_url", body.avatar_url),
+        ("bio", body.bio),
+    ] {
+        match value {
+            Some(Some(value)) => {
+                set.insert(field, value);
+            }
+            Some(None) => {
+                unset.insert(field, "");
+            }
+            None => {}
+        }
+    }
+
+    let mut update = Document::new();
+    if !set.is_empty() || !unset.is_empty() {
+        set.insert("updated_at", DateTime::now());
+        update.insert("$inc", doc! {"version": 1});
+    }
+    if !set.is_empty() {
+        update.insert("$set", set);
+    }
+    if !unset.is_empty() {
+        update.insert("$unset", unset);
+    }
+
+    let filter = if_match.apply(doc! {"uid": &claims.sub});
+    let user = if update.is_empty() {
+        timed(users_collection.find_one(filter, None)).await
+    } else {
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        timed(users_collection.find_one_and_update(filter, update, options)).await
+    }
+    .map_err(reject::custom)?;
+    let Some(user) = user else {
+        return Err(reject::custom(
+            missed_update(&users_collection, &claims.sub).await,
+        ));
+    };
+
+    let version = user.version;
+    Ok(etag::with_etag(
+        reply::json(&UserResponse::from(user)),
+        version,
+    ))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateUserRequest {
+    #[serde(deserialize_with = "normalized_email")]
+    pub email: String,
+    pub pw: String,
+    pub role: String,
+}
+
+/// Creates an account with any known role. Unlike public signup the email
+/// is trusted, so no verification mail is sent.
+#[utoipa::path(
+    post,
+    path = "/users",
+    tag = "users",
+    request_body = CreateUserRequest,
+    responses(
+        (status = 201, description = "The new user", body = UserResponse),
+        (status = 400, description = "Unknown role", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 409, description = "Email already in use", body = ErrorResponse),
+        (status = 422, description = "Invalid email or password", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn create_user_handler(
+    _claims: Claims,
+    context: AuthContext,
+    users_collection: Collection<User>,
+    body: CreateUserRequest,
+) -> WebResult<impl Reply> {
+    let mut validator = Validator::new();
+    validator.email("email", &body.email);
+    validator.password("pw", &body.pw);
+    validator.finish().map_err(reject::custom)?;
+
+    let role = Role::from_str(&body.role).map_err(reject::custom)?;
+    if !context.roles().is_assignable(&role) {
+        return Err(reject::custom(Error::InvalidRoleError));
+    }
+
+    let existing_user = timed(users_collection.find_one(by_email(&body.email), None))
+        .await
+        .map_err(reject::custom)?;
+    if existing_user.is_some() {
+        return Err(reject::custom(Error::UserAlreadyExistsError));
+    }
+
+    let pw = password::hash(&body.pw).map_err(reject::custom)?;
+    let user = User::new(body.email, pw, &role);
+    timed(documents(&users_collection).insert_one(user.document(), None))
+        .await
+        .map_err(|e| match e {
+            Error::DuplicateKeyError => reject::custom(Error::UserAlreadyExistsError),
+            other => reject::custom(other),
+        })?;
+
+    Ok(reply::with_status(
+        reply::json(&UserResponse::from(user)),
+        StatusCode::CREATED,
+    ))
+}
+
+/// Creates the first admin from `BOOTSTRAP_ADMIN_EMAIL` and
+/// `BOOTSTRAP_ADMIN_PASSWORD` when both are set and no admin exists yet,
+/// since signup can't create one.
+pub async fn bootstrap_admin(users_collection: &Collection<User>) -> Result<()> {
+    let (Ok(email), Ok(pw)) = (
+        env::var("BOOTSTRAP_ADMIN_EMAIL"),
+        env::var("BOOTSTRAP_ADMIN_PASSWORD"),
+    ) else {
+        return Ok(());
+    };
+
+    let admins = users_collection
+        .count_documents(active(doc! {"role": Role::Admin.to_string()}), None)
+        .await
+        .map_err(Error::from)?;
+    if admins > 0 {
+        return Ok(());
+    }
+    let email = normalize_email(&email);
+    let existing_user = users_collection
+        .find_one(by_email(&email), None)
+        .await
+        .map_err(Error::from)?;
+    if existing_user.is_some() {
+        return Err(Error::UserAlreadyExistsError);
+    }
+
+    let pw = password::hash(&pw)?;
+    documents(users_collection)
+        .insert_one(User::new(email, pw, &Role::Admin).document(), None)
+        .await
+        .map_err(Error::from)?;
+    Ok(())
+}
+
+/// What [`create_admin`] did, with the account's uid.
+pub enum AdminCreated {
+    Created(String),
+    Promoted(String),
+}
+
+/// Makes `email` an admin with the password `pw`, for the `create-admin`
+/// command. An existing account with the email is refused unless `force`
+/// is set, in which case it is promoted, given `pw` and signed out
+/// everywhere, so its old tokens cannot carry on as the lesser role.
+pub async fn create_admin(
+    users_collection: &Collection<User>,
+    email: &str,
+    pw: &str,
+    force: bool,
+) -> Result<AdminCreated> {
+    let email = normalize_email(email);
+    let mut validator = Validator::new();
+    validator.email("email", &email);
+    validator.password("password", pw);
+    validator.finish()?;
+
+    let pw = password::hash(pw)?;
+    let existing_user = users_collection
+        .find_one(by_email(&email), None)
+        .await
+        .map_err(Error::from)?;
+    let Some(user) = existing_user else {
+        let user = User::new(email, pw, &Role::Admin);
+        documents(users_collection)
+            .insert_one(user.document(), None)
+            .await
+            .map_err(|e| {
+                if is_duplicate_key(&e) {
+                    Error::UserAlreadyExistsError
+                } else {
+                    Error::from(e)
+                }
+            })?;
+        return Ok(AdminCreated::Created(user.uid));
+    };
+    if !force {
+        return Err(Error::UserAlreadyExistsError);
+    }
+    users_collection
+        .update_one(
+            doc! {"uid": &user.uid},
+            doc! {
+                "$set": {
+                    "role": Role::Admin.to_string(),
+                    "pw": pw,
+                    "updated_at": DateTime::now(),
+                },
+                "$inc": {"token_version": 1, "version": 1},
+            },
+            None,
+        )
+        .await
+        .map_err(Error::from)?;
+    Ok(AdminCreated::Promoted(user.uid))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateUserRoleRequest {
+    pub role: String,
+}
+
+/// Changes a user's role. The new role takes effect on the user's next
+/// login or refresh, since issued access tokens keep their role claim.
+#[utoipa::path(
+    put,
+    path = "/users/{uid}/role",
+    tag = "users",
+    params(
+        ("uid" = String, Path, description = "User id"),
+        ("If-Match" = String, Header, description = "The user's `ETag`"),
+    ),
+    request_body = UpdateUserRoleRequest,
+    responses(
+        (status = 200, description = "The updated user", body = UserResponse,
+            headers(("ETag" = String, description = "The user's new version"))),
+        (status = 400, description = "Unknown role or invalid uid", body = ErrorResponse),
+        (status = 403, description = "Not an admin, or a `remember_me` session with \
+                                        `REQUIRE_FRESH_LOGIN`", body = ErrorResponse),
+        (status = 404, description = "No such user", body = ErrorResponse),
+        (status = 409, description = "Would demote the last admin", body = ErrorResponse),
+        (status = 412, description = "The user changed since the `ETag` was read", body = ErrorResponse),
+        (status = 428, description = "No `If-Match`", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn update_user_role_handler(
+    uid: String,
+    claims: Claims,
+    context: AuthContext,
+    users_collection: Collection<User>,
+    sessions_collection: Collection<Session>,
+    client: ClientInfo,
+    if_match: IfMatch,
+    body: UpdateUserRoleRequest,
+) -> WebResult<impl Reply> {
+    sessions::require_fresh_login(&sessions_collection, &claims)
+        .await
+        .map_err(reject::custom)?;
+    validate_uid(&uid).map_err(reject::custom)?;
+    let role = Role::from_str(&body.role).map_err(reject::custom)?;
+    if !context.roles().is_assignable(&role) {
+        return Err(reject::custom(Error::InvalidRoleError));
+    }
+
+    let user = timed(users_collection.find_one(doc! {"uid": &uid}, None))
+        .await
+        .map_err(reject::custom)?
+        .ok_or_else(|| reject::custom(Error::UserNotFoundError))?;
+    if !if_match.matches(user.version) {
+        return Err(reject::custom(Error::PreconditionFailedError));
+    }
+
+    // Demoting the only admin who can sign in would leave nobody able to
+    // manage roles.
+    let demotes_admin = is_usable_admin(&user) && role != Role::Admin;
+    if demotes_admin {
+        let admins = timed(users_collection.count_documents(usable_admins(), None))
+            .await
+            .map_err(reject::custom)?;
+        if admins <= 1 {
+            return Err(reject::custom(Error::LastAdminError));
+        }
+    }
+
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let updated = timed(users_collection.find_one_and_update(
+        if_match.apply(doc! {"uid": &uid}),
+        doc! {
+            "$set": {
+                "role": role.to_string(),
+                "role_changed_by": &claims.sub,
+                "role_changed_at": DateTime::now(),
+                "updated_at": DateTime::now(),
+            },
+            "$inc": {"version": 1},
+        },
+        options,
+    ))
+    .await
+    .map_err(reject::custom)?;
+    let Some(updated) = updated else {
+        return Err(reject::custom(missed_update(&users_collection, &uid).await));
+    };
+    if demotes_admin
+        && !admin_remains(&users_collection)
+            .await
+            .map_err(reject::custom)?
+    {
+        restore_role(&users_collection, &user)
+            .await
+            .map_err(reject::custom)?;
+        return Err(reject::custom(Error::LastAdminError));
+    }
+    context.forget_user(&uid);
+    audit::record(
+        AuditEvent::new(AuditAction::RoleChanged, &client)
+            .actor(&claims.sub)
+            .target(&uid)
+            .detail(&format!("{} -> {}", user.role, updated.role)),
+    );
+    webhooks::dispatch(WebhookEvent::UserRoleChanged, &updated, Some(&user.role));
+
+    let version = updated.version;
+    Ok(etag::with_etag(
+        reply::json(&UserResponse::from(updated)),
+        version,
+    ))
+}
+
+const MIN_SEARCH_LENGTH: usize = 2;
+const MAX_SEARCH_RESULTS: i64 = 20;
+
+/// Sorting on _id walks the default index, so paging never needs an
+/// in-memory sort of the whole collection.
+const USER_ORDER: Order = &[SortKey {
+    field: "_id",
+    descending: false,
+    kind: ElementType::ObjectId,
+}];
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListUsersQuery {
+    /// The `next_cursor` of the previous page; the first page without it.
+    pub cursor: Option<String>,
+    /// Deprecated: a page number starting at 1, instead of `cursor`.
+    pub page: Option<u64>,
+    /// Users per page, at most 200 (default 50).
+    pub limit: Option<u64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/users",
+    tag = "users",
+    params(ListUsersQuery),
+    responses(
+        (status = 200, description = "One page of users", body = UserPage),
+        (status = 400, description = "`page` or `limit` out of range, or an invalid `cursor`",
+            body = ErrorResponse),
+        (status = 403, description = "Token lacks the `users:read` scope", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn list_users_handler(
+    _claims: Claims,
+    users_collection: Collection<User>,
+    query: ListUsersQuery,
+) -> WebResult<impl Reply> {
+    let request = PageRequest::new(USER_ORDER, query.cursor.as_deref(), query.page, query.limit)
+        .map_err(reject::custom)?;
+    let page = pagination::find(
+        &users_collection,
+        Document::new(),
+        USER_ORDER,
+        &request,
+        None,
+    )
+    .await
+    .map_err(reject::custom)?;
+    Ok(pagination::reply(&page.map(UserResponse::from), &request))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchUsersQuery {
+    /// Email prefix, at least 2 characters.
+    pub q: String,
+}
+
+/// Escapes everything but ASCII alphanumerics, so the query only ever
+/// matches itself literally.
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if !c.is_ascii_alphanumeric() {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Finds users whose email starts with `q`, ignoring case. The anchored
+/// regex on the lowercased copy of the email can use its index.
+#[utoipa::path(
+    get,
+    path = "/users/search",
+    tag = "users",
+    params(SearchUsersQuery),
+    responses(
+        (status = 200, description = "Up to 20 matching users", body = Vec<UserResponse>),
+        (status = 400, description = "`q` too short", body = ErrorResponse),
+        (status = 403, description = "Token lacks the `users:read` scope", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn search_users_handler(
+    _claims: Claims,
+    users_collection: Collection<User>,
+    query: SearchUsersQuery,
+) -> WebResult<impl Reply> {
+    let prefix = query.q.trim().to_lowercase();
+    if prefix.chars().count() < MIN_SEARCH_LENGTH {
+        return Err(reject::custom(Error::InvalidSearchQueryError));
+    }
+
+    let options = FindOptions::builder()
+        .sort(doc! {"email_lower": 1})
+        .limit(MAX_SEARCH_RESULTS)
+        .build();
+    let mut cursor = timed(users_collection.find(
+        doc! {"email_lower": {"$regex": format!("^{}", escape_regex(&prefix))}},
+        options,
+    ))
+    .await
+    .map_err(reject::custom)?;
+
+    let mut users = Vec::new();
+    while timed(cursor.advance()).await.map_err(reject::custom)? {
+        let user: User = cursor
+            .deserialize_current()
+            .map_err(|e| reject::custom(Error::from(e)))?;
+        users.push(UserResponse::from(user));
+    }
+
+    Ok(reply::json(&users))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{uid}",
+    tag = "users",
+    params(("uid" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The user", body = UserResponse,
+            headers(("ETag" = String, description = "The user's version, for `If-Match`"))),
+        (status = 400, description = "The uid is not a UUID", body = ErrorResponse),
+        (status = 403, description = "Token lacks the `users:read` scope", body = ErrorResponse),
+        (status = 404, description = "No such user", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn get_user_handler(
+    uid: String,
+    _claims: Claims,
+    users_collection: Collection<User>,
+) -> WebResult<impl Reply> {
+    validate_uid(&uid).map_err(reject::custom)?;
+    let user = timed(users_collection.find_one(doc! {"uid": &uid}, None))
+        .await
+        .map_err(reject::custom)?
+        .ok_or_else(|| reject::custom(Error::UserNotFoundError))?;
+
+    let version = user.version;
+    Ok(etag::with_etag(
+        reply::json(&UserResponse::from(user)),
+        version,
+    ))
+}
+
+/// Everything stored per user that must go when the user is deleted.
+#[derive(Clone)]
+pub struct UserData {
+    pub sessions: Collection<Session>,
+    pub password_resets: Collection<PasswordReset>,
+    pub pending_logins: Collection<PendingLogin>,
+    pub magic_links: Collection<MagicLink>,
+    pub federated_identities: Collection<FederatedIdentity>,
+    pub api_keys: Collection<ApiKey>,
+    pub memberships: Collection<Membership>,
+    /// For deleting all of it together with the account.
+    pub transactions: Transactions,
+}
+
+impl UserData {
+    /// Removes every credential that could still sign `uid` in. Linked
+    /// external accounts and organization memberships are kept so a
+    /// restored user can keep using them.
+    async fn revoke_access(
+        &self,
+        uid: &str,
+        session: &mut ClientSession,
+    ) -> mongodb::error::Result<()> {
+        let filter = doc! {"uid": uid};
+        self.sessions
+            .delete_many_with_session(filter.clone(), None, session)
+            .await?;
+        self.password_resets
+            .delete_many_with_session(filter.clone(), None, session)
+            .await?;
+        self.pending_logins
+            .delete_many_with_session(filter.clone(), None, session)
+            .await?;
+        self.magic_links
+            .delete_many_with_session(filter.clone(), None, session)
+            .await?;
+        self.api_keys
+            .delete_many_with_session(filter, None, session)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_for(
+        &self,
+        uid: &str,
+        session: &mut ClientSession,
+    ) -> mongodb::error::Result<()> {
+        self.revoke_access(uid, session).await?;
+        self.federated_identities
+            .delete_many_with_session(doc! {"uid": uid}, None, session)
+            .await?;
+        self.memberships
+            .delete_many_with_session(doc! {"uid": uid}, None, session)
+            .await?;
+        Ok(())
+    }
+}
+
+pub fn with_user_data(
+    data: UserData,
+) -> impl Filter<Extract = (UserData,), Error = Infallible> + Clone {
+    warp::any().map(move || data.clone())
+}
+
+/// Restricts a user query to accounts that haven't been soft-deleted.
+/// Checks that must keep deleted emails reserved query without it.
+pub fn active(mut filter: Document) -> Document {
+    filter.insert("deleted_at", Bson::Null);
+    filter
+}
+
+/// Restricts a user query to accounts without a ban in force.
+pub fn not_banned(mut filter: Document) -> Document {
+    filter.insert(
+        "$nor",
+        vec![doc! {
+            "banned_at": {"$ne": Bson::Null},
+            "$or": [
+                {"banned_until": Bson::Null},
+                {"banned_until": {"$gt": DateTime::now()}},
+            ],
+        }],
+    );
+    filter
+}
+
+/// Admins who can sign in: not soft-deleted, deactivated or banned. The
+/// last-admin guards keep at least one of these.
+pub fn usable_admins() -> Document {
+    not_banned(active(
+        doc! {"role": Role::Admin.to_string(), "active": {"$ne": false}},
+    ))
+}
+
+/// Whether `user` is one of [`usable_admins`].
+pub fn is_usable_admin(user: &User) -> bool {
+    user.role == Role::Admin.to_string()
+        && user.deleted_at.is_none()
+        && user.active
+        && !user.is_banned()
+}
+
+/// Whether an admin who can sign in is left. Demotions check this after
+/// their write rather than only before it: two admins demoted at once
+/// would each see the other beforehand, but whichever counts last sees
+/// both demotions and undoes its own.
+pub async fn admin_remains(users_collection: &Collection<User>) -> Result<bool> {
+    let admins = timed(users_collection.count_documents(usable_admins(), None)).await?;
+    Ok(admins > 0)
+}
+
+/// Gives `previous` back the role it had, after [`admin_remains`] found
+/// that demoting it left no admin.
+pub async fn restore_role(users_collection: &Collection<User>, previous: &User) -> Result<()> {
+    timed(users_collection.update_one(
+        doc! {"uid": &previous.uid},
+        doc! {
+            "$set": {
+                "role": &previous.role,
+                "role_changed_by": &previous.role_changed_by,
+                "role_changed_at": previous.role_changed_at,
+                "updated_at": DateTime::now(),
+            },
+            "$inc": {"version": 1},
+        },
+        None,
+    ))
+    .await?;
+    Ok(())
+}
+
+/// The 403 for a sign-in to a banned account. The reason is moderators'
+/// business unless `SHOW_BAN_REASON` is on.
+pub fn ban_error(user: &User) -> Error {
+    Error::AccountBannedError {
+        until: user
+            .banned_until
+            .and_then(|until| until.try_to_rfc3339_string().ok()),
+        reason: config::show_ban_reason()
+            .then(|| user.ban_reason.clone())
+            .flatten(),
+    }
+}
+
+/// Clears a ban that has run out from a user signing in, so it stops
+/// showing on the account.
+pub async fn lift_expired_ban(users: &UserRepo, user: &User, client: &ClientInfo) -> Result<()> {
+    if users.clear_expired_ban(&user.uid).await? {
+        audit::record(
+            AuditEvent::new(AuditAction::UserUnbanned, client)
+                .target(&user.uid)
+                .detail("expired"),
+        );
+    }
+    Ok(())
+}
+
+/// The users collection for writing whole accounts, which must include
+/// the password hash; see [`User::document`].
+pub fn documents<'a>(users_collection: &Collection<User>) -> Collection<UserDocument<'a>> {
+    users_collection.clone_with_type()
+}
+
+/// Periodically hard-deletes accounts that were soft-deleted more than
+/// `retention` ago and guests older than `guest_max_age`, along with their
+/// remaining data, and drops expired email changes.
+pub fn spawn_purge(
+    context: AuthContext,
+    users_collection: Collection<User>,
+    user_data: UserData,
+    retention: Duration,
+    guest_max_age: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = purge_deleted(&context, &users_collection, &user_data, retention).await
+            {
+                tracing::error!("purging deleted users failed: {}", Chain(&e));
+            }
+            if let Err(e) =
+                purge_guests(&context, &users_collection, &user_data, guest_max_age).await
+            {
+                tracing::error!("purging old guests failed: {}", Chain(&e));
+            }
+            if let Err(e) = email_change::purge_expired(&users_collection).await {
+                tracing::error!("purging expired email changes failed: {}", Chain(&e));
+            }
+        }
+    })
+}
+
+fn cutoff(age: Duration) -> DateTime {
+    DateTime::from_millis(DateTime::now().timestamp_millis() - age.as_millis() as i64)
+}
+
+#[tracing::instrument(skip_all)]
+async fn purge_deleted(
+    context: &AuthContext,
+    users_collection: &Collection<User>,
+    user_data: &UserData,
+    retention: Duration,
+) -> Result<()> {
+    let mut cursor = users_collection
+        .find(doc! {"deleted_at": {"$lt": cutoff(retention)}}, None)
+        .await?;
+    while cursor.advance().await? {
+        let uid = cursor.deserialize_current()?.uid;
+        let event = AuditEvent::new(AuditAction::AccountDeleted, &ClientInfo::default())
+            .target(&uid)
+            .detail("purged after the retention period");
+        purge(context, users_collection, user_data, &uid, Some(&event)).await?;
+    }
+    Ok(())
+}
+
+/// Guests are not audited: nobody signed up, and there would be one entry
+/// per visitor who tried the app.
+#[tracing::instrument(skip_all)]
+async fn purge_guests(
+    context: &AuthContext,
+    users_collection: &Collection<User>,
+    user_data: &UserData,
+    max_age: Duration,
+) -> Result<()> {
+    let mut cursor = users_collection
+        .find(
+            doc! {"role": Role::Guest.to_string(), "created_at": {"$lt": cutoff(max_age)}},
+            None,
+        )
+        .await?;
+    let mut purged = 0;
+    while cursor.advance().await? {
+        let uid = cursor.deserialize_current()?.uid;
+        purge(context, users_collection, user_data, &uid, None).await?;
+        purged += 1;
+    }
+    if purged > 0 {
+        tracing::info!(purged, "purged guests that were never upgraded");
+    }
+    Ok(())
+}
+
+/// Deletes `uid` and its data for good, with `event` in the same
+/// transaction, and drops the account from the token version cache.
+async fn purge(
+    context: &AuthContext,
+    users_collection: &Collection<User>,
+    user_data: &UserData,
+    uid: &str,
+    event: Option<&AuditEvent>,
+) -> Result<()> {
+    user_data
+        .transactions
+        .run(
+            (uid, users_collection, user_data, event),
+            |session, (uid, users_collection, user_data, event)| {
+                async move {
+                    user_data.delete_for(uid, session).await?;
+                    users_collection
+                        .delete_one_with_session(doc! {"uid": &**uid}, None, session)
+                        .await?;
+                    if let Some(event) = event {
+                        audit::record_with_session(event, session).await?;
+                    }
+                    Ok(())
+                }
+                .boxed()
+            },
+        )
+        .await?;
+    context.forget_user(uid);
+    Ok(())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/{uid}",
+    tag = "users",
+    params(("uid" = String, Path, description = "User id")),
+    responses(
+        (status = 204, description = "User soft-deleted"),
+        (status = 400, description = "The uid is not a UUID", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such user", body = ErrorResponse),
+        (status = 409, description = "Admins cannot delete themselves", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn delete_user_handler(
+    uid: String,
+    claims: Claims,
+    context: AuthContext,
+    users_collection: Collection<User>,
+    user_data: UserData,
+    client: ClientInfo,
+) -> WebResult<impl Reply> {
+    validate_uid(&uid).map_err(reject::custom)?;
+    if uid == claims.sub {
+        return Err(reject::custom(Error::CannotDeleteSelfError));
+    }
+
+    let event = AuditEvent::new(AuditAction::AccountDeleted, &client)
+        .actor(&claims.sub)
+        .target(&uid)
+        .detail("soft-deleted");
+    soft_delete(&uid, &context, &users_collection, &user_data, event)
+        .await
+        .map_err(reject::custom)?;
+
+    Ok(reply::with_status(reply(), StatusCode::NO_CONTENT))
+}
+
+/// Marks `uid` deleted, to be purged after the retention period, removes
+/// every way back in and records `event`, all in one transaction.
+async fn soft_delete(
+    uid: &str,
+    context: &AuthContext,
+    users_collection: &Collection<User>,
+    user_data: &UserData,
+    event: AuditEvent,
+) -> Result<()> {
+    let deleted = user_data
+        .transactions
+        .run(
+            (uid, users_collection, user_data, &event),
+            |session, (uid, users_collection, user_data, event)| {
+                async move {
+                    let deleted = users_collection
+                        .find_one_and_update_with_session(
+                            active(doc! {"uid": *uid}),
+                            doc! {
+                                "$set": {"deleted_at": DateTime::now(), "updated_at": DateTime::now()},
+                                "$inc": {"version": 1},
+                            },
+                            None,
+                            session,
+                        )
+                        .await?
+                        .ok_or(Error::UserNotFoundError)?;
+                    user_data.revoke_access(uid, session).await?;
+                    audit::record_with_session(event, session).await?;
+                    Ok(deleted)
+                }
+                .boxed()
+            },
+        )
+        .await?;
+
+    // Access tokens are rejected from here on because `with_auth` only
+    // accepts active users; refresh tokens and API keys are removed outright.
+    context.forget_user(uid);
+    webhooks::dispatch(WebhookEvent::UserDeleted, &deleted, None);
+    Ok(())
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct DeleteAccountRequest {
+    /// The caller's current password.
+    pub pw: String,
+}
+
+/// Lets users delete their own account. Like an admin deletion it is a
+/// soft delete that `POST /users/{uid}/restore` can undo until the account
+/// is purged. The last admin can't leave this way, since nobody would be
+/// left to manage the server.
+#[utoipa::path(
+    delete,
+    path = "/me",
+    tag = "profile",
+    request_body = DeleteAccountRequest,
+    responses(
+        (status = 204, description = "Account deleted; its tokens no longer work"),
+        (status = 403, description = "Wrong password, or while impersonating", body = ErrorResponse),
+        (status = 409, description = "The caller is the last admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn delete_me_handler(
+    claims: Claims,
+    context: AuthContext,
+    users_collection: Collection<User>,
+    user_data: UserData,
+    client: ClientInfo,
+    body: DeleteAccountRequest,
+) -> WebResult<impl Reply> {
+    if claims.impersonator.is_some() {
+        return Err(reject::custom(Error::ImpersonationForbiddenError));
+    }
+    let user = timed(users_collection.find_one(active(doc! {"uid": &claims.sub}), None))
+        .await
+        .map_err(reject::custom)?
+        .ok_or_else(|| reject::custom(Error::UserNotFoundError))?;
+    if !password::verify(&body.pw, &user.pw).map_err(reject::custom)? {
+        return Err(reject::custom(Error::WrongCredentialsError));
+    }
+
+    if is_usable_admin(&user) {
+        let admins = timed(users_collection.count_documents(usable_admins(), None))
+            .await
+            .map_err(reject::custom)?;
+        if admins <= 1 {
+            return Err(reject::custom(Error::LastAdminError));
+        }
+    }
+
+    let event = AuditEvent::new(AuditAction::AccountDeleted, &client)
+        .actor(&user.uid)
+        .target(&user.uid)
+        .detail("deleted by the user");
+    soft_delete(&user.uid, &context, &users_collection, &user_data, event)
+        .await
+        .map_err(reject::custom)?;
+    // `with_auth` already refuses deleted accounts; this also covers the
+    // calling token when `AUTH_VERIFY_USER` is off.
+    context.revoke(&claims).await.map_err(reject::custom)?;
+
+    Ok(reply::with_status(reply(), StatusCode::NO_CONTENT))
+}
+
+/// Undoes a soft delete that hasn't been purged yet. The user signs in
+/// again from scratch; their old sessions and API keys are gone.
+#[utoipa::path(
+    post,
+    path = "/users/{uid}/restore",
+    tag = "users",
+    params(("uid" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The restored user", body = UserResponse),
+        (status = 400, description = "The uid is not a UUID", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such user", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn restore_user_handler(
+    uid: String,
+    _claims: Claims,
+    users_collection: Collection<User>,
+) -> WebResult<impl Reply> {
+    validate_uid(&uid).map_err(reject::custom)?;
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let user = timed(users_collection.find_one_and_update(
+        doc! {"uid": &uid, "deleted_at": {"$ne": Bson::Null}},
+        doc! {
+            "$set": {"updated_at": DateTime::now()},
+            "$unset": {"deleted_at": ""},
+            "$inc": {"version": 1},
+        },
+        options,
+    ))
+    .await
+    .map_err(reject::custom)?
+    .ok_or_else(|| reject::custom(Error::UserNotFoundError))?;
+
+    Ok(reply::json(&UserResponse::from(user)))
+}
+
+#[tracing::instrument(skip(users_collection))]
+async fn find_existing(users_collection: &Collection<User>, uid: &str) -> WebResult<User> {
+    timed(users_collection.find_one(active(doc! {"uid": uid}), None))
+        .await
+        .map_err(reject::custom)?
+        .ok_or_else(|| reject::custom(Error::UserNotFoundError))
+}
+
+/// Suspends an account: it can't sign in, and its sessions, access tokens
+/// and API keys stop working. Already inactive accounts are left as is.
+#[utoipa::path(
+    post,
+    path = "/users/{uid}/deactivate",
+    tag = "users",
+    params(("uid" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The deactivated user", body = UserResponse),
+        (status = 400, description = "The uid is not a UUID", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such user", body = ErrorResponse),
+        (status = 409, description = "Admins cannot deactivate themselves", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn deactivate_user_handler(
+    uid: String,
+    claims: Claims,
+    context: AuthContext,
+    users_collection: Collection<User>,
+    sessions_collection: Collection<Session>,
+) -> WebResult<impl Reply> {
+    validate_uid(&uid).map_err(reject::custom)?;
+    if uid == claims.sub {
+        return Err(reject::custom(Error::CannotDeactivateSelfError));
+    }
+
+    // Bumping the version means tokens from before the suspension stay
+    // invalid after a later reactivation.
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let updated = timed(users_collection.find_one_and_update(
+        active(doc! {"uid": &uid, "active": {"$ne": false}}),
+        doc! {
+            "$set": {"active": false, "updated_at": DateTime::now()},
+            "$inc": {"token_version": 1, "version": 1},
+        },
+        options,
+    ))
+    .await
+    .map_err(reject::custom)?;
+    let Some(user) = updated else {
+        let user = find_existing(&users_collection, &uid).await?;
+        return Ok(reply::json(&UserResponse::from(user)));
+    };
+
+    context.forget_user(&uid);
+    timed(sessions_collection.delete_many(doc! {"uid": &uid}, None))
+        .await
+        .map_err(reject::custom)?;
+
+    Ok(reply::json(&UserResponse::from(user)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/{uid}/activate",
+    tag = "users",
+    params(("uid" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The activated user", body = UserResponse),
+        (status = 400, description = "The uid is not a UUID", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such user", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn activate_user_handler(
+    uid: String,
+    _claims: Claims,
+    users_collection: Collection<User>,
+) -> WebResult<impl Reply> {
+    validate_uid(&uid).map_err(reject::custom)?;
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let updated = timed(users_collection.find_one_and_update(
+        active(doc! {"uid": &uid, "active": false}),
+        doc! {
+            "$set": {"active": true, "updated_at": DateTime::now()},
+            "$inc": {"version": 1},
+        },
+        options,
+    ))
+    .await
+    .map_err(reject::custom)?;
+    let user = match updated {
+        Some(user) => user,
+        None => find_existing(&users_collection, &uid).await?,
+    };
+
+    Ok(reply::json(&UserResponse::from(user)))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct BanRequest {
+    /// Why, for the audit log and other moderators; at most 200
+    /// characters.
+    pub reason: String,
+    /// Days until the ban lifts by itself, at most 3650; permanent when
+    /// left out.
+    pub expires_in_days: Option<u64>,
+}
+
+impl Validate for BanRequest {
+    fn validate(&self, validator: &mut Validator) {
+        if validator.non_empty("reason", &self.reason) {
+            validator.max_length("reason", &self.reason, MAX_BAN_REASON_LENGTH);
+        }
+    }
+}
+
+/// Bans an account, for moderation. Unlike deactivation a ban carries a
+/// reason and may expire. Sign-ins are refused with 403 `ACCOUNT_BANNED`
+/// and the ban's end, its sessions end and its access tokens and API keys
+/// stop working. Banning again replaces the reason and expiry.
+#[utoipa::path(
+    post,
+    path = "/users/{uid}/ban",
+    tag = "users",
+    params(("uid" = String, Path, description = "User id")),
+    request_body = BanRequest,
+    responses(
+        (status = 200, description = "The banned user", body = UserResponse),
+        (status = 400, description = "The uid is not a UUID, or `expires_in_days` is out of range",
+            body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such user", body = ErrorResponse),
+        (status = 409, description = "Admins cannot ban themselves", body = ErrorResponse),
+        (status = 422, description = "Missing or overlong reason", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn ban_user_handler(
+    uid: String,
+    claims: Claims,
+    context: AuthContext,
+    users_collection: Collection<User>,
+    sessions_collection: Collection<Session>,
+    client: ClientInfo,
+    body: BanRequest,
+) -> WebResult<impl Reply> {
+    validate_uid(&uid).map_err(reject::custom)?;
+    if uid == claims.sub {
+        return Err(reject::custom(Error::CannotBanSelfError));
+    }
+    if body
+        .expires_in_days
+        .is_some_and(|days| !(1..=MAX_BAN_DAYS).contains(&days))
+    {
+        return Err(reject::custom(Error::InvalidBanRequestError));
+    }
+
+    let now = DateTime::now();
+    let banned_until = body
+        .expires_in_days
+        .map(|days| now.saturating_add_duration(Duration::from_secs(days * 24 * 60 * 60)));
+    let reason = body.reason.trim();
+    // As with deactivation, tokens from before the ban stay invalid after
+    // it lifts.
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let user = timed(users_collection.find_one_and_update(
+        active(doc! {"uid": &uid}),
+        doc! {
+            "$set": {
+                "banned_at": now,
+                "banned_until": banned_until,
+                "ban_reason": reason,
+                "updated_at": now,
+            },
+            "$inc": {"token_version": 1, "version": 1},
+        },
+        options,
+    ))
+    .await
+    .map_err(reject::custom)?
+    .ok_or_else(|| reject::custom(Error::UserNotFoundError))?;
+
+    context.forget_user(&uid);
+    timed(sessions_collection.delete_many(doc! {"uid": &uid}, None))
+        .await
+        .map_err(reject::custom)?;
+    let until = banned_until
+        .and_then(|until| until.try_to_rfc3339_string().ok())
+        .map_or_else(
+            || "permanent".to_string(),
+            |until| format!("until {}", until),
+        );
+    audit::record(
+        AuditEvent::new(AuditAction::UserBanned, &client)
+            .actor(&claims.sub)
+            .target(&uid)
+            .detail(&format!("{}: {}", until, reason)),
+    );
+
+    Ok(reply::json(&UserResponse::from(user)))
+}
+
+/// Lifts a ban before it runs out. A no-op for an account that isn't
+/// banned.
+#[utoipa::path(
+    post,
+    path = "/users/{uid}/unban",
+    tag = "users",
+    params(("uid" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The unbanned user", body = UserResponse),
+        (status = 400, description = "The uid is not a UUID", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such user", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn unban_user_handler(
+    uid: String,
+    claims: Claims,
+    users_collection: Collection<User>,
+    client: ClientInfo,
+) -> WebResult<impl Reply> {
+    validate_uid(&uid).map_err(reject::custom)?;
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let updated = timed(users_collection.find_one_and_update(
+        active(doc! {"uid": &uid, "banned_at": {"$ne": Bson::Null}}),
+        doc! {
+            "$unset": {"banned_at": "", "banned_until": "", "ban_reason": ""},
+            "$set": {"updated_at": DateTime::now()},
+            "$inc": {"version": 1},
+        },
+        options,
+    ))
+    .await
+    .map_err(reject::custom)?;
+    let Some(user) = updated else {
+        let user = find_existing(&users_collection, &uid).await?;
+        return Ok(reply::json(&UserResponse::from(user)));
+    };
+
+    audit::record(
+        AuditEvent::new(AuditAction::UserUnbanned, &client)
+            .actor(&claims.sub)
+            .target(&uid),
+    );
+    Ok(reply::json(&UserResponse::from(user)))
+}
+
+/// Makes the account change its password at its next password login, for
+/// when it may have leaked. Until then a correct password only gets a
+/// token for `PUT /me/password`. Its sessions end and its access tokens
+/// stop working right away, since whoever has the password may have used
+/// it already.
+#[utoipa::path(
+    post,
+    path = "/users/{uid}/require-password-change",
+    tag = "users",
+    params(("uid" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The user, with `must_change_password` set", body = UserResponse),
+        (status = 400, description = "The uid is not a UUID", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such user", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn require_password_change_handler(
+    uid: String,
+    claims: Claims,
+    context: AuthContext,
+    users_collection: Collection<User>,
+    sessions_collection: Collection<Session>,
+    client: ClientInfo,
+) -> WebResult<impl Reply> {
+    validate_uid(&uid).map_err(reject::custom)?;
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let user = timed(users_collection.find_one_and_update(
+        active(doc! {"uid": &uid}),
+        doc! {
+            "$set": {"must_change_password": true, "updated_at": DateTime::now()},
+            "$inc": {"token_version": 1, "version": 1},
+        },
+        options,
+    ))
+    .await
+    .map_err(reject::custom)?
+    .ok_or_else(|| reject::custom(Error::UserNotFoundError))?;
+
+    context.forget_user(&uid);
+    timed(sessions_collection.delete_many(doc! {"uid": &uid}, None))
+        .await
+        .map_err(reject::custom)?;
+    audit::record(
+        AuditEvent::new(AuditAction::PasswordChangeRequired, &client)
+            .actor(&claims.sub)
+            .target(&uid),
+    );
+
+    Ok(reply::json(&UserResponse::from(user)))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ImpersonationResponse {
+    /// Access token acting as the user, sent as `Authorization: Bearer
+    /// <token>`.
+    pub token: String,
+    /// Always `Bearer`.
+    #[schema(value_type = String, example = "Bearer")]
+    pub token_type: &'static str,
+    /// Seconds until `token` expires.
+    #[schema(example = 900)]
+    pub expires_in: u64,
+}
+
+/// Issues a 15 minute access token acting as `uid`, for support to see the
+/// app as that user does. Every request made with it is audited with both
+/// identities. It cannot be refreshed, used to impersonate anyone in turn,
+/// or used to change the user's password.
+#[utoipa::path(
+    post,
+    path = "/users/{uid}/impersonate",
+    tag = "users",
+    params(("uid" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "Impersonation token", body = ImpersonationResponse),
+        (status = 400, description = "The uid is not a UUID", body = ErrorResponse),
+        (status = 403, description = "Not an admin, the user has privileged scopes or is deactivated, or \
+            the caller is impersonating already", body = ErrorResponse),
+        (status = 404, description = "No such user", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn impersonate_user_handler(
+    uid: String,
+    claims: Claims,
+    context: AuthContext,
+    users_collection: Collection<User>,
+    client: ClientInfo,
+) -> WebResult<impl Reply> {
+    if claims.impersonator.is_some() {
+        return Err(reject::custom(Error::ImpersonationForbiddenError));
+    }
+    validate_uid(&uid).map_err(reject::custom)?;
+    let user = find_existing(&users_collection, &uid).await?;
+    let role = context
+        .roles()
+        .resolve(&user.uid, &user.role)
+        .map_err(reject::custom)?;
+    // Custom roles granted privileged scopes are as off limits as admins,
+    // so an impersonation token never carries more than a user's scopes.
+    if scopes::is_privileged_role(&role, context.roles()) {
+        return Err(reject::custom(Error::CannotImpersonateAdminError));
+    }
+    if !user.active {
+        return Err(reject::custom(Error::AccountDisabledError));
+    }
+
+    let access =
+        create_impersonation_jwt(&context, &user.uid, &role, user.token_version, &claims.sub)
+            .map_err(reject::custom)?;
+    audit::record(
+        AuditEvent::new(AuditAction::ImpersonationStarted, &client)
+            .actor(&claims.sub)
+            .target(&user.uid),
+    );
+
+    Ok(reply::json(&ImpersonationResponse {
+        token: access.token,
+        token_type: "Bearer",
+        expires_in: access.expires_in,
+    }))
+}
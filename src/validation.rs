@@ -0,0 +1,171 @@
+use crate::{body, config, error::Error, Result};
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use warp::{reject, Filter, Rejection};
+
+const MAX_EMAIL_LENGTH: usize = 254;
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+const MIN_USERNAME_LENGTH: usize = 3;
+const MAX_USERNAME_LENGTH: usize = 30;
+
+/// Messages for each rejected request field, reported back in the error
+/// body.
+pub type FieldErrors = BTreeMap<&'static str, Vec<String>>;
+
+/// Collects every problem with a request so clients can show them all at
+/// once instead of fixing one field per round trip.
+#[derive(Default)]
+pub struct Validator {
+    errors: FieldErrors,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Validator::default()
+    }
+
+    /// Records a problem found by a check of the caller's own.
+    pub fn fail(&mut self, field: &'static str, message: impl Into<String>) {
+        self.errors.entry(field).or_default().push(message.into());
+    }
+
+    /// Returns false (after recording the error) if `value` is blank.
+    pub fn non_empty(&mut self, field: &'static str, value: &str) -> bool {
+        if value.trim().is_empty() {
+            self.fail(field, "must not be empty");
+            return false;
+        }
+        true
+    }
+
+    pub fn max_length(&mut self, field: &'static str, value: &str, max: usize) {
+        if value.chars().count() > max {
+            self.fail(field, format!("must be at most {} characters", max));
+        }
+    }
+
+    pub fn email(&mut self, field: &'static str, value: &str) {
+        if !self.non_empty(field, value) {
+            return;
+        }
+        if value.len() > MAX_EMAIL_LENGTH {
+            self.fail(
+                field,
+                format!("must be at most {} characters", MAX_EMAIL_LENGTH),
+            );
+        } else if !is_valid_email(value) {
+            self.fail(field, "must be a valid email address");
+        }
+    }
+
+    /// 3 to 30 ASCII letters, digits or underscores. Never contains an `@`,
+    /// so a username can't be mistaken for an email at login.
+    pub fn username(&mut self, field: &'static str, value: &str) {
+        if !self.non_empty(field, value) {
+            return;
+        }
+        let length = value.len();
+        if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length) {
+            self.fail(
+                field,
+                format!(
+                    "must be {} to {} characters",
+                    MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH
+                ),
+            );
+        } else if !value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            self.fail(field, "may only contain letters, digits and underscores");
+        }
+    }
+
+    pub fn password(&mut self, field: &'static str, value: &str) {
+        if !self.non_empty(field, value) {
+            return;
+        }
+        let min_length = config::min_password_length();
+        if value.chars().count() < min_length {
+            self.fail(field, format!("must be at least {} characters", min_length));
+        }
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::ValidationError(self.errors))
+        }
+    }
+}
+
+/// A request body that can check its own fields.
+pub trait Validate {
+    fn validate(&self, validator: &mut Validator);
+}
+
+/// Like `body::json`, but also rejects bodies that fail `T`'s
+/// validation with a `ValidationError`.
+pub fn validated_json<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Validate + Send,
+{
+    body::json().and_then(|body: T| async move {
+        validate(&body).map_err(reject::custom)?;
+        Ok::<_, Rejection>(body)
+    })
+}
+
+/// [`validated_json`] for routes that also take form bodies; see
+/// `body::json_or_form`.
+pub fn validated_json_or_form<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Validate + Send,
+{
+    body::json_or_form().and_then(|body: T| async move {
+        validate(&body).map_err(reject::custom)?;
+        Ok::<_, Rejection>(body)
+    })
+}
+
+/// Runs `body`'s validation, for bodies not taken with [`validated_json`].
+pub fn validate<T: Validate>(body: &T) -> Result<()> {
+    let mut validator = Validator::new();
+    body.validate(&mut validator);
+    validator.finish()
+}
+
+/// A pragmatic subset of RFC 5322: a plain dot-atom local part and a
+/// domain of at least two DNS labels. Quoted local parts and IP literals
+/// are deliberately not accepted.
+fn is_valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return false;
+    };
+    is_valid_local_part(local) && is_valid_domain(domain)
+}
+
+fn is_valid_local_part(local: &str) -> bool {
+    const SPECIALS: &str = "!#$%&'*+/=?^_`{|}~-";
+    !local.is_empty()
+        && local.len() <= MAX_LOCAL_PART_LENGTH
+        && local.split('.').all(|atom| {
+            !atom.is_empty()
+                && atom
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || SPECIALS.contains(c))
+        })
+}
+
+fn is_valid_domain(domain: &str) -> bool {
+    let labels: Vec<&str> = domain.split('.').collect();
+    labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && labels
+            .last()
+            .is_some_and(|tld| tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_alphabetic()))
+}
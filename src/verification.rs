@@ -0,0 +1,352 @@
+use crate::{
+    auth::{hash_token, random_token},
+    config,
+    error::Error,
+    mailer::{EmailSender, Mailer},
+    repository::timed,
+    users, Result, User, WebResult,
+};
+use mongodb::{
+    bson::{doc, DateTime},
+    options::IndexOptions,
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+use warp::{http::StatusCode, reject, reply, Reply};
+
+const VERIFICATION_TOKEN_LENGTH: usize = 48;
+/// The least time between two verification emails resent to one address.
+const RESEND_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// The day over which at most [`RESENDS_PER_DAY`] are resent.
+const RESEND_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+const RESENDS_PER_DAY: u32 = 5;
+
+/// The verification emails resent to one address, keyed by the address
+/// whether or not an account has it, so the limit reveals nothing.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VerificationResend {
+    pub email: String,
+    pub last_sent_at: DateTime,
+    /// Resends in the day that ends at `expires_at`, which starts with the
+    /// first of them.
+    pub sent: u32,
+    pub expires_at: DateTime,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ResendVerificationRequest {
+    #[serde(deserialize_with = "crate::users::normalized_email")]
+    pub email: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct VerifyQuery {
+    /// The token from the verification email.
+    pub token: String,
+}
+
+pub async fn create_indexes(
+    collection: &Collection<VerificationResend>,
+) -> mongodb::error::Result<()> {
+    let email_index = IndexModel::builder()
+        .keys(doc! {"email": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    let ttl_index = IndexModel::builder()
+        .keys(doc! {"expires_at": 1})
+        .options(
+            IndexOptions::builder()
+                .expire_after(Duration::from_secs(0))
+                .build(),
+        )
+        .build();
+    collection
+        .create_indexes(vec![email_index, ttl_index], None)
+        .await?;
+    Ok(())
+}
+
+/// Returns a fresh verification token and the hash to store on the user.
+pub fn create_verification_token() -> (String, String) {
+    let token = random_token(VERIFICATION_TOKEN_LENGTH);
+    let token_hash = hash_token(&token);
+    (token, token_hash)
+}
+
+pub async fn send_verification_email(
+    mailer: &dyn EmailSender,
+    email: &str,
+    token: &str,
+) -> Result<()> {
+    let body = format!(
+        "Confirm your email address by visiting {}/verify?token={}",
+        config::api_prefix(),
+        token
+    );
+    mailer.send(email, "Verify your email address", &body).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/verify",
+    tag = "account",
+    params(VerifyQuery),
+    responses(
+        (status = 200, description = "Email verified", body = String),
+        (status = 400, description = "Unknown token", body = ErrorResponse),
+    )
+)]
+pub async fn verify_email_handler(
+    query: VerifyQuery,
+    users_collection: Collection<User>,
+) -> WebResult<impl Reply> {
+    let result = timed(users_collection.update_one(
+        doc! {"verification_token_hash": hash_token(&query.token)},
+        doc! {
+            "$set": {"email_verified": true, "updated_at": DateTime::now()},
+            "$unset": {"verification_token_hash": ""},
+            "$inc": {"version": 1},
+        },
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?;
+
+    if result.matched_count == 0 {
+        return Err(reject::custom(Error::InvalidVerificationTokenError));
+    }
+
+    Ok(reply::with_status(
+        "Email verified successfully",
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/verify/resend",
+    tag = "account",
+    request_body = ResendVerificationRequest,
+    responses(
+        (status = 202, description = "Sent if the account exists and is unverified; the same either way",
+            body = String),
+        (status = 429, description = "Sent to this address too recently or too often today",
+            body = ErrorResponse, headers(("Retry-After" = u64, description = "Seconds to wait"))),
+    )
+)]
+pub async fn resend_verification_handler(
+    mailer: Mailer,
+    users_collection: Collection<User>,
+    resends: Collection<VerificationResend>,
+    body: ResendVerificationRequest,
+) -> WebResult<impl Reply> {
+    record_resend(&resends, &body.email)
+        .await
+        .map_err(reject::custom)?;
+
+    // Replacing the hash invalidates the token from any earlier email.
+    // Verified, deleted and unknown accounts match nothing and get no
+    // email, but the same answer.
+    let (token, token_hash) = create_verification_token();
+    let mut filter = users::active(users::by_email(&body.email));
+    filter.insert("email_verified", false);
+    let user = timed(users_collection.find_one_and_update(
+        filter,
+        doc! {"$set": {"verification_token_hash": token_hash}},
+        None,
+    ))
+    .await
+    .map_err(reject::custom)?;
+    if let Some(user) = user {
+        // Only logged, like a failure for an unknown address would be.
+        if send_verification_email(mailer.as_ref(), &user.email, &token)
+            .await
+            .is_err()
+        {
+            tracing::error!("verification email for {} was not resent", user.uid);
+        }
+    }
+
+    Ok(reply::with_status(
+        "If the account exists and is unverified, a verification email has been sent",
+        StatusCode::ACCEPTED,
+    ))
+}
+
+/// Counts a resend to `email`, or refuses it with `TooManyRequestsError`
+/// when the last one was under [`RESEND_INTERVAL`] ago or the day's
+/// [`RESENDS_PER_DAY`] are used up. Each update only applies to the record
+/// as it was read, so concurrent requests can't both get through.
+async fn record_resend(resends: &Collection<VerificationResend>, email: &str) -> Result<()> {
+    let now = DateTime::now();
+    let Some(previous) = timed(resends.find_one(doc! {"email": email}, None)).await? else {
+        let resend = VerificationResend {
+            email: email.to_string(),
+            last_sent_at: now,
+            sent: 1,
+            expires_at: now.saturating_add_duration(RESEND_WINDOW),
+        };
+        return match timed(resends.insert_one(resend, None)).await {
+            Ok(_) => Ok(()),
+            Err(Error::DuplicateKeyError) => Err(retry_after(RESEND_INTERVAL)),
+            Err(e) => Err(e),
+        };
+    };
+
+    let next_allowed = previous
+        .last_sent_at
+        .saturating_add_duration(RESEND_INTERVAL);
+    if next_allowed > now {
+        return Err(retry_after(until(now, next_allowed)));
+    }
+    let update = if previous.expires_at <= now {
+        doc! {"$set": {
+            "last_sent_at": now,
+            "sent": 1,
+            "expires_at": now.saturating_add_duration(RESEND_WINDOW),
+        }}
+    } else if previous.sent >= RESENDS_PER_DAY {
+        return Err(retry_after(until(now, previous.expires_at)));
+    } else {
+        doc! {"$set": {"last_sent_at": now}, "$inc": {"sent": 1}}
+    };
+    let result = timed(resends.update_one(
+        doc! {"email": email, "last_sent_at": previous.last_sent_at},
+        update,
+        None,
+    ))
+    .await?;
+    if result.matched_count == 0 {
+        return Err(retry_after(RESEND_INTERVAL));
+    }
+    Ok(())
+}
+
+fn until(now: DateTime, then: DateTime) -> Duration {
+    Duration::from_millis((then.timestamp_millis() - now.timestamp_millis()).max(0) as u64)
+}
+
+fn retry_after(wait: Duration) -> Error {
+    Error::TooManyRequestsError {
+        retry_after_secs: wait.as_secs().max(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::Role, test_support};
+    use async_trait::async_trait;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps the bodies of the emails it is given.
+    #[derive(Default)]
+    struct Outbox(Mutex<Vec<String>>);
+
+    #[async_trait]
+    impl EmailSender for Outbox {
+        async fn send(&self, _to: &str, _subject: &str, body: &str) -> Result<()> {
+            self.0.lock().unwrap().push(body.to_string());
+            Ok(())
+        }
+    }
+
+    async fn resends() -> Collection<VerificationResend> {
+        let resends = test_support::database()
+            .await
+            .collection("verification_resends");
+        create_indexes(&resends).await.unwrap();
+        resends
+    }
+
+    /// Moves the `field` of `email`'s record `by` into the past.
+    async fn backdate(
+        resends: &Collection<VerificationResend>,
+        email: &str,
+        field: &str,
+        by: Duration,
+    ) {
+        let record = resends
+            .find_one(doc! {"email": email}, None)
+            .await
+            .unwrap()
+            .unwrap();
+        let then = match field {
+            "last_sent_at" => record.last_sent_at,
+            _ => record.expires_at,
+        };
+        let then = DateTime::from_millis(then.timestamp_millis() - by.as_millis() as i64);
+        resends
+            .update_one(doc! {"email": email}, doc! {"$set": {field: then}}, None)
+            .await
+            .unwrap();
+    }
+
+    fn retry_after_secs(result: Result<()>) -> u64 {
+        match result {
+            Err(Error::TooManyRequestsError { retry_after_secs }) => retry_after_secs,
+            _ => panic!("expected TooManyRequestsError"),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn resends_wait_out_the_interval_and_the_days_allowance() {
+        let resends = resends().await;
+        let email = "a@example.com";
+        record_resend(&resends, email).await.unwrap();
+        let wait = retry_after_secs(record_resend(&resends, email).await);
+        assert!((299..=300).contains(&wait), "{}", wait);
+
+        for _ in 1..RESENDS_PER_DAY {
+            backdate(&resends, email, "last_sent_at", RESEND_INTERVAL).await;
+            record_resend(&resends, email).await.unwrap();
+        }
+        // The day's allowance is used up: the wait is until the day ends,
+        // not just the interval.
+        backdate(&resends, email, "last_sent_at", RESEND_INTERVAL).await;
+        let wait = retry_after_secs(record_resend(&resends, email).await);
+        assert!(wait > RESEND_INTERVAL.as_secs(), "{}", wait);
+
+        // A new day starts the count over.
+        backdate(&resends, email, "expires_at", RESEND_WINDOW).await;
+        record_resend(&resends, email).await.unwrap();
+        let record = resends
+            .find_one(doc! {"email": email}, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.sent, 1);
+        // Other addresses have allowances of their own.
+        record_resend(&resends, "b@example.com").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB at TEST_MONGO_URI"]
+    async fn a_resent_token_replaces_the_previous_one() {
+        let app = test_support::app().await;
+        let (old_token, old_hash) = create_verification_token();
+        let mut user = User::new("a@example.com".to_string(), String::new(), &Role::User);
+        user.email_verified = false;
+        user.verification_token_hash = Some(old_hash);
+        app.users.insert_one(&user, None).await.unwrap();
+
+        let outbox = Arc::new(Outbox::default());
+        let body = ResendVerificationRequest {
+            email: user.email.clone(),
+        };
+        resend_verification_handler(outbox.clone(), app.users.clone(), resends().await, body)
+            .await
+            .unwrap();
+        let sent = outbox.0.lock().unwrap().pop().expect("an email was sent");
+        let new_token = sent.rsplit("token=").next().unwrap().to_string();
+        assert_ne!(new_token, old_token);
+
+        let verify = |token: String| verify_email_handler(VerifyQuery { token }, app.users.clone());
+        assert!(verify(old_token).await.is_err());
+        assert!(verify(new_token).await.is_ok());
+    }
+}
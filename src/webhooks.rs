@@ -0,0 +1,423 @@
+use crate::{auth::Claims, error::Error, repository::timed, request_id, User, WebResult};
+use hmac::{Hmac, Mac};
+use mongodb::{
+    bson::{doc, uuid::Uuid, DateTime},
+    options::{FindOptions, IndexOptions},
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{env, str::FromStr, sync::OnceLock, time::Duration};
+use url::Url;
+use utoipa::{IntoParams, ToSchema};
+use warp::{reject, reply, Reply};
+
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+pub const TIMESTAMP_HEADER: &str = "x-webhook-timestamp";
+const MAX_ATTEMPTS: u32 = 4;
+/// Delay before the first retry, doubled for each one after it.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long delivery attempts are kept for `GET /webhooks/deliveries`.
+const DELIVERY_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Longest receiver error kept with an attempt.
+const MAX_ERROR_LENGTH: usize = 256;
+const DEFAULT_PAGE_LIMIT: u64 = 50;
+const MAX_PAGE_LIMIT: u64 = 200;
+
+static WEBHOOKS: OnceLock<Webhooks> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
+pub enum WebhookEvent {
+    #[serde(rename = "user.signed_up")]
+    UserSignedUp,
+    #[serde(rename = "user.deleted")]
+    UserDeleted,
+    #[serde(rename = "user.role_changed")]
+    UserRoleChanged,
+}
+
+impl WebhookEvent {
+    const ALL: [WebhookEvent; 3] = [
+        WebhookEvent::UserSignedUp,
+        WebhookEvent::UserDeleted,
+        WebhookEvent::UserRoleChanged,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            WebhookEvent::UserSignedUp => "user.signed_up",
+            WebhookEvent::UserDeleted => "user.deleted",
+            WebhookEvent::UserRoleChanged => "user.role_changed",
+        }
+    }
+}
+
+impl FromStr for WebhookEvent {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        WebhookEvent::ALL
+            .into_iter()
+            .find(|event| event.name() == s)
+            .ok_or(())
+    }
+}
+
+/// The account fields a webhook carries: enough to identify the user,
+/// nothing that helps sign in as them.
+#[derive(Serialize)]
+struct WebhookUser {
+    uid: String,
+    email: String,
+    username: Option<String>,
+    role: String,
+    created_at: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Payload {
+    /// Unique per event and the same across retries, so receivers can
+    /// drop duplicates.
+    id: String,
+    event: WebhookEvent,
+    timestamp: Option<String>,
+    user: WebhookUser,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_role: Option<String>,
+}
+
+/// One attempt to deliver an event to one URL.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    pub delivery_id: String,
+    pub event: WebhookEvent,
+    pub url: String,
+    /// 1 for the first try.
+    pub attempt: u32,
+    pub succeeded: bool,
+    /// The receiver's response status, absent when none came back.
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub at: DateTime,
+}
+
+/// Where events are delivered and how they are signed, from the
+/// environment; see [`WebhookSettings::from_env`].
+#[derive(Clone)]
+pub struct WebhookSettings {
+    urls: Vec<Url>,
+    events: Vec<WebhookEvent>,
+    secret: String,
+}
+
+impl WebhookSettings {
+    /// Reads `WEBHOOK_URLS` (comma-separated), `WEBHOOK_SECRET` (required
+    /// with URLs) and `WEBHOOK_EVENTS` (comma-separated event names, default
+    /// all). `None` without `WEBHOOK_URLS`, or when a setting is invalid,
+    /// which is recorded in `problems`.
+    pub(crate) fn from_env(problems: &mut Vec<String>) -> Option<Self> {
+        let urls = env::var("WEBHOOK_URLS").unwrap_or_default();
+        if urls.trim().is_empty() {
+            return None;
+        }
+        let urls: Vec<Url> = urls
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .filter_map(|url| {
+                let parsed = Url::parse(url)
+                    .ok()
+                    .filter(|url| matches!(url.scheme(), "http" | "https"));
+                if parsed.is_none() {
+                    problems.push(format!(
+                        "WEBHOOK_URLS entry {:?} is not an http(s) URL",
+                        url
+                    ));
+                }
+                parsed
+            })
+            .collect();
+        let secret = env::var("WEBHOOK_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty());
+        if secret.is_none() {
+            problems.push("WEBHOOK_SECRET must be set when WEBHOOK_URLS is set".to_string());
+        }
+        let events = match env::var("WEBHOOK_EVENTS") {
+            Ok(events) if !events.trim().is_empty() => events
+                .split(',')
+                .map(str::trim)
+                .filter_map(|name| {
+                    let event = name.parse().ok();
+                    if event.is_none() {
+                        problems.push(format!(
+                            "WEBHOOK_EVENTS entry {:?} is not one of user.signed_up, \
+                             user.deleted, user.role_changed",
+                            name
+                        ));
+                    }
+                    event
+                })
+                .collect(),
+            _ => WebhookEvent::ALL.to_vec(),
+        };
+        Some(WebhookSettings {
+            urls,
+            events,
+            secret: secret?,
+        })
+    }
+}
+
+struct Webhooks {
+    urls: Vec<Url>,
+    events: Vec<WebhookEvent>,
+    secret: String,
+    http: reqwest::Client,
+    deliveries: Collection<WebhookDelivery>,
+}
+
+/// Expires attempts after `DELIVERY_RETENTION` and serves the newest-first
+/// listing.
+pub async fn create_indexes(
+    collection: &Collection<WebhookDelivery>,
+) -> mongodb::error::Result<()> {
+    let index = IndexModel::builder()
+        .keys(doc! {"at": -1})
+        .options(
+            IndexOptions::builder()
+                .expire_after(DELIVERY_RETENTION)
+                .build(),
+        )
+        .build();
+    collection.create_index(index, None).await?;
+    Ok(())
+}
+
+/// Makes [`dispatch`] deliver to the URLs in `settings`, recording attempts
+/// in `deliveries`. Without settings events are dropped.
+pub fn init(settings: Option<WebhookSettings>, deliveries: Collection<WebhookDelivery>) {
+    let Some(WebhookSettings {
+        urls,
+        events,
+        secret,
+    }) = settings
+    else {
+        return;
+    };
+    let http = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("building the webhook HTTP client failed");
+    WEBHOOKS
+        .set(Webhooks {
+            urls,
+            events,
+            secret,
+            http,
+            deliveries,
+        })
+        .ok();
+}
+
+/// Announces `event` about `user` to every configured URL subscribed to
+/// it. The deliveries run in the background, so neither a slow receiver
+/// nor a failing one affects the request that caused the event.
+/// `previous_role` is only sent with `user.role_changed`.
+pub fn dispatch(event: WebhookEvent, user: &User, previous_role: Option<&str>) {
+    let Some(webhooks) = WEBHOOKS.get() else {
+        return;
+    };
+    if !webhooks.events.contains(&event) {
+        return;
+    }
+    let payload = Payload {
+        id: Uuid::new().to_string(),
+        event,
+        timestamp: DateTime::now().try_to_rfc3339_string().ok(),
+        user: WebhookUser {
+            uid: user.uid.clone(),
+            email: user.email.clone(),
+            username: user.username.clone(),
+            role: user.role.clone(),
+            created_at: user
+                .created_at
+                .and_then(|at| at.try_to_rfc3339_string().ok()),
+        },
+        previous_role: previous_role.map(str::to_string),
+    };
+    let body = serde_json::to_string(&payload).expect("webhook payloads serialize");
+    for url in &webhooks.urls {
+        let url = url.clone();
+        let body = body.clone();
+        let delivery_id = payload.id.clone();
+        request_id::spawn(async move { deliver(webhooks, url, body, event, delivery_id).await });
+    }
+}
+
+/// `sha256=` and the hex HMAC-SHA256, keyed with `WEBHOOK_SECRET`, of the
+/// timestamp header, a `.` and the body. Receivers compute the same and
+/// compare, and reject old timestamps to stop replays.
+fn signature(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+async fn deliver(
+    webhooks: &Webhooks,
+    url: Url,
+    body: String,
+    event: WebhookEvent,
+    delivery_id: String,
+) {
+    for attempt in 1..=MAX_ATTEMPTS {
+        let timestamp = chrono::Utc::now().timestamp();
+        let result = webhooks
+            .http
+            .post(url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(
+                SIGNATURE_HEADER,
+                signature(&webhooks.secret, timestamp, &body),
+            )
+            .body(body.clone())
+            .send()
+            .await;
+        let (status_code, error) = match &result {
+            Ok(response) if response.status().is_success() => (Some(response.status()), None),
+            Ok(response) => (Some(response.status()), Some(response.status().to_string())),
+            Err(e) => (e.status(), Some(e.to_string())),
+        };
+        let succeeded = error.is_none();
+        let record = WebhookDelivery {
+            delivery_id: delivery_id.clone(),
+            event,
+            url: url.to_string(),
+            attempt,
+            succeeded,
+            status_code: status_code.map(|status| status.as_u16()),
+            error: error.map(|e| e.chars().take(MAX_ERROR_LENGTH).collect()),
+            at: DateTime::now(),
+        };
+        if let Err(e) = webhooks.deliveries.insert_one(&record, None).await {
+            tracing::error!("recording a webhook delivery failed: {}", e);
+        }
+        if succeeded {
+            return;
+        }
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(FIRST_RETRY_DELAY * 2u32.pow(attempt - 1)).await;
+        }
+    }
+    tracing::warn!(
+        "giving up on the {} webhook to {} after {} attempts",
+        event.name(),
+        url,
+        MAX_ATTEMPTS
+    );
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeliveriesQuery {
+    /// Starting at 1 (the default).
+    pub page: Option<u64>,
+    /// Attempts per page, at most 200 (default 50).
+    pub limit: Option<u64>,
+}
+
+/// A `WebhookDelivery` as `GET /webhooks/deliveries` shows it.
+#[derive(Serialize, ToSchema)]
+pub struct DeliveryEntry {
+    pub delivery_id: String,
+    pub event: WebhookEvent,
+    pub url: String,
+    pub attempt: u32,
+    pub succeeded: bool,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub at: Option<String>,
+}
+
+impl From<WebhookDelivery> for DeliveryEntry {
+    fn from(delivery: WebhookDelivery) -> Self {
+        DeliveryEntry {
+            delivery_id: delivery.delivery_id,
+            event: delivery.event,
+            url: delivery.url,
+            attempt: delivery.attempt,
+            succeeded: delivery.succeeded,
+            status_code: delivery.status_code,
+            error: delivery.error,
+            at: delivery.at.try_to_rfc3339_string().ok(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DeliveryPage {
+    pub deliveries: Vec<DeliveryEntry>,
+    pub page: u64,
+    pub limit: u64,
+    pub total: u64,
+    pub next_page: Option<u64>,
+}
+
+/// Recent delivery attempts, newest first, kept for 7 days.
+#[utoipa::path(
+    get,
+    path = "/webhooks/deliveries",
+    tag = "users",
+    params(DeliveriesQuery),
+    responses(
+        (status = 200, description = "One page of delivery attempts", body = DeliveryPage),
+        (status = 400, description = "`page` or `limit` out of range", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn list_deliveries_handler(
+    _claims: Claims,
+    deliveries: Collection<WebhookDelivery>,
+    query: DeliveriesQuery,
+) -> WebResult<impl Reply> {
+    let page = query.page.unwrap_or(1);
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if page == 0 || limit == 0 || limit > MAX_PAGE_LIMIT {
+        return Err(reject::custom(Error::InvalidPaginationError));
+    }
+
+    let total = timed(deliveries.count_documents(None, None))
+        .await
+        .map_err(reject::custom)?;
+
+    let options = FindOptions::builder()
+        .sort(doc! {"at": -1})
+        .skip((page - 1) * limit)
+        .limit(limit as i64)
+        .build();
+    let mut cursor = timed(deliveries.find(None, options))
+        .await
+        .map_err(reject::custom)?;
+
+    let mut entries = Vec::new();
+    while timed(cursor.advance()).await.map_err(reject::custom)? {
+        let delivery: WebhookDelivery = cursor
+            .deserialize_current()
+            .map_err(|e| reject::custom(Error::from(e)))?;
+        entries.push(DeliveryEntry::from(delivery));
+    }
+
+    Ok(reply::json(&DeliveryPage {
+        deliveries: entries,
+        page,
+        limit,
+        total,
+        next_page: (page * limit < total).then_some(page + 1),
+    }))
+}